@@ -0,0 +1,103 @@
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use msgpack_tracing::{
+    bench::{drive, sample_tape},
+    printer::Printer,
+    restart::RestartableMachine,
+    rotate::Rotate,
+    storage::{Load, Store},
+    string_cache::{StringCache, StringUncache},
+};
+use std::io;
+
+const EVENTS: usize = 1000;
+
+fn encode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    group.throughput(Throughput::Elements(EVENTS as u64));
+
+    group.bench_function("store", |b| {
+        b.iter(|| drive(&mut Store::new(io::sink()), EVENTS))
+    });
+    group.bench_function("store+checksums", |b| {
+        b.iter(|| drive(&mut Store::new(io::sink()).with_checksums(true), EVENTS))
+    });
+    group.finish();
+}
+
+fn cache(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cache");
+    group.throughput(Throughput::Elements(EVENTS as u64));
+
+    group.bench_function("string_cache", |b| {
+        b.iter(|| drive(&mut StringCache::new(Store::new(io::sink())), EVENTS))
+    });
+    group.bench_function("string_cache+restartable", |b| {
+        b.iter(|| {
+            drive(
+                &mut RestartableMachine::new(StringCache::new(Store::new(io::sink()))),
+                EVENTS,
+            )
+        })
+    });
+    group.finish();
+}
+
+fn rotate(c: &mut Criterion) {
+    let dir = std::env::temp_dir().join(format!("bench-rotate-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("app.log");
+
+    let mut group = c.benchmark_group("rotate");
+    group.throughput(Throughput::Elements(EVENTS as u64));
+
+    group.bench_function("rotate", |b| {
+        b.iter(|| {
+            let rotate = Rotate::new(&path, u64::MAX).unwrap();
+            drive(
+                &mut RestartableMachine::new(StringCache::new(rotate)),
+                EVENTS,
+            )
+        })
+    });
+    group.bench_function("rotate+index", |b| {
+        b.iter(|| {
+            let rotate = Rotate::new(&path, u64::MAX)
+                .unwrap()
+                .with_index(64 * 1024)
+                .unwrap();
+            drive(
+                &mut RestartableMachine::new(StringCache::new(rotate)),
+                EVENTS,
+            )
+        })
+    });
+    group.finish();
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+fn printer(c: &mut Criterion) {
+    let tape = sample_tape(EVENTS);
+
+    let mut group = c.benchmark_group("printer");
+    group.throughput(Throughput::Elements(EVENTS as u64));
+
+    for color in [false, true] {
+        let name = match color {
+            true => "colored",
+            false => "plain",
+        };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut printer = StringUncache::new(Printer::new(io::sink(), color));
+                Load::new(tape.as_slice())
+                    .forward_cached(&mut printer)
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, encode, cache, rotate, printer);
+criterion_main!(benches);
@@ -0,0 +1,22 @@
+//! Receives tapes streamed by `SocketStore` and writes them into a single file.
+//!
+//! ```shell
+//! cargo run --example receiver -- 0.0.0.0:7000 received.log
+//! ```
+
+use msgpack_tracing::{
+    receiver::Receiver, restart::RestartableMachine, rotate::Rotate, string_cache::StringCache,
+};
+use std::net::TcpListener;
+
+fn main() -> std::io::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let (Some(address), Some(path)) = (args.next(), args.next()) else {
+        eprintln!("Usage: receiver ADDRESS FILE");
+        std::process::exit(1);
+    };
+
+    let rotate = Rotate::new(&path, 100_000_000)?;
+    let receiver = Receiver::new(RestartableMachine::new(StringCache::new(rotate)));
+    receiver.serve_tcp(TcpListener::bind(address)?)
+}
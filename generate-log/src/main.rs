@@ -22,7 +22,7 @@ fn main() {
     }
 }
 
-fn install_logger(path: &str, rotate: Option<u64>) {
+fn install_logger(path: &str, rotate: Option<u64>) -> msgpack_tracing::LoggerHandle {
     match rotate {
         Some(max_len) => msgpack_tracing::install_rotate_logger(
             path,
@@ -30,12 +30,10 @@ fn install_logger(path: &str, rotate: Option<u64>) {
             msgpack_tracing::WithConsole::AnsiColors,
         )
         .unwrap(),
-        None => {
-            msgpack_tracing::install_logger(
-                File::create(path).unwrap(),
-                msgpack_tracing::WithConsole::AnsiColors,
-            );
-        }
+        None => msgpack_tracing::install_logger(
+            File::create(path).unwrap(),
+            msgpack_tracing::WithConsole::AnsiColors,
+        ),
     }
 }
 
@@ -1,44 +1,1013 @@
+use chrono::{DateTime, Local, Utc};
 use msgpack_tracing::{
-    printer::Printer, storage::Load, string_cache::StringUncache, tape::TapeMachine,
+    annotation::Annotations,
+    bundle::{BUNDLE_EXTENSION, Bundle},
+    cardinality::Cardinality,
+    chrome_trace::ChromeTrace,
+    flamegraph::FoldedStacks,
+    follow::FollowLoad,
+    level_filter::{LevelFilter, TargetFilter},
+    merge::Merge,
+    printer::{FieldFilter, FieldOrder, NewEvent, Printer, Separator, SpanFilter},
+    query::Query,
+    receiver::SplitSources,
+    repair, rotate,
+    slice::Slice,
+    span_histogram::SpanDurations,
+    stats::TapeStats,
+    storage::{Load, Store},
+    string_cache::{CacheInstruction, CacheInstructionSet, CacheStats, StringCache, StringUncache},
+    tape::TapeMachine,
+    time_index::{build_index, index_path, span_index_path},
+    time_range::{TimeRangeFilter, parse_time},
+    verify::verify,
 };
-use std::{fs::File, io};
+use regex::Regex;
+use std::{
+    fs::File,
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::Level;
+
+#[derive(Default)]
+struct Options {
+    color: bool,
+    threads: bool,
+    span_close: bool,
+    span_filter: Option<SpanFilter>,
+    target_filter: Option<TargetFilter>,
+    field_filter: Vec<FieldFilter>,
+    query: Option<Query>,
+    separator: Option<Separator>,
+    field_order: Option<FieldOrder>,
+    cache_stats: bool,
+    tail: Option<usize>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    level: Option<Level>,
+    follow: bool,
+    json: bool,
+    slice: Option<PathBuf>,
+    chrome_trace: Option<PathBuf>,
+    repair: Option<PathBuf>,
+    bundle: Option<PathBuf>,
+    split_sources: Option<PathBuf>,
+    grep: Option<Regex>,
+}
 
 fn main() {
-    let mut color = atty::is(atty::Stream::Stdout);
+    let args = match parse_args(
+        std::env::args().skip(1),
+        atty::is(atty::Stream::Stdout),
+        !atty::is(atty::Stream::Stdin),
+    ) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    if let Some(out) = args.options.slice.as_deref()
+        && let Err(e) = File::create(out)
+    {
+        eprintln!("Error creating {}: {e}", out.display());
+        std::process::exit(1);
+    }
+
+    let Args {
+        options,
+        mode,
+        paths,
+    } = &args;
+    let ok = match args.merged() {
+        true => {
+            merge_logs(paths, options);
+            true
+        }
+        false => {
+            let mut ok = true;
+            for path in paths {
+                ok &= load_path(path, *mode, options);
+            }
+            ok
+        }
+    };
+    if !ok && *mode == Mode::Verify {
+        std::process::exit(1);
+    }
+}
+
+/// Command line of the printer.
+struct Args {
+    options: Options,
+    mode: Mode,
+    paths: Vec<String>,
+}
+
+/// Parses the arguments, without the program name. Without files, the standard input is read
+/// when it is piped.
+fn parse_args<I>(args: I, color: bool, stdin_piped: bool) -> Result<Args, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut options = Options {
+        color,
+        ..Default::default()
+    };
+    let mut mode = Mode::Print;
+    let mut paths = Vec::new();
 
-    for arg in std::env::args().skip(1) {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
         match arg.as_str() {
-            "--color" | "-c" => color = true,
-            "--no-color" => color = false,
-            path => {
-                if let Err(e) = print_log(path, color) {
-                    eprintln!("Error loading {path}: {e}");
-                    eprintln!("{e:?}");
+            "--color" | "-c" => options.color = true,
+            "--no-color" => options.color = false,
+            "--cache-stats" => options.cache_stats = true,
+            "--threads" => options.threads = true,
+            "--span-close" => options.span_close = true,
+            "--follow" | "-f" => options.follow = true,
+            "--json" => options.json = true,
+            "--span-histogram" => mode = mode.switch(Mode::SpanHistogram)?,
+            "--flamegraph" => mode = mode.switch(Mode::Flamegraph)?,
+            "--stats" => mode = mode.switch(Mode::Stats)?,
+            "--lint" => mode = mode.switch(Mode::Lint)?,
+            "--verify" => mode = mode.switch(Mode::Verify)?,
+            "--index" => mode = mode.switch(Mode::Index)?,
+            "--span" => match args.next().map(|filter| filter.parse::<SpanFilter>()) {
+                Some(Ok(filter)) => options.span_filter = Some(filter),
+                Some(Err(e)) => return Err(format!("Invalid --span filter: {e}")),
+                None => return Err("--span expects a span name, optionally with fields".into()),
+            },
+            "--target" => match args.next() {
+                Some(target) => options.target_filter = Some(TargetFilter::new(&target)),
+                None => return Err("--target expects a target or module prefix".into()),
+            },
+            "--field" => match args.next().map(|filter| filter.parse::<FieldFilter>()) {
+                Some(Ok(filter)) => options.field_filter.push(filter),
+                Some(Err(e)) => return Err(format!("Invalid --field filter: {e}")),
+                None => return Err("--field expects field=value".into()),
+            },
+            "--query" => match args.next().map(|query| query.parse::<Query>()) {
+                Some(Ok(query)) => options.query = Some(query),
+                Some(Err(e)) => return Err(format!("Invalid --query: {e}")),
+                None => return Err("--query expects a filter expression".into()),
+            },
+            "--separator" => match args.next().map(|separator| separator.parse::<Separator>()) {
+                Some(Ok(separator)) => options.separator = Some(separator),
+                Some(Err(e)) => return Err(format!("Invalid --separator: {e}")),
+                None => return Err("--separator expects day or hour".into()),
+            },
+            "--sort-fields" => match args.next().map(|order| order.parse::<FieldOrder>()) {
+                Some(Ok(order)) => options.field_order = Some(order),
+                Some(Err(e)) => return Err(format!("Invalid --sort-fields: {e}")),
+                None => return Err("--sort-fields expects alpha or a list of fields".into()),
+            },
+            "--tail" | "-n" => {
+                options.tail = args.next().and_then(|n| n.parse().ok());
+                if options.tail.is_none() {
+                    return Err("--tail expects the number of events".into());
                 }
             }
+            "--level" | "-l" => match args.next().map(|level| level.parse::<Level>()) {
+                Some(Ok(level)) => options.level = Some(level),
+                Some(Err(e)) => return Err(format!("Invalid --level: {e}")),
+                None => return Err("--level expects error, warn, info, debug or trace".into()),
+            },
+            "--since" | "--until" => {
+                match args.next().map(|time| parse_time(&time, Local::now())) {
+                    Some(Ok(time)) if arg == "--since" => options.since = Some(time),
+                    Some(Ok(time)) => options.until = Some(time),
+                    Some(Err(e)) => return Err(format!("Invalid {arg}: {e}")),
+                    None => {
+                        return Err(format!(
+                            "{arg} expects a time, a date or a duration like 2h"
+                        ));
+                    }
+                }
+            }
+            "--slice" => match args.next() {
+                Some(out) => options.slice = Some(out.into()),
+                None => return Err("--slice expects the output file".into()),
+            },
+            "--chrome-trace" => match args.next() {
+                Some(out) => options.chrome_trace = Some(out.into()),
+                None => return Err("--chrome-trace expects the output file".into()),
+            },
+            "--repair" => match args.next() {
+                Some(out) => options.repair = Some(out.into()),
+                None => return Err("--repair expects the output file".into()),
+            },
+            "--bundle" => match args.next() {
+                Some(out) => options.bundle = Some(out.into()),
+                None => return Err("--bundle expects the output file".into()),
+            },
+            "--split-sources" => match args.next() {
+                Some(dir) => options.split_sources = Some(dir.into()),
+                None => return Err("--split-sources expects the output directory".into()),
+            },
+            "--grep" => match args.next().map(|regex| Regex::new(&regex)) {
+                Some(Ok(regex)) => options.grep = Some(regex),
+                Some(Err(e)) => return Err(format!("Invalid --grep: {e}")),
+                None => return Err("--grep expects a regular expression".into()),
+            },
+            path => paths.push(path.to_owned()),
+        }
+    }
+    if paths.is_empty() && stdin_piped {
+        paths.push(STDIN.to_owned());
+    }
+
+    let args = Args {
+        options,
+        mode,
+        paths,
+    };
+    args.check()?;
+    Ok(args)
+}
+
+impl Args {
+    /// Whether several files are printed interleaved by time.
+    fn merged(&self) -> bool {
+        self.paths.len() > 1
+            && self.mode == Mode::Print
+            && self.options.outputs().is_empty()
+            && !self.paths.iter().any(|path| path == STDIN)
+    }
+
+    /// Rejects flags that would be ignored by the others.
+    fn check(&self) -> Result<(), String> {
+        let options = &self.options;
+        let outputs = options.outputs();
+        if let [first, second, ..] = outputs[..] {
+            return Err(conflict(first, second));
+        }
+        let report = match (self.mode, outputs.first()) {
+            (Mode::Print, None) => None,
+            (Mode::Print, Some(output)) => Some(*output),
+            (mode, None) => Some(mode.flag()),
+            (mode, Some(output)) => return Err(conflict(mode.flag(), output)),
+        };
+
+        for (flag, given) in [
+            ("--tail", options.tail.is_some()),
+            ("--follow", options.follow),
+        ] {
+            if !given {
+                continue;
+            }
+            if let Some(other) = report {
+                return Err(conflict(flag, other));
+            }
+            if self.merged() {
+                return Err(format!("{flag} expects a single file"));
+            }
+        }
+        if let Some(other) = report {
+            // Only --slice filters the events, and no report nor output formats them
+            let mut ignored = options.formats();
+            if other != "--slice" {
+                ignored.extend(options.filters());
+            }
+            if let Some(flag) = ignored.first() {
+                return Err(conflict(flag, other));
+            }
+        }
+        if options.tail.is_some() && options.since.is_some() {
+            return Err(conflict("--tail", "--since"));
+        }
+        if options.follow
+            && let Some(path) = self.paths.iter().find(|path| !followable(path))
+        {
+            return Err(format!("--follow expects a tape file, not {path}"));
+        }
+
+        Ok(())
+    }
+}
+
+fn conflict(flag: &str, other: &str) -> String {
+    format!("{flag} can't be combined with {other}")
+}
+
+/// Whether `path` can grow while it is printed. The standard input is read until it is closed
+/// anyway, and bundles are never appended to.
+fn followable(path: &str) -> bool {
+    path != STDIN && Path::new(path).extension() != Some(BUNDLE_EXTENSION.as_ref())
+}
+
+impl Options {
+    /// Flags writing to a file instead of printing the events.
+    fn outputs(&self) -> Vec<&'static str> {
+        given([
+            ("--slice", self.slice.is_some()),
+            ("--chrome-trace", self.chrome_trace.is_some()),
+            ("--repair", self.repair.is_some()),
+            ("--split-sources", self.split_sources.is_some()),
+            ("--bundle", self.bundle.is_some()),
+        ])
+    }
+
+    /// Flags choosing the events that are printed.
+    fn filters(&self) -> Vec<&'static str> {
+        given([
+            ("--span", self.span_filter.is_some()),
+            ("--target", self.target_filter.is_some()),
+            ("--field", !self.field_filter.is_empty()),
+            ("--query", self.query.is_some()),
+            ("--grep", self.grep.is_some()),
+            ("--level", self.level.is_some()),
+            ("--since", self.since.is_some()),
+            ("--until", self.until.is_some()),
+        ])
+    }
+
+    /// Flags changing how the events are printed.
+    fn formats(&self) -> Vec<&'static str> {
+        given([
+            ("--threads", self.threads),
+            ("--span-close", self.span_close),
+            ("--separator", self.separator.is_some()),
+            ("--sort-fields", self.field_order.is_some()),
+            ("--json", self.json),
+            ("--cache-stats", self.cache_stats),
+        ])
+    }
+}
+
+fn given<const N: usize>(flags: [(&'static str, bool); N]) -> Vec<&'static str> {
+    flags
+        .into_iter()
+        .filter_map(|(flag, given)| given.then_some(flag))
+        .collect()
+}
+
+/// Path reading from the standard input.
+const STDIN: &str = "-";
+
+/// Report printed instead of the events.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Print,
+    SpanHistogram,
+    Flamegraph,
+    Stats,
+    Lint,
+    Verify,
+    Index,
+}
+impl Mode {
+    /// Flag selecting the mode.
+    fn flag(self) -> &'static str {
+        match self {
+            Mode::Print => "",
+            Mode::SpanHistogram => "--span-histogram",
+            Mode::Flamegraph => "--flamegraph",
+            Mode::Stats => "--stats",
+            Mode::Lint => "--lint",
+            Mode::Verify => "--verify",
+            Mode::Index => "--index",
+        }
+    }
+
+    /// Switches to `mode`, unless another one was already asked for.
+    fn switch(self, mode: Mode) -> Result<Mode, String> {
+        match self {
+            Mode::Print => Ok(mode),
+            current => Err(conflict(current.flag(), mode.flag())),
+        }
+    }
+}
+
+/// Loads `path` as asked by `mode`, returning whether it succeeded.
+fn load_path(path: &str, mode: Mode, options: &Options) -> bool {
+    let result = match path {
+        path if mode == Mode::Index => write_index(path),
+        STDIN if mode == Mode::Verify => print_verify(Load::new(io::stdin().lock())),
+        path if mode == Mode::Verify => Load::open(path).and_then(print_verify),
+        STDIN if mode == Mode::Lint => print_lint(Load::new(io::stdin().lock())),
+        path if mode == Mode::Lint => Load::open(path).and_then(print_lint),
+        STDIN if mode == Mode::SpanHistogram => print_span_histogram(Load::new(io::stdin().lock())),
+        path if mode == Mode::SpanHistogram => Load::open(path).and_then(print_span_histogram),
+        STDIN if mode == Mode::Flamegraph => print_flamegraph(Load::new(io::stdin().lock())),
+        path if mode == Mode::Flamegraph => Load::open(path).and_then(print_flamegraph),
+        STDIN if mode == Mode::Stats => print_stats(Load::new(io::stdin().lock())),
+        path if mode == Mode::Stats => Load::open(path).and_then(print_stats),
+        STDIN if let Some(out) = options.slice.as_deref() => {
+            slice_log(Load::new(io::stdin().lock()), out, options)
+        }
+        path if let Some(out) = options.slice.as_deref() => {
+            Load::open(path).and_then(|load| slice_log(load, out, options))
+        }
+        STDIN if let Some(out) = options.chrome_trace.as_deref() => {
+            write_chrome_trace(Load::new(io::stdin().lock()), out)
+        }
+        path if let Some(out) = options.chrome_trace.as_deref() => {
+            Load::open(path).and_then(|load| write_chrome_trace(load, out))
+        }
+        STDIN if let Some(out) = options.repair.as_deref() => repair_log(io::stdin().lock(), out),
+        path if let Some(out) = options.repair.as_deref() => {
+            File::open(path).and_then(|file| repair_log(file, out))
+        }
+        STDIN if let Some(dir) = options.split_sources.as_deref() => {
+            split_sources(Load::new(io::stdin().lock()), dir)
+        }
+        path if let Some(dir) = options.split_sources.as_deref() => {
+            Load::open(path).and_then(|load| split_sources(load, dir))
+        }
+        path if let Some(out) = options.bundle.as_deref() => write_bundle(path, out),
+        path if Path::new(path).extension() == Some(BUNDLE_EXTENSION.as_ref()) => {
+            print_bundle(path, options)
+        }
+        path => print_log(path, options),
+    };
+
+    if let Err(e) = &result {
+        eprintln!("Error loading {path}: {e}");
+        eprintln!("{e:?}");
+    }
+    result.is_ok()
+}
+
+type PrintMachine = TimeRangeFilter<LevelFilter<StringUncache<Printer<io::Stdout>>>>;
+
+fn print_machine(options: &Options) -> PrintMachine {
+    let mut printer = Printer::new(std::io::stdout(), options.color)
+        .with_threads(options.threads)
+        .with_span_close(options.span_close)
+        .with_json(options.json);
+    if let Some(span_filter) = options.span_filter.clone() {
+        printer = printer.with_span_filter(span_filter);
+    }
+    if let Some(target_filter) = options.target_filter.clone() {
+        printer = printer.with_target_filter(target_filter);
+    }
+    for field_filter in options.field_filter.iter() {
+        printer = printer.with_field_filter(field_filter.clone());
+    }
+    if let Some(query) = options.query.clone() {
+        printer = printer.with_query(query);
+    }
+    if let Some(grep) = options.grep.clone() {
+        printer = printer.with_event_filter(Box::new(move |event, _| grep_event(&grep, event)));
+    }
+    if let Some(separator) = options.separator {
+        printer = printer.with_separator(separator);
+    }
+    if let Some(field_order) = options.field_order.clone() {
+        printer = printer.with_field_order(field_order);
+    }
+    TimeRangeFilter::new(
+        LevelFilter::new(
+            StringUncache::new(printer),
+            options.level.unwrap_or(Level::TRACE),
+        ),
+        options.since,
+        options.until,
+    )
+}
+
+fn print_log(path: &str, options: &Options) -> io::Result<()> {
+    let mut printer = print_machine(options);
+
+    if path == STDIN {
+        // Read as it is piped, which also follows it
+        let stdin = io::stdin().lock();
+        match options.tail {
+            Some(n) => {
+                // Spooled to a file, so that only the last events are read back
+                let spool = std::env::temp_dir()
+                    .join(format!("msgpack-tracing-tail-{}", std::process::id()));
+                let result = spool_stdin(stdin, &spool)
+                    .and_then(|file| Load::new(file).last_events(n, &mut printer));
+                let _ = std::fs::remove_file(&spool);
+                result?;
+            }
+            None => forward_all(&mut Load::new(stdin), &mut printer, &Default::default()),
+        }
+        if options.cache_stats {
+            print_cache_stats(path, printer.into_inner().into_inner().stats());
         }
+        return Ok(());
     }
+    let mut load = Load::open(path)?;
+    let annotations = Annotations::open(Path::new(path))?;
+
+    if let Some(n) = options.tail {
+        load.last_events(n, &mut printer)?;
+    } else if !options.follow {
+        if let Some(since) = options.since {
+            load.seek_to_time(since)?;
+        }
+        forward_all(&mut load, &mut printer, &annotations);
+    }
+
+    if options.follow {
+        let mut load = FollowLoad::new(path, load);
+        loop {
+            if let Err(e) = load.poll(&mut printer) {
+                skip_error(&e);
+                load.restart();
+                continue;
+            }
+            std::thread::sleep(FOLLOW_INTERVAL);
+        }
+    }
+
+    if options.cache_stats {
+        print_cache_stats(path, printer.into_inner().into_inner().stats());
+    }
+
+    Ok(())
 }
 
-fn print_log(path: &str, color: bool) -> io::Result<()> {
-    let mut printer = StringUncache::new(Printer::new(std::io::stdout(), color));
-    let mut load = Load::new(File::open(path)?);
+/// Copies `stdin` into a new file at `path`, rewound to be read back.
+fn spool_stdin<R: Read>(mut stdin: R, path: &Path) -> io::Result<File> {
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    io::copy(&mut stdin, &mut file)?;
+    file.rewind()?;
+    Ok(file)
+}
 
+/// Prints the events of several tapes interleaved by time, tagged with their file. Rotated files
+/// are tagged with the file they were rotated from, as segments of its tape.
+fn merge_logs(paths: &[String], options: &Options) {
+    let mut merge = Merge::new(StringCache::new(print_machine(options)));
+    for path in paths {
+        match Load::open(path) {
+            Ok(load) => {
+                let source = rotate::rotated_from(Path::new(path));
+                merge = merge.with_source(&source.to_string_lossy(), load);
+            }
+            Err(e) => {
+                eprintln!("Error loading {path}: {e}");
+                eprintln!("{e:?}");
+            }
+        }
+    }
+    merge.run(|path, e| {
+        eprintln!("In {path}:");
+        skip_error(&e);
+    });
+}
+
+/// Prints the tape of a bundle, with its annotations.
+fn print_bundle(path: &str, options: &Options) -> io::Result<()> {
+    let bundle = Bundle::open(path)?;
+    let mut load = bundle.load()?;
+    let mut printer = print_machine(options);
+
+    if let Some(n) = options.tail {
+        load.last_events(n, &mut printer)?;
+    } else {
+        if let Some(since) = options.since {
+            load.seek_to_time(since)?;
+        }
+        forward_all(&mut load, &mut printer, bundle.annotations());
+    }
+
+    if options.cache_stats {
+        print_cache_stats(path, printer.into_inner().into_inner().stats());
+    }
+
+    Ok(())
+}
+
+/// Packages the tape at `path`, with its index and annotations, into the `--bundle` file.
+fn write_bundle(path: &str, out: &Path) -> io::Result<()> {
+    let out = File::create(out)?;
+    Bundle::create(path)?.write(out)?;
+    Ok(())
+}
+
+/// Appends the events of `load` matching the filters of the printer, like `--span`, `--query`
+/// or `--since`, with their spans, to the `--slice` file.
+fn slice_log<R: Read>(mut load: Load<R>, out: &Path, options: &Options) -> io::Result<()> {
+    let out = File::options().append(true).open(out)?;
+    let span_filter = options.span_filter.clone();
+    let target_filter = options.target_filter.clone();
+    let field_filter = options.field_filter.clone();
+    let query = options.query.clone();
+    let grep = options.grep.clone();
+    let slice = Slice::new(
+        StringCache::new(Store::new(out)),
+        Box::new(move |event, spans| {
+            span_filter
+                .as_ref()
+                .is_none_or(|filter| spans.iter().any(|span| filter.matches(span)))
+                && target_filter
+                    .as_ref()
+                    .is_none_or(|filter| filter.matches(&event.target))
+                && field_filter
+                    .iter()
+                    .all(|filter| filter.matches_event(event, spans))
+                && query
+                    .as_ref()
+                    .is_none_or(|query| query.matches(event, spans))
+                && grep.as_ref().is_none_or(|grep| grep_event(grep, event))
+        }),
+    );
+    let mut slice = TimeRangeFilter::new(
+        LevelFilter::new(
+            StringUncache::new(slice),
+            options.level.unwrap_or(Level::TRACE),
+        ),
+        options.since,
+        options.until,
+    );
+    forward_all(&mut load, &mut slice, &Default::default());
+    slice.flush();
+
+    Ok(())
+}
+
+/// Writes the events of a tape of a `Receiver` into a tape per source, in the `--split-sources`
+/// directory. Characters of the source that can't be in a file name are replaced by `_`.
+fn split_sources<R: Read>(mut load: Load<R>, dir: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let dir = dir.to_owned();
+    let split = SplitSources::new(move |source: &str| {
+        let name = source.replace(['/', '\\', ':'], "_");
+        let path = dir.join(format!("{name}.log"));
+        let out: Box<dyn Write + Send> = match File::create(&path) {
+            Ok(file) => Box::new(file),
+            Err(e) => {
+                eprintln!("Error creating {}: {e}", path.display());
+                Box::new(io::sink())
+            }
+        };
+        StringCache::new(Store::new(out))
+    });
+    let mut split = StringUncache::new(split);
+    forward_all(&mut load, &mut split, &Default::default());
+    for tape in split.into_inner().into_sources().values_mut() {
+        tape.flush();
+    }
+
+    Ok(())
+}
+
+/// Whether `--grep` matches the message or any other field value of `event`.
+fn grep_event(grep: &Regex, event: &NewEvent) -> bool {
+    event
+        .records
+        .iter()
+        .filter_map(|record| FieldFilter::value_text(&record.value))
+        .any(|text| grep.is_match(&text))
+}
+
+/// Converts the spans of `load` into the `--chrome-trace` file.
+fn write_chrome_trace<R: Read>(mut load: Load<R>, out: &Path) -> io::Result<()> {
+    let out = io::BufWriter::new(File::create(out)?);
+    let mut trace = StringUncache::new(ChromeTrace::new(out));
+    forward_all(&mut load, &mut trace, &Default::default());
+    trace.into_inner().finish()?;
+
+    Ok(())
+}
+
+/// Writes the index and span index of the tape at `path`, for `--since`.
+fn write_index(path: &str) -> io::Result<()> {
+    if path == STDIN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only files can be indexed",
+        ));
+    }
+
+    let index = build_index(Load::open(path)?);
+    index.write(io::BufWriter::new(File::create(index_path(path.as_ref()))?))?;
+    index.write_spans(io::BufWriter::new(File::create(span_index_path(
+        path.as_ref(),
+    ))?))?;
+    eprintln!(
+        "Indexed {} restarts and {} spans of {path}",
+        index.entries.len(),
+        index.spans.len()
+    );
+
+    Ok(())
+}
+
+/// Copies the tape of `input` into the `--repair` file, without its undecodable regions.
+fn repair_log<R: Read>(input: R, out: &Path) -> io::Result<()> {
+    let out = io::BufWriter::new(File::create(out)?);
+    let (_, skipped) = repair::repair(input, out)?;
+    for region in skipped.iter() {
+        eprintln!(
+            "Skipped {} bytes at offset {}",
+            region.end - region.start,
+            region.start
+        );
+    }
+
+    Ok(())
+}
+
+/// Forwards every instruction, with the annotations of each event, skipping to the next
+/// `Restart` on errors.
+fn forward_all<R, T>(load: &mut Load<R>, machine: &mut T, annotations: &Annotations)
+where
+    R: Read,
+    T: TapeMachine<CacheInstructionSet>,
+{
+    let mut event = 0;
     loop {
         let instruction = match load.fetch_one_cached() {
             Ok(Some(instruction)) => instruction,
             Ok(None) => break,
             Err(e) => {
-                eprintln!("Error loading instruction: {e}");
-                eprintln!("{e:?}");
-                eprintln!("Skipping to next Restart instruction");
+                skip_error(&e);
                 load.restart();
                 continue;
             }
         };
 
-        printer.handle(instruction);
+        let start_event = matches!(instruction, CacheInstruction::StartEvent { .. });
+        if let CacheInstruction::FinishedEvent = instruction {
+            annotations.forward(event, machine);
+        }
+        machine.handle(instruction);
+        if start_event {
+            event = load.last_offset();
+        }
     }
+}
+
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
+fn skip_error(e: &io::Error) {
+    eprintln!("Error loading instruction: {e}");
+    eprintln!("{e:?}");
+    eprintln!("Skipping to next Restart instruction");
+}
+
+fn print_span_histogram<R: Read>(mut load: Load<R>) -> io::Result<()> {
+    let mut durations = StringUncache::new(SpanDurations::new());
+    load.forward_cached(&mut durations)?;
+
+    let mut out = String::new();
+    durations.into_inner().write_prometheus(&mut out).unwrap();
+    print!("{out}");
 
     Ok(())
 }
+
+fn print_flamegraph<R: Read>(mut load: Load<R>) -> io::Result<()> {
+    let mut stacks = StringUncache::new(FoldedStacks::new());
+    load.forward_cached(&mut stacks)?;
+
+    let mut out = String::new();
+    stacks.into_inner().write_folded(&mut out).unwrap();
+    print!("{out}");
+
+    Ok(())
+}
+
+fn print_stats<R: Read>(mut load: Load<R>) -> io::Result<()> {
+    let mut stats = StringUncache::new(TapeStats::new());
+    load.forward_cached(&mut stats)?;
+
+    let mut out = String::new();
+    stats.into_inner().write_summary(&mut out).unwrap();
+    print!("{out}");
+
+    Ok(())
+}
+
+/// Minimum distinct strings for `--lint` to report a site.
+const LINT_MIN_DISTINCT: u64 = 100;
+
+fn print_lint<R: Read>(mut load: Load<R>) -> io::Result<()> {
+    let mut cardinality = StringUncache::new(Cardinality::new());
+    load.forward_cached(&mut cardinality)?;
+
+    let mut out = String::new();
+    cardinality
+        .into_inner()
+        .write_report(&mut out, LINT_MIN_DISTINCT)
+        .unwrap();
+    print!("{out}");
+
+    Ok(())
+}
+
+fn print_verify<R: Read>(load: Load<R>) -> io::Result<()> {
+    let verification = verify(load);
+
+    let mut out = String::new();
+    verification.write_report(&mut out).unwrap();
+    print!("{out}");
+
+    match verification.is_ok() {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} problems found", verification.problems().len()),
+        )),
+    }
+}
+
+fn print_cache_stats(path: &str, stats: &CacheStats) {
+    eprintln!("Cache statistics for {path}:");
+    eprintln!("  cached strings: {}", stats.strings);
+    eprintln!("  references to cached strings: {}", stats.references);
+    eprintln!("  bytes saved: {}", stats.bytes_saved);
+    eprintln!("  largest cached strings:");
+    for str in stats.largest.iter() {
+        eprintln!("    {} bytes: {str:?}", str.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()), false, false)
+    }
+
+    fn rejected(args: &[&str]) -> String {
+        match parse(args) {
+            Ok(_) => panic!("{args:?} was accepted"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn flags_and_paths() {
+        let args = parse(&["-n", "5", "--level", "warn", "--color", "a.log"]).unwrap();
+        assert_eq!(args.options.tail, Some(5));
+        assert_eq!(args.options.level, Some(Level::WARN));
+        assert!(args.options.color);
+        assert_eq!(args.paths, ["a.log"]);
+        assert!(!args.merged());
+
+        let args = parse(&["--stats", "a.log", "b.log"]).unwrap();
+        assert!(args.mode == Mode::Stats);
+        assert!(!args.merged());
+        assert!(parse(&["a.log", "b.log"]).unwrap().merged());
+        assert!(!parse(&["a.log", "-"]).unwrap().merged());
+    }
+
+    #[test]
+    fn piped_stdin() {
+        let args = parse_args(Vec::new(), true, true).unwrap();
+        assert!(args.options.color);
+        assert_eq!(args.paths, [STDIN]);
+
+        let args = parse_args(vec!["a.log".to_string()], false, true).unwrap();
+        assert_eq!(args.paths, ["a.log"]);
+        assert!(parse(&[]).unwrap().paths.is_empty());
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert_eq!(rejected(&["--tail"]), "--tail expects the number of events");
+        assert_eq!(
+            rejected(&["--tail", "many"]),
+            "--tail expects the number of events"
+        );
+        assert_eq!(rejected(&["--slice"]), "--slice expects the output file");
+        assert!(rejected(&["--level", "loud"]).starts_with("Invalid --level: "));
+        assert!(rejected(&["--grep", "("]).starts_with("Invalid --grep: "));
+    }
+
+    #[test]
+    fn conflicting_flags() {
+        let cases: &[(&[&str], &str)] = &[
+            (
+                &["--stats", "--lint", "a.log"],
+                "--stats can't be combined with --lint",
+            ),
+            (
+                &["--stats", "--slice", "out", "a.log"],
+                "--stats can't be combined with --slice",
+            ),
+            (
+                &["--bundle", "out", "--verify", "a.log"],
+                "--verify can't be combined with --bundle",
+            ),
+            (
+                &["--slice", "out", "--bundle", "out", "a.log"],
+                "--slice can't be combined with --bundle",
+            ),
+            (
+                &["--tail", "5", "--since", "2h", "a.log"],
+                "--tail can't be combined with --since",
+            ),
+            (
+                &["--follow", "--flamegraph", "a.log"],
+                "--follow can't be combined with --flamegraph",
+            ),
+            (
+                &["--tail", "5", "--repair", "out", "a.log"],
+                "--tail can't be combined with --repair",
+            ),
+            (
+                &["--tail", "5", "a.log", "b.log"],
+                "--tail expects a single file",
+            ),
+            (
+                &["--follow", "a.log", "b.log"],
+                "--follow expects a single file",
+            ),
+        ];
+        for (args, error) in cases {
+            assert_eq!(&rejected(args), error);
+        }
+    }
+
+    #[test]
+    fn slice_filters_events() {
+        let args = parse(&["--slice", "out", "--level", "warn", "--grep", "x", "a.log"]).unwrap();
+        assert_eq!(args.options.filters(), ["--grep", "--level"]);
+        assert_eq!(args.options.outputs(), ["--slice"]);
+    }
+
+    #[test]
+    fn follow_needs_a_tape_file() {
+        assert_eq!(
+            rejected(&["-f", "-"]),
+            "--follow expects a tape file, not -"
+        );
+        let bundle = format!("a.{BUNDLE_EXTENSION}");
+        assert_eq!(
+            rejected(&["-f", &bundle]),
+            format!("--follow expects a tape file, not {bundle}")
+        );
+        assert_eq!(
+            parse_args(vec!["-f".to_string()], false, true)
+                .err()
+                .unwrap(),
+            "--follow expects a tape file, not -"
+        );
+        assert!(parse(&["-f", "-n", "5", "a.log"]).unwrap().options.follow);
+    }
+
+    #[test]
+    fn spooled_stdin() {
+        let path = std::env::temp_dir().join(format!("printer-spool-{}", std::process::id()));
+        let mut file = spool_stdin(&b"tape"[..], &path).unwrap();
+        let mut data = Vec::new();
+        file.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"tape");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn slice_filters() {
+        use msgpack_tracing::tape::Instruction;
+
+        let mut tape = Vec::new();
+        Store::do_handle(&mut tape, Instruction::Restart).unwrap();
+        for (minute, priority) in [
+            (0, Level::WARN),
+            (1, Level::INFO),
+            (1, Level::WARN),
+            (2, Level::ERROR),
+            (3, Level::ERROR),
+        ] {
+            let start = Instruction::StartEvent {
+                time: DateTime::from_timestamp(minute * 60, 0).unwrap(),
+                span: None,
+                target: "app",
+                priority,
+                location: None,
+                thread: None,
+            };
+            Store::do_handle(&mut tape, start).unwrap();
+            Store::do_handle(&mut tape, Instruction::FinishedEvent).unwrap();
+        }
+
+        let out = std::env::temp_dir().join(format!("printer-slice-{}", std::process::id()));
+        File::create(&out).unwrap();
+        let options = Options {
+            level: Some(Level::WARN),
+            since: DateTime::from_timestamp(60, 0),
+            until: DateTime::from_timestamp(180, 0),
+            ..Default::default()
+        };
+        slice_log(Load::new(tape.as_slice()), &out, &options).unwrap();
+
+        let mut stats = StringUncache::new(TapeStats::new());
+        Load::open(&out)
+            .unwrap()
+            .forward_cached(&mut stats)
+            .unwrap();
+        std::fs::remove_file(&out).unwrap();
+        let stats = stats.into_inner();
+        assert_eq!(stats.events(), 2);
+        assert_eq!(
+            stats.levels().keys().collect::<Vec<_>>(),
+            [&Level::ERROR, &Level::WARN]
+        );
+    }
+}
@@ -0,0 +1,223 @@
+use crate::{
+    rotate,
+    string_cache::{CacheInstruction, CacheInstructionSet, CacheString},
+    tape::{FieldValue, TapeMachine, Value},
+};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+/// Extension of the annotations written next to a tape, see [`annotation_path`].
+pub const ANNOTATION_EXTENSION: &str = "notes";
+
+/// Prefix of the field names annotations are forwarded as, see [`Annotations::forward`].
+pub const ANNOTATION_PREFIX: char = '#';
+
+/// Note attached to the event starting at `offset`, as given by
+/// [`Load::last_offset`](crate::storage::Load::last_offset).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Annotation {
+    pub offset: u64,
+    pub tag: String,
+    pub comment: String,
+}
+impl Annotation {
+    /// One line of the sidecar file: `offset<TAB>tag<TAB>comment`. Tabs and line breaks of the
+    /// tag and comment become spaces.
+    pub fn to_line(&self) -> String {
+        let clean = |str: &str| str.replace(['\t', '\r', '\n'], " ");
+        format!(
+            "{}\t{}\t{}\n",
+            self.offset,
+            clean(&self.tag),
+            clean(&self.comment)
+        )
+    }
+
+    pub fn parse_line(line: &str) -> Option<Self> {
+        let mut parts = line.trim_end_matches(['\r', '\n']).splitn(3, '\t');
+        Some(Self {
+            offset: parts.next()?.parse().ok()?,
+            tag: parts.next()?.to_owned(),
+            comment: parts.next().unwrap_or_default().to_owned(),
+        })
+    }
+}
+
+/// Path of the annotations of the tape at `path`: `app.log.notes` for both `app.log` and
+/// `app.log.gz`.
+pub fn annotation_path(path: &Path) -> PathBuf {
+    let path = match rotate::is_compressed(path) {
+        true => path.with_extension(""),
+        false => path.to_owned(),
+    };
+    let mut path = path.into_os_string();
+    path.push(".");
+    path.push(ANNOTATION_EXTENSION);
+    PathBuf::from(path)
+}
+
+/// Appends an annotation to the sidecar of the tape at `path`, leaving the tape untouched.
+pub fn append_annotation(path: &Path, annotation: &Annotation) -> io::Result<()> {
+    File::options()
+        .create(true)
+        .append(true)
+        .open(annotation_path(path))?
+        .write_all(annotation.to_line().as_bytes())
+}
+
+/// Annotations of a tape, by event offset.
+#[derive(Clone, Debug, Default)]
+pub struct Annotations {
+    offset: BTreeMap<u64, Vec<Annotation>>,
+}
+impl Annotations {
+    /// Reads annotations, one per line, ignoring malformed lines.
+    pub fn read<R: BufRead>(read: R) -> io::Result<Self> {
+        let mut annotations = Self::default();
+        for line in read.lines() {
+            if let Some(annotation) = Annotation::parse_line(&line?) {
+                annotations.insert(annotation);
+            }
+        }
+        Ok(annotations)
+    }
+
+    /// Reads the sidecar of the tape at `path`, which may not exist.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        match File::open(annotation_path(path)) {
+            Ok(file) => Self::read(BufReader::new(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn insert(&mut self, annotation: Annotation) {
+        self.offset
+            .entry(annotation.offset)
+            .or_default()
+            .push(annotation);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offset.is_empty()
+    }
+
+    /// Every annotation, by offset.
+    pub fn iter(&self) -> impl Iterator<Item = &Annotation> {
+        self.offset.values().flatten()
+    }
+
+    pub fn get(&self, offset: u64) -> &[Annotation] {
+        self.offset.get(&offset).map(Vec::as_slice).unwrap_or(&[])
+    }
+
+    /// Forwards the annotations of the event at `offset` as its fields, named by the tag
+    /// prefixed by [`ANNOTATION_PREFIX`]. Call it before forwarding the `FinishedEvent`.
+    pub fn forward<T>(&self, offset: u64, machine: &mut T)
+    where
+        T: TapeMachine<CacheInstructionSet>,
+    {
+        for annotation in self.get(offset) {
+            let name = format!("{ANNOTATION_PREFIX}{}", annotation.tag);
+            machine.handle(CacheInstruction::AddValue(FieldValue {
+                name: CacheString::Present(&name),
+                value: Value::String(CacheString::Present(&annotation.comment)),
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        string_cache::StringUncache,
+        tape::{Instruction, InstructionSet},
+    };
+
+    fn annotation(offset: u64, tag: &str, comment: &str) -> Annotation {
+        Annotation {
+            offset,
+            tag: tag.to_owned(),
+            comment: comment.to_owned(),
+        }
+    }
+
+    #[test]
+    fn sidecar_lines() {
+        let mut data = annotation(42, "triage", "root\tcause").to_line();
+        data.push_str("garbage\n");
+        let annotations = Annotations::read(data.as_bytes()).unwrap();
+
+        assert_eq!(annotations.get(42)[0].comment, "root cause");
+        assert!(annotations.get(0).is_empty());
+        assert_eq!(
+            annotation_path(Path::new("app.log.gz")),
+            Path::new("app.log.notes")
+        );
+    }
+
+    #[test]
+    fn malformed_lines() {
+        // Empty, without tag, with a bad offset, without comment and cut mid-write
+        let data = "\n7\nx\ttag\tcomment\n9\ttag\n11\tta";
+        let annotations = Annotations::read(data.as_bytes()).unwrap();
+
+        assert_eq!(
+            annotations.iter().cloned().collect::<Vec<_>>(),
+            [annotation(9, "tag", ""), annotation(11, "ta", "")]
+        );
+    }
+
+    #[test]
+    fn append_and_open() {
+        let path = std::env::temp_dir().join(format!("annotation-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(annotation_path(&path));
+        assert!(Annotations::open(&path).unwrap().is_empty());
+
+        append_annotation(&path, &annotation(3, "first", "a")).unwrap();
+        append_annotation(&path, &annotation(3, "second", "b")).unwrap();
+        let annotations = Annotations::open(&path).unwrap();
+        assert_eq!(
+            annotations.get(3),
+            [annotation(3, "first", "a"), annotation(3, "second", "b")]
+        );
+
+        std::fs::remove_file(annotation_path(&path)).unwrap();
+    }
+
+    #[test]
+    fn forward_as_fields() {
+        struct Fields(Vec<(String, String)>);
+        impl TapeMachine<InstructionSet> for Fields {
+            fn needs_restart(&mut self) -> bool {
+                false
+            }
+
+            fn handle(&mut self, instruction: Instruction) {
+                if let Instruction::AddValue(FieldValue {
+                    name,
+                    value: Value::String(comment),
+                }) = instruction
+                {
+                    self.0.push((name.to_owned(), comment.to_owned()));
+                }
+            }
+        }
+
+        let mut annotations = Annotations::default();
+        annotations.insert(annotation(3, "triage", "root cause"));
+        let mut fields = StringUncache::new(Fields(Vec::new()));
+        annotations.forward(4, &mut fields);
+        annotations.forward(3, &mut fields);
+
+        assert_eq!(
+            fields.into_inner().0,
+            [("#triage".to_owned(), "root cause".to_owned())]
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use crate::{
+    storage::Store,
+    string_cache::StringCache,
+    tape::{FieldValue, Instruction, InstructionSet, TapeMachine, Value},
+};
+use chrono::DateTime;
+use std::num::NonZeroU64;
+use tracing::Level;
+
+/// Targets used by [`drive`], repeated like the modules of an application.
+const TARGETS: [&str; 4] = [
+    "app::server",
+    "app::server::handler",
+    "app::db::pool",
+    "app::cache",
+];
+
+/// Sends `events` events to `machine`, shaped like the tape of a server: every ten events run
+/// inside a request span that is recorded to and then deleted, and events carry a message, a
+/// number and a debug value.
+pub fn drive<T>(machine: &mut T, events: usize)
+where
+    T: TapeMachine<InstructionSet> + ?Sized,
+{
+    machine.handle(Instruction::Restart);
+
+    let mut span = None;
+    for i in 0..events {
+        if i % 10 == 0 {
+            if let Some(span) = span.take() {
+                machine.handle(Instruction::DeleteSpan(span));
+            }
+            let id = NonZeroU64::new(i as u64 / 10 + 1).unwrap();
+            machine.handle(Instruction::NewSpan {
+                parent: None,
+                span: id,
+                name: "request",
+            });
+            machine.handle(Instruction::AddValue(FieldValue {
+                name: "id",
+                value: Value::Unsigned(i as u64),
+            }));
+            machine.handle(Instruction::FinishedSpan);
+            span = Some(id);
+        }
+        if i % 10 == 5
+            && let Some(span) = span
+        {
+            machine.handle(Instruction::NewRecord(span));
+            machine.handle(Instruction::AddValue(FieldValue {
+                name: "status",
+                value: Value::String("ok"),
+            }));
+            machine.handle(Instruction::FinishedRecord);
+        }
+
+        machine.handle(Instruction::StartEvent {
+            time: DateTime::from_timestamp(1_700_000_000 + i as i64, 0).unwrap(),
+            span,
+            target: TARGETS[i % TARGETS.len()],
+            priority: Level::INFO,
+            location: None,
+            thread: None,
+        });
+        machine.handle(Instruction::AddValue(FieldValue {
+            name: "message",
+            value: Value::Debug("handled request"),
+        }));
+        machine.handle(Instruction::AddValue(FieldValue {
+            name: "elapsed",
+            value: Value::Unsigned(i as u64 % 1000),
+        }));
+        machine.handle(Instruction::AddValue(FieldValue {
+            name: "peer",
+            value: Value::Debug("Peer { addr: 127.0.0.1:8080 }"),
+        }));
+        machine.handle(Instruction::FinishedEvent);
+    }
+
+    if let Some(span) = span {
+        machine.handle(Instruction::DeleteSpan(span));
+    }
+}
+
+/// Tape of `events` events from [`drive`], cached like the tapes written by the logger.
+pub fn sample_tape(events: usize) -> Vec<u8> {
+    let mut store = StringCache::new(Store::new(Vec::new()));
+    drive(&mut store, events);
+    store.into_inner().into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{stats::TapeStats, storage::Load, string_cache::StringUncache, verify::verify};
+
+    #[test]
+    fn sample_tape_decodes() {
+        let empty = verify(Load::new(sample_tape(0).as_slice()));
+        assert!(empty.is_ok());
+        assert_eq!(empty.instructions(), 1);
+
+        let tape = sample_tape(25);
+        assert!(verify(Load::new(tape.as_slice())).is_ok());
+
+        let mut stats = StringUncache::new(TapeStats::new());
+        Load::new(tape.as_slice())
+            .forward_cached(&mut stats)
+            .unwrap();
+        let stats = stats.into_inner();
+        assert_eq!(stats.events(), 25);
+        assert_eq!(stats.spans(), 3);
+        assert_eq!(stats.targets()["app::server"], 7);
+        assert_eq!(stats.targets()["app::cache"], 6);
+    }
+}
@@ -0,0 +1,377 @@
+use crate::{
+    annotation::{self, Annotations},
+    rotate,
+    storage::{self, Load},
+    time_index::{self, IndexEntry},
+};
+use chrono::{SecondsFormat, Utc};
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Write},
+    path::Path,
+};
+
+/// Extension of bundles, which are plain tar archives.
+pub const BUNDLE_EXTENSION: &str = "tar";
+
+/// Name of the manifest inside a bundle.
+pub const MANIFEST: &str = "manifest";
+
+/// First line of the manifest, identifying the bundle format.
+const FORMAT: &str = "format: msgpack-tracing bundle 1";
+
+/// Read-only package of a tape with its index, its annotations and metadata about the session,
+/// in a single file that can be attached to a ticket.
+///
+/// Bundles are tar archives, so they can also be unpacked by `tar`. The [`MANIFEST`] lists the
+/// metadata as `key: value` lines, including the name of the tape.
+pub struct Bundle {
+    tape_name: String,
+    tape: Vec<u8>,
+    index: Vec<IndexEntry>,
+    annotations: Annotations,
+    metadata: Vec<(String, String)>,
+}
+impl Bundle {
+    /// Packages the tape at `path` with its index and annotations, when present. The time and
+    /// the host are recorded as metadata.
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let tape_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid tape name"))?;
+        let index = match File::open(time_index::index_path(path)) {
+            Ok(index) => time_index::read_index(index)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        let bundle = Self {
+            tape_name: tape_name.to_owned(),
+            tape: std::fs::read(path)?,
+            index,
+            annotations: Annotations::open(path)?,
+            metadata: Vec::new(),
+        };
+        let created = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+        let bundle = bundle.with_metadata("created", &created);
+        Ok(match std::env::var("HOSTNAME") {
+            Ok(host) => bundle.with_metadata("host", &host),
+            Err(_) => bundle,
+        })
+    }
+
+    /// Adds a `key: value` line to the manifest, e.g. the ticket. Line breaks become spaces.
+    pub fn with_metadata(mut self, key: &str, value: &str) -> Self {
+        let clean = |str: &str| str.replace(['\r', '\n'], " ");
+        self.metadata
+            .push((clean(key).replace(':', " "), clean(value)));
+        self
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn read<R: Read>(mut read: R) -> io::Result<Self> {
+        let mut entries = Vec::new();
+        while let Some(entry) = tar::read_entry(&mut read)? {
+            entries.push(entry);
+        }
+        let mut take = |name: &str| {
+            let position = entries.iter().position(|(entry, _)| entry == name)?;
+            Some(entries.swap_remove(position).1)
+        };
+
+        let manifest = take(MANIFEST).ok_or_else(|| invalid("bundle without manifest"))?;
+        let manifest = String::from_utf8(manifest).map_err(|_| invalid("invalid manifest"))?;
+        let mut lines = manifest.lines();
+        if lines.next() != Some(FORMAT) {
+            return Err(invalid("unknown bundle format"));
+        }
+        let mut tape_name = None;
+        let mut metadata = Vec::new();
+        for line in lines {
+            let Some((key, value)) = line.split_once(": ") else {
+                continue;
+            };
+            match key {
+                "tape" => tape_name = Some(value.to_owned()),
+                key => metadata.push((key.to_owned(), value.to_owned())),
+            }
+        }
+        let tape_name = tape_name.ok_or_else(|| invalid("bundle without tape"))?;
+
+        let tape = take(&tape_name).ok_or_else(|| invalid("bundle without tape"))?;
+        let index = match take(&Self::sidecar(time_index::index_path, &tape_name)) {
+            Some(index) => time_index::read_index(index.as_slice())?,
+            None => Vec::new(),
+        };
+        let annotations = match take(&Self::sidecar(annotation::annotation_path, &tape_name)) {
+            Some(annotations) => Annotations::read(annotations.as_slice())?,
+            None => Annotations::default(),
+        };
+
+        Ok(Self {
+            tape_name,
+            tape,
+            index,
+            annotations,
+            metadata,
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut out: W) -> io::Result<W> {
+        let mut manifest = format!("{FORMAT}\ntape: {}\n", self.tape_name);
+        for (key, value) in self.metadata.iter() {
+            manifest.push_str(&format!("{key}: {value}\n"));
+        }
+        tar::write_entry(&mut out, MANIFEST, manifest.as_bytes())?;
+        tar::write_entry(&mut out, &self.tape_name, &self.tape)?;
+
+        if !self.index.is_empty() {
+            let index = self
+                .index
+                .iter()
+                .flat_map(IndexEntry::encode)
+                .collect::<Vec<_>>();
+            let name = Self::sidecar(time_index::index_path, &self.tape_name);
+            tar::write_entry(&mut out, &name, &index)?;
+        }
+        if !self.annotations.is_empty() {
+            let annotations = self
+                .annotations
+                .iter()
+                .map(annotation::Annotation::to_line)
+                .collect::<String>();
+            let name = Self::sidecar(annotation::annotation_path, &self.tape_name);
+            tar::write_entry(&mut out, &name, annotations.as_bytes())?;
+        }
+
+        tar::write_end(&mut out)?;
+        Ok(out)
+    }
+
+    /// Name of the tape, e.g. `app.log` or `app.log.gz`.
+    pub fn tape_name(&self) -> &str {
+        &self.tape_name
+    }
+
+    /// Metadata of the manifest, besides the tape name.
+    pub fn metadata(&self) -> &[(String, String)] {
+        &self.metadata
+    }
+
+    pub fn annotations(&self) -> &Annotations {
+        &self.annotations
+    }
+
+    /// Loads the tape, decompressing it if needed, with its index.
+    pub fn load(&self) -> io::Result<Load<io::Cursor<Vec<u8>>>> {
+        let path = Path::new(&self.tape_name);
+        let tape = match rotate::is_compressed(path) {
+            true => storage::decompress(self.tape.as_slice(), path)?,
+            false => self.tape.clone(),
+        };
+        Ok(Load::new(io::Cursor::new(tape)).with_index(self.index.clone()))
+    }
+
+    fn sidecar(path: fn(&Path) -> std::path::PathBuf, tape_name: &str) -> String {
+        path(Path::new(tape_name)).to_string_lossy().into_owned()
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Just enough of the ustar format for flat archives of regular files.
+mod tar {
+    use super::invalid;
+    use std::io::{self, Read, Write};
+
+    const BLOCK: usize = 512;
+
+    pub fn write_entry<W: Write>(out: &mut W, name: &str, data: &[u8]) -> io::Result<()> {
+        if name.len() > 100 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "name too long for a bundle",
+            ));
+        }
+
+        let mut header = [0; BLOCK];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..108].copy_from_slice(b"0000644\0");
+        header[108..116].copy_from_slice(b"0000000\0");
+        header[116..124].copy_from_slice(b"0000000\0");
+        header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+        header[136..148].copy_from_slice(b"00000000000\0");
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        let checksum = checksum(&header);
+        header[148..156].copy_from_slice(format!("{checksum:06o}\0 ").as_bytes());
+
+        out.write_all(&header)?;
+        out.write_all(data)?;
+        out.write_all(&[0; BLOCK][..padding(data.len())])
+    }
+
+    pub fn write_end<W: Write>(out: &mut W) -> io::Result<()> {
+        out.write_all(&[0; BLOCK * 2])
+    }
+
+    /// Name and data of the next entry, or `None` at the end of the archive.
+    pub fn read_entry<R: Read>(read: &mut R) -> io::Result<Option<(String, Vec<u8>)>> {
+        let mut header = [0; BLOCK];
+        match read.read_exact(&mut header) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        if header.iter().all(|&byte| byte == 0) {
+            return Ok(None);
+        }
+        if octal(&header[148..156]) != Some(checksum(&header)) {
+            return Err(invalid("not a bundle"));
+        }
+
+        let name = &header[..100];
+        let name = &name[..name.iter().position(|&byte| byte == 0).unwrap_or(100)];
+        let name = String::from_utf8(name.to_vec()).map_err(|_| invalid("invalid entry name"))?;
+        let size = octal(&header[124..136]).ok_or_else(|| invalid("invalid entry size"))?;
+
+        let mut data = vec![0; size as usize];
+        read.read_exact(&mut data)?;
+        io::copy(&mut read.take(padding(data.len()) as u64), &mut io::sink())?;
+
+        Ok(Some((name, data)))
+    }
+
+    /// Sum of the header bytes, with the checksum field as spaces.
+    fn checksum(header: &[u8; BLOCK]) -> u64 {
+        header
+            .iter()
+            .enumerate()
+            .map(|(i, &byte)| match i {
+                148..156 => b' ' as u64,
+                _ => byte as u64,
+            })
+            .sum()
+    }
+
+    fn octal(field: &[u8]) -> Option<u64> {
+        let field = std::str::from_utf8(field).ok()?;
+        let field = field.trim_matches(|char: char| char == '\0' || char == ' ');
+        u64::from_str_radix(field, 8).ok()
+    }
+
+    fn padding(len: usize) -> usize {
+        (BLOCK - len % BLOCK) % BLOCK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        annotation::{Annotation, append_annotation},
+        storage::Store,
+        string_cache::{CacheInstruction, StringCache},
+        test_util::golden_tape,
+    };
+
+    fn bundle(name: &str, tape: Vec<u8>) -> Bundle {
+        Bundle {
+            tape_name: name.to_owned(),
+            tape,
+            index: Vec::new(),
+            annotations: Annotations::default(),
+            metadata: Vec::new(),
+        }
+    }
+
+    fn error(data: &[u8]) -> String {
+        match Bundle::read(data) {
+            Ok(_) => panic!("read an invalid bundle"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = std::env::temp_dir().join(format!("bundle-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+
+        let mut tape = StringCache::new(Store::new(File::create(&path).unwrap()));
+        golden_tape(&mut tape);
+        drop(tape);
+        let annotation = Annotation {
+            offset: 1,
+            tag: "triage".to_owned(),
+            comment: "root cause".to_owned(),
+        };
+        append_annotation(&path, &annotation).unwrap();
+
+        let bundle = Bundle::create(&path)
+            .unwrap()
+            .with_metadata("ticket", "OPS-1");
+        let data = bundle.write(Vec::new()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let bundle = Bundle::read(data.as_slice()).unwrap();
+        assert_eq!(bundle.tape_name(), "app.log");
+        assert!(
+            bundle
+                .metadata()
+                .contains(&("ticket".to_owned(), "OPS-1".to_owned()))
+        );
+        assert_eq!(bundle.annotations().get(1), [annotation]);
+        let mut load = bundle.load().unwrap();
+        let mut events = 0;
+        while let Some(instruction) = load.fetch_one_cached().unwrap() {
+            if let CacheInstruction::StartEvent { .. } = instruction {
+                events += 1;
+            }
+        }
+        assert_eq!(events, 2);
+    }
+
+    #[test]
+    fn empty_tape() {
+        let data = bundle("app.log", Vec::new()).write(Vec::new()).unwrap();
+        let bundle = Bundle::read(data.as_slice()).unwrap();
+
+        assert!(bundle.metadata().is_empty());
+        assert!(bundle.load().unwrap().fetch_one().unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_bundles() {
+        let data = bundle("app.log", vec![1; 600]).write(Vec::new()).unwrap();
+
+        assert_eq!(error(&[]), "bundle without manifest");
+        assert_eq!(error(&data[..1]), "bundle without manifest");
+        let mut corrupt = data.clone();
+        corrupt[0] ^= 1;
+        assert_eq!(error(&corrupt), "not a bundle");
+        // Cut in the data of the tape
+        assert_eq!(
+            error(&data[..1024 + 512 + 100]),
+            "failed to fill whole buffer"
+        );
+
+        let mut manifest = Vec::new();
+        tar::write_entry(&mut manifest, MANIFEST, b"format: something else\n").unwrap();
+        assert_eq!(error(&manifest), "unknown bundle format");
+        let mut manifest = Vec::new();
+        tar::write_entry(&mut manifest, MANIFEST, format!("{FORMAT}\n").as_bytes()).unwrap();
+        assert_eq!(error(&manifest), "bundle without tape");
+
+        let long = "a".repeat(101);
+        let e = bundle(&long, Vec::new()).write(Vec::new()).err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+}
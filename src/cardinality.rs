@@ -0,0 +1,194 @@
+use crate::tape::{Instruction, InstructionSet, TapeMachine, Value};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    hash::{BuildHasher, RandomState},
+};
+
+/// Where a string is logged, see [`Cardinality`].
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Site {
+    Target,
+    SpanName,
+    FieldName,
+    /// String and debug values of the named field.
+    FieldValue(String),
+}
+impl fmt::Display for Site {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Site::Target => write!(f, "target"),
+            Site::SpanName => write!(f, "span name"),
+            Site::FieldName => write!(f, "field name"),
+            Site::FieldValue(name) => write!(f, "values of field {name:?}"),
+        }
+    }
+}
+
+/// Strings seen at a [`Site`].
+#[derive(Clone, Debug, Default)]
+pub struct Distinct {
+    pub occurrences: u64,
+    /// Bytes of all occurrences.
+    pub bytes: u64,
+    hashes: HashSet<u64>,
+}
+impl Distinct {
+    pub fn distinct(&self) -> u64 {
+        self.hashes.len() as u64
+    }
+
+    /// Whether more than half of the occurrences were new strings, which the string cache can't
+    /// save.
+    pub fn mostly_distinct(&self) -> bool {
+        self.distinct() * 2 > self.occurrences
+    }
+}
+
+/// Counts the distinct strings of targets, span names, field names and field values while
+/// replaying a tape, to find instrumentation that defeats the string cache (e.g. raw UUIDs in
+/// targets or field names).
+///
+/// Strings are remembered by their hash, so the count may be slightly low.
+#[derive(Default)]
+pub struct Cardinality {
+    hasher: RandomState,
+    sites: BTreeMap<Site, Distinct>,
+}
+impl Cardinality {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn sites(&self) -> &BTreeMap<Site, Distinct> {
+        &self.sites
+    }
+
+    /// Mostly distinct sites with at least `min_distinct` strings, most costly first.
+    pub fn high_cardinality(&self, min_distinct: u64) -> Vec<(&Site, &Distinct)> {
+        let mut sites = self
+            .sites
+            .iter()
+            .filter(|(_, distinct)| {
+                distinct.distinct() >= min_distinct && distinct.mostly_distinct()
+            })
+            .collect::<Vec<_>>();
+        sites.sort_by_key(|(_, distinct)| std::cmp::Reverse(distinct.bytes));
+        sites
+    }
+
+    /// Writes the [`high_cardinality`](Self::high_cardinality) sites, one per line.
+    pub fn write_report<W: fmt::Write>(&self, out: &mut W, min_distinct: u64) -> fmt::Result {
+        let sites = self.high_cardinality(min_distinct);
+        if sites.is_empty() {
+            return writeln!(out, "No high-cardinality strings found");
+        }
+
+        for (site, distinct) in sites {
+            writeln!(
+                out,
+                "{site}: {} distinct strings in {} occurrences, {} bytes",
+                distinct.distinct(),
+                distinct.occurrences,
+                distinct.bytes
+            )?;
+        }
+
+        Ok(())
+    }
+
+    fn observe(&mut self, site: Site, str: &str) {
+        let hash = self.hasher.hash_one(str);
+        let distinct = self.sites.entry(site).or_default();
+        distinct.occurrences += 1;
+        distinct.bytes += str.len() as u64;
+        distinct.hashes.insert(hash);
+    }
+}
+impl TapeMachine<InstructionSet> for Cardinality {
+    fn needs_restart(&mut self) -> bool {
+        false
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::NewSpan { name, .. } => self.observe(Site::SpanName, name),
+            Instruction::StartEvent { target, .. } => self.observe(Site::Target, target),
+            Instruction::AddValue(value) => {
+                self.observe(Site::FieldName, value.name);
+                if let Value::Debug(str) | Value::String(str) = value.value {
+                    self.observe(Site::FieldValue(value.name.to_owned()), str);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tape::FieldValue,
+        test_util::{golden_tape, start_event},
+    };
+    use chrono::DateTime;
+    use tracing::Level;
+
+    #[test]
+    fn flags_unique_targets() {
+        let mut cardinality = Cardinality::new();
+        for i in 0..100 {
+            let target = format!("request-{i:032x}");
+            cardinality.handle(start_event(
+                DateTime::UNIX_EPOCH,
+                None,
+                &target,
+                Level::INFO,
+            ));
+            cardinality.handle(Instruction::AddValue(FieldValue {
+                name: "status",
+                value: Value::String(if i % 2 == 0 { "ok" } else { "error" }),
+            }));
+        }
+
+        let sites = cardinality.high_cardinality(10);
+        assert_eq!(sites.len(), 1);
+        assert_eq!(*sites[0].0, Site::Target);
+        assert_eq!(sites[0].1.distinct(), 100);
+        assert_eq!(sites[0].1.bytes, 100 * 40);
+    }
+
+    #[test]
+    fn empty_tape() {
+        let mut report = String::new();
+        Cardinality::new().write_report(&mut report, 0).unwrap();
+        assert_eq!(report, "No high-cardinality strings found\n");
+    }
+
+    #[test]
+    fn golden_tape_sites() {
+        let mut cardinality = Cardinality::new();
+        golden_tape(&mut cardinality);
+
+        let counts = cardinality
+            .sites()
+            .iter()
+            .map(|(site, distinct)| (site.to_string(), distinct.occurrences, distinct.distinct()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            counts,
+            [
+                ("target".to_string(), 2, 1),
+                ("span name".to_string(), 2, 1),
+                ("field name".to_string(), 18, 3),
+                ("values of field \"status\"".to_string(), 1, 1),
+                ("values of field \"value\"".to_string(), 4, 2),
+            ]
+        );
+
+        // A single value is mostly distinct, but below the minimum
+        assert_eq!(cardinality.high_cardinality(1).len(), 1);
+        assert!(cardinality.high_cardinality(2).is_empty());
+    }
+}
@@ -0,0 +1,314 @@
+use crate::tape::{FieldValueOwned, Instruction, InstructionSet, SpanRecords, TapeMachine};
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use std::{collections::HashMap, io, num::NonZeroU64};
+
+/// Converts the spans of a tape into the Chrome trace event format, a JSON array of `B`/`E`
+/// events, viewable in Perfetto UI or `chrome://tracing`.
+///
+/// Spans recorded with [`with_span_timing`](crate::tape::TapeMachineLogger::with_span_timing)
+/// begin and end on every enter and exit. Other spans are timed against the latest event, like
+/// [`SpanDurations`](crate::span_histogram::SpanDurations), and last from their creation to
+/// their deletion.
+///
+/// Spans are shown on one track per root span, as the tape does not record the thread entering
+/// them.
+pub struct ChromeTrace<W> {
+    out: W,
+    empty: bool,
+    clock: Option<DateTime<Utc>>,
+    span: HashMap<NonZeroU64, TracedSpan>,
+    new_records: Option<(NonZeroU64, SpanRecords)>,
+}
+impl<W> ChromeTrace<W>
+where
+    W: io::Write + Send + 'static,
+{
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            empty: true,
+            clock: None,
+            span: Default::default(),
+            new_records: None,
+        }
+    }
+
+    /// Closes the JSON array, returning the output.
+    pub fn finish(mut self) -> io::Result<W> {
+        match self.empty {
+            true => self.out.write_all(b"[]\n")?,
+            false => self.out.write_all(b"\n]\n")?,
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    /// Track of `span`: the id of its root span.
+    fn track(&self, mut span: NonZeroU64) -> NonZeroU64 {
+        while let Some(parent) = self
+            .span
+            .get(&span)
+            .and_then(|traced| traced.records.parent)
+        {
+            if parent == span {
+                break;
+            }
+            span = parent;
+        }
+        span
+    }
+
+    /// Writes a `B` or `E` event of `span` at `time`.
+    fn write_event(&mut self, phase: &str, span: NonZeroU64, time: DateTime<Utc>) {
+        let Some(traced) = self.span.get(&span) else {
+            return;
+        };
+        let mut event = json!({
+            "name": traced.records.name,
+            "ph": phase,
+            "ts": time.timestamp_micros(),
+            "pid": 1,
+            "tid": self.track(span).get(),
+        });
+        if phase == "B" {
+            event["args"] = args(&traced.records.records);
+        }
+
+        let separator: &[u8] = match self.empty {
+            true => b"[\n",
+            false => b",\n",
+        };
+        self.empty = false;
+        let _ = self.out.write_all(separator);
+        let _ = self.out.write_all(event.to_string().as_bytes());
+    }
+}
+impl<W> TapeMachine<InstructionSet> for ChromeTrace<W>
+where
+    W: io::Write + Send + 'static,
+{
+    fn needs_restart(&mut self) -> bool {
+        false
+    }
+
+    fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Restart => {
+                self.new_records = None;
+            }
+            Instruction::NewSpan { parent, span, name } => {
+                self.new_records = Some((
+                    span,
+                    SpanRecords {
+                        parent,
+                        name: name.to_owned(),
+                        records: Default::default(),
+                    },
+                ));
+            }
+            Instruction::NewRecord(span) => {
+                let records = self
+                    .span
+                    .get(&span)
+                    .map(|traced| traced.records.clone())
+                    .unwrap_or_else(|| SpanRecords::lost(span));
+                self.new_records = Some((span, records));
+            }
+            Instruction::FinishedSpan => {
+                let Some((span, records)) = self.new_records.take() else {
+                    return;
+                };
+                // Spans are replayed after a restart, keep their original start
+                self.span.entry(span).or_insert(TracedSpan {
+                    records,
+                    start: self.clock,
+                    entered: false,
+                });
+            }
+            Instruction::FinishedRecord => {
+                let Some((span, records)) = self.new_records.take() else {
+                    return;
+                };
+                match self.span.get_mut(&span) {
+                    Some(traced) => traced.records = records,
+                    None => {
+                        self.span.insert(
+                            span,
+                            TracedSpan {
+                                records,
+                                start: self.clock,
+                                entered: false,
+                            },
+                        );
+                    }
+                }
+            }
+            Instruction::AddValue(value) => {
+                if let Some((_, records)) = self.new_records.as_mut() {
+                    records.record(value.to_owned());
+                }
+            }
+            Instruction::StartEvent { time, .. } | Instruction::SpanTiming { time, .. } => {
+                self.clock = Some(time);
+            }
+            Instruction::EnterSpan { time, span } => {
+                self.clock = Some(time);
+                if let Some(traced) = self.span.get_mut(&span) {
+                    traced.entered = true;
+                }
+                self.write_event("B", span, time);
+            }
+            Instruction::ExitSpan { time, span } => {
+                self.clock = Some(time);
+                self.write_event("E", span, time);
+            }
+            Instruction::DeleteSpan(span) => {
+                if let Some(TracedSpan {
+                    start: Some(start),
+                    entered: false,
+                    ..
+                }) = self.span.get(&span)
+                    && let Some(end) = self.clock
+                {
+                    let start = *start;
+                    self.write_event("B", span, start);
+                    self.write_event("E", span, end);
+                }
+                self.span.remove(&span);
+            }
+            Instruction::FinishedEvent | Instruction::FilterChanged(..) => (),
+        }
+    }
+}
+
+/// Span known to [`ChromeTrace`].
+struct TracedSpan {
+    records: SpanRecords,
+    start: Option<DateTime<Utc>>,
+    entered: bool,
+}
+
+fn args(records: &[FieldValueOwned]) -> serde_json::Value {
+    records
+        .iter()
+        .map(|record| (record.name.clone(), record.value.to_json()))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tape::Value,
+        test_util::{event, golden_tape, new_span, span, start_event},
+    };
+    use tracing::Level;
+
+    fn time(micros: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp_micros(micros).unwrap()
+    }
+
+    fn events(trace: ChromeTrace<Vec<u8>>) -> serde_json::Value {
+        serde_json::from_slice(&trace.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn begin_end_events() {
+        let mut trace = ChromeTrace::new(Vec::new());
+        trace.handle(Instruction::Restart);
+        event(
+            &mut trace,
+            start_event(time(100), None, "target", Level::INFO),
+            &[],
+        );
+        let values = [("id", Value::Unsigned(7))];
+        new_span(&mut trace, None, span(1), "request", &values);
+        new_span(&mut trace, Some(span(1)), span(2), "query", &[]);
+        trace.handle(Instruction::EnterSpan {
+            time: time(150),
+            span: span(2),
+        });
+        trace.handle(Instruction::ExitSpan {
+            time: time(250),
+            span: span(2),
+        });
+        trace.handle(Instruction::DeleteSpan(span(2)));
+        trace.handle(Instruction::DeleteSpan(span(1)));
+
+        assert_eq!(
+            events(trace),
+            json!([
+                {"name": "query", "ph": "B", "ts": 150, "pid": 1, "tid": 1, "args": {}},
+                {"name": "query", "ph": "E", "ts": 250, "pid": 1, "tid": 1},
+                {"name": "request", "ph": "B", "ts": 100, "pid": 1, "tid": 1, "args": {"id": 7}},
+                {"name": "request", "ph": "E", "ts": 250, "pid": 1, "tid": 1},
+            ])
+        );
+    }
+
+    #[test]
+    fn empty_trace() {
+        assert_eq!(events(ChromeTrace::new(Vec::new())), json!([]));
+
+        // Spans created before any event have no start
+        let mut trace = ChromeTrace::new(Vec::new());
+        golden_tape(&mut trace);
+        assert_eq!(events(trace), json!([]));
+    }
+
+    #[test]
+    fn unclosed_spans() {
+        let mut trace = ChromeTrace::new(Vec::new());
+        event(
+            &mut trace,
+            start_event(time(100), None, "target", Level::INFO),
+            &[],
+        );
+        new_span(&mut trace, None, span(1), "never deleted", &[]);
+        new_span(&mut trace, None, span(2), "never exited", &[]);
+        trace.handle(Instruction::EnterSpan {
+            time: time(150),
+            span: span(2),
+        });
+        // Cut in the middle of a span
+        trace.handle(Instruction::NewSpan {
+            parent: None,
+            span: span(3),
+            name: "cut",
+        });
+        trace.handle(Instruction::Restart);
+        trace.handle(Instruction::DeleteSpan(span(3)));
+
+        assert_eq!(
+            events(trace),
+            json!([
+                {"name": "never exited", "ph": "B", "ts": 150, "pid": 1, "tid": 2, "args": {}},
+            ])
+        );
+    }
+
+    #[test]
+    fn write_error() {
+        struct Failing;
+        impl io::Write for Failing {
+            fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut trace = ChromeTrace::new(Failing);
+        golden_tape(&mut trace);
+        let e = trace.finish().err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
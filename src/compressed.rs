@@ -0,0 +1,327 @@
+use crate::{
+    rotate,
+    storage::{FlushPolicy, Flusher, Store},
+    string_cache::{CacheInstruction, CacheInstructionSet},
+    tape::{Instruction, InstructionSet, InstructionTrait, TapeMachine},
+};
+use std::{
+    io::{self, Read},
+    path::Path,
+};
+
+/// Streaming compression of a [`CompressedStore`]. [`Load::open`](crate::storage::Load::open)
+/// decompresses files named with its [`extension`](Self::extension).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// A gzip member per restart.
+    #[cfg(feature = "gzip")]
+    Gzip,
+    /// An LZ4 frame per restart, faster to write than gzip but larger.
+    #[cfg(feature = "lz4")]
+    Lz4,
+}
+impl Compression {
+    const ALL: &[Compression] = &[
+        #[cfg(feature = "gzip")]
+        Compression::Gzip,
+        #[cfg(feature = "lz4")]
+        Compression::Lz4,
+    ];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => rotate::GZIP_EXTENSION,
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => rotate::LZ4_EXTENSION,
+        }
+    }
+
+    /// Compression of the file at `path`, from its extension.
+    pub fn of(path: &Path) -> Option<Self> {
+        let extension = path.extension()?;
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|compression| extension == compression.extension())
+    }
+}
+impl Default for Compression {
+    /// Gzip, or LZ4 when only the `lz4` feature is enabled.
+    fn default() -> Self {
+        #[cfg(feature = "gzip")]
+        let compression = Compression::Gzip;
+        #[cfg(not(feature = "gzip"))]
+        let compression = Compression::Lz4;
+        compression
+    }
+}
+
+/// [`Store`] compressing its output as it is written, see [`Compression`].
+///
+/// Every `Restart` starts a new gzip member or LZ4 frame, so a damaged one only loses the
+/// instructions up to the next restart. Flushing emits a sync point, the data written so far can
+/// be decompressed even while the file is still open. Name the file with the
+/// [`extension`](Compression::extension) of its compression for
+/// [`Load::open`](crate::storage::Load::open) to decompress it.
+pub struct CompressedStore<W: io::Write> {
+    out: Option<Encoder<W>>,
+    compression: Compression,
+    flush: Flusher,
+}
+impl<W> CompressedStore<W>
+where
+    W: io::Write + Send + 'static,
+{
+    /// Uses [`FlushPolicy::EveryEvent`], as every flush makes the compression worse.
+    pub fn new(out: W) -> Self {
+        Self::with_flush_policy(out, FlushPolicy::EveryEvent)
+    }
+
+    pub fn with_flush_policy(out: W, flush: FlushPolicy) -> Self {
+        Self {
+            out: Some(Encoder::Idle(out)),
+            compression: Default::default(),
+            flush: flush.into(),
+        }
+    }
+
+    /// Applies from the next restart on, as well as to the first instruction.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Finishes the last member and returns the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        match self.finish_member()?.out.take() {
+            Some(Encoder::Idle(out)) => Ok(out),
+            _ => unreachable!(),
+        }
+    }
+
+    fn finish_member(&mut self) -> io::Result<&mut Self> {
+        let out = match self.out.take() {
+            Some(Encoder::Idle(out)) => out,
+            Some(encoder) => encoder.finish()?,
+            None => return Err(failed()),
+        };
+        self.out = Some(Encoder::Idle(out));
+        Ok(self)
+    }
+
+    /// Writer of the current member, starting one if needed.
+    fn member(&mut self) -> io::Result<&mut Encoder<W>> {
+        if let Some(Encoder::Idle(_)) = self.out
+            && let Some(Encoder::Idle(out)) = self.out.take()
+        {
+            self.out = Some(Encoder::new(out, self.compression));
+        }
+        self.out.as_mut().ok_or_else(failed)
+    }
+
+    fn handle_cached(&mut self, instruction: CacheInstruction) -> io::Result<()> {
+        if let CacheInstruction::Restart = instruction {
+            self.finish_member()?;
+        }
+
+        let flush = self.flush.should_flush(instruction.id());
+        let out = self.member()?;
+        Store::do_write_cached(out, instruction)?;
+        if flush {
+            io::Write::flush(out)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush_member(&mut self) {
+        if let Some(out) = self.out.as_mut() {
+            let _ = io::Write::flush(out);
+        }
+    }
+}
+impl<W> TapeMachine<CacheInstructionSet> for CompressedStore<W>
+where
+    W: io::Write + Send + 'static,
+{
+    fn needs_restart(&mut self) -> bool {
+        false
+    }
+
+    fn handle(&mut self, instruction: CacheInstruction) {
+        let _ = self.handle_cached(instruction);
+    }
+
+    fn flush(&mut self) {
+        self.flush_member();
+    }
+}
+impl<W> TapeMachine<InstructionSet> for CompressedStore<W>
+where
+    W: io::Write + Send + 'static,
+{
+    fn needs_restart(&mut self) -> bool {
+        false
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        let _ = self.handle_cached(Store::<W>::as_cached(instruction));
+    }
+
+    fn flush(&mut self) {
+        self.flush_member();
+    }
+}
+
+fn failed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "previous member failed")
+}
+
+/// Output of a [`CompressedStore`], between members or inside one.
+enum Encoder<W: io::Write> {
+    Idle(W),
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::write::GzEncoder<W>),
+    #[cfg(feature = "lz4")]
+    Lz4(lz4_flex::frame::FrameEncoder<W>),
+}
+impl<W: io::Write> Encoder<W> {
+    fn new(out: W, compression: Compression) -> Self {
+        match compression {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                Encoder::Gzip(flate2::write::GzEncoder::new(out, Default::default()))
+            }
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Encoder::Lz4(lz4_flex::frame::FrameEncoder::new(out)),
+        }
+    }
+
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Idle(out) => Ok(out),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(encoder) => encoder.finish(),
+            #[cfg(feature = "lz4")]
+            Encoder::Lz4(encoder) => Ok(encoder.finish()?),
+        }
+    }
+}
+impl<W: io::Write> io::Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Idle(out) => out.write(buf),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            #[cfg(feature = "lz4")]
+            Encoder::Lz4(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Idle(out) => out.flush(),
+            #[cfg(feature = "gzip")]
+            Encoder::Gzip(encoder) => encoder.flush(),
+            #[cfg(feature = "lz4")]
+            Encoder::Lz4(encoder) => {
+                // Only writes the pending block
+                encoder.flush()?;
+                encoder.get_mut().flush()
+            }
+        }
+    }
+}
+
+/// Decompresses the members written by a [`CompressedStore`].
+pub(crate) enum Decoder<R: Read> {
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::read::MultiGzDecoder<R>),
+    #[cfg(feature = "lz4")]
+    Lz4(lz4_flex::frame::FrameDecoder<R>),
+}
+impl<R: Read> Decoder<R> {
+    pub(crate) fn new(read: R, compression: Compression) -> Self {
+        match compression {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Decoder::Gzip(flate2::read::MultiGzDecoder::new(read)),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Decoder::Lz4(lz4_flex::frame::FrameDecoder::new(read)),
+        }
+    }
+}
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = match self {
+            #[cfg(feature = "gzip")]
+            Decoder::Gzip(decoder) => decoder.read(buf),
+            #[cfg(feature = "lz4")]
+            // Every frame ends with an empty read, and holds at least a `Restart`, so the tape
+            // only ends at two empty reads in a row
+            Decoder::Lz4(decoder) => match decoder.read(buf) {
+                Ok(0) if !buf.is_empty() => decoder.read(buf),
+                result => result,
+            },
+        };
+        match result {
+            // Still being written by a `CompressedStore`, ends at what was flushed so far
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            result => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::Load;
+    use std::fs::File;
+
+    #[test]
+    fn compressed_store_while_writing() {
+        for &compression in Compression::ALL {
+            let path = std::env::temp_dir().join(format!(
+                "compressed-{}.log.{}",
+                std::process::id(),
+                compression.extension()
+            ));
+            assert_eq!(Compression::of(&path), Some(compression));
+            let mut store =
+                CompressedStore::new(File::create(&path).unwrap()).with_compression(compression);
+            let machine: &mut dyn TapeMachine<InstructionSet> = &mut store;
+            for filter in ["first", "second"] {
+                machine.handle(Instruction::Restart);
+                machine.handle(Instruction::FilterChanged(filter));
+            }
+            machine.flush();
+
+            // Second member is not finished yet
+            let mut load = Load::open(&path).unwrap();
+            for filter in ["first", "second"] {
+                assert!(matches!(
+                    load.fetch_one().unwrap(),
+                    Some(Instruction::Restart)
+                ));
+                assert!(matches!(
+                    load.fetch_one().unwrap(),
+                    Some(Instruction::FilterChanged(loaded)) if loaded == filter
+                ));
+            }
+            assert!(load.fetch_one().unwrap().is_none());
+
+            store.finish().unwrap();
+            let data = std::fs::read(&path).unwrap();
+            let tape = crate::storage::decompress(data.as_slice(), &path).unwrap();
+            let mut load = Load::new(tape.as_slice());
+            assert_eq!(load.iter().count(), 4);
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn empty_store() {
+        let out = CompressedStore::new(Vec::new()).finish().unwrap();
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,141 @@
+use std::{fmt, sync::Arc};
+
+/// Encoder for application types, storing them as [`Value::Custom`](crate::tape::Value::Custom)
+/// instead of Debug strings.
+///
+/// `tracing` only exposes application types through their `Debug` implementation, so encoders
+/// parse the Debug representation back into a compact binary form.
+pub trait CustomValue: Send + Sync {
+    /// Identifies the type on the tape. Must be unique among the registered encoders.
+    fn tag(&self) -> i8;
+
+    /// Encodes the Debug representation of field `field` into `out`.
+    ///
+    /// Returns `false` if the value is not handled by this encoder.
+    fn encode(&self, field: &str, debug: &str, out: &mut Vec<u8>) -> bool;
+
+    /// Renders data previously produced by [`CustomValue::encode`].
+    fn render(&self, data: &[u8], out: &mut dyn fmt::Write) -> fmt::Result;
+}
+
+/// Set of registered [`CustomValue`] encoders.
+#[derive(Clone, Default)]
+pub struct CustomValues(Vec<Arc<dyn CustomValue>>);
+impl CustomValues {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with<C>(mut self, custom: C) -> Self
+    where
+        C: CustomValue + 'static,
+    {
+        self.0.push(Arc::new(custom));
+        self
+    }
+
+    /// Tries every encoder in order, returning the tag of the one that encoded the value.
+    pub fn encode(&self, field: &str, debug: &str, out: &mut Vec<u8>) -> Option<i8> {
+        self.0.iter().find_map(|custom| {
+            out.clear();
+            custom.encode(field, debug, out).then(|| custom.tag())
+        })
+    }
+
+    /// Renders using the encoder registered for `tag`, or as hex if there is none.
+    pub fn render(&self, tag: i8, data: &[u8], out: &mut dyn fmt::Write) -> fmt::Result {
+        if let Some(custom) = self.0.iter().find(|custom| custom.tag() == tag) {
+            return custom.render(data, out);
+        }
+
+        write!(out, "#{tag}:")?;
+        for byte in data {
+            write!(out, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stores `Ipv4Addr` Debug strings as their four octets.
+    struct Ipv4;
+    impl CustomValue for Ipv4 {
+        fn tag(&self) -> i8 {
+            4
+        }
+
+        fn encode(&self, _field: &str, debug: &str, out: &mut Vec<u8>) -> bool {
+            match debug.parse::<std::net::Ipv4Addr>() {
+                Ok(addr) => {
+                    out.extend(addr.octets());
+                    true
+                }
+                Err(_) => false,
+            }
+        }
+
+        fn render(&self, data: &[u8], out: &mut dyn fmt::Write) -> fmt::Result {
+            let octets: [u8; 4] = data.try_into().map_err(|_| fmt::Error)?;
+            write!(out, "{}", std::net::Ipv4Addr::from(octets))
+        }
+    }
+
+    /// Only handles the `port` field, leaving bytes behind when it gives up.
+    struct Port;
+    impl CustomValue for Port {
+        fn tag(&self) -> i8 {
+            -1
+        }
+
+        fn encode(&self, field: &str, debug: &str, out: &mut Vec<u8>) -> bool {
+            out.push(0xff);
+            let Ok(port) = debug.parse::<u16>() else {
+                return false;
+            };
+            out.clear();
+            out.extend(port.to_be_bytes());
+            field == "port"
+        }
+
+        fn render(&self, data: &[u8], out: &mut dyn fmt::Write) -> fmt::Result {
+            let bytes: [u8; 2] = data.try_into().map_err(|_| fmt::Error)?;
+            write!(out, ":{}", u16::from_be_bytes(bytes))
+        }
+    }
+
+    fn rendered(custom: &CustomValues, tag: i8, data: &[u8]) -> String {
+        let mut out = String::new();
+        custom.render(tag, data, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn round_trip() {
+        let custom = CustomValues::new().with(Port).with(Ipv4);
+        let mut data = Vec::new();
+
+        // Port gives up on addresses, without leaving its bytes to Ipv4
+        assert_eq!(custom.encode("peer", "10.0.0.7", &mut data), Some(4));
+        assert_eq!(data, [10, 0, 0, 7]);
+        assert_eq!(rendered(&custom, 4, &data), "10.0.0.7");
+
+        assert_eq!(custom.encode("port", "8080", &mut data), Some(-1));
+        assert_eq!(rendered(&custom, -1, &data), ":8080");
+
+        assert_eq!(custom.encode("count", "8080", &mut data), None);
+        assert_eq!(custom.encode("peer", "Peer", &mut data), None);
+    }
+
+    #[test]
+    fn unknown_tag() {
+        let custom = CustomValues::new().with(Ipv4);
+        assert_eq!(rendered(&custom, 9, &[0x01, 0xab]), "#9:01ab");
+        assert_eq!(rendered(&CustomValues::new(), -3, &[]), "#-3:");
+
+        let mut out = String::new();
+        assert!(custom.render(4, &[1, 2], &mut out).is_err());
+    }
+}
@@ -0,0 +1,339 @@
+use crate::{
+    printer::NewEvent,
+    tape::{FieldValue, Instruction, InstructionSet, TapeMachine, Value},
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use std::collections::{HashSet, VecDeque};
+
+/// Drops events carrying a value of a field already seen within a time window, for events
+/// re-emitted by retries upstream.
+///
+/// Events are held until `FinishedEvent`, as the field may be any of their values. Events
+/// without the field are always forwarded. The window is measured with the time of the events.
+pub struct Dedup<T> {
+    forward: T,
+    field: String,
+    window: TimeDelta,
+    seen: HashSet<String>,
+    expiry: VecDeque<(DateTime<Utc>, String)>,
+    new_event: Option<NewEvent>,
+}
+impl<T> Dedup<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    /// Keys events by their `field`, e.g. `event_id`, forgetting keys after `window`.
+    pub fn new(forward: T, field: &str, window: TimeDelta) -> Self {
+        Self {
+            forward,
+            field: field.to_owned(),
+            window,
+            seen: Default::default(),
+            expiry: Default::default(),
+            new_event: None,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.forward
+    }
+
+    /// Whether `event` repeats a key seen within the window, remembering its key otherwise.
+    fn duplicated(&mut self, event: &NewEvent) -> bool {
+        while let Some((time, _)) = self.expiry.front()
+            && *time + self.window <= event.time
+        {
+            let (_, key) = self.expiry.pop_front().unwrap();
+            self.seen.remove(&key);
+        }
+
+        let Some(record) = event
+            .records
+            .iter()
+            .find(|record| record.name == self.field)
+        else {
+            return false;
+        };
+        let key = format!("{:?}", record.value);
+        if !self.seen.insert(key.clone()) {
+            return true;
+        }
+
+        self.expiry.push_back((event.time, key));
+        false
+    }
+}
+impl<T> TapeMachine<InstructionSet> for Dedup<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    fn needs_restart(&mut self) -> bool {
+        self.forward.needs_restart()
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::StartEvent {
+                time,
+                span,
+                target,
+                priority,
+                location,
+                thread,
+            } => {
+                self.new_event = Some(NewEvent {
+                    time,
+                    span,
+                    target: target.to_owned(),
+                    priority,
+                    location: location.map(|location| location.map(str::to_owned)),
+                    thread: thread.map(|thread| thread.map(str::to_owned)),
+                    records: Default::default(),
+                });
+            }
+            Instruction::AddValue(value) if let Some(event) = self.new_event.as_mut() => {
+                event.records.push(value.to_owned());
+            }
+            Instruction::FinishedEvent => {
+                let Some(event) = self.new_event.take() else {
+                    return;
+                };
+                if !self.duplicated(&event) {
+                    event.forward(&mut self.forward);
+                }
+            }
+            instruction => {
+                if let Instruction::Restart = instruction {
+                    self.new_event = None;
+                }
+                self.forward.handle(instruction);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.forward.flush();
+    }
+}
+
+/// Field added by [`Repeats`] to events that were repeated.
+pub const REPEAT_COUNT_FIELD: &str = "repeat_count";
+
+/// Collapses consecutive identical events into the first one, with a [`REPEAT_COUNT_FIELD`]
+/// counting them all, like syslog's "last message repeated N times".
+///
+/// Events are identical when only their time differs. The last event is held until a different
+/// one, a `Restart`, the deletion of its span or a flush.
+pub struct Repeats<T> {
+    forward: T,
+    held: Option<(NewEvent, u64)>,
+    new_event: Option<NewEvent>,
+}
+impl<T> Repeats<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    pub fn new(forward: T) -> Self {
+        Self {
+            forward,
+            held: None,
+            new_event: None,
+        }
+    }
+
+    pub fn into_inner(mut self) -> T {
+        self.release();
+        self.forward
+    }
+
+    fn release(&mut self) {
+        let Some((mut event, count)) = self.held.take() else {
+            return;
+        };
+        if count > 1 {
+            event.records.push(
+                FieldValue {
+                    name: REPEAT_COUNT_FIELD,
+                    value: Value::<&str>::Unsigned(count),
+                }
+                .to_owned(),
+            );
+        }
+        event.forward(&mut self.forward);
+    }
+
+    fn repeats(held: &NewEvent, event: &NewEvent) -> bool {
+        held.span == event.span
+            && held.priority == event.priority
+            && held.target == event.target
+            && held.records == event.records
+    }
+}
+impl<T> TapeMachine<InstructionSet> for Repeats<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    fn needs_restart(&mut self) -> bool {
+        self.forward.needs_restart()
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::StartEvent {
+                time,
+                span,
+                target,
+                priority,
+                location,
+                thread,
+            } => {
+                self.new_event = Some(NewEvent {
+                    time,
+                    span,
+                    target: target.to_owned(),
+                    priority,
+                    location: location.map(|location| location.map(str::to_owned)),
+                    thread: thread.map(|thread| thread.map(str::to_owned)),
+                    records: Default::default(),
+                });
+            }
+            Instruction::AddValue(value) if let Some(event) = self.new_event.as_mut() => {
+                event.records.push(value.to_owned());
+            }
+            Instruction::FinishedEvent => {
+                let Some(event) = self.new_event.take() else {
+                    return;
+                };
+                match self.held.as_mut() {
+                    Some((held, count)) if Self::repeats(held, &event) => *count += 1,
+                    _ => {
+                        self.release();
+                        self.held = Some((event, 1));
+                    }
+                }
+            }
+            instruction => {
+                match instruction {
+                    Instruction::Restart => {
+                        self.release();
+                        self.new_event = None;
+                    }
+                    Instruction::DeleteSpan(span)
+                        if self
+                            .held
+                            .as_ref()
+                            .is_some_and(|(held, _)| held.span == Some(span)) =>
+                    {
+                        self.release();
+                    }
+                    _ => (),
+                }
+                self.forward.handle(instruction);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.release();
+        self.forward.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tape::{FieldValue, Value};
+    use std::sync::{Arc, Mutex};
+    use tracing::Level;
+
+    struct Times(Arc<Mutex<Vec<i64>>>);
+    impl TapeMachine<InstructionSet> for Times {
+        fn needs_restart(&mut self) -> bool {
+            false
+        }
+
+        fn handle(&mut self, instruction: Instruction) {
+            if let Instruction::StartEvent { time, .. } = instruction {
+                self.0.lock().unwrap().push(time.timestamp());
+            }
+        }
+    }
+
+    #[test]
+    fn drops_repeated_ids() {
+        let times = Arc::new(Mutex::new(Vec::new()));
+        let mut dedup = Dedup::new(
+            Times(times.clone()),
+            "event_id",
+            TimeDelta::try_seconds(10).unwrap(),
+        );
+
+        dedup.handle(Instruction::Restart);
+        for (secs, id) in [
+            (0, Some(1)),
+            (1, Some(1)),
+            (2, Some(2)),
+            (3, None),
+            (4, None),
+            (11, Some(1)),
+        ] {
+            dedup.handle(Instruction::StartEvent {
+                time: DateTime::from_timestamp(secs, 0).unwrap(),
+                span: None,
+                target: "retrying",
+                priority: Level::INFO,
+                location: None,
+                thread: None,
+            });
+            if let Some(id) = id {
+                dedup.handle(Instruction::AddValue(FieldValue {
+                    name: "event_id",
+                    value: Value::Unsigned(id),
+                }));
+            }
+            dedup.handle(Instruction::FinishedEvent);
+        }
+
+        assert_eq!(*times.lock().unwrap(), [0, 2, 3, 4, 11]);
+    }
+
+    struct Counts(Vec<(i64, Option<u64>)>);
+    impl TapeMachine<InstructionSet> for Counts {
+        fn needs_restart(&mut self) -> bool {
+            false
+        }
+
+        fn handle(&mut self, instruction: Instruction) {
+            match instruction {
+                Instruction::StartEvent { time, .. } => self.0.push((time.timestamp(), None)),
+                Instruction::AddValue(FieldValue {
+                    name: REPEAT_COUNT_FIELD,
+                    value: Value::Unsigned(count),
+                }) => self.0.last_mut().unwrap().1 = Some(count),
+                _ => (),
+            }
+        }
+    }
+
+    #[test]
+    fn collapses_repeats() {
+        let mut repeats = Repeats::new(Counts(Vec::new()));
+        for (secs, message) in [(0, "a"), (1, "a"), (2, "a"), (3, "b"), (4, "a")] {
+            repeats.handle(Instruction::StartEvent {
+                time: DateTime::from_timestamp(secs, 0).unwrap(),
+                span: None,
+                target: "target",
+                priority: Level::INFO,
+                location: None,
+                thread: None,
+            });
+            repeats.handle(Instruction::AddValue(FieldValue {
+                name: "message",
+                value: Value::Debug(message),
+            }));
+            repeats.handle(Instruction::FinishedEvent);
+        }
+
+        assert_eq!(repeats.into_inner().0, [(0, Some(3)), (3, None), (4, None)]);
+    }
+}
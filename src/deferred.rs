@@ -0,0 +1,169 @@
+use crate::{
+    storage::{FlushPolicy, Load, Store},
+    tape::{Instruction, InstructionSet, TapeMachine},
+};
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+/// Buffers the tape in memory until the machine that should receive it is known.
+///
+/// Clones share the same buffer, so one clone can be given to a logger while the other one later
+/// sets the target with [`set`](Self::set).
+#[derive(Clone)]
+pub struct Deferred {
+    state: Arc<Mutex<DeferredState>>,
+}
+enum DeferredState {
+    Buffering(Box<Store<Vec<u8>>>),
+    Forwarding(Box<dyn TapeMachine<InstructionSet>>),
+    Discarding,
+}
+impl Default for Deferred {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Deferred {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(DeferredState::Buffering(Box::new(
+                Store::with_flush_policy(Vec::new(), FlushPolicy::Manual),
+            )))),
+        }
+    }
+
+    /// Replays the buffered instructions into `machine`, which then receives every later
+    /// instruction.
+    pub fn set(&self, mut machine: Box<dyn TapeMachine<InstructionSet>>) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let replay = match std::mem::replace(&mut *state, DeferredState::Discarding) {
+            DeferredState::Buffering(store) => {
+                Load::new((*store).into_inner().as_slice()).forward(&mut machine)
+            }
+            DeferredState::Forwarding(..) | DeferredState::Discarding => Ok(()),
+        };
+        *state = DeferredState::Forwarding(machine);
+
+        replay
+    }
+
+    /// Drops the buffered instructions, and every later one.
+    pub fn discard(&self) {
+        *self.state.lock().unwrap() = DeferredState::Discarding;
+    }
+}
+impl TapeMachine<InstructionSet> for Deferred {
+    fn needs_restart(&mut self) -> bool {
+        match &mut *self.state.lock().unwrap() {
+            DeferredState::Forwarding(machine) => machine.needs_restart(),
+            DeferredState::Buffering(..) | DeferredState::Discarding => false,
+        }
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match &mut *self.state.lock().unwrap() {
+            DeferredState::Buffering(store) => {
+                TapeMachine::<InstructionSet>::handle(&mut **store, instruction)
+            }
+            DeferredState::Forwarding(machine) => machine.handle(instruction),
+            DeferredState::Discarding => (),
+        }
+    }
+
+    fn flush(&mut self) {
+        if let DeferredState::Forwarding(machine) = &mut *self.state.lock().unwrap() {
+            machine.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{Shared, event, golden_tape, start_event};
+    use chrono::DateTime;
+    use tracing::Level;
+
+    struct Targets(Arc<Mutex<Vec<String>>>);
+    impl TapeMachine<InstructionSet> for Targets {
+        fn needs_restart(&mut self) -> bool {
+            false
+        }
+
+        fn handle(&mut self, instruction: Instruction) {
+            match instruction {
+                Instruction::StartEvent { target, .. } => {
+                    self.0.lock().unwrap().push(target.to_owned());
+                }
+                Instruction::FinishedEvent => self.0.lock().unwrap().push("finished".to_owned()),
+                _ => (),
+            }
+        }
+    }
+
+    fn start(target: &str) -> Instruction<'_> {
+        start_event(DateTime::UNIX_EPOCH, None, target, Level::INFO)
+    }
+
+    #[test]
+    fn replays_buffer() {
+        let mut deferred = Deferred::new();
+        deferred.handle(Instruction::Restart);
+        event(&mut deferred, start("early"), &[]);
+
+        let targets = Arc::new(Mutex::new(Vec::new()));
+        deferred
+            .clone()
+            .set(Box::new(Targets(targets.clone())))
+            .unwrap();
+        event(&mut deferred, start("late"), &[]);
+
+        assert_eq!(
+            *targets.lock().unwrap(),
+            ["early", "finished", "late", "finished"]
+        );
+    }
+
+    #[test]
+    fn replays_golden_tape() {
+        let mut expected = Store::new(Vec::new());
+        golden_tape(&mut expected);
+
+        let mut deferred = Deferred::new();
+        golden_tape(&mut deferred);
+        let out = Shared::default();
+        deferred.set(Box::new(Store::new(out.clone()))).unwrap();
+
+        assert_eq!(*out.0.lock().unwrap(), expected.into_inner());
+    }
+
+    #[test]
+    fn event_split_by_set() {
+        let mut deferred = Deferred::new();
+        deferred.handle(Instruction::Restart);
+        deferred.handle(start("split"));
+
+        let targets = Arc::new(Mutex::new(Vec::new()));
+        deferred.set(Box::new(Targets(targets.clone()))).unwrap();
+        deferred.handle(Instruction::FinishedEvent);
+
+        assert_eq!(*targets.lock().unwrap(), ["split", "finished"]);
+    }
+
+    #[test]
+    fn set_after_discard() {
+        let mut deferred = Deferred::new();
+        deferred.handle(Instruction::Restart);
+        event(&mut deferred, start("discarded"), &[]);
+        deferred.discard();
+        event(&mut deferred, start("discarded"), &[]);
+
+        let targets = Arc::new(Mutex::new(Vec::new()));
+        deferred.set(Box::new(Targets(targets.clone()))).unwrap();
+        event(&mut deferred, start("late"), &[]);
+
+        assert_eq!(*targets.lock().unwrap(), ["late", "finished"]);
+    }
+}
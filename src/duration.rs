@@ -0,0 +1,34 @@
+use std::time::Duration;
+
+/// Formats `duration` with three significant digits, as `tracing_subscriber::fmt` does for
+/// span timings.
+pub fn format_duration(duration: Duration) -> String {
+    let mut value = duration.as_nanos() as f64;
+    for unit in ["ns", "µs", "ms"] {
+        if value < 1000.0 {
+            return format_significant(value, unit);
+        }
+        value /= 1000.0;
+    }
+    format_significant(value, "s")
+}
+
+fn format_significant(value: f64, unit: &str) -> String {
+    match value {
+        value if value < 10.0 => format!("{value:.2}{unit}"),
+        value if value < 100.0 => format!("{value:.1}{unit}"),
+        value => format!("{value:.0}{unit}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration_format() {
+        let formatted = [1, 12_345, 123_456_789, 12_345_678_901, 1_234_567_890_123]
+            .map(|nanos| format_duration(Duration::from_nanos(nanos)));
+        assert_eq!(formatted, ["1.00ns", "12.3µs", "123ms", "12.3s", "1235s"]);
+    }
+}
@@ -0,0 +1,195 @@
+use crate::{
+    printer::NewEvent,
+    storage::{Load, Store, decode_err},
+    string_cache::{CacheInstruction, CacheString},
+    tape::{Instruction, InstructionTrait},
+};
+use std::io;
+
+/// Bytes [`Store`] writes for `instruction`, without checksums.
+///
+/// Runs the encoder into a counter, so it never gets out of sync with it, but nothing is
+/// buffered.
+pub fn encoded_size(instruction: &Instruction) -> usize {
+    encoded_size_cached(&Store::<Vec<u8>>::as_cached(*instruction))
+}
+
+/// [`encoded_size`] of a cached instruction, as written after a
+/// [`StringCache`](crate::string_cache::StringCache).
+pub fn encoded_size_cached(instruction: &CacheInstruction) -> usize {
+    let mut counter = ByteCounter(0);
+    let _ = Store::do_write_cached(&mut counter, *instruction);
+    counter.0
+}
+
+struct ByteCounter(usize);
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Encodes one event into a standalone blob, with all strings present, so it can be decoded with
+/// [`decode_event`] without any other context.
+///
+/// The blob is itself a valid tape, so concatenated blobs can also be read with [`Load`]. Only
+/// the id of the event span is kept, not the span fields.
+pub fn encode_event(event: &NewEvent, out: &mut Vec<u8>) -> io::Result<()> {
+    Store::do_write_cached(out, CacheInstruction::Restart)?;
+    Store::do_write_cached(
+        out,
+        CacheInstruction::StartEvent {
+            time: event.time,
+            span: event.span,
+            target: CacheString::Present(&event.target),
+            priority: event.priority,
+            location: event
+                .location
+                .as_ref()
+                .map(|location| location.as_ref().map(|str| CacheString::Present(str))),
+            thread: event
+                .thread
+                .as_ref()
+                .map(|thread| thread.as_ref().map(|str| CacheString::Present(str))),
+        },
+    )?;
+    for record in event.records.iter() {
+        Store::do_write_cached(
+            out,
+            Store::<Vec<u8>>::as_cached(Instruction::AddValue(record.as_ref())),
+        )?;
+    }
+    Store::do_write_cached(out, CacheInstruction::FinishedEvent)?;
+
+    Ok(())
+}
+
+/// Decodes a blob produced by [`encode_event`].
+pub fn decode_event(blob: &[u8]) -> io::Result<NewEvent> {
+    let mut load = Load::new(blob);
+    let mut event = None;
+
+    while let Some(instruction) = load.fetch_one()? {
+        match (instruction, event.as_mut()) {
+            (Instruction::Restart, None) => (),
+            (
+                Instruction::StartEvent {
+                    time,
+                    span,
+                    target,
+                    priority,
+                    location,
+                    thread,
+                },
+                None,
+            ) => {
+                event = Some(NewEvent {
+                    time,
+                    span,
+                    target: target.to_owned(),
+                    priority,
+                    location: location.map(|location| location.map(str::to_owned)),
+                    thread: thread.map(|thread| thread.map(str::to_owned)),
+                    records: Default::default(),
+                });
+            }
+            (Instruction::AddValue(value), Some(event)) => event.records.push(value.to_owned()),
+            (Instruction::FinishedEvent, Some(_)) => return Ok(event.unwrap()),
+            (instruction, _) => {
+                return Err(decode_err(format!(
+                    "Unexpected instruction {:?} in event blob",
+                    instruction.id()
+                )));
+            }
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "Event blob ended before FinishedEvent",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tape::{FieldValue, Location, ThreadInfo, Value};
+    use chrono::DateTime;
+    use std::num::NonZeroU64;
+    use tracing::Level;
+
+    #[test]
+    fn encoded_size_matches_store() {
+        let instructions = [
+            Instruction::Restart,
+            Instruction::NewSpan {
+                parent: None,
+                span: NonZeroU64::new(300).unwrap(),
+                name: "request",
+            },
+            Instruction::StartEvent {
+                time: DateTime::from_timestamp(1_700_000_000, 5).unwrap(),
+                span: NonZeroU64::new(300),
+                target: "target",
+                priority: Level::WARN,
+                location: None,
+                thread: Some(ThreadInfo {
+                    id: 7,
+                    name: "main",
+                }),
+            },
+            Instruction::AddValue(FieldValue {
+                name: "bytes",
+                value: Value::ByteArray(&[0; 300]),
+            }),
+            Instruction::FinishedEvent,
+        ];
+
+        for instruction in instructions {
+            let mut out = Vec::new();
+            Store::do_handle(&mut out, instruction).unwrap();
+            assert_eq!(encoded_size(&instruction), out.len(), "{instruction:?}");
+        }
+    }
+
+    #[test]
+    fn event_blob_roundtrip() {
+        let event = NewEvent {
+            time: DateTime::from_timestamp(1_700_000_000, 5).unwrap(),
+            span: NonZeroU64::new(3),
+            target: "target".to_string(),
+            priority: Level::WARN,
+            location: Some(Location {
+                file: "src/main.rs".to_string(),
+                line: 42,
+                module_path: "app".to_string(),
+            }),
+            thread: Some(ThreadInfo {
+                id: 1,
+                name: "main".to_string(),
+            }),
+            records: vec![
+                FieldValue {
+                    name: "message",
+                    value: Value::Debug("hello"),
+                }
+                .to_owned(),
+            ],
+        };
+
+        let mut blob = Vec::new();
+        encode_event(&event, &mut blob).unwrap();
+        let decoded = decode_event(&blob).unwrap();
+
+        assert_eq!(decoded.to_line(false, &[]), event.to_line(false, &[]));
+        assert_eq!(decoded.span, event.span);
+        assert_eq!(decoded.location, event.location);
+        assert_eq!(decoded.thread, event.thread);
+        assert!(decode_event(&blob[..blob.len() - 1]).is_err());
+    }
+}
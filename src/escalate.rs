@@ -0,0 +1,180 @@
+use crate::tape::{Instruction, InstructionSet, TapeMachine};
+use chrono::{DateTime, TimeDelta, Utc};
+use tracing::Level;
+
+/// Forwards only events up to `threshold`, but temporarily lowers the threshold after a failure.
+///
+/// Once an event at `trigger` level (ERROR by default) is seen, events up to `escalated` level
+/// (DEBUG by default) are forwarded for the next `window` (30 seconds by default). The subscriber
+/// filter must let the escalated levels through for this machine to see them.
+pub struct Escalate<T> {
+    forward: T,
+    threshold: Level,
+    escalated: Level,
+    trigger: Level,
+    window: TimeDelta,
+    until: Option<DateTime<Utc>>,
+    skipping: bool,
+}
+impl<T> Escalate<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    pub fn new(forward: T, threshold: Level) -> Self {
+        Self {
+            forward,
+            threshold,
+            escalated: Level::DEBUG,
+            trigger: Level::ERROR,
+            window: TimeDelta::seconds(30),
+            until: None,
+            skipping: false,
+        }
+    }
+
+    pub fn with_escalated(mut self, escalated: Level) -> Self {
+        self.escalated = escalated;
+        self
+    }
+
+    pub fn with_trigger(mut self, trigger: Level) -> Self {
+        self.trigger = trigger;
+        self
+    }
+
+    pub fn with_window(mut self, window: TimeDelta) -> Self {
+        self.window = window;
+        self
+    }
+
+    fn enabled(&mut self, time: DateTime<Utc>, priority: Level) -> bool {
+        if priority <= self.trigger {
+            self.until = Some(time + self.window);
+        }
+
+        let threshold = match self.until {
+            Some(until) if time <= until => self.escalated,
+            _ => self.threshold,
+        };
+
+        priority <= threshold
+    }
+}
+impl<T> TapeMachine<InstructionSet> for Escalate<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    fn needs_restart(&mut self) -> bool {
+        self.forward.needs_restart()
+    }
+
+    fn flush(&mut self) {
+        self.forward.flush();
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Restart => {
+                self.skipping = false;
+                self.forward.handle(instruction);
+            }
+            Instruction::StartEvent { time, priority, .. } => {
+                self.skipping = !self.enabled(time, priority);
+                if !self.skipping {
+                    self.forward.handle(instruction);
+                }
+            }
+            Instruction::AddValue(..) if self.skipping => (),
+            Instruction::FinishedEvent if self.skipping => {
+                self.skipping = false;
+            }
+            instruction => self.forward.handle(instruction),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::Store,
+        tape::{FieldValue, Value},
+        test_util::{event, golden_tape, start_event},
+    };
+
+    /// Levels of the forwarded events, and the names of their values.
+    #[derive(Default)]
+    struct Levels(Vec<Level>, Vec<String>);
+    impl TapeMachine<InstructionSet> for Levels {
+        fn needs_restart(&mut self) -> bool {
+            false
+        }
+
+        fn handle(&mut self, instruction: Instruction) {
+            match instruction {
+                Instruction::StartEvent { priority, .. } => self.0.push(priority),
+                Instruction::AddValue(value) => self.1.push(value.name.to_owned()),
+                _ => (),
+            }
+        }
+    }
+
+    #[test]
+    fn escalates_after_error() {
+        let mut escalate = Escalate::new(Levels::default(), Level::INFO);
+        let start = DateTime::<Utc>::default();
+
+        for (seconds, priority) in [
+            (0, Level::DEBUG),
+            (1, Level::ERROR),
+            (2, Level::DEBUG),
+            (3, Level::TRACE),
+            (40, Level::DEBUG),
+            (41, Level::INFO),
+        ] {
+            let time = start + TimeDelta::seconds(seconds);
+            event(
+                &mut escalate,
+                start_event(time, None, "target", priority),
+                &[],
+            );
+        }
+
+        assert_eq!(
+            escalate.forward.0,
+            [Level::ERROR, Level::DEBUG, Level::INFO]
+        );
+    }
+
+    #[test]
+    fn forwards_everything_up_to_threshold() {
+        let mut expected = Store::new(Vec::new());
+        golden_tape(&mut expected);
+        let mut escalate = Escalate::new(Store::new(Vec::new()), Level::TRACE);
+        golden_tape(&mut escalate);
+
+        assert_eq!(escalate.forward.into_inner(), expected.into_inner());
+    }
+
+    #[test]
+    fn skipped_event_cut_by_restart() {
+        let mut escalate = Escalate::new(Levels::default(), Level::INFO);
+        let time = DateTime::<Utc>::default();
+
+        // The writer restarted before finishing the skipped event
+        escalate.handle(start_event(time, None, "target", Level::DEBUG));
+        escalate.handle(Instruction::AddValue(FieldValue {
+            name: "skipped",
+            value: Value::Bool(true),
+        }));
+        escalate.handle(Instruction::Restart);
+        event(
+            &mut escalate,
+            start_event(time, None, "target", Level::INFO),
+            &[("kept", Value::Bool(true))],
+        );
+
+        assert_eq!(escalate.forward.0, [Level::INFO]);
+        assert_eq!(escalate.forward.1, ["kept"]);
+    }
+}
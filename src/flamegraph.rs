@@ -0,0 +1,180 @@
+use crate::tape::{Instruction, InstructionSet, TapeMachine};
+use chrono::{DateTime, Utc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    num::NonZeroU64,
+    time::Duration,
+};
+
+/// Aggregates the time spent per chain of span names while replaying a tape, for flamegraphs.
+///
+/// Spans recorded with [`with_span_timing`](crate::tape::TapeMachineLogger::with_span_timing)
+/// count their busy time. Other spans are timed against the latest event, like
+/// [`SpanDurations`](crate::span_histogram::SpanDurations), from their creation to their
+/// deletion. Spans never deleted are not measured.
+#[derive(Default)]
+pub struct FoldedStacks {
+    clock: Option<DateTime<Utc>>,
+    open: HashMap<NonZeroU64, OpenSpan>,
+    stacks: BTreeMap<String, Duration>,
+}
+impl FoldedStacks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time spent per chain of span names from the root, separated by `;`, including the time of
+    /// their children.
+    pub fn stacks(&self) -> &BTreeMap<String, Duration> {
+        &self.stacks
+    }
+
+    /// Writes the folded stacks consumed by `inferno` and `flamegraph.pl`: one line per chain with
+    /// the microseconds spent in it, excluding its children.
+    pub fn write_folded<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        let mut children = HashMap::<&str, Duration>::new();
+        for (stack, time) in self.stacks.iter() {
+            if let Some((parent, _)) = stack.rsplit_once(';') {
+                *children.entry(parent).or_default() += *time;
+            }
+        }
+
+        for (stack, time) in self.stacks.iter() {
+            let own = children
+                .get(stack.as_str())
+                .map_or(*time, |children| time.saturating_sub(*children));
+            let micros = own.as_micros();
+            if micros > 0 {
+                writeln!(out, "{stack} {micros}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+impl TapeMachine<InstructionSet> for FoldedStacks {
+    fn needs_restart(&mut self) -> bool {
+        false
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::NewSpan { parent, span, name } => {
+                // `;` separates the frames
+                let name = name.replace(';', ":");
+                let stack = match parent.and_then(|parent| self.open.get(&parent)) {
+                    Some(parent) => format!("{};{name}", parent.stack),
+                    None => name,
+                };
+                // Spans are replayed after a restart, keep their original start
+                self.open.entry(span).or_insert(OpenSpan {
+                    stack,
+                    start: self.clock,
+                    busy: None,
+                });
+            }
+            Instruction::StartEvent { time, .. }
+            | Instruction::EnterSpan { time, .. }
+            | Instruction::ExitSpan { time, .. } => {
+                self.clock = Some(time);
+            }
+            Instruction::SpanTiming {
+                time, span, busy, ..
+            } => {
+                self.clock = Some(time);
+                if let Some(open) = self.open.get_mut(&span) {
+                    open.busy = Some(busy);
+                }
+            }
+            Instruction::DeleteSpan(span) => {
+                let Some(open) = self.open.remove(&span) else {
+                    return;
+                };
+                let time = match (open.busy, open.start, self.clock) {
+                    (Some(busy), _, _) => busy,
+                    (None, Some(start), Some(end)) => (end - start).to_std().unwrap_or_default(),
+                    _ => return,
+                };
+
+                *self.stacks.entry(open.stack).or_default() += time;
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Span not yet deleted, with its chain of names.
+struct OpenSpan {
+    stack: String,
+    start: Option<DateTime<Utc>>,
+    busy: Option<Duration>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{event, golden_tape, new_span, span, start_event};
+    use tracing::Level;
+
+    fn folded(stacks: &FoldedStacks) -> String {
+        let mut out = String::new();
+        stacks.write_folded(&mut out).unwrap();
+        out
+    }
+
+    fn clock(stacks: &mut FoldedStacks, seconds: i64) {
+        let time = DateTime::from_timestamp(seconds, 0).unwrap();
+        event(stacks, start_event(time, None, "target", Level::INFO), &[]);
+    }
+
+    #[test]
+    fn folded_output() {
+        let mut stacks = FoldedStacks::new();
+        let timing = |stacks: &mut FoldedStacks, id, busy| {
+            stacks.handle(Instruction::SpanTiming {
+                time: Utc::now(),
+                span: span(id),
+                target: "target",
+                priority: Level::INFO,
+                busy: Duration::from_micros(busy),
+                idle: Duration::ZERO,
+            });
+            stacks.handle(Instruction::DeleteSpan(span(id)));
+        };
+        new_span(&mut stacks, None, span(1), "request", &[]);
+        new_span(&mut stacks, Some(span(1)), span(2), "query", &[]);
+        timing(&mut stacks, 2, 300);
+        new_span(&mut stacks, Some(span(1)), span(3), "query", &[]);
+        timing(&mut stacks, 3, 200);
+        timing(&mut stacks, 1, 1000);
+
+        assert_eq!(folded(&stacks), "request 500\nrequest;query 500\n");
+    }
+
+    #[test]
+    fn empty_stacks() {
+        assert_eq!(folded(&FoldedStacks::new()), "");
+
+        // Spans created before any event have no start
+        let mut stacks = FoldedStacks::new();
+        golden_tape(&mut stacks);
+        assert!(stacks.stacks().is_empty());
+    }
+
+    #[test]
+    fn timed_against_events() {
+        let mut stacks = FoldedStacks::new();
+        clock(&mut stacks, 1);
+        new_span(&mut stacks, None, span(1), "a;b", &[]);
+        new_span(&mut stacks, None, span(2), "never deleted", &[]);
+        clock(&mut stacks, 2);
+        // Replayed after a restart, keeping its start
+        stacks.handle(Instruction::Restart);
+        new_span(&mut stacks, None, span(1), "a;b", &[]);
+        clock(&mut stacks, 4);
+        stacks.handle(Instruction::DeleteSpan(span(1)));
+
+        assert_eq!(folded(&stacks), "a:b 3000000\n");
+    }
+}
@@ -0,0 +1,165 @@
+use crate::{
+    storage::{Load, TapeFile},
+    string_cache::CacheInstructionSet,
+    tape::TapeMachine,
+};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Reads a tape file as it is written, like `tail -f`.
+///
+/// Once the file is rotated or truncated, the rest of the previous file is read and then the new
+/// file is opened from the start. Rotation is detected by the file identity on Unix, elsewhere by
+/// the new file being shorter than what was already read.
+pub struct FollowLoad {
+    path: PathBuf,
+    load: Load<TapeFile>,
+}
+impl FollowLoad {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(&path, Load::open(&path)?))
+    }
+
+    /// Continues following `path` from the current position of `load`.
+    pub fn new<P: AsRef<Path>>(path: P, load: Load<TapeFile>) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+            load,
+        }
+    }
+
+    pub fn load_mut(&mut self) -> &mut Load<TapeFile> {
+        &mut self.load
+    }
+
+    pub fn restart(&mut self) {
+        self.load.restart();
+    }
+
+    /// Forwards every complete instruction written so far, see [`Load::forward_complete`].
+    pub fn poll<T>(&mut self, machine: &mut T) -> io::Result<()>
+    where
+        T: TapeMachine<CacheInstructionSet>,
+    {
+        let replaced = self.replaced()?;
+        self.load.forward_complete(machine)?;
+
+        if replaced {
+            self.load = Load::open(&self.path)?;
+            self.load.forward_complete(machine)?;
+        }
+
+        Ok(())
+    }
+
+    /// Polls every `poll_interval`, forever. Instructions that can't be decoded are skipped up to
+    /// the next `Restart`, any other error is returned.
+    pub fn follow<T>(&mut self, machine: &mut T, poll_interval: Duration) -> io::Result<()>
+    where
+        T: TapeMachine<CacheInstructionSet>,
+    {
+        loop {
+            match self.poll(machine) {
+                Ok(()) => std::thread::sleep(poll_interval),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput
+                    ) =>
+                {
+                    self.restart()
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Whether `path` is no longer the file being read.
+    fn replaced(&mut self) -> io::Result<bool> {
+        let current = match std::fs::metadata(&self.path) {
+            Ok(current) => current,
+            // Rotating, the new file is not created yet
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        #[cfg(any(feature = "gzip", feature = "lz4"))]
+        let file = match self.load.get_ref() {
+            TapeFile::Plain(file) => file,
+            TapeFile::Compressed(..) => return Ok(false),
+        };
+        #[cfg(not(any(feature = "gzip", feature = "lz4")))]
+        let TapeFile::Plain(file) = self.load.get_ref();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            let opened = file.metadata()?;
+            if (current.dev(), current.ino()) != (opened.dev(), opened.ino()) {
+                return Ok(true);
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = file;
+
+        Ok(current.len() < self.load.offset())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::Store, tape::Instruction, test_util::Targets};
+    use chrono::DateTime;
+    use std::fs::File;
+    use tracing::Level;
+
+    #[test]
+    fn follow_rotation() {
+        let path = std::env::temp_dir().join(format!("follow-rotate-{}.log", std::process::id()));
+        let rotated = path.with_extension("log.1");
+        let write = |path: &Path, targets: &[&str]| {
+            let mut out = Vec::new();
+            for target in targets {
+                Store::do_handle(
+                    &mut out,
+                    Instruction::StartEvent {
+                        time: DateTime::UNIX_EPOCH,
+                        span: None,
+                        target,
+                        priority: Level::INFO,
+                        location: None,
+                        thread: None,
+                    },
+                )
+                .unwrap();
+                Store::do_handle(&mut out, Instruction::FinishedEvent).unwrap();
+            }
+            let mut file = File::options().append(true).open(path).unwrap();
+            io::Write::write_all(&mut file, &out).unwrap();
+        };
+
+        let mut out = Vec::new();
+        Store::do_handle(&mut out, Instruction::Restart).unwrap();
+        std::fs::write(&path, &out).unwrap();
+        write(&path, &["first"]);
+        let mut follow = FollowLoad::open(&path).unwrap();
+        let mut targets = Targets(Vec::new());
+        follow.poll(&mut targets).unwrap();
+        assert_eq!(targets.0, ["first"]);
+
+        // Written just before rotating
+        write(&path, &["second"]);
+        std::fs::rename(&path, &rotated).unwrap();
+        std::fs::write(&path, &out).unwrap();
+        write(&path, &["third"]);
+        follow.poll(&mut targets).unwrap();
+        assert_eq!(targets.0, ["first", "second", "third"]);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&rotated).unwrap();
+    }
+}
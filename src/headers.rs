@@ -0,0 +1,161 @@
+use crate::tape::{Instruction, InstructionSet, TapeMachine};
+
+/// Records only the headers of events: their time, level, target and span. Field values,
+/// locations, threads and span records are dropped.
+///
+/// Meant for tiny tapes auditing event rates over long periods, where payloads aren't needed.
+/// Spans are kept, without their fields, so events can still be grouped by them.
+pub struct HeadersOnly<T> {
+    forward: T,
+}
+impl<T> HeadersOnly<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    pub fn new(forward: T) -> Self {
+        Self { forward }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.forward
+    }
+}
+impl<T> TapeMachine<InstructionSet> for HeadersOnly<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    fn needs_restart(&mut self) -> bool {
+        self.forward.needs_restart()
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::AddValue(..)
+            | Instruction::NewRecord(..)
+            | Instruction::FinishedRecord => {}
+            Instruction::StartEvent {
+                time,
+                span,
+                target,
+                priority,
+                ..
+            } => self.forward.handle(Instruction::StartEvent {
+                time,
+                span,
+                target,
+                priority,
+                location: None,
+                thread: None,
+            }),
+            instruction => self.forward.handle(instruction),
+        }
+    }
+
+    fn flush(&mut self) {
+        self.forward.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::{Load, Store},
+        string_cache::StringCache,
+        tape::{FieldValue, InstructionId, InstructionOwned, InstructionTrait, Value},
+        test_util::{event, golden_tape, span, start_event},
+    };
+    use chrono::DateTime;
+    use tracing::Level;
+
+    #[test]
+    fn drops_payloads() {
+        let mut full = StringCache::new(Store::new(Vec::new()));
+        golden_tape(&mut full);
+        let full = full.into_inner().into_inner();
+        let mut headers = HeadersOnly::new(StringCache::new(Store::new(Vec::new())));
+        golden_tape(&mut headers);
+        let headers = headers.into_inner().into_inner().into_inner();
+        assert!(headers.len() * 2 < full.len());
+
+        let mut load = Load::new(headers.as_slice());
+        let mut instructions = Vec::new();
+        while let Some(instruction) = load.fetch_one_cached().unwrap() {
+            if instruction.id() != InstructionId::NewString {
+                instructions.push(instruction.id());
+            }
+        }
+        assert_eq!(
+            instructions,
+            [
+                InstructionId::Restart,
+                InstructionId::FilterChanged,
+                InstructionId::NewSpan,
+                InstructionId::FinishedSpan,
+                InstructionId::NewSpan,
+                InstructionId::FinishedSpan,
+                InstructionId::StartEvent,
+                InstructionId::FinishedEvent,
+                InstructionId::StartEvent,
+                InstructionId::FinishedEvent,
+                InstructionId::DeleteSpan,
+                InstructionId::Restart,
+                InstructionId::DeleteSpan,
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_headers() {
+        let mut headers = HeadersOnly::new(Store::new(Vec::new()));
+        golden_tape(&mut headers);
+        let headers = headers.into_inner().into_inner();
+
+        let event = Load::new(headers.as_slice())
+            .iter()
+            .map(Result::unwrap)
+            .find(|instruction| matches!(instruction, InstructionOwned::StartEvent { .. }));
+        assert_eq!(
+            event,
+            Some(InstructionOwned::StartEvent {
+                time: DateTime::UNIX_EPOCH,
+                span: Some(span(u64::MAX)),
+                target: "target".to_owned(),
+                priority: Level::ERROR,
+                location: None,
+                thread: None,
+            })
+        );
+    }
+
+    #[test]
+    fn event_cut_by_restart() {
+        let mut headers = HeadersOnly::new(Store::new(Vec::new()));
+        headers.handle(Instruction::Restart);
+        headers.handle(start_event(DateTime::UNIX_EPOCH, None, "cut", Level::INFO));
+        headers.handle(Instruction::AddValue(FieldValue {
+            name: "message",
+            value: Value::Debug("never finished"),
+        }));
+        headers.handle(Instruction::Restart);
+        let start = start_event(DateTime::UNIX_EPOCH, None, "target", Level::INFO);
+        event(&mut headers, start, &[("message", Value::Debug("dropped"))]);
+        let headers = headers.into_inner().into_inner();
+
+        let mut load = Load::new(headers.as_slice());
+        let mut instructions = Vec::new();
+        while let Some(instruction) = load.fetch_one().unwrap() {
+            instructions.push(instruction.id());
+        }
+        assert_eq!(
+            instructions,
+            [
+                InstructionId::Restart,
+                InstructionId::StartEvent,
+                InstructionId::Restart,
+                InstructionId::StartEvent,
+                InstructionId::FinishedEvent
+            ]
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use crate::{
+    string_cache::{CacheInstruction, CacheInstructionSet},
+    tape::{Instruction, InstructionSet, TapeMachine},
+};
+use tracing::{Level, level_filters};
+
+/// Drops events less severe than a level, before their strings are resolved.
+///
+/// Everything else, including the strings cached by the dropped events, is forwarded.
+pub struct LevelFilter<T> {
+    forward: T,
+    level: Level,
+    skipping: bool,
+}
+impl<T> LevelFilter<T>
+where
+    T: TapeMachine<CacheInstructionSet>,
+{
+    /// Keeps events of `level` and more severe ones.
+    pub fn new(forward: T, level: Level) -> Self {
+        Self {
+            forward,
+            level,
+            skipping: false,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.forward
+    }
+}
+impl<T> TapeMachine<CacheInstructionSet> for LevelFilter<T>
+where
+    T: TapeMachine<CacheInstructionSet>,
+{
+    fn needs_restart(&mut self) -> bool {
+        self.forward.needs_restart()
+    }
+
+    fn handle(&mut self, instruction: CacheInstruction) {
+        match instruction {
+            CacheInstruction::StartEvent { priority, .. } if priority > self.level => {
+                self.skipping = true;
+            }
+            CacheInstruction::AddValue(..) if self.skipping => (),
+            CacheInstruction::FinishedEvent if self.skipping => self.skipping = false,
+            instruction => {
+                if let CacheInstruction::Restart = instruction {
+                    self.skipping = false;
+                }
+                self.forward.handle(instruction);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.forward.flush();
+    }
+}
+
+/// Drops whole events above a maximum level, before they reach the string cache or the disk.
+///
+/// The level can be overridden for targets under a prefix, the longest matching prefix winning.
+/// Spans are always forwarded, as kept events may be inside them.
+pub struct FilterMachine<T> {
+    forward: T,
+    max_level: level_filters::LevelFilter,
+    targets: Vec<(TargetFilter, level_filters::LevelFilter)>,
+    skipping: bool,
+}
+impl<T> FilterMachine<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    pub fn new<L: Into<level_filters::LevelFilter>>(forward: T, max_level: L) -> Self {
+        Self {
+            forward,
+            max_level: max_level.into(),
+            targets: Vec::new(),
+            skipping: false,
+        }
+    }
+
+    /// Uses `max_level` for `prefix` and its submodules, e.g. `LevelFilter::OFF` to drop them.
+    pub fn with_target<L: Into<level_filters::LevelFilter>>(
+        mut self,
+        prefix: &str,
+        max_level: L,
+    ) -> Self {
+        self.targets
+            .push((TargetFilter::new(prefix), max_level.into()));
+        self.targets
+            .sort_by_key(|(filter, _)| std::cmp::Reverse(filter.prefix().len()));
+        self
+    }
+
+    pub fn into_inner(self) -> T {
+        self.forward
+    }
+
+    pub fn enabled(&self, target: &str, level: Level) -> bool {
+        let max_level = self
+            .targets
+            .iter()
+            .find(|(filter, _)| filter.matches(target))
+            .map_or(self.max_level, |(_, max_level)| *max_level);
+        level <= max_level
+    }
+}
+impl<T> TapeMachine<InstructionSet> for FilterMachine<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    fn needs_restart(&mut self) -> bool {
+        self.forward.needs_restart()
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::StartEvent {
+                target, priority, ..
+            } if !self.enabled(target, priority) => {
+                self.skipping = true;
+            }
+            Instruction::AddValue(..) if self.skipping => (),
+            Instruction::FinishedEvent if self.skipping => self.skipping = false,
+            instruction => {
+                if let Instruction::Restart = instruction {
+                    self.skipping = false;
+                }
+                self.forward.handle(instruction);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.forward.flush();
+    }
+}
+
+/// Matches event targets by prefix, on module boundaries: `my_crate::db` matches
+/// `my_crate::db` and `my_crate::db::pool`, but not `my_crate::dbx`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TargetFilter {
+    prefix: String,
+}
+impl TargetFilter {
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.trim_end_matches("::").to_owned(),
+        }
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    pub fn matches(&self, target: &str) -> bool {
+        match target.strip_prefix(self.prefix.as_str()) {
+            Some(rest) => rest.is_empty() || rest.starts_with("::"),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::{Load, Store},
+        string_cache::{StringCache, StringUncache},
+        tape::{FieldValue, Value},
+    };
+    use chrono::DateTime;
+
+    #[derive(Default)]
+    struct Targets(Vec<(String, Vec<String>)>);
+    impl TapeMachine<InstructionSet> for Targets {
+        fn needs_restart(&mut self) -> bool {
+            false
+        }
+
+        fn handle(&mut self, instruction: Instruction) {
+            match instruction {
+                Instruction::StartEvent { target, .. } => {
+                    self.0.push((target.to_owned(), Vec::new()));
+                }
+                Instruction::AddValue(value) => {
+                    self.0.last_mut().unwrap().1.push(value.name.to_owned());
+                }
+                _ => (),
+            }
+        }
+    }
+
+    #[test]
+    fn skips_verbose_events() {
+        let mut tape = StringCache::new(Store::new(Vec::new()));
+        tape.handle(Instruction::Restart);
+        for (priority, target) in [
+            (Level::DEBUG, "debug"),
+            (Level::WARN, "warn"),
+            (Level::INFO, "info"),
+        ] {
+            tape.handle(Instruction::StartEvent {
+                time: DateTime::UNIX_EPOCH,
+                span: None,
+                target,
+                priority,
+                location: None,
+                thread: None,
+            });
+            tape.handle(Instruction::AddValue(FieldValue {
+                name: "message",
+                value: Value::Debug(target),
+            }));
+            tape.handle(Instruction::FinishedEvent);
+        }
+        let tape = tape.into_inner().into_inner();
+
+        let mut filter = LevelFilter::new(StringUncache::new(Store::new(Vec::new())), Level::INFO);
+        Load::new(tape.as_slice())
+            .forward_cached(&mut filter)
+            .unwrap();
+        let filtered = filter.into_inner().into_inner().into_inner();
+
+        let mut load = Load::new(filtered.as_slice());
+        let mut targets = Vec::new();
+        while let Some(instruction) = load.fetch_one().unwrap() {
+            match instruction {
+                Instruction::StartEvent { target, .. } => targets.push(target.to_owned()),
+                Instruction::AddValue(FieldValue {
+                    value: Value::Debug(message),
+                    ..
+                }) => assert_eq!(message, targets.last().unwrap()),
+                _ => (),
+            }
+        }
+        assert_eq!(targets, ["warn", "info"]);
+    }
+
+    #[test]
+    fn filter_machine() {
+        let mut filter = FilterMachine::new(Targets::default(), Level::INFO)
+            .with_target("app", Level::DEBUG)
+            .with_target("app::noisy", level_filters::LevelFilter::OFF);
+        for (target, priority) in [
+            ("app", Level::DEBUG),
+            ("app::noisy", Level::ERROR),
+            ("application", Level::DEBUG),
+            ("other", Level::INFO),
+        ] {
+            filter.handle(Instruction::StartEvent {
+                time: DateTime::UNIX_EPOCH,
+                span: None,
+                target,
+                priority,
+                location: None,
+                thread: None,
+            });
+            filter.handle(Instruction::AddValue(FieldValue {
+                name: target,
+                value: Value::Bool(true),
+            }));
+            filter.handle(Instruction::FinishedEvent);
+        }
+
+        assert_eq!(
+            filter.into_inner().0,
+            [
+                ("app".to_owned(), vec!["app".to_owned()]),
+                ("other".to_owned(), vec!["other".to_owned()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn target_prefix() {
+        let filter = TargetFilter::new("my_crate::db");
+        assert!(filter.matches("my_crate::db"));
+        assert!(filter.matches("my_crate::db::pool"));
+        assert!(!filter.matches("my_crate::dbx"));
+        assert!(!filter.matches("my_crate"));
+        assert!(TargetFilter::new("my_crate::").matches("my_crate::db"));
+    }
+}
@@ -1,18 +1,99 @@
+use deferred::Deferred;
 use printer::Printer;
 use restart::RestartableMachine;
-use rotate::Rotate;
-use std::{io, path::Path};
-use storage::Store;
-use string_cache::StringCache;
-use tape::{InstructionSet, TapeMachine, TapeMachineLogger};
-use tracing_subscriber::{Registry, layer::SubscriberExt, util::SubscriberInitExt};
+use rotate::{MaxLen, Rotate};
+use sampling::{SampleRate, SamplingMachine};
+use slice::Slice;
+#[cfg(feature = "env-filter")]
+use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    io,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread::JoinHandle,
+    time::Duration,
+};
+use storage::{FlushPolicy, Store};
+use string_cache::{SNAPSHOT_MAX_STRINGS, StringCache};
+#[cfg(feature = "env-filter")]
+use tape::Instruction;
+use tape::{
+    InstructionSet, PipelineFull, Reentrancy, SpanEvents, TapeMachine, TapeMachineLogger, Tee,
+};
+use tracing::{Level, Metadata};
+#[cfg(feature = "env-filter")]
+use tracing_subscriber::{EnvFilter, filter::ParseError, reload};
+use tracing_subscriber::{
+    Layer, Registry, filter::filter_fn, layer::SubscriberExt, util::SubscriberInitExt,
+};
 
+pub mod annotation;
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod bundle;
+pub mod cardinality;
+#[cfg(feature = "json")]
+pub mod chrome_trace;
+#[cfg(any(feature = "gzip", feature = "lz4"))]
+pub mod compressed;
+pub mod custom_value;
+pub mod dedup;
+pub mod deferred;
+pub mod duration;
+pub mod encoding;
+pub mod escalate;
+pub mod flamegraph;
+pub mod follow;
+pub mod headers;
+pub mod level_filter;
+pub mod merge;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod pipeline;
 pub mod printer;
+#[cfg(feature = "pseudonymize")]
+pub mod pseudonymize;
+pub mod query;
+pub mod rate_limit;
+pub mod receiver;
+pub mod redact;
+pub mod repair;
 pub mod restart;
 pub mod rotate;
+pub mod sampling;
+pub mod slice;
+pub mod socket;
+pub mod span_histogram;
+pub mod span_remap;
+pub mod stats;
 pub mod storage;
 pub mod string_cache;
+pub mod syslog;
 pub mod tape;
+#[cfg(test)]
+pub(crate) mod test_util;
+pub mod time_index;
+pub mod time_range;
+pub mod verify;
+
+/// Emits an event with a target computed at runtime.
+///
+/// The target is carried by the [`TARGET_FIELD`](tape::TARGET_FIELD) field, which is only
+/// understood by [`TapeMachineLogger`](tape::TapeMachineLogger). Other layers see it as a regular
+/// field.
+///
+/// ```
+/// let tenant = "tenant-42";
+/// msgpack_tracing::event_with_target!(tenant, tracing::Level::INFO, answer = 42, "Routed");
+/// ```
+#[macro_export]
+macro_rules! event_with_target {
+    ($target:expr, $level:expr, $($arg:tt)*) => {
+        ::tracing::event!($level, tape.target = %$target, $($arg)*)
+    };
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum WithConsole {
@@ -21,76 +102,1046 @@ pub enum WithConsole {
     Disabled,
 }
 
-pub fn install_logger<W>(out: W, console: WithConsole)
+/// Handle to a logger installed by one of the `install_*` functions.
+///
+/// Dropping it leaves the logger installed. See [`LoggerGuard`] to also flush its outputs on exit.
+pub struct LoggerHandle {
+    reload: Reload,
+    flush: Box<dyn Fn() + Send + Sync>,
+}
+impl LoggerHandle {
+    /// Writes out anything buffered by the tape outputs.
+    pub fn flush(&self) {
+        (self.flush)();
+    }
+
+    /// Replaces the filter directives at runtime.
+    ///
+    /// The new directives are recorded into the tape, so that readers know why verbosity changed.
+    #[cfg(feature = "env-filter")]
+    pub fn set_filter(&self, directives: &str) -> Result<(), FilterError> {
+        self.reload.filter.set(directives)
+    }
+
+    /// Changes the length at which the tape of [`LoggerBuilder::rotate`] is rotated. The error log
+    /// is unaffected.
+    pub fn set_rotate_max_len(&self, max_len: u64) -> Result<(), ConfigError> {
+        let handle = self.reload.outputs.max_len.as_ref();
+        handle
+            .ok_or(ConfigError::Unsupported(ROTATE_MAX_LEN))?
+            .set(max_len);
+        Ok(())
+    }
+
+    /// Changes the fraction of events kept by [`LoggerBuilder::sampling`].
+    pub fn set_sampling(&self, fraction: f64) -> Result<(), ConfigError> {
+        let handle = self.reload.outputs.sampling.as_ref();
+        handle
+            .ok_or(ConfigError::Unsupported(SAMPLING))?
+            .set(fraction);
+        Ok(())
+    }
+
+    /// Applies the settings held by `path`, then polls it every `interval` and applies them again
+    /// whenever they change.
+    ///
+    /// Every line of the file is `key = value`, `key` being `filter` (see
+    /// [`set_filter`](Self::set_filter)), `rotate_max_len` (see
+    /// [`set_rotate_max_len`](Self::set_rotate_max_len)) or `sampling` (see
+    /// [`set_sampling`](Self::set_sampling)). Empty lines and lines starting with `#` are ignored.
+    ///
+    /// A missing file, or a setting removed from it, keeps the current value. Watching stops when
+    /// the returned watcher is dropped.
+    ///
+    /// ```text
+    /// filter = info,my_crate=debug
+    /// rotate_max_len = 10000000
+    /// sampling = 0.25
+    /// ```
+    pub fn watch_config_file<P: AsRef<Path>>(&self, path: P, interval: Duration) -> ConfigWatcher {
+        ConfigWatcher::new(self.reload.clone(), path.as_ref().to_owned(), interval)
+    }
+}
+
+impl LoggerHandle {
+    /// Guard flushing the tape outputs once dropped.
+    pub fn into_guard(self) -> LoggerGuard {
+        LoggerGuard(self)
+    }
+}
+
+/// [`LoggerHandle`] that flushes the tape outputs when dropped, so it should be kept alive until
+/// the end of `main`.
+#[must_use = "dropping the guard flushes the logger outputs"]
+pub struct LoggerGuard(LoggerHandle);
+impl Deref for LoggerGuard {
+    type Target = LoggerHandle;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+impl Drop for LoggerGuard {
+    fn drop(&mut self) {
+        self.0.flush();
+    }
+}
+
+#[cfg(feature = "env-filter")]
+#[derive(thiserror::Error, Debug)]
+pub enum FilterError {
+    #[error("Invalid filter directives: {0}")]
+    Parse(#[from] ParseError),
+    #[error("Could not reload filter: {0}")]
+    Reload(#[from] reload::Error),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ConfigError {
+    #[error("Expected `key = value`, got {0:?}")]
+    Syntax(String),
+    #[error("Unknown setting {0:?}")]
+    UnknownKey(String),
+    #[error("Invalid value for {0}: {1:?}")]
+    Value(&'static str, String),
+    #[error("Setting {0} is not supported by this logger")]
+    Unsupported(&'static str),
+    #[cfg(feature = "env-filter")]
+    #[error(transparent)]
+    Filter(#[from] FilterError),
+}
+
+const FILTER: &str = "filter";
+const ROTATE_MAX_LEN: &str = "rotate_max_len";
+const SAMPLING: &str = "sampling";
+
+/// Settings of a config file, see [`LoggerHandle::watch_config_file`].
+fn parse_config(config: &str) -> Result<Vec<(&str, &str)>, ConfigError> {
+    config
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| ConfigError::Syntax(line.to_owned()))?;
+            Ok((key.trim(), value.trim()))
+        })
+        .collect()
+}
+
+#[cfg(feature = "env-filter")]
+#[derive(Clone)]
+struct FilterReload {
+    filter: reload::Handle<EnvFilter, Registry>,
+    tape: Arc<dyn Fn(Instruction) + Send + Sync>,
+}
+#[cfg(feature = "env-filter")]
+impl FilterReload {
+    /// The new directives are recorded into the tape, so that readers know why verbosity changed.
+    fn set(&self, directives: &str) -> Result<(), FilterError> {
+        let filter = EnvFilter::try_new(directives)?;
+        self.filter.reload(filter)?;
+        (self.tape)(Instruction::FilterChanged(directives));
+        tracing::trace!(directives, "Filter changed");
+
+        Ok(())
+    }
+}
+
+/// Handles changing the settings of the outputs while they are written.
+#[derive(Clone, Default)]
+struct OutputHandles {
+    max_len: Option<MaxLen>,
+    sampling: Option<SampleRate>,
+}
+
+/// Everything of an installed logger that can be changed while it runs.
+#[derive(Clone)]
+struct Reload {
+    #[cfg(feature = "env-filter")]
+    filter: FilterReload,
+    outputs: OutputHandles,
+}
+impl Reload {
+    /// Applies the setting `key` of a config file.
+    fn set(&self, key: &str, value: &str) -> Result<(), ConfigError> {
+        match key {
+            #[cfg(feature = "env-filter")]
+            FILTER => self.filter.set(value)?,
+            #[cfg(not(feature = "env-filter"))]
+            FILTER => return Err(ConfigError::Unsupported(FILTER)),
+            ROTATE_MAX_LEN => {
+                let max_len = value
+                    .parse()
+                    .map_err(|_| ConfigError::Value(ROTATE_MAX_LEN, value.to_owned()))?;
+                let handle = self.outputs.max_len.as_ref();
+                handle
+                    .ok_or(ConfigError::Unsupported(ROTATE_MAX_LEN))?
+                    .set(max_len);
+            }
+            SAMPLING => {
+                let fraction = value
+                    .parse()
+                    .ok()
+                    .filter(|fraction| (0.0..=1.0).contains(fraction))
+                    .ok_or_else(|| ConfigError::Value(SAMPLING, value.to_owned()))?;
+                let handle = self.outputs.sampling.as_ref();
+                handle
+                    .ok_or(ConfigError::Unsupported(SAMPLING))?
+                    .set(fraction);
+            }
+            key => return Err(ConfigError::UnknownKey(key.to_owned())),
+        }
+        tracing::trace!(key, value, "Setting changed");
+
+        Ok(())
+    }
+}
+
+/// Background thread started by [`LoggerHandle::watch_config_file`].
+pub struct ConfigWatcher {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+impl ConfigWatcher {
+    /// The settings of the file are applied once before returning.
+    fn new(reload: Reload, path: PathBuf, interval: Duration) -> Self {
+        let mut file = ConfigFile::new(path);
+        file.poll(&reload);
+
+        let (stop, stopped) = mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            while stopped.recv_timeout(interval) == Err(mpsc::RecvTimeoutError::Timeout) {
+                file.poll(&reload);
+            }
+        });
+
+        Self {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+}
+impl Drop for ConfigWatcher {
+    fn drop(&mut self) {
+        self.stop = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Config file of a [`ConfigWatcher`], with the settings applied from it.
+struct ConfigFile {
+    path: PathBuf,
+    applied: HashMap<String, String>,
+    rejected: HashMap<String, String>,
+}
+impl ConfigFile {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            applied: Default::default(),
+            rejected: Default::default(),
+        }
+    }
+
+    /// Applies the settings of the file that changed since they were last applied. Settings that
+    /// fail are tried again at every poll, but only warned about once.
+    fn poll(&mut self, reload: &Reload) {
+        let Ok(config) = std::fs::read_to_string(&self.path) else {
+            return;
+        };
+        let path = &self.path;
+        let settings = match parse_config(&config) {
+            Ok(settings) => settings,
+            Err(e) => {
+                tracing::warn!(%e, ?path, "Invalid config file");
+                return;
+            }
+        };
+
+        for (key, value) in settings {
+            if self.applied.get(key).map(String::as_str) == Some(value) {
+                continue;
+            }
+            match reload.set(key, value) {
+                Ok(()) => {
+                    self.applied.insert(key.to_owned(), value.to_owned());
+                    self.rejected.remove(key);
+                }
+                Err(e) => {
+                    if self.rejected.get(key).map(String::as_str) != Some(value) {
+                        tracing::warn!(%e, ?path, "Invalid config file");
+                        self.rejected.insert(key.to_owned(), value.to_owned());
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn install_logger<W>(out: W, console: WithConsole) -> LoggerHandle
 where
     W: io::Write + Send + 'static,
 {
-    do_installer_logger(out_logger(out), console);
+    let logger = boxed(out_machine(out, FlushPolicy::default(), false));
+    LoggerBuilder::new()
+        .console(console)
+        .install(Some(logger), None)
+}
+
+/// Same as [`install_logger`], flushing `out` when the returned guard is dropped.
+pub fn install_logger_with_guard<W>(out: W, console: WithConsole) -> LoggerGuard
+where
+    W: io::Write + Send + 'static,
+{
+    install_logger(out, console).into_guard()
 }
 
 pub fn install_rotate_logger<P: AsRef<Path>>(
     path: P,
     max_len: u64,
     console: WithConsole,
-) -> io::Result<()> {
-    let rotate = rotate_logger(path.as_ref(), max_len)?;
-    do_installer_logger(rotate, console);
-    Ok(())
+) -> io::Result<LoggerHandle> {
+    LoggerBuilder::new()
+        .console(console)
+        .rotate(path, max_len)
+        .init()
 }
 
-fn do_installer_logger<T>(logger: TapeMachineLogger<T>, console: WithConsole)
+/// Same as [`install_rotate_logger`], flushing the file when the returned guard is dropped.
+pub fn install_rotate_logger_with_guard<P: AsRef<Path>>(
+    path: P,
+    max_len: u64,
+    console: WithConsole,
+) -> io::Result<LoggerGuard> {
+    install_rotate_logger(path, max_len, console).map(LoggerHandle::into_guard)
+}
+
+/// Same as [`install_rotate_logger`], but also writes WARN+ events into a separate uncached file.
+///
+/// The error log is self-contained: it can be read without the main log, and is meant for quick
+/// triage. It only holds the spans of its events, so busy spans of lower levels don't rotate the
+/// events out.
+pub fn install_rotate_logger_with_error_log<P: AsRef<Path>, E: AsRef<Path>>(
+    path: P,
+    max_len: u64,
+    error_path: E,
+    error_max_len: u64,
+    console: WithConsole,
+) -> io::Result<LoggerHandle> {
+    LoggerBuilder::new()
+        .console(console)
+        .rotate(path, max_len)
+        .error_log(error_path, error_max_len)
+        .init()
+}
+
+type BoxedMachine = Box<dyn TapeMachine<InstructionSet>>;
+type BoxedLogger = TapeMachineLogger<BoxedMachine>;
+
+fn boxed<T>(machine: T) -> BoxedMachine
 where
     T: TapeMachine<InstructionSet>,
 {
-    let registry = Registry::default();
+    Box::new(machine)
+}
+
+enum Output {
+    Writer(Box<dyn io::Write + Send>),
+    Rotate {
+        path: PathBuf,
+        max_len: u64,
+        keep: usize,
+        index: Option<u64>,
+        #[cfg(feature = "gzip")]
+        compress: bool,
+    },
+}
+
+/// Composes the logger outputs before installing it as the global subscriber.
+///
+/// ```no_run
+/// msgpack_tracing::LoggerBuilder::new()
+///     .console(msgpack_tracing::WithConsole::AnsiColors)
+///     .rotate("app.log", 10_000_000)
+///     .error_log("errors.log", 1_000_000)
+///     .init()
+///     .unwrap();
+/// ```
+pub struct LoggerBuilder {
+    console: WithConsole,
+    output: Option<Output>,
+    error_log: Option<(PathBuf, u64)>,
     #[cfg(feature = "env-filter")]
-    let (filter, registry) = {
-        let filter = std::env::var("RUST_LOG").unwrap_or("warn".to_string());
-        let registry = registry.with(tracing_subscriber::EnvFilter::from(&filter));
-        (filter, registry)
-    };
-    #[cfg(not(feature = "env-filter"))]
-    let filter: Option<()> = None;
-
-    let registry = registry.with(logger);
-    let init = match console {
-        console @ WithConsole::AnsiColors | console @ WithConsole::PureText => registry
-            .with(printer_logger(
-                io::stderr(),
-                console == WithConsole::AnsiColors,
-            ))
-            .try_init(),
-        WithConsole::Disabled => registry.try_init(),
-    };
+    filter: Option<String>,
+    flush: FlushPolicy,
+    checksums: bool,
+    delta_time: bool,
+    snapshots: Option<u64>,
+    location: bool,
+    thread: bool,
+    span_timing: bool,
+    span_events: SpanEvents,
+    serialized_console: bool,
+    headers_only: bool,
+    compact_span_ids: bool,
+    sampling: Option<f64>,
+    reentrancy: Reentrancy,
+    staging: bool,
+    pipeline: bool,
+    pipeline_full: PipelineFull,
+}
+impl Default for LoggerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl LoggerBuilder {
+    /// A builder with no outputs. Console output is disabled.
+    pub fn new() -> Self {
+        Self {
+            console: WithConsole::Disabled,
+            output: None,
+            error_log: None,
+            #[cfg(feature = "env-filter")]
+            filter: None,
+            flush: Default::default(),
+            checksums: false,
+            delta_time: false,
+            snapshots: None,
+            location: false,
+            thread: false,
+            span_timing: false,
+            span_events: SpanEvents::NONE,
+            serialized_console: false,
+            headers_only: false,
+            compact_span_ids: false,
+            sampling: None,
+            reentrancy: Reentrancy::Drop,
+            staging: false,
+            pipeline: false,
+            pipeline_full: PipelineFull::Drop,
+        }
+    }
+
+    pub fn console(mut self, console: WithConsole) -> Self {
+        self.console = console;
+        self
+    }
+
+    /// Prints the console from the same layer as the tape, through a [`Tee`], instead of from a
+    /// layer of its own, so both always agree on the order of events. Without a tape output, the
+    /// console keeps its own layer. With [`install_early`](Self::install_early), the console is
+    /// buffered along with the tape until the outputs are given.
+    pub fn serialized_console(mut self, serialized: bool) -> Self {
+        self.serialized_console = serialized;
+        self
+    }
+
+    /// Sets what the tape does with events emitted while it is locked by the same thread, e.g. by a
+    /// custom machine using `tracing`. See [`Reentrancy`].
+    pub fn reentrancy(mut self, reentrancy: Reentrancy) -> Self {
+        self.reentrancy = reentrancy;
+        self
+    }
+
+    /// Encodes events on the emitting thread, locking the tape only to append them. See
+    /// [`TapeMachineLogger::with_staging`].
+    pub fn staging(mut self, staging: bool) -> Self {
+        self.staging = staging;
+        self
+    }
+
+    /// Writes the tape from a thread of its own, so emitting events never waits for it. See
+    /// [`TapeMachineLogger::with_pipeline`].
+    pub fn pipeline(mut self, pipeline: bool) -> Self {
+        self.pipeline = pipeline;
+        self
+    }
+
+    /// What the [`pipeline`](Self::pipeline) does once it is too far behind. See
+    /// [`TapeMachineLogger::with_pipeline_full`].
+    pub fn pipeline_full(mut self, full: PipelineFull) -> Self {
+        self.pipeline_full = full;
+        self
+    }
+
+    /// Writes the tape into `out`.
+    pub fn writer<W>(mut self, out: W) -> Self
+    where
+        W: io::Write + Send + 'static,
+    {
+        self.output = Some(Output::Writer(Box::new(out)));
+        self
+    }
+
+    /// Writes the tape into `path`, rotating it once it reaches `max_len` bytes.
+    pub fn rotate<P: AsRef<Path>>(mut self, path: P, max_len: u64) -> Self {
+        self.output = Some(Output::Rotate {
+            path: path.as_ref().to_owned(),
+            max_len,
+            keep: 1,
+            index: None,
+            #[cfg(feature = "gzip")]
+            compress: false,
+        });
+        self
+    }
+
+    /// Number of rotated files kept by [`rotate`](Self::rotate), named `path.1` to `path.{keep}`.
+    /// Defaults to 1.
+    pub fn keep_rotated(mut self, keep: usize) -> Self {
+        if let Some(Output::Rotate { keep: current, .. }) = self.output.as_mut() {
+            *current = keep;
+        }
+        self
+    }
+
+    /// Writes an index next to the files of [`rotate`](Self::rotate), restarting every `interval`
+    /// bytes. See [`Rotate::with_index`].
+    pub fn index_rotated(mut self, interval: u64) -> Self {
+        if let Some(Output::Rotate { index, .. }) = self.output.as_mut() {
+            *index = Some(interval);
+        }
+        self
+    }
+
+    /// Compresses files rotated by [`rotate`](Self::rotate) with gzip.
+    #[cfg(feature = "gzip")]
+    pub fn compress_rotated(mut self, compress: bool) -> Self {
+        if let Some(Output::Rotate {
+            compress: current, ..
+        }) = self.output.as_mut()
+        {
+            *current = compress;
+        }
+        self
+    }
+
+    /// Also writes WARN+ events into a separate uncached file. See
+    /// [`install_rotate_logger_with_error_log`].
+    pub fn error_log<P: AsRef<Path>>(mut self, path: P, max_len: u64) -> Self {
+        self.error_log = Some((path.as_ref().to_owned(), max_len));
+        self
+    }
+
+    /// Filter directives. Defaults to the `RUST_LOG` environment variable, or `warn` if unset.
+    #[cfg(feature = "env-filter")]
+    pub fn filter(mut self, directives: &str) -> Self {
+        self.filter = Some(directives.to_owned());
+        self
+    }
+
+    /// Flush policy of the tape, written by [`writer`](Self::writer) or [`rotate`](Self::rotate).
+    /// The error log is flushed after every instruction.
+    pub fn flush_policy(mut self, flush: FlushPolicy) -> Self {
+        self.flush = flush;
+        self
+    }
+
+    /// Follows every instruction of writer outputs with its CRC32. See
+    /// [`Store::with_checksums`].
+    pub fn checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    /// Writes timestamps as deltas from the previous one, in writer and rotate outputs. See
+    /// [`Store::with_delta_time`].
+    pub fn delta_time(mut self, delta: bool) -> Self {
+        self.delta_time = delta;
+        self
+    }
+
+    /// Restarts the tape every `interval` bytes, writing all the cached strings again after each
+    /// restart, so that readers starting in the middle of a file can resolve them. See
+    /// [`StringCache::with_snapshots`]. At most [`SNAPSHOT_MAX_STRINGS`] strings are cached, later
+    /// ones are written in full.
+    pub fn string_snapshots(mut self, interval: u64) -> Self {
+        self.snapshots = Some(interval);
+        self
+    }
+
+    /// Records the file, line and module path of every event, and prints them on the console.
+    pub fn source_location(mut self, location: bool) -> Self {
+        self.location = location;
+        self
+    }
+
+    /// Records the thread of every event, and prints it on the console.
+    pub fn thread_info(mut self, thread: bool) -> Self {
+        self.thread = thread;
+        self
+    }
+
+    /// Records the time spent inside and outside of every span, and prints it on the console when
+    /// the span closes.
+    pub fn span_timing(mut self, span_timing: bool) -> Self {
+        self.span_timing = span_timing;
+        self
+    }
+
+    /// Records an event on every moment of the life of spans in `span_events`, in the tape and on
+    /// the console. See [`TapeMachineLogger::with_span_events`].
+    pub fn span_events(mut self, span_events: SpanEvents) -> Self {
+        self.span_events = span_events;
+        self
+    }
+
+    /// Records only the time, level, target and span of events in the tape, see
+    /// [`HeadersOnly`](headers::HeadersOnly). The error log and the console are unaffected.
+    pub fn headers_only(mut self, headers_only: bool) -> Self {
+        self.headers_only = headers_only;
+        self
+    }
 
-    match init {
-        Ok(()) => tracing::trace!(?filter, ?console, "Logger initialized"),
-        Err(e) => {
-            tracing::warn!(%e, "Trying to initialize logger twice");
-            tracing::debug!(?e);
+    /// Renumbers spans in the tape with small ids, see [`SpanRemap`](span_remap::SpanRemap). The
+    /// error log and the console are unaffected.
+    pub fn compact_span_ids(mut self, compact: bool) -> Self {
+        self.compact_span_ids = compact;
+        self
+    }
+
+    /// Keeps about `fraction` of the events in the tape, deciding by their root span, see
+    /// [`SamplingMachine`]. The error log and the console are unaffected.
+    pub fn sampling(mut self, fraction: f64) -> Self {
+        self.sampling = Some(fraction);
+        self
+    }
+
+    pub fn init(mut self) -> io::Result<LoggerHandle> {
+        let (logger, errors, outputs) = self.machines()?;
+        let mut handle = self.install(logger, errors);
+        handle.reload.outputs = outputs;
+
+        Ok(handle)
+    }
+
+    /// Installs the global subscriber right away, with everything but the outputs of this
+    /// builder. The tape is buffered in memory until [`EarlyLogger::init`] gives the outputs, so
+    /// events emitted during startup, before the outputs are known, are not lost.
+    pub fn install_early(self) -> EarlyLogger {
+        let tape = Deferred::new();
+        let errors = Deferred::new();
+        let handle = self.install(Some(boxed(tape.clone())), Some(boxed(errors.clone())));
+
+        EarlyLogger {
+            handle,
+            tape,
+            errors,
         }
     }
+
+    /// Machines of the tape and of the error log, with the handles changing their settings.
+    fn machines(
+        &mut self,
+    ) -> io::Result<(Option<BoxedMachine>, Option<BoxedMachine>, OutputHandles)> {
+        let mut handles = OutputHandles::default();
+        let logger = match self.output.take() {
+            Some(Output::Writer(out)) => {
+                let store = Store::with_flush_policy(out, self.flush)
+                    .with_checksums(self.checksums)
+                    .with_delta_time(self.delta_time);
+                Some(match self.snapshots {
+                    Some(interval) => boxed(RestartableMachine::new(
+                        StringCache::with_limit(
+                            store.with_restart_interval(interval),
+                            SNAPSHOT_MAX_STRINGS,
+                        )
+                        .with_snapshots(true),
+                    )),
+                    None => boxed(StringCache::new(store)),
+                })
+            }
+            Some(Output::Rotate {
+                path,
+                max_len,
+                keep,
+                index,
+                #[cfg(feature = "gzip")]
+                compress,
+            }) => {
+                let mut rotate = Rotate::new(&path, max_len)?
+                    .with_retention(keep)
+                    .with_flush_policy(self.flush)
+                    .with_delta_time(self.delta_time);
+                if let Some(interval) = index {
+                    rotate = rotate.with_index(interval)?;
+                }
+                if let Some(interval) = self.snapshots {
+                    rotate = rotate.with_restart_interval(interval);
+                }
+                #[cfg(feature = "gzip")]
+                let rotate = rotate.with_compression(compress);
+                handles.max_len = Some(rotate.max_len());
+                Some(boxed(rotate_machine(rotate, self.snapshots.is_some())))
+            }
+            None => None,
+        };
+        let logger = match self.headers_only {
+            true => logger.map(|logger| boxed(headers::HeadersOnly::new(logger))),
+            false => logger,
+        };
+        let logger = match self.compact_span_ids {
+            true => logger.map(|logger| boxed(span_remap::SpanRemap::new(logger))),
+            false => logger,
+        };
+        let logger = match self.sampling {
+            Some(fraction) => logger.map(|logger| {
+                let sampling = SamplingMachine::new(logger, fraction);
+                handles.sampling = Some(sampling.rate());
+                boxed(sampling)
+            }),
+            None => logger,
+        };
+        let errors = match self.error_log.take() {
+            Some((path, max_len)) => Some(boxed(error_machine(&path, max_len)?)),
+            None => None,
+        };
+
+        Ok((logger, errors, handles))
+    }
+
+    fn install(self, logger: Option<BoxedMachine>, errors: Option<BoxedMachine>) -> LoggerHandle {
+        let console = self.console;
+        let (location, thread) = (self.location, self.thread);
+        let (span_timing, span_events) = (self.span_timing, self.span_events);
+        let printer = match console {
+            WithConsole::AnsiColors | WithConsole::PureText => Some(
+                Printer::new(io::stderr(), console == WithConsole::AnsiColors)
+                    .with_threads(thread)
+                    .with_span_close(span_timing),
+            ),
+            WithConsole::Disabled => None,
+        };
+        let (logger, printer) = match (logger, printer) {
+            (Some(logger), Some(printer)) if self.serialized_console => {
+                (Some(boxed(Tee::new(logger, printer))), None)
+            }
+            (logger, printer) => (logger, printer),
+        };
+        let (reentrancy, staging) = (self.reentrancy, self.staging);
+        let (pipeline, pipeline_full) = (self.pipeline, self.pipeline_full);
+        let logger = logger.map(|logger| {
+            BoxedLogger::new(logger)
+                .with_source_location(location)
+                .with_thread_info(thread)
+                .with_span_timing(span_timing)
+                .with_span_events(span_events)
+                .with_reentrancy(reentrancy)
+                .with_staging(staging)
+                .with_pipeline_full(pipeline_full)
+                .with_pipeline(pipeline)
+        });
+        let errors = errors.map(|errors| {
+            BoxedLogger::new(errors)
+                .with_source_location(location)
+                .with_thread_info(thread)
+        });
+        let console = printer.map(|printer| {
+            TapeMachineLogger::new(printer)
+                .with_source_location(location)
+                .with_thread_info(thread)
+                .with_span_timing(span_timing)
+                .with_span_events(span_events)
+        });
+        let registry = Registry::default();
+        #[cfg(feature = "env-filter")]
+        let (filter, reload_handle, registry) = {
+            let filter = self
+                .filter
+                .or_else(|| std::env::var("RUST_LOG").ok())
+                .unwrap_or("warn".to_string());
+            let (layer, reload_handle) = reload::Layer::new(EnvFilter::from(&filter));
+            let registry = registry.with(layer);
+            (filter, reload_handle, registry)
+        };
+        #[cfg(not(feature = "env-filter"))]
+        let filter: Option<()> = None;
+
+        #[cfg(feature = "env-filter")]
+        let tape = logger.as_ref().map(TapeMachineLogger::handle);
+        let flush = [
+            logger.as_ref().map(TapeMachineLogger::handle),
+            errors.as_ref().map(TapeMachineLogger::handle),
+        ];
+        let handle = LoggerHandle {
+            reload: Reload {
+                #[cfg(feature = "env-filter")]
+                filter: FilterReload {
+                    filter: reload_handle,
+                    tape: Arc::new(move |instruction| {
+                        if let Some(tape) = tape.as_ref() {
+                            tape.handle(instruction);
+                        }
+                    }),
+                },
+                outputs: OutputHandles::default(),
+            },
+            flush: Box::new(move || {
+                for tape in flush.iter().flatten() {
+                    tape.flush();
+                }
+            }),
+        };
+
+        let errors = errors.map(|errors| errors.with_filter(filter_fn(error_log_enabled)));
+        let init = registry.with(logger).with(errors).with(console).try_init();
+
+        match init {
+            Ok(()) => tracing::trace!(?filter, console = ?self.console, "Logger initialized"),
+            Err(e) => {
+                tracing::warn!(%e, "Trying to initialize logger twice");
+                tracing::debug!(?e);
+            }
+        }
+
+        handle
+    }
+}
+
+/// Logger installed by [`LoggerBuilder::install_early`], buffering the tape until
+/// [`init`](Self::init).
+#[must_use = "the tape is buffered in memory until init"]
+pub struct EarlyLogger {
+    handle: LoggerHandle,
+    tape: Deferred,
+    errors: Deferred,
+}
+impl EarlyLogger {
+    /// Writes the buffered tape into the outputs of `outputs`, which then receive the rest of it.
+    ///
+    /// Only the outputs, error log, flush policy, checksums, delta timestamps, string snapshots,
+    /// headers only mode, compact span ids and sampling of `outputs` are used, everything else was
+    /// set by [`LoggerBuilder::install_early`]. Without outputs, the tape is dropped.
+    pub fn init(mut self, mut outputs: LoggerBuilder) -> io::Result<LoggerHandle> {
+        let (logger, errors, handles) = outputs.machines()?;
+        for (deferred, machine) in [(&self.tape, logger), (&self.errors, errors)] {
+            match machine {
+                Some(machine) => deferred.set(machine)?,
+                None => deferred.discard(),
+            }
+        }
+
+        self.handle.reload.outputs = handles;
+
+        Ok(self.handle)
+    }
 }
 
 pub fn out_logger<W>(out: W) -> TapeMachineLogger<impl TapeMachine<InstructionSet>>
 where
     W: io::Write + Send + 'static,
 {
-    TapeMachineLogger::new(StringCache::new(Store::new(out)))
+    TapeMachineLogger::new(out_machine(out, FlushPolicy::default(), false))
+}
+
+fn out_machine<W>(out: W, flush: FlushPolicy, checksums: bool) -> impl TapeMachine<InstructionSet>
+where
+    W: io::Write + Send + 'static,
+{
+    StringCache::new(Store::with_flush_policy(out, flush).with_checksums(checksums))
 }
 
 pub fn rotate_logger(
     path: &Path,
     max_len: u64,
 ) -> io::Result<TapeMachineLogger<impl TapeMachine<InstructionSet>>> {
-    Ok(TapeMachineLogger::new(RestartableMachine::new(
-        StringCache::new(Rotate::new(path, max_len)?),
+    Ok(TapeMachineLogger::new(rotate_machine(
+        Rotate::new(path, max_len)?,
+        false,
     )))
 }
 
+fn rotate_machine(rotate: Rotate, snapshots: bool) -> impl TapeMachine<InstructionSet> {
+    let cache = match snapshots {
+        true => StringCache::with_limit(rotate, SNAPSHOT_MAX_STRINGS).with_snapshots(true),
+        false => StringCache::new(rotate),
+    };
+    RestartableMachine::new(cache)
+}
+
 pub fn printer_logger<W>(out: W, color: bool) -> TapeMachineLogger<impl TapeMachine<InstructionSet>>
 where
     W: io::Write + Send + 'static,
 {
     TapeMachineLogger::new(Printer::new(out, color))
 }
+
+/// Logger writing JSON lines, see [`JsonLines`](printer::JsonLines). Add it as another layer
+/// to write JSON next to the tape.
+#[cfg(feature = "json")]
+pub fn json_logger<W>(out: W) -> TapeMachineLogger<printer::JsonLines<W>>
+where
+    W: io::Write + Send + 'static,
+{
+    TapeMachineLogger::new(printer::JsonLines::new(out))
+}
+
+/// Uncached logger of WARN+ events, with only the spans they are in.
+pub fn error_logger(
+    path: &Path,
+    max_len: u64,
+) -> io::Result<TapeMachineLogger<impl TapeMachine<InstructionSet>>> {
+    Ok(TapeMachineLogger::new(error_machine(path, max_len)?))
+}
+
+fn error_machine(path: &Path, max_len: u64) -> io::Result<impl TapeMachine<InstructionSet>> {
+    let slice = Slice::new(
+        Rotate::new(path, max_len)?,
+        Box::new(|event, _| event.priority <= Level::WARN),
+    );
+    Ok(RestartableMachine::new(slice))
+}
+
+fn error_log_enabled(metadata: &Metadata) -> bool {
+    metadata.is_span() || *metadata.level() <= Level::WARN
+}
+
+#[cfg(all(test, feature = "env-filter"))]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn watch_config_file() {
+        let dir = std::env::temp_dir().join(format!("config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("log.conf");
+        std::fs::write(&path, "filter = info\nsampling = 0.5\n").unwrap();
+
+        let (_layer, filter) = reload::Layer::<_, Registry>::new(EnvFilter::new("warn"));
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let tape = changes.clone();
+        let rotate = Rotate::new(dir.join("app.log"), 1000).unwrap();
+        let sampling = SamplingMachine::new(Printer::new(io::sink(), false), 1.0);
+        let reload = Reload {
+            filter: FilterReload {
+                filter,
+                tape: Arc::new(move |instruction| {
+                    if let Instruction::FilterChanged(directives) = instruction {
+                        tape.lock().unwrap().push(directives.to_owned());
+                    }
+                }),
+            },
+            outputs: OutputHandles {
+                max_len: Some(rotate.max_len()),
+                sampling: Some(sampling.rate()),
+            },
+        };
+
+        let mut file = ConfigFile::new(path.clone());
+        file.poll(&reload);
+        assert_eq!(sampling.rate().get(), 0.5);
+        std::fs::write(
+            &path,
+            "# Tuned\nfilter = debug\nrotate_max_len = 2000\nsampling = 0.5\n",
+        )
+        .unwrap();
+        file.poll(&reload);
+        std::fs::write(&path, "filter = trace\nrotate_max_len\n").unwrap();
+        file.poll(&reload);
+
+        assert_eq!(*changes.lock().unwrap(), ["info", "debug"]);
+        assert_eq!(rotate.max_len().get(), 2000);
+        assert_eq!(sampling.rate().get(), 0.5);
+
+        // Failed settings are not applied, so they are tried again
+        std::fs::write(&path, "sampling = 2\n").unwrap();
+        file.poll(&reload);
+        assert_eq!(file.applied["sampling"], "0.5");
+        assert_eq!(file.rejected["sampling"], "2");
+        std::fs::write(&path, "sampling = 0.25\n").unwrap();
+        file.poll(&reload);
+        assert_eq!(sampling.rate().get(), 0.25);
+        assert!(file.rejected.is_empty());
+
+        // The watcher applies the file before returning
+        std::fs::write(&path, "sampling = 0.75\n").unwrap();
+        drop(ConfigWatcher::new(reload, path, Duration::from_secs(60)));
+        assert_eq!(sampling.rate().get(), 0.75);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn guard_flushes_on_drop() {
+        let (_layer, filter) = reload::Layer::<_, Registry>::new(EnvFilter::new("warn"));
+        let flushes = Arc::new(Mutex::new(0));
+        let handle = || {
+            let flushes = flushes.clone();
+            LoggerHandle {
+                reload: Reload {
+                    filter: FilterReload {
+                        filter: filter.clone(),
+                        tape: Arc::new(|_| ()),
+                    },
+                    outputs: OutputHandles::default(),
+                },
+                flush: Box::new(move || *flushes.lock().unwrap() += 1),
+            }
+        };
+
+        drop(handle());
+        assert_eq!(*flushes.lock().unwrap(), 0);
+        drop(handle().into_guard());
+        assert_eq!(*flushes.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn error_log_keeps_warnings_among_busy_spans() {
+        use crate::{
+            storage::Load,
+            tape::{InstructionOwned, Value},
+            test_util::{event, new_span, span, start_event},
+        };
+        use chrono::DateTime;
+
+        let path = std::env::temp_dir().join(format!("errors-{}.log", std::process::id()));
+        let rotated = path.with_extension("log.1");
+        let _ = std::fs::remove_file(&path);
+        let mut errors = error_machine(&path, 4096).unwrap();
+        let time = DateTime::UNIX_EPOCH;
+        errors.handle(Instruction::Restart);
+        new_span(&mut errors, None, span(1), "request", &[]);
+        let warn = start_event(time, Some(span(1)), "app", Level::WARN);
+        event(&mut errors, warn, &[("message", Value::Debug("slow"))]);
+        for id in 2..2000 {
+            new_span(
+                &mut errors,
+                Some(span(1)),
+                span(id),
+                "poll",
+                &[("attempt", Value::Unsigned(id))],
+            );
+            let trace = start_event(time, Some(span(id)), "app", Level::TRACE);
+            event(&mut errors, trace, &[]);
+            errors.handle(Instruction::DeleteSpan(span(id)));
+        }
+        errors.flush();
+        drop(errors);
+
+        let instructions = Load::open(&path)
+            .unwrap()
+            .iter()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        let spans = instructions
+            .iter()
+            .filter(|instruction| matches!(instruction, InstructionOwned::NewSpan { .. }))
+            .count();
+        let events = instructions
+            .iter()
+            .filter_map(|instruction| match instruction {
+                InstructionOwned::StartEvent { priority, .. } => Some(*priority),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(spans, 1);
+        assert_eq!(events, [Level::WARN]);
+        assert!(!rotated.exists());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
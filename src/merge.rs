@@ -0,0 +1,517 @@
+use crate::{
+    printer::NewEvent,
+    receiver::{Receiver, SourceTape},
+    storage::Load,
+    string_cache::StringUncache,
+    tape::{FieldValueOwned, Instruction, InstructionSet, SpanRecords, TapeMachine},
+};
+use chrono::{DateTime, Utc};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    io::{self, Read},
+    num::NonZeroU64,
+    sync::MutexGuard,
+    time::Duration,
+};
+use tracing::Level;
+
+/// Merges several tapes, e.g. rotated files or tapes of other hosts, into a single machine,
+/// interleaving their events by time.
+///
+/// Every tape keeps its own string cache. As with a [`Receiver`], events are tagged with a
+/// [`SOURCE_FIELD`](crate::receiver::SOURCE_FIELD) and spans get new ids, so the tapes don't
+/// collide. Each tape is assumed to be in chronological order.
+///
+/// Tapes added with the same source, e.g. a file and its rotated files, are segments of a single
+/// tape: spans replayed after a `Restart` are matched with the spans already known, instead of
+/// showing up twice.
+pub struct Merge<R, T> {
+    receiver: Receiver<T>,
+    tapes: Vec<MergeTape<T>>,
+    sources: Vec<MergeSource<R>>,
+}
+impl<R, T> Merge<R, T>
+where
+    R: Read,
+    T: TapeMachine<InstructionSet> + Send + 'static,
+{
+    pub fn new(machine: T) -> Self {
+        Self {
+            receiver: Receiver::new(machine),
+            tapes: Vec::new(),
+            sources: Vec::new(),
+        }
+    }
+
+    /// Adds a tape, tagging its events with `source`. Tapes of the same `source` may be added in
+    /// any order.
+    pub fn with_source(mut self, source: &str, load: Load<R>) -> Self {
+        let tape = match self.tapes.iter().position(|tape| tape.source == source) {
+            Some(tape) => tape,
+            None => {
+                self.tapes.push(MergeTape {
+                    tape: self.receiver.source(source),
+                    source: source.to_owned(),
+                    spans: Default::default(),
+                    replaying: None,
+                });
+                self.tapes.len() - 1
+            }
+        };
+        self.sources.push(MergeSource {
+            load,
+            pending: StringUncache::new(Pending::default()),
+            tape,
+            source: source.to_owned(),
+            ended: false,
+        });
+        self
+    }
+
+    /// Forwards every tape until their end. Errors are given to `on_error` along with the
+    /// source, and the tape is skipped to its next `Restart`.
+    pub fn run<F>(&mut self, mut on_error: F)
+    where
+        F: FnMut(&str, io::Error),
+    {
+        loop {
+            for source in self.sources.iter_mut() {
+                source.fill(&mut on_error);
+                // Before the next segment of the same source
+                if source.ended && source.pending.get_ref().events == 0 {
+                    source.forward_rest(&mut self.tapes[source.tape]);
+                }
+            }
+
+            let next = self
+                .sources
+                .iter()
+                .enumerate()
+                .filter_map(|(index, source)| Some((index, source.next_time()?)))
+                .min_by_key(|(_, time)| *time);
+            let Some((index, _)) = next else {
+                break;
+            };
+            let source = &mut self.sources[index];
+            source.forward_event(&mut self.tapes[source.tape]);
+        }
+
+        for tape in self.tapes.iter_mut() {
+            tape.tape.delete_spans();
+        }
+    }
+
+    /// Locks the machine, e.g. to flush it.
+    pub fn machine(&self) -> MutexGuard<'_, T> {
+        self.receiver.machine()
+    }
+}
+
+/// Tape of a source, made of the segments of every [`MergeSource`] with that source.
+struct MergeTape<T> {
+    tape: SourceTape<T>,
+    source: String,
+    /// Spans forwarded and not deleted yet, by their id in the source.
+    spans: HashMap<NonZeroU64, SpanRecords>,
+    /// Spans not replayed yet since the last `Restart`.
+    replaying: Option<HashSet<NonZeroU64>>,
+}
+impl<T> MergeTape<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    fn forward(&mut self, item: Item) {
+        if let Item::Restart = item {
+            self.end_replay();
+            self.replaying = Some(self.spans.keys().copied().collect());
+            return;
+        }
+        if let Item::Span(span, records) = &item
+            && let Some(replaying) = self.replaying.as_mut()
+            && self.spans.get(span) == Some(records)
+        {
+            replaying.remove(span);
+            return;
+        }
+
+        match &item {
+            Item::Span(span, records) => {
+                if let Some(replaying) = self.replaying.as_mut() {
+                    replaying.remove(span);
+                }
+                // Another span with the same id
+                if self.spans.insert(*span, records.clone()).is_some() {
+                    Item::Delete(*span).forward(&mut self.tape);
+                }
+            }
+            Item::Record(span, values) => {
+                if let Some(records) = self.spans.get_mut(span) {
+                    for value in values.iter() {
+                        records.record(value.clone());
+                    }
+                }
+                self.end_replay();
+            }
+            Item::Delete(span) => {
+                self.end_replay();
+                self.spans.remove(span);
+            }
+            _ => self.end_replay(),
+        }
+        item.forward(&mut self.tape);
+    }
+
+    /// Deletes the spans not replayed after the last `Restart`, which were closed meanwhile.
+    fn end_replay(&mut self) {
+        for span in self.replaying.take().into_iter().flatten() {
+            self.spans.remove(&span);
+            Item::Delete(span).forward(&mut self.tape);
+        }
+    }
+}
+
+struct MergeSource<R> {
+    load: Load<R>,
+    pending: StringUncache<Pending>,
+    /// Index of the [`MergeTape`].
+    tape: usize,
+    source: String,
+    ended: bool,
+}
+impl<R> MergeSource<R>
+where
+    R: Read,
+{
+    /// Reads until an event is pending or the tape ends.
+    fn fill<F>(&mut self, on_error: &mut F)
+    where
+        F: FnMut(&str, io::Error),
+    {
+        while !self.ended && self.pending.get_ref().events == 0 {
+            match self.load.fetch_one_cached() {
+                Ok(Some(instruction)) => self.pending.handle(instruction),
+                Ok(None) => self.ended = true,
+                Err(e) => {
+                    on_error(&self.source, e);
+                    self.load.restart();
+                }
+            }
+        }
+    }
+
+    fn next_time(&self) -> Option<DateTime<Utc>> {
+        self.pending
+            .get_ref()
+            .items
+            .iter()
+            .find_map(|item| match item {
+                Item::Event(event) => Some(event.time),
+                _ => None,
+            })
+    }
+
+    /// Forwards everything up to the first pending event, inclusive.
+    fn forward_event<T>(&mut self, tape: &mut MergeTape<T>)
+    where
+        T: TapeMachine<InstructionSet>,
+    {
+        let pending = self.pending.get_mut();
+        while let Some(item) = pending.items.pop_front() {
+            let event = matches!(item, Item::Event(..));
+            tape.forward(item);
+            if event {
+                pending.events -= 1;
+                return;
+            }
+        }
+    }
+
+    fn forward_rest<T>(&mut self, tape: &mut MergeTape<T>)
+    where
+        T: TapeMachine<InstructionSet>,
+    {
+        let pending = self.pending.get_mut();
+        pending.events = 0;
+        for item in pending.items.drain(..) {
+            tape.forward(item);
+        }
+    }
+}
+
+/// Instruction of a tape waiting for its turn, with spans and events complete.
+enum Item {
+    Restart,
+    Span(NonZeroU64, SpanRecords),
+    Record(NonZeroU64, Vec<FieldValueOwned>),
+    Event(NewEvent),
+    Delete(NonZeroU64),
+    Enter(DateTime<Utc>, NonZeroU64),
+    Exit(DateTime<Utc>, NonZeroU64),
+    Timing {
+        time: DateTime<Utc>,
+        span: NonZeroU64,
+        target: String,
+        priority: Level,
+        busy: Duration,
+        idle: Duration,
+    },
+}
+impl Item {
+    fn forward<T>(&self, machine: &mut T)
+    where
+        T: TapeMachine<InstructionSet>,
+    {
+        match self {
+            Item::Restart => machine.handle(Instruction::Restart),
+            Item::Span(span, records) => {
+                machine.handle(Instruction::NewSpan {
+                    parent: records.parent,
+                    span: *span,
+                    name: &records.name,
+                });
+                for record in records.records.iter() {
+                    machine.handle(Instruction::AddValue(record.as_ref()));
+                }
+                machine.handle(Instruction::FinishedSpan);
+            }
+            Item::Record(span, values) => {
+                machine.handle(Instruction::NewRecord(*span));
+                for value in values.iter() {
+                    machine.handle(Instruction::AddValue(value.as_ref()));
+                }
+                machine.handle(Instruction::FinishedRecord);
+            }
+            Item::Event(event) => event.forward(machine),
+            Item::Delete(span) => machine.handle(Instruction::DeleteSpan(*span)),
+            Item::Enter(time, span) => machine.handle(Instruction::EnterSpan {
+                time: *time,
+                span: *span,
+            }),
+            Item::Exit(time, span) => machine.handle(Instruction::ExitSpan {
+                time: *time,
+                span: *span,
+            }),
+            Item::Timing {
+                time,
+                span,
+                target,
+                priority,
+                busy,
+                idle,
+            } => machine.handle(Instruction::SpanTiming {
+                time: *time,
+                span: *span,
+                target,
+                priority: *priority,
+                busy: *busy,
+                idle: *idle,
+            }),
+        }
+    }
+}
+
+/// Items of a tape not yet forwarded.
+#[derive(Default)]
+struct Pending {
+    items: VecDeque<Item>,
+    /// Events among the items.
+    events: usize,
+    open: Option<Item>,
+}
+impl TapeMachine<InstructionSet> for Pending {
+    fn needs_restart(&mut self) -> bool {
+        false
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        let item = match instruction {
+            Instruction::Restart => Item::Restart,
+            Instruction::NewSpan { parent, span, name } => {
+                self.open = Some(Item::Span(
+                    span,
+                    SpanRecords {
+                        parent,
+                        name: name.to_owned(),
+                        records: Default::default(),
+                    },
+                ));
+                return;
+            }
+            Instruction::NewRecord(span) => {
+                self.open = Some(Item::Record(span, Vec::new()));
+                return;
+            }
+            Instruction::StartEvent {
+                time,
+                span,
+                target,
+                priority,
+                location,
+                thread,
+            } => {
+                self.open = Some(Item::Event(NewEvent {
+                    time,
+                    span,
+                    target: target.to_owned(),
+                    priority,
+                    location: location.map(|location| location.map(str::to_owned)),
+                    thread: thread.map(|thread| thread.map(str::to_owned)),
+                    records: Default::default(),
+                }));
+                return;
+            }
+            Instruction::AddValue(value) => {
+                match self.open.as_mut() {
+                    Some(Item::Span(_, records)) => records.record(value.to_owned()),
+                    Some(Item::Record(_, values)) => values.push(value.to_owned()),
+                    Some(Item::Event(event)) => event.records.push(value.to_owned()),
+                    _ => (),
+                }
+                return;
+            }
+            Instruction::FinishedSpan
+            | Instruction::FinishedRecord
+            | Instruction::FinishedEvent => {
+                let Some(item) = self.open.take() else {
+                    return;
+                };
+                item
+            }
+            Instruction::DeleteSpan(span) => Item::Delete(span),
+            Instruction::EnterSpan { time, span } => Item::Enter(time, span),
+            Instruction::ExitSpan { time, span } => Item::Exit(time, span),
+            Instruction::SpanTiming {
+                time,
+                span,
+                target,
+                priority,
+                busy,
+                idle,
+            } => Item::Timing {
+                time,
+                span,
+                target: target.to_owned(),
+                priority,
+                busy,
+                idle,
+            },
+            // Filters are of each tape
+            Instruction::FilterChanged(..) => return,
+        };
+
+        if let Item::Event(..) = item {
+            self.events += 1;
+        }
+        self.items.push_back(item);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        receiver::SOURCE_FIELD,
+        storage::Store,
+        string_cache::StringCache,
+        tape::{FieldValue, Value},
+    };
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Events(Vec<(i64, String, Option<NonZeroU64>)>, Vec<NonZeroU64>);
+    impl TapeMachine<InstructionSet> for Arc<Mutex<Events>> {
+        fn needs_restart(&mut self) -> bool {
+            false
+        }
+
+        fn handle(&mut self, instruction: Instruction) {
+            let mut events = self.lock().unwrap();
+            match instruction {
+                Instruction::StartEvent { time, span, .. } => {
+                    events.0.push((time.timestamp(), String::new(), span))
+                }
+                Instruction::AddValue(FieldValue {
+                    name: SOURCE_FIELD,
+                    value: Value::String(source),
+                }) => events.0.last_mut().unwrap().1 = source.to_owned(),
+                Instruction::NewSpan { span, .. } => events.1.push(span),
+                _ => (),
+            }
+        }
+    }
+
+    fn tape(times: &[i64]) -> Vec<u8> {
+        let mut store = StringCache::new(Store::new(Vec::new()));
+        let span = NonZeroU64::new(1).unwrap();
+        store.handle(Instruction::Restart);
+        store.handle(Instruction::NewSpan {
+            parent: None,
+            span,
+            name: "request",
+        });
+        store.handle(Instruction::FinishedSpan);
+        for time in times {
+            store.handle(Instruction::StartEvent {
+                time: DateTime::from_timestamp(*time, 0).unwrap(),
+                span: Some(span),
+                target: "target",
+                priority: Level::INFO,
+                location: None,
+                thread: None,
+            });
+            store.handle(Instruction::FinishedEvent);
+        }
+        store.into_inner().into_inner()
+    }
+
+    #[test]
+    fn interleaved_by_time() {
+        let events = Arc::new(Mutex::new(Events::default()));
+        let first = tape(&[1, 4, 5]);
+        let second = tape(&[2, 3, 6]);
+        let mut merge = Merge::new(events.clone())
+            .with_source("first", Load::new(first.as_slice()))
+            .with_source("second", Load::new(second.as_slice()));
+        merge.run(|source, e| panic!("{source}: {e}"));
+
+        let events = events.lock().unwrap();
+        let order = events
+            .0
+            .iter()
+            .map(|(time, source, _)| (*time, source.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            [
+                (1, "first"),
+                (2, "second"),
+                (3, "second"),
+                (4, "first"),
+                (5, "first"),
+                (6, "second"),
+            ]
+        );
+        assert_ne!(events.0[0].2, events.0[1].2);
+    }
+
+    #[test]
+    fn rotated_segments() {
+        let events = Arc::new(Mutex::new(Events::default()));
+        let rotated = tape(&[1, 2]);
+        let current = tape(&[3, 4]);
+        let mut merge = Merge::new(events.clone())
+            .with_source("app", Load::new(current.as_slice()))
+            .with_source("app", Load::new(rotated.as_slice()));
+        merge.run(|source, e| panic!("{source}: {e}"));
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.1.len(), 1);
+        let spans = events
+            .0
+            .iter()
+            .map(|(time, _, span)| (*time, *span))
+            .collect::<Vec<_>>();
+        let span = Some(events.1[0]);
+        assert_eq!(spans, [(1, span), (2, span), (3, span), (4, span)]);
+    }
+}
@@ -0,0 +1,518 @@
+use crate::{
+    printer::{NewEvent, resolve_spans},
+    tape::{FieldValueOwned, Instruction, InstructionSet, SpanRecords, TapeMachine, ValueOwned},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde_json::{Value as Json, json};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    io::{self, BufRead, BufReader, Write},
+    net::TcpStream,
+    num::NonZeroU64,
+    str::FromStr,
+    time::Duration,
+};
+use tracing::Level;
+
+/// OTLP/HTTP endpoint receiving logs, e.g. `http://localhost:4318/v1/logs`.
+///
+/// Only plain HTTP is supported, so TLS must be terminated by a local collector.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    address: String,
+    path: String,
+}
+impl Endpoint {
+    /// Default port of OTLP/HTTP.
+    pub const PORT: u16 = 4318;
+    /// Default path of the logs service.
+    pub const PATH: &str = "/v1/logs";
+
+    /// Posts an export request, failing unless the endpoint answers with a 2xx status.
+    pub fn post(&self, body: &[u8]) -> io::Result<()> {
+        let mut stream = TcpStream::connect(&self.address)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.address,
+            body.len()
+        )?;
+        stream.write_all(body)?;
+
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status)?;
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(io::Error::other(format!(
+                "OTLP endpoint answered {:?}",
+                status.trim_end()
+            ))),
+        }
+    }
+}
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let Some(rest) = str.strip_prefix("http://") else {
+            return Err(format!("Expected an http:// endpoint, got {str:?}"));
+        };
+        let (address, path) = match rest.find('/') {
+            Some(split) => rest.split_at(split),
+            None => (rest, Self::PATH),
+        };
+        if address.is_empty() {
+            return Err(format!("Missing host in {str:?}"));
+        }
+        let address = match address.contains(':') {
+            true => address.to_owned(),
+            false => format!("{address}:{}", Self::PORT),
+        };
+
+        Ok(Self {
+            address,
+            path: path.to_owned(),
+        })
+    }
+}
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Exports events as OpenTelemetry log records to an OTLP/HTTP endpoint, using the JSON
+/// encoding.
+///
+/// Each event becomes a log record with the target as its instrumentation scope. The message is
+/// the body and the other fields are attributes, as are the fields of its spans, prefixed by the
+/// span name. The span of the event is the span id of the record, and its root span is the trace
+/// id.
+///
+/// Records are sent in batches, on [`flush`](TapeMachine::flush) and when dropped. Sending blocks
+/// and failed batches are dropped.
+pub struct OtlpExporter {
+    endpoint: Endpoint,
+    service_name: String,
+    batch: usize,
+    span: HashMap<NonZeroU64, SpanRecords>,
+    new_records: Option<(NonZeroU64, SpanRecords)>,
+    new_event: Option<NewEvent>,
+    pending: BTreeMap<String, Vec<Json>>,
+    pending_len: usize,
+}
+impl OtlpExporter {
+    pub fn new(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            service_name: "unknown_service".to_string(),
+            batch: 512,
+            span: Default::default(),
+            new_records: None,
+            new_event: None,
+            pending: Default::default(),
+            pending_len: 0,
+        }
+    }
+
+    /// The `service.name` resource attribute.
+    pub fn with_service_name(mut self, service_name: &str) -> Self {
+        self.service_name = service_name.to_owned();
+        self
+    }
+
+    /// Sends the records once `batch` are pending.
+    pub fn with_batch(mut self, batch: usize) -> Self {
+        self.batch = batch.max(1);
+        self
+    }
+
+    /// Export request with the pending records, clearing them.
+    fn take_request(&mut self) -> Json {
+        let scope_logs = std::mem::take(&mut self.pending)
+            .into_iter()
+            .map(|(target, records)| {
+                json!({
+                    "scope": { "name": target },
+                    "logRecords": records,
+                })
+            })
+            .collect::<Vec<_>>();
+        self.pending_len = 0;
+
+        json!({
+            "resourceLogs": [{
+                "resource": {
+                    "attributes": [
+                        attribute("service.name", json!({ "stringValue": self.service_name })),
+                    ],
+                },
+                "scopeLogs": scope_logs,
+            }],
+        })
+    }
+
+    fn send(&mut self) {
+        if self.pending_len == 0 {
+            return;
+        }
+
+        let request = self.take_request();
+        let _ = self.endpoint.post(request.to_string().as_bytes());
+    }
+}
+impl TapeMachine<InstructionSet> for OtlpExporter {
+    fn needs_restart(&mut self) -> bool {
+        false
+    }
+
+    fn flush(&mut self) {
+        self.send();
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Restart => {
+                self.span.clear();
+                self.new_records = None;
+                self.new_event = None;
+            }
+            Instruction::NewSpan { parent, span, name } => {
+                self.new_records = Some((
+                    span,
+                    SpanRecords {
+                        parent,
+                        name: name.to_owned(),
+                        records: Default::default(),
+                    },
+                ));
+            }
+            Instruction::NewRecord(span) => {
+                let records = self
+                    .span
+                    .remove(&span)
+                    .unwrap_or_else(|| SpanRecords::lost(span));
+                self.new_records = Some((span, records));
+            }
+            Instruction::FinishedSpan | Instruction::FinishedRecord => {
+                if let Some((span, records)) = self.new_records.take() {
+                    self.span.insert(span, records);
+                }
+            }
+            Instruction::StartEvent {
+                time,
+                span,
+                target,
+                priority,
+                location,
+                thread,
+            } => {
+                self.new_event = Some(NewEvent {
+                    time,
+                    span,
+                    target: target.to_owned(),
+                    priority,
+                    location: location.map(|location| location.map(str::to_owned)),
+                    thread: thread.map(|thread| thread.map(str::to_owned)),
+                    records: Default::default(),
+                });
+            }
+            Instruction::AddValue(value) => match (&mut self.new_records, &mut self.new_event) {
+                (Some((_, records)), _) => records.record(value.to_owned()),
+                (_, Some(event)) => event.records.push(value.to_owned()),
+                _ => (),
+            },
+            Instruction::FinishedEvent => {
+                let Some(event) = self.new_event.take() else {
+                    return;
+                };
+                let spans = resolve_spans(&self.span, event.span);
+                let record = log_record(&event, &spans);
+                self.pending.entry(event.target).or_default().push(record);
+                self.pending_len += 1;
+                if self.pending_len >= self.batch {
+                    self.send();
+                }
+            }
+            Instruction::DeleteSpan(span) => {
+                self.span.remove(&span);
+            }
+            Instruction::FilterChanged(..)
+            | Instruction::EnterSpan { .. }
+            | Instruction::ExitSpan { .. }
+            | Instruction::SpanTiming { .. } => (),
+        }
+    }
+}
+impl Drop for OtlpExporter {
+    fn drop(&mut self) {
+        self.send();
+    }
+}
+
+/// OTLP log record of `event`, in the JSON encoding, with `spans` from the root as given by
+/// [`resolve_spans`].
+pub fn log_record(event: &NewEvent, spans: &[Cow<SpanRecords>]) -> Json {
+    let mut body = None;
+    let mut attributes = Vec::new();
+    for record in event.records.iter() {
+        match record {
+            FieldValueOwned {
+                name,
+                value: ValueOwned::Debug(message) | ValueOwned::String(message),
+            } if name == "message" => body = Some(json!({ "stringValue": message })),
+            record => attributes.push(attribute(&record.name, any_value(&record.value))),
+        }
+    }
+    for span in spans.iter() {
+        for record in span.records.iter() {
+            let name = format!("{}.{}", span.name, record.name);
+            attributes.push(attribute(&name, any_value(&record.value)));
+        }
+    }
+    if let Some(location) = event.location.as_ref() {
+        attributes.push(attribute(
+            "code.filepath",
+            json!({ "stringValue": location.file }),
+        ));
+        attributes.push(attribute(
+            "code.lineno",
+            json!({ "intValue": location.line.to_string() }),
+        ));
+    }
+    if let Some(thread) = event.thread.as_ref() {
+        attributes.push(attribute(
+            "thread.id",
+            json!({ "intValue": thread.id.to_string() }),
+        ));
+        attributes.push(attribute(
+            "thread.name",
+            json!({ "stringValue": thread.name }),
+        ));
+    }
+
+    let time = event
+        .time
+        .timestamp_nanos_opt()
+        .unwrap_or_default()
+        .max(0)
+        .to_string();
+    let mut record = json!({
+        "timeUnixNano": time,
+        "observedTimeUnixNano": time,
+        "severityNumber": severity_number(event.priority),
+        "severityText": event.priority.as_str(),
+        "attributes": attributes,
+    });
+    if let Some(body) = body {
+        record["body"] = body;
+    }
+    if let Some(span) = event.span {
+        let root = root_span(spans, span);
+        record["traceId"] = format!("{:032x}", root.get()).into();
+        record["spanId"] = format!("{:016x}", span.get()).into();
+    }
+
+    record
+}
+
+/// Id of the root of `spans`, which only keep their parent ids.
+fn root_span(spans: &[Cow<SpanRecords>], span: NonZeroU64) -> NonZeroU64 {
+    spans.get(1).and_then(|child| child.parent).unwrap_or(span)
+}
+
+fn severity_number(level: Level) -> u8 {
+    match level {
+        Level::TRACE => 1,
+        Level::DEBUG => 5,
+        Level::INFO => 9,
+        Level::WARN => 13,
+        Level::ERROR => 17,
+    }
+}
+
+fn attribute(key: &str, value: Json) -> Json {
+    json!({ "key": key, "value": value })
+}
+
+/// OTLP `AnyValue` of a field. 64-bit integers are strings, as in the protobuf JSON mapping.
+fn any_value(value: &ValueOwned) -> Json {
+    match value {
+        ValueOwned::Debug(value) | ValueOwned::String(value) => json!({ "stringValue": value }),
+        ValueOwned::Float(value) => json!({ "doubleValue": value }),
+        ValueOwned::Integer(value) => json!({ "intValue": value.to_string() }),
+        ValueOwned::Unsigned(value) if i64::try_from(*value).is_ok() => {
+            json!({ "intValue": value.to_string() })
+        }
+        ValueOwned::Unsigned(value) => json!({ "stringValue": value.to_string() }),
+        ValueOwned::I128(value) if i64::try_from(*value).is_ok() => {
+            json!({ "intValue": value.to_string() })
+        }
+        ValueOwned::U128(value) if i64::try_from(*value).is_ok() => {
+            json!({ "intValue": value.to_string() })
+        }
+        ValueOwned::I128(value) => json!({ "stringValue": value.to_string() }),
+        ValueOwned::U128(value) => json!({ "stringValue": value.to_string() }),
+        ValueOwned::Bool(value) => json!({ "boolValue": value }),
+        ValueOwned::Error { message, sources } => {
+            let chain = std::iter::once(message.clone()).chain(sources.strings());
+            json!({ "stringValue": chain.collect::<Vec<_>>().join(": caused by: ") })
+        }
+        ValueOwned::ByteArray(items) => json!({ "bytesValue": STANDARD.encode(items) }),
+        ValueOwned::Custom { tag, data } => json!({
+            "kvlistValue": {
+                "values": [
+                    attribute("tag", json!({ "intValue": tag.to_string() })),
+                    attribute("data", json!({ "bytesValue": STANDARD.encode(data) })),
+                ],
+            },
+        }),
+        ValueOwned::Array(nested) => json!({
+            "arrayValue": {
+                "values": nested
+                    .values()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(any_value)
+                    .collect::<Vec<_>>(),
+            },
+        }),
+        ValueOwned::Map(nested) => json!({
+            "kvlistValue": {
+                "values": nested
+                    .entries()
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|(key, value)| attribute(key, any_value(value)))
+                    .collect::<Vec<_>>(),
+            },
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tape::{FieldValue, Value};
+    use std::{io::Read, net::TcpListener};
+
+    #[test]
+    fn parse_endpoint() {
+        assert_eq!(
+            "http://collector".parse::<Endpoint>().unwrap(),
+            Endpoint {
+                address: "collector:4318".to_string(),
+                path: "/v1/logs".to_string(),
+            }
+        );
+        assert_eq!(
+            "http://127.0.0.1:9000/logs".parse::<Endpoint>().unwrap(),
+            Endpoint {
+                address: "127.0.0.1:9000".to_string(),
+                path: "/logs".to_string(),
+            }
+        );
+        assert!("https://collector".parse::<Endpoint>().is_err());
+    }
+
+    #[test]
+    fn export_batch() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            let body = loop {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let request = String::from_utf8_lossy(&request);
+                let Some((head, body)) = request.split_once("\r\n\r\n") else {
+                    continue;
+                };
+                let len = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .unwrap()
+                    .parse::<usize>()
+                    .unwrap();
+                if body.len() == len {
+                    assert!(head.starts_with("POST /v1/logs HTTP/1.1"));
+                    break body.to_string();
+                }
+            };
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            serde_json::from_str::<Json>(&body).unwrap()
+        });
+
+        let mut exporter = OtlpExporter::new(endpoint.parse().unwrap())
+            .with_service_name("test")
+            .with_batch(2);
+        let span = |id| NonZeroU64::new(id).unwrap();
+        exporter.handle(Instruction::Restart);
+        exporter.handle(Instruction::NewSpan {
+            parent: None,
+            span: span(1),
+            name: "request",
+        });
+        exporter.handle(Instruction::AddValue(FieldValue {
+            name: "id",
+            value: Value::Unsigned(7),
+        }));
+        exporter.handle(Instruction::FinishedSpan);
+        exporter.handle(Instruction::NewSpan {
+            parent: Some(span(1)),
+            span: span(2),
+            name: "query",
+        });
+        exporter.handle(Instruction::FinishedSpan);
+        for (target, priority) in [("db", Level::WARN), ("app", Level::INFO)] {
+            exporter.handle(Instruction::StartEvent {
+                time: Default::default(),
+                span: Some(span(2)),
+                target,
+                priority,
+                location: None,
+                thread: None,
+            });
+            exporter.handle(Instruction::AddValue(FieldValue {
+                name: "message",
+                value: Value::Debug("done"),
+            }));
+            exporter.handle(Instruction::AddValue(FieldValue {
+                name: "rows",
+                value: Value::Integer(-1),
+            }));
+            exporter.handle(Instruction::FinishedEvent);
+        }
+
+        let request = server.join().unwrap();
+        let resource = &request["resourceLogs"][0];
+        assert_eq!(
+            resource["resource"]["attributes"][0]["value"]["stringValue"],
+            "test"
+        );
+        let scope = &resource["scopeLogs"][1];
+        assert_eq!(scope["scope"]["name"], "db");
+        assert_eq!(
+            scope["logRecords"][0],
+            json!({
+                "timeUnixNano": "0",
+                "observedTimeUnixNano": "0",
+                "severityNumber": 13,
+                "severityText": "WARN",
+                "body": { "stringValue": "done" },
+                "attributes": [
+                    { "key": "rows", "value": { "intValue": "-1" } },
+                    { "key": "request.id", "value": { "intValue": "7" } },
+                ],
+                "traceId": format!("{:032x}", 1),
+                "spanId": format!("{:016x}", 2),
+            })
+        );
+    }
+}
@@ -0,0 +1,97 @@
+use crate::{
+    storage::Load,
+    string_cache::StringUncache,
+    tape::{InstructionSet, TapeMachine},
+};
+use std::io::{self, Read};
+
+/// Size of the buffers of [`export`], so that tapes are read and written in large sequential
+/// chunks.
+pub const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Replays a whole tape into `output`, through the machines built by `transforms`, e.g.
+/// `|out| FilterMachine::new(out, Level::INFO)`, or `std::convert::identity`.
+///
+/// The input is read in chunks of [`BUFFER_SIZE`], and strings are resolved once by the string
+/// cache. Wrap the writer of the exporter in a `BufWriter` of the same size. Returns the machine,
+/// flushed, to get the exporter back.
+pub fn export<R, F, M, T>(input: R, transforms: F, output: T) -> io::Result<M>
+where
+    R: Read,
+    F: FnOnce(T) -> M,
+    M: TapeMachine<InstructionSet>,
+{
+    let mut machine = StringUncache::new(transforms(output));
+    Load::with_capacity(BUFFER_SIZE, input).forward_cached(&mut machine)?;
+    machine.flush();
+    Ok(machine.into_inner())
+}
+
+/// Converts a tape into JSON lines, see [`JsonLines`](crate::printer::JsonLines).
+#[cfg(feature = "json")]
+pub fn export_json<R, W>(input: R, output: W) -> io::Result<W>
+where
+    R: Read,
+    W: io::Write + Send + 'static,
+{
+    let output = io::BufWriter::with_capacity(BUFFER_SIZE, output);
+    let json = export(
+        input,
+        std::convert::identity,
+        crate::printer::JsonLines::new(output),
+    )?;
+    json.into_inner()
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        printer::Printer,
+        storage::Store,
+        string_cache::StringCache,
+        tape::{FieldValue, FilterMachine, Instruction, Value},
+    };
+    use chrono::DateTime;
+    use std::sync::{Arc, Mutex};
+    use tracing::Level;
+
+    #[test]
+    fn export_filtered() {
+        let mut tape = StringCache::new(Store::new(Vec::new()));
+        tape.handle(Instruction::Restart);
+        for (priority, message) in [(Level::DEBUG, "verbose"), (Level::INFO, "kept")] {
+            tape.handle(Instruction::StartEvent {
+                time: DateTime::UNIX_EPOCH,
+                span: None,
+                target: "target",
+                priority,
+                location: None,
+                thread: None,
+            });
+            tape.handle(Instruction::AddValue(FieldValue {
+                name: "message",
+                value: Value::Debug(message),
+            }));
+            tape.handle(Instruction::FinishedEvent);
+        }
+        let tape = tape.into_inner().into_inner();
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let sink = lines.clone();
+        let printer = Printer::new(io::sink(), false)
+            .with_sink(move |line| sink.lock().unwrap().push(line.to_owned()));
+        export(
+            tape.as_slice(),
+            |out| FilterMachine::new(out, Level::INFO),
+            printer,
+        )
+        .unwrap();
+        assert_eq!(
+            *lines.lock().unwrap(),
+            ["1970-01-01T00:00:00Z  INFO target: kept"]
+        );
+    }
+}
@@ -1,11 +1,21 @@
-use crate::tape::{
-    FieldValueOwned, Instruction, InstructionSet, SpanRecords, TapeMachine, ValueOwned,
+use crate::{
+    annotation::ANNOTATION_PREFIX,
+    custom_value::CustomValues,
+    duration::format_duration,
+    level_filter::TargetFilter,
+    query::Query,
+    storage::{FlushPolicy, Flusher},
+    tape::{
+        FieldValueOwned, Instruction, InstructionSet, InstructionTrait, Location, SpanRecords,
+        TapeMachine, ThreadInfo, ValueOwned,
+    },
 };
 use chrono::{DateTime, Utc};
 use nu_ansi_term::{Color, Style};
 use std::borrow::Cow;
 use std::fmt::Write;
 use std::num::NonZeroU64;
+use std::str::FromStr;
 use std::{collections::HashMap, io};
 use tracing::Level;
 
@@ -15,7 +25,29 @@ pub struct Printer<W> {
     span: HashMap<NonZeroU64, SpanRecords>,
     new_records: Option<(NonZeroU64, SpanRecords)>,
     new_event: Option<NewEvent>,
+    custom_values: CustomValues,
+    threads: bool,
+    span_close: bool,
+    span_filter: Option<SpanFilter>,
+    target_filter: Option<TargetFilter>,
+    field_filter: Vec<FieldFilter>,
+    query: Option<Query>,
+    event_filter: Option<EventFilter>,
+    separator: Option<Separator>,
+    field_order: Option<FieldOrder>,
+    last_period: Option<String>,
+    #[cfg(feature = "json")]
+    json: bool,
+    sink: Option<LineSink>,
+    flush: Flusher,
 }
+
+/// Receiver of the lines of [`Printer::with_sink`].
+pub type LineSink = Box<dyn FnMut(&str) + Send>;
+
+/// Predicate of [`Printer::with_event_filter`], given the event and its spans from the root.
+pub type EventFilter = Box<dyn FnMut(&NewEvent, &[Cow<SpanRecords>]) -> bool + Send>;
+
 impl<W> Printer<W>
 where
     W: io::Write + Send + 'static,
@@ -27,51 +59,194 @@ where
             span: Default::default(),
             new_records: None,
             new_event: None,
+            custom_values: Default::default(),
+            threads: false,
+            span_close: false,
+            span_filter: None,
+            target_filter: None,
+            field_filter: Vec::new(),
+            query: None,
+            event_filter: None,
+            separator: None,
+            field_order: None,
+            last_period: None,
+            #[cfg(feature = "json")]
+            json: false,
+            sink: None,
+            flush: FlushPolicy::default().into(),
         }
     }
 
-    fn get_span(&self, span: NonZeroU64) -> Cow<SpanRecords> {
-        match self.span.get(&span) {
-            Some(span) => Cow::Borrowed(span),
-            None => Cow::Owned(SpanRecords::lost(span)),
-        }
+    /// Flushes the output according to `flush`, instead of after every line.
+    pub fn with_flush_policy(mut self, flush: FlushPolicy) -> Self {
+        self.flush = flush.into();
+        self
     }
 
-    fn take_span(&mut self, span: NonZeroU64) -> SpanRecords {
-        match self.span.remove(&span) {
-            Some(records) => records,
-            None => SpanRecords::lost(span),
-        }
+    /// Renders custom values using the encoders in `custom_values`.
+    pub fn with_custom_values(mut self, custom_values: CustomValues) -> Self {
+        self.custom_values = custom_values;
+        self
+    }
+
+    /// Prints the thread of events recorded with
+    /// [`with_thread_info`](crate::tape::TapeMachineLogger::with_thread_info).
+    pub fn with_threads(mut self, threads: bool) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Prints a `close time.busy=… time.idle=…` event when a span recorded with
+    /// [`with_span_timing`](crate::tape::TapeMachineLogger::with_span_timing) closes, like
+    /// `tracing_subscriber::fmt` with `FmtSpan::CLOSE`.
+    pub fn with_span_close(mut self, span_close: bool) -> Self {
+        self.span_close = span_close;
+        self
+    }
+
+    /// Only prints events inside a span matching `span_filter`, directly or through any ancestor.
+    pub fn with_span_filter(mut self, span_filter: SpanFilter) -> Self {
+        self.span_filter = Some(span_filter);
+        self
+    }
+
+    /// Only prints events whose target matches `target_filter`.
+    pub fn with_target_filter(mut self, target_filter: TargetFilter) -> Self {
+        self.target_filter = Some(target_filter);
+        self
+    }
+
+    /// Only prints events with a field matching `field_filter`, in the event or in any of its
+    /// spans. Every filter added must match.
+    pub fn with_field_filter(mut self, field_filter: FieldFilter) -> Self {
+        self.field_filter.push(field_filter);
+        self
+    }
+
+    /// Only prints events matching `query`.
+    pub fn with_query(mut self, query: Query) -> Self {
+        self.query = Some(query);
+        self
+    }
+
+    /// Only prints events for which `event_filter` returns true, e.g. searching their fields.
+    pub fn with_event_filter(mut self, event_filter: EventFilter) -> Self {
+        self.event_filter = Some(event_filter);
+        self
+    }
+
+    /// Prints every event as a JSON object on its own line, see [`NewEvent::to_json`], instead
+    /// of text. Separators and filter changes are not printed.
+    #[cfg(feature = "json")]
+    pub fn with_json(mut self, json: bool) -> Self {
+        self.json = json;
+        self
     }
 
-    fn span_iter<'a, F>(&'a self, span: NonZeroU64, f: &mut F)
+    /// Gives every line to `sink`, without the newline, instead of writing it to the output,
+    /// which is then unused. Lines are still rendered with colors if enabled.
+    pub fn with_sink<F>(mut self, sink: F) -> Self
     where
-        F: FnMut(Cow<'a, SpanRecords>),
+        F: FnMut(&str) + Send + 'static,
     {
-        let records = self.get_span(span);
-        if let Some(parent) = records.parent {
-            self.span_iter(parent, f);
+        self.sink = Some(Box::new(sink));
+        self
+    }
+
+    /// Prints a separator line before the first event of each day or hour.
+    pub fn with_separator(mut self, separator: Separator) -> Self {
+        self.separator = Some(separator);
+        self
+    }
+
+    /// Sorts the fields of events and spans, so lines from different runs can be diffed.
+    pub fn with_field_order(mut self, field_order: FieldOrder) -> Self {
+        self.field_order = Some(field_order);
+        self
+    }
+
+    /// Writes the separator line if `time` starts a new period, returning that period.
+    fn write_separator(&self, time: DateTime<Utc>, line: &mut String) -> Option<String> {
+        let period = self.separator?.period(time);
+        if self.last_period.as_ref() == Some(&period) {
+            return None;
         }
-        f(records);
+
+        let dimmed = self.color.then(|| Style::new().dimmed());
+        NewEvent::with_style(dimmed, line, |line| {
+            write!(line, "--- {period} ({}) ---", time.format("%G-W%V, %A"))
+        })
+        .unwrap();
+
+        Some(period)
     }
 
-    fn span_from_root(&self, span: NonZeroU64) -> Vec<Cow<SpanRecords>> {
-        let mut r = Vec::new();
-        self.span_iter(span, &mut |records| {
-            r.push(records);
-        });
-        r
+    fn write_line(&mut self, line: &str) {
+        if let Some(sink) = self.sink.as_mut() {
+            sink(line);
+            return;
+        }
+
+        let _ = self.out.write_all(line.as_bytes());
+        let _ = self.out.write_all(b"\n");
     }
-}
-impl<W> TapeMachine<InstructionSet> for Printer<W>
-where
-    W: io::Write + Send + 'static,
-{
-    fn needs_restart(&mut self) -> bool {
-        false
+
+    fn print_event(&mut self, mut new_event: NewEvent) {
+        if let Some(target_filter) = self.target_filter.as_ref()
+            && !target_filter.matches(&new_event.target)
+        {
+            return;
+        }
+        let mut spans = resolve_spans(&self.span, new_event.span);
+        if let Some(span_filter) = self.span_filter.as_ref()
+            && !spans.iter().any(|span| span_filter.matches(span))
+        {
+            return;
+        }
+        if !self
+            .field_filter
+            .iter()
+            .all(|filter| filter.matches_event(&new_event, &spans))
+        {
+            return;
+        }
+        if let Some(query) = self.query.as_ref()
+            && !query.matches(&new_event, &spans)
+        {
+            return;
+        }
+        if let Some(event_filter) = self.event_filter.as_mut()
+            && !event_filter(&new_event, &spans)
+        {
+            return;
+        }
+
+        #[cfg(feature = "json")]
+        if self.json {
+            let line = new_event.to_json(&spans).to_string();
+            self.write_line(&line);
+            return;
+        }
+
+        if let Some(field_order) = self.field_order.as_ref() {
+            field_order.sort(&mut new_event.records);
+            for span in spans.iter_mut() {
+                field_order.sort(&mut span.to_mut().records);
+            }
+        }
+
+        let mut line = String::new();
+        new_event.write_line_with(self.color, &self.custom_values, &spans, &mut line);
+
+        let mut separator = String::new();
+        if let Some(period) = self.write_separator(new_event.time, &mut separator) {
+            self.last_period = Some(period);
+            self.write_line(&separator);
+        }
+        self.write_line(&line);
     }
 
-    fn handle(&mut self, instruction: Instruction) {
+    fn print_instruction(&mut self, instruction: Instruction) {
         match instruction {
             Instruction::Restart => {
                 self.new_event = None;
@@ -101,6 +276,8 @@ where
                 span,
                 target,
                 priority,
+                location,
+                thread,
             } => {
                 assert!(self.new_event.is_none());
                 self.new_event = Some(NewEvent {
@@ -108,26 +285,21 @@ where
                     span,
                     target: target.to_owned(),
                     priority,
+                    location: location.map(|location| location.map(str::to_owned)),
+                    thread: thread
+                        .filter(|_| self.threads)
+                        .map(|thread| thread.map(str::to_owned)),
                     records: Default::default(),
                 });
             }
             Instruction::FinishedEvent => {
                 let new_event = self.new_event.take().unwrap();
-                let spans = new_event
-                    .span
-                    .map(|span| self.span_from_root(span))
-                    .unwrap_or_default();
-
-                let line = new_event.to_line(self.color, &spans);
-
-                let _ = self.out.write_all(line.as_bytes());
-                let _ = self.out.write_all(b"\n");
-                let _ = self.out.flush();
+                self.print_event(new_event);
             }
             Instruction::AddValue(field_value) => {
                 match (&mut self.new_records, &mut self.new_event) {
                     (Some(new_records), None) => {
-                        new_records.1.records.push(field_value.to_owned());
+                        new_records.1.record(field_value.to_owned());
                     }
                     (None, Some(new_event)) => {
                         new_event.records.push(field_value.to_owned());
@@ -138,38 +310,456 @@ where
             Instruction::DeleteSpan(id) => {
                 self.span.remove(&id);
             }
+            Instruction::FilterChanged(filter) => {
+                #[cfg(feature = "json")]
+                if self.json {
+                    return;
+                }
+
+                let dimmed = self.color.then(|| Style::new().dimmed());
+                let mut line = String::new();
+                NewEvent::with_style(dimmed, &mut line, |line| {
+                    write!(line, "filter changed to {filter:?}")
+                })
+                .unwrap();
+
+                self.write_line(&line);
+            }
+            Instruction::EnterSpan { .. } | Instruction::ExitSpan { .. } => (),
+            Instruction::SpanTiming {
+                time,
+                span,
+                target,
+                priority,
+                busy,
+                idle,
+            } => {
+                if !self.span_close {
+                    return;
+                }
+
+                let field = |name: &str, value: String| FieldValueOwned {
+                    name: name.to_owned(),
+                    value: ValueOwned::Debug(value),
+                };
+                self.print_event(NewEvent {
+                    time,
+                    span: Some(span),
+                    target: target.to_owned(),
+                    priority,
+                    location: None,
+                    thread: None,
+                    records: vec![
+                        field("message", "close".to_owned()),
+                        field("time.busy", format_duration(busy)),
+                        field("time.idle", format_duration(idle)),
+                    ],
+                });
+            }
+        }
+    }
+
+    fn take_span(&mut self, span: NonZeroU64) -> SpanRecords {
+        match self.span.remove(&span) {
+            Some(records) => records,
+            None => SpanRecords::lost(span),
+        }
+    }
+}
+impl<W> TapeMachine<InstructionSet> for Printer<W>
+where
+    W: io::Write + Send + 'static,
+{
+    fn needs_restart(&mut self) -> bool {
+        false
+    }
+
+    fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        let id = instruction.id();
+        self.print_instruction(instruction);
+        if self.flush.should_flush(id) {
+            let _ = self.out.flush();
         }
     }
 }
 
+/// Writes every event as a JSON object on its own line, see [`NewEvent::to_json`].
+///
+/// Meant as an additional output of an application, next to its tape, for tools expecting JSON.
+#[cfg(feature = "json")]
+pub struct JsonLines<W> {
+    printer: Printer<W>,
+}
+#[cfg(feature = "json")]
+impl<W> JsonLines<W>
+where
+    W: io::Write + Send + 'static,
+{
+    pub fn new(out: W) -> Self {
+        Self {
+            printer: Printer::new(out, false).with_threads(true).with_json(true),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.printer.out
+    }
+}
+#[cfg(feature = "json")]
+impl<W> TapeMachine<InstructionSet> for JsonLines<W>
+where
+    W: io::Write + Send + 'static,
+{
+    fn needs_restart(&mut self) -> bool {
+        false
+    }
+
+    fn flush(&mut self) {
+        self.printer.flush();
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        self.printer.handle(instruction);
+    }
+}
+
+/// Matches spans by name and, optionally, by field values.
+///
+/// Parsed from `name` or `name{field=value ...}`, where fields are separated by spaces or commas.
+/// Values are compared with their text: strings and debug values without quotes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpanFilter {
+    name: String,
+    fields: Vec<FieldFilter>,
+}
+impl SpanFilter {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            fields: Default::default(),
+        }
+    }
+
+    pub fn field(mut self, name: &str, value: &str) -> Self {
+        self.fields.push(FieldFilter::new(name, value));
+        self
+    }
+
+    pub fn matches(&self, span: &SpanRecords) -> bool {
+        span.name == self.name && self.fields.iter().all(|field| field.matches(&span.records))
+    }
+}
+
+/// Matches a field by name and value text: strings and debug values without quotes.
+///
+/// Parsed from `name=value`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldFilter {
+    name: String,
+    value: String,
+}
+impl FieldFilter {
+    pub fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            value: value.to_owned(),
+        }
+    }
+
+    pub fn matches(&self, records: &[FieldValueOwned]) -> bool {
+        records.iter().any(|record| {
+            record.name == self.name
+                && Self::value_text(&record.value).as_deref() == Some(self.value.as_str())
+        })
+    }
+
+    /// Whether the field is in `event` or in any of its `spans`.
+    pub fn matches_event(&self, event: &NewEvent, spans: &[Cow<SpanRecords>]) -> bool {
+        self.matches(&event.records) || spans.iter().any(|span| self.matches(&span.records))
+    }
+
+    /// Text a value is matched by: strings and debug values without quotes. `None` for binary
+    /// and nested values.
+    pub fn value_text(value: &ValueOwned) -> Option<Cow<'_, str>> {
+        Some(match value {
+            ValueOwned::Debug(str)
+            | ValueOwned::String(str)
+            | ValueOwned::Error { message: str, .. } => Cow::Borrowed(str),
+            ValueOwned::Float(value) => Cow::Owned(value.to_string()),
+            ValueOwned::Integer(value) => Cow::Owned(value.to_string()),
+            ValueOwned::Unsigned(value) => Cow::Owned(value.to_string()),
+            ValueOwned::I128(value) => Cow::Owned(value.to_string()),
+            ValueOwned::U128(value) => Cow::Owned(value.to_string()),
+            ValueOwned::Bool(value) => Cow::Owned(value.to_string()),
+            ValueOwned::ByteArray(_)
+            | ValueOwned::Custom { .. }
+            | ValueOwned::Array(_)
+            | ValueOwned::Map(_) => return None,
+        })
+    }
+}
+impl FromStr for FieldFilter {
+    type Err = String;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let (name, value) = str
+            .split_once('=')
+            .ok_or_else(|| format!("Expected field=value, got {str:?}"))?;
+        Ok(Self::new(name, value.trim_matches('"')))
+    }
+}
+impl FromStr for SpanFilter {
+    type Err = String;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let Some((name, fields)) = str.split_once('{') else {
+            return Ok(Self::new(str));
+        };
+        let fields = fields
+            .strip_suffix('}')
+            .ok_or_else(|| format!("Missing closing brace in {str:?}"))?;
+
+        fields
+            .split([' ', ','])
+            .filter(|field| !field.is_empty())
+            .try_fold(Self::new(name), |mut filter, field| {
+                filter.fields.push(field.parse()?);
+                Ok(filter)
+            })
+    }
+}
+
+/// When [`Printer::with_separator`] prints a separator line between events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Separator {
+    Day,
+    Hour,
+}
+impl Separator {
+    fn period(self, time: DateTime<Utc>) -> String {
+        match self {
+            Separator::Day => time.format("%Y-%m-%d").to_string(),
+            Separator::Hour => time.format("%Y-%m-%d %H:00").to_string(),
+        }
+    }
+}
+impl FromStr for Separator {
+    type Err = String;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        match str {
+            "day" => Ok(Separator::Day),
+            "hour" => Ok(Separator::Hour),
+            _ => Err(format!("Expected day or hour, got {str:?}")),
+        }
+    }
+}
+
+/// How [`Printer::with_field_order`] sorts fields. The message always comes first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FieldOrder {
+    Alphabetical,
+    /// The listed fields first, in the given order, then the others alphabetically.
+    Priority(Vec<String>),
+}
+impl FieldOrder {
+    pub fn sort(&self, records: &mut [FieldValueOwned]) {
+        records.sort_by_cached_key(|record| {
+            let priority = match self {
+                _ if record.name == "message" => 0,
+                FieldOrder::Alphabetical => 1,
+                FieldOrder::Priority(names) => names
+                    .iter()
+                    .position(|name| *name == record.name)
+                    .map_or(names.len() + 1, |position| position + 1),
+            };
+            (priority, record.name.clone())
+        });
+    }
+}
+impl FromStr for FieldOrder {
+    type Err = String;
+
+    /// `alpha`, or a comma separated list of field names.
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        match str {
+            "alpha" => Ok(FieldOrder::Alphabetical),
+            "" => Err("Expected alpha or a list of fields".to_string()),
+            names => Ok(FieldOrder::Priority(
+                names.split(',').map(str::to_owned).collect(),
+            )),
+        }
+    }
+}
+
+/// Chain of spans from the root to `span`, as known from `known`. Unknown spans are lost.
+pub fn resolve_spans(
+    known: &HashMap<NonZeroU64, SpanRecords>,
+    span: Option<NonZeroU64>,
+) -> Vec<Cow<'_, SpanRecords>> {
+    let mut spans = Vec::new();
+    let mut next = span;
+    while let Some(span) = next {
+        let records = match known.get(&span) {
+            Some(records) => Cow::Borrowed(records),
+            None => Cow::Owned(SpanRecords::lost(span)),
+        };
+        next = records.parent;
+        spans.push(records);
+    }
+    spans.reverse();
+    spans
+}
+
+/// How [`format_event`] renders an event.
+#[derive(Clone, Default)]
+pub struct FormatOptions {
+    pub color: bool,
+    pub custom_values: CustomValues,
+}
+
+/// Renders `event` exactly as [`Printer`] prints it, with `spans` from the root as given by
+/// [`resolve_spans`].
+pub fn format_event(
+    event: &NewEvent,
+    spans: &[Cow<SpanRecords>],
+    options: &FormatOptions,
+) -> String {
+    let mut line = String::new();
+    event.write_line_with(options.color, &options.custom_values, spans, &mut line);
+    line
+}
+
 pub struct NewEvent {
     pub time: DateTime<Utc>,
     pub span: Option<NonZeroU64>,
     pub target: String,
     pub priority: Level,
+    pub location: Option<Location<String>>,
+    pub thread: Option<ThreadInfo<String>>,
     pub records: Vec<FieldValueOwned>,
 }
 impl NewEvent {
+    /// Sends the event again, from `StartEvent` to `FinishedEvent`.
+    pub fn forward<T>(&self, machine: &mut T)
+    where
+        T: TapeMachine<InstructionSet> + ?Sized,
+    {
+        machine.handle(Instruction::StartEvent {
+            time: self.time,
+            span: self.span,
+            target: &self.target,
+            priority: self.priority,
+            location: self
+                .location
+                .as_ref()
+                .map(|location| location.as_ref().map(String::as_str)),
+            thread: self
+                .thread
+                .as_ref()
+                .map(|thread| thread.as_ref().map(String::as_str)),
+        });
+        for record in self.records.iter() {
+            machine.handle(Instruction::AddValue(record.as_ref()));
+        }
+        machine.handle(Instruction::FinishedEvent);
+    }
+
     pub fn to_line(&self, color: bool, spans: &[Cow<SpanRecords>]) -> String {
         let mut line = String::new();
         self.write_line(color, spans, &mut line);
         line
     }
 
+    /// The event as a JSON object, with the chain of `spans` from the root and their fields.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self, spans: &[Cow<SpanRecords>]) -> serde_json::Value {
+        use serde_json::json;
+
+        let fields = |records: &[FieldValueOwned]| {
+            records
+                .iter()
+                .map(|record| (record.name.clone(), record.value.to_json()))
+                .collect::<serde_json::Map<_, _>>()
+        };
+        let spans = spans
+            .iter()
+            .map(|span| json!({ "name": span.name, "fields": fields(&span.records) }))
+            .collect::<Vec<_>>();
+
+        let mut event = json!({
+            "timestamp": self.time.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true),
+            "level": self.priority.as_str(),
+            "target": self.target,
+            "spans": spans,
+            "fields": fields(&self.records),
+        });
+        if let Some(location) = self.location.as_ref() {
+            event["location"] = json!({
+                "file": location.file,
+                "line": location.line,
+                "module_path": location.module_path,
+            });
+        }
+        if let Some(thread) = self.thread.as_ref() {
+            event["thread"] = json!({ "id": thread.id, "name": thread.name });
+        }
+
+        event
+    }
+
     pub fn write_line<W>(&self, color: bool, spans: &[Cow<SpanRecords>], line: &mut W)
     where
         W: Write,
+    {
+        self.write_line_with(color, &Default::default(), spans, line);
+    }
+
+    pub fn write_line_with<W>(
+        &self,
+        color: bool,
+        custom_values: &CustomValues,
+        spans: &[Cow<SpanRecords>],
+        line: &mut W,
+    ) where
+        W: Write,
     {
         let dimmed = color.then(|| Style::new().dimmed());
-        let bold = color.then(|| Style::new().bold());
         let level_color = color.then(|| Self::level_style(self.priority));
-        let field_style = color.then(|| Style::new().italic());
 
         Self::with_style(dimmed, line, |line| write!(line, "{:?}", self.time)).unwrap();
         Self::with_style(level_color, line, |line| {
             write!(line, " {}", Self::level_padded(self.priority))
         })
         .unwrap();
+        if let Some(thread) = self.thread.as_ref() {
+            Self::with_style(dimmed, line, |line| {
+                write!(line, " {}#{}", thread.name, thread.id)
+            })
+            .unwrap();
+        }
+
+        self.write_body_with(color, custom_values, spans, line);
+    }
+
+    /// Writes the line without its time, level and thread: the spans, target, location and
+    /// fields, starting with a space.
+    pub fn write_body_with<W>(
+        &self,
+        color: bool,
+        custom_values: &CustomValues,
+        spans: &[Cow<SpanRecords>],
+        line: &mut W,
+    ) where
+        W: Write,
+    {
+        let dimmed = color.then(|| Style::new().dimmed());
+        let bold = color.then(|| Style::new().bold());
+        let field_style = color.then(|| Style::new().italic());
 
         for (idx, span) in spans.iter().enumerate() {
             if idx == 0 {
@@ -184,17 +774,23 @@ impl NewEvent {
                 if idx > 0 {
                     write!(line, " ").unwrap();
                 }
-                Self::write_record(record, field_style, false, line).unwrap();
+                Self::write_record(record, field_style, false, custom_values, line).unwrap();
             }
             write!(line, "}}").unwrap();
             Self::with_style(dimmed, line, |line| write!(line, ":")).unwrap();
         }
 
         Self::with_style(dimmed, line, |line| write!(line, " {}:", self.target)).unwrap();
+        if let Some(location) = self.location.as_ref() {
+            Self::with_style(dimmed, line, |line| {
+                write!(line, " {}:{}:", location.file, location.line)
+            })
+            .unwrap();
+        }
 
         for record in self.records.iter() {
             write!(line, " ").unwrap();
-            Self::write_record(record, field_style, true, line).unwrap();
+            Self::write_record(record, field_style, true, custom_values, line).unwrap();
         }
     }
 
@@ -223,6 +819,7 @@ impl NewEvent {
         record: &FieldValueOwned,
         field_style: Option<Style>,
         with_message: bool,
+        custom_values: &CustomValues,
         out: &mut W,
     ) -> std::fmt::Result
     where
@@ -230,19 +827,31 @@ impl NewEvent {
     {
         let name = &record.name;
 
-        if name == "message" && with_message {
-            if let ValueOwned::Debug(str) = &record.value {
-                return write!(out, "{}", str);
-            }
+        if name == "message"
+            && with_message
+            && let ValueOwned::Debug(str) = &record.value
+        {
+            return write!(out, "{}", str);
+        }
+
+        if name.starts_with(ANNOTATION_PREFIX)
+            && let ValueOwned::String(comment) = &record.value
+        {
+            let style = field_style.map(|_| Color::Cyan.bold());
+            return Self::with_style(style, out, |out| write!(out, "[{name}: {comment}]"));
         }
 
         Self::with_style(field_style, out, |out| write!(out, "{name}"))?;
 
         write!(out, "=")?;
-        Self::write_value(&record.value, out)
+        Self::write_value(&record.value, custom_values, out)
     }
 
-    fn write_value<W>(value: &ValueOwned, out: &mut W) -> std::fmt::Result
+    fn write_value<W>(
+        value: &ValueOwned,
+        custom_values: &CustomValues,
+        out: &mut W,
+    ) -> std::fmt::Result
     where
         W: Write,
     {
@@ -252,6 +861,8 @@ impl NewEvent {
             ValueOwned::Float(value) => write!(out, "{value}"),
             ValueOwned::Integer(value) => write!(out, "{value}"),
             ValueOwned::Unsigned(value) => write!(out, "{value}"),
+            ValueOwned::I128(value) => write!(out, "{value}"),
+            ValueOwned::U128(value) => write!(out, "{value}"),
             ValueOwned::Bool(value) => write!(out, "{value}"),
             ValueOwned::ByteArray(items) => {
                 for &char in items.iter() {
@@ -259,6 +870,41 @@ impl NewEvent {
                 }
                 Ok(())
             }
+            ValueOwned::Custom { tag, data } => custom_values.render(*tag, data, out),
+            ValueOwned::Array(nested) => {
+                let Ok(values) = nested.values() else {
+                    return write!(out, "<malformed array>");
+                };
+                write!(out, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(out, ", ")?;
+                    }
+                    Self::write_value(value, custom_values, out)?;
+                }
+                write!(out, "]")
+            }
+            ValueOwned::Map(nested) => {
+                let Ok(entries) = nested.entries() else {
+                    return write!(out, "<malformed map>");
+                };
+                write!(out, "{{")?;
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(out, ", ")?;
+                    }
+                    write!(out, "{key}: ")?;
+                    Self::write_value(value, custom_values, out)?;
+                }
+                write!(out, "}}")
+            }
+            ValueOwned::Error { message, sources } => {
+                write!(out, "{message}")?;
+                for source in sources.strings() {
+                    write!(out, ": caused by: {source}")?;
+                }
+                Ok(())
+            }
         }
     }
 
@@ -280,8 +926,16 @@ impl NewEvent {
 }
 
 #[cfg(test)]
-pub mod tests {
+mod tests {
     use super::*;
+    use crate::{
+        custom_value::CustomValue,
+        tape::{FieldValue, NestedOwned, Value},
+    };
+    use std::{
+        net::Ipv4Addr,
+        sync::{Arc, Mutex},
+    };
 
     #[test]
     fn print_debug() {
@@ -290,6 +944,8 @@ pub mod tests {
             span: None,
             target: "target".to_string(),
             priority: Level::INFO,
+            location: None,
+            thread: None,
             records: vec![
                 FieldValueOwned {
                     name: "dbg".to_string(),
@@ -308,6 +964,270 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn nested_value_print() {
+        let event = NewEvent {
+            time: Default::default(),
+            span: None,
+            target: "target".to_string(),
+            priority: Level::INFO,
+            location: None,
+            thread: None,
+            records: vec![
+                FieldValueOwned {
+                    name: "map".to_string(),
+                    value: ValueOwned::Map(NestedOwned::map([
+                        (
+                            "ids",
+                            Value::Array(NestedOwned::array([1u64.into(), 2u64.into()]).as_ref()),
+                        ),
+                        ("name", Value::String("thing")),
+                    ])),
+                },
+                FieldValueOwned {
+                    name: "single".to_string(),
+                    value: ValueOwned::Array(NestedOwned::array([Value::Bool(true)])),
+                },
+            ],
+        };
+
+        assert_eq!(
+            event.to_line(false, &[]),
+            r#"1970-01-01T00:00:00Z  INFO target: map={ids: [1, 2], name: "thing"} single=[true]"#
+        );
+    }
+
+    #[test]
+    fn wide_integer_print() {
+        let event = NewEvent {
+            time: Default::default(),
+            span: None,
+            target: "target".to_string(),
+            priority: Level::INFO,
+            location: None,
+            thread: None,
+            records: vec![
+                FieldValueOwned {
+                    name: "wide".to_string(),
+                    value: ValueOwned::U128(u128::MAX),
+                },
+                FieldValueOwned {
+                    name: "negative".to_string(),
+                    value: ValueOwned::I128(i128::MIN),
+                },
+            ],
+        };
+
+        assert_eq!(
+            event.to_line(false, &[]),
+            concat!(
+                "1970-01-01T00:00:00Z  INFO target: wide=340282366920938463463374607431768211455",
+                " negative=-170141183460469231731687303715884105728",
+            )
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn event_to_json() {
+        let event = NewEvent {
+            time: Default::default(),
+            span: NonZeroU64::new(1),
+            target: "target".to_string(),
+            priority: Level::WARN,
+            location: None,
+            thread: None,
+            records: [
+                ("message", ValueOwned::Debug("hello".to_string())),
+                ("float", ValueOwned::Float(0.5)),
+                ("signed", ValueOwned::Integer(-1)),
+                ("bytes", ValueOwned::ByteArray(vec![0, 1, 2])),
+                (
+                    "custom",
+                    ValueOwned::Custom {
+                        tag: 3,
+                        data: vec![255],
+                    },
+                ),
+                (
+                    "nested",
+                    ValueOwned::Array(NestedOwned::array([
+                        Value::Bool(true),
+                        Value::Map(NestedOwned::map([("a", Value::Integer(1))]).as_ref()),
+                    ])),
+                ),
+            ]
+            .into_iter()
+            .map(|(name, value)| FieldValueOwned {
+                name: name.to_string(),
+                value,
+            })
+            .collect(),
+        };
+        let span = SpanRecords {
+            parent: None,
+            name: "request".to_string(),
+            records: vec![FieldValueOwned {
+                name: "id".to_string(),
+                value: ValueOwned::Unsigned(7),
+            }],
+        };
+
+        assert_eq!(
+            event.to_json(&[Cow::Owned(span)]),
+            serde_json::json!({
+                "timestamp": "1970-01-01T00:00:00Z",
+                "level": "WARN",
+                "target": "target",
+                "spans": [{ "name": "request", "fields": { "id": 7 } }],
+                "fields": {
+                    "message": "hello",
+                    "float": 0.5,
+                    "signed": -1,
+                    "bytes": "AAEC",
+                    "custom": { "tag": 3, "data": "/w==" },
+                    "nested": [true, { "a": 1 }],
+                },
+            })
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_lines() {
+        let mut json = JsonLines::new(Vec::new());
+        json.handle(Instruction::Restart);
+        json.handle(Instruction::NewSpan {
+            parent: None,
+            span: NonZeroU64::new(1).unwrap(),
+            name: "request",
+        });
+        json.handle(Instruction::FinishedSpan);
+        for message in ["first", "second"] {
+            json.handle(Instruction::StartEvent {
+                time: Default::default(),
+                span: NonZeroU64::new(1),
+                target: "target",
+                priority: Level::INFO,
+                location: None,
+                thread: None,
+            });
+            json.handle(Instruction::AddValue(FieldValue {
+                name: "message",
+                value: Value::String(message),
+            }));
+            json.handle(Instruction::FinishedEvent);
+        }
+
+        let out = String::from_utf8(json.into_inner()).unwrap();
+        let lines = out
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["fields"]["message"], "second");
+        assert_eq!(lines[1]["spans"][0]["name"], "request");
+    }
+
+    #[test]
+    fn field_order() {
+        let mut records = ["zone", "message", "id", "attempt"]
+            .into_iter()
+            .map(|name| FieldValueOwned {
+                name: name.to_string(),
+                value: ValueOwned::Unsigned(0),
+            })
+            .collect::<Vec<_>>();
+        let names = |records: &[FieldValueOwned]| {
+            records
+                .iter()
+                .map(|record| record.name.clone())
+                .collect::<Vec<_>>()
+        };
+
+        FieldOrder::Alphabetical.sort(&mut records);
+        assert_eq!(names(&records), ["message", "attempt", "id", "zone"]);
+
+        "zone,id".parse::<FieldOrder>().unwrap().sort(&mut records);
+        assert_eq!(names(&records), ["message", "zone", "id", "attempt"]);
+    }
+
+    #[test]
+    fn format_with_spans() {
+        let span = |id| NonZeroU64::new(id).unwrap();
+        let known = HashMap::from([
+            (
+                span(1),
+                SpanRecords {
+                    parent: None,
+                    name: "request".to_string(),
+                    records: vec![FieldValueOwned {
+                        name: "id".to_string(),
+                        value: ValueOwned::Unsigned(7),
+                    }],
+                },
+            ),
+            (
+                span(2),
+                SpanRecords {
+                    parent: Some(span(1)),
+                    name: "query".to_string(),
+                    records: Vec::new(),
+                },
+            ),
+        ]);
+        let event = NewEvent {
+            time: Default::default(),
+            span: Some(span(2)),
+            target: "db".to_string(),
+            priority: Level::INFO,
+            location: None,
+            thread: None,
+            records: vec![FieldValueOwned {
+                name: "message".to_string(),
+                value: ValueOwned::Debug("done".to_string()),
+            }],
+        };
+
+        let spans = resolve_spans(&known, event.span);
+        assert_eq!(
+            format_event(&event, &spans, &Default::default()),
+            "1970-01-01T00:00:00Z  INFO request{id=7}:query{}: db: done"
+        );
+    }
+
+    #[test]
+    fn field_filter() {
+        let span = SpanRecords {
+            parent: None,
+            name: "request".to_string(),
+            records: vec![FieldValueOwned {
+                name: "user_id".to_string(),
+                value: ValueOwned::Unsigned(42),
+            }],
+        };
+        let event = NewEvent {
+            time: Default::default(),
+            span: None,
+            target: "target".to_string(),
+            priority: Level::INFO,
+            location: None,
+            thread: None,
+            records: vec![FieldValueOwned {
+                name: "status".to_string(),
+                value: ValueOwned::String("ok".to_string()),
+            }],
+        };
+        let spans = [Cow::Borrowed(&span)];
+
+        let filter = |str: &str| str.parse::<FieldFilter>().unwrap();
+        assert!(filter("user_id=42").matches_event(&event, &spans));
+        assert!(filter("status=\"ok\"").matches_event(&event, &spans));
+        assert!(!filter("user_id=43").matches_event(&event, &spans));
+        assert!(!filter("user_id=42").matches_event(&event, &[]));
+        assert!("user_id".parse::<FieldFilter>().is_err());
+    }
+
     #[test]
     fn log_levels_ident() {
         for (priority, str) in [
@@ -322,6 +1242,8 @@ pub mod tests {
                 span: None,
                 target: "target".to_string(),
                 priority,
+                location: None,
+                thread: None,
                 records: Default::default(),
             };
 
@@ -339,6 +1261,8 @@ pub mod tests {
             span: None,
             target: "target".to_string(),
             priority: Level::INFO,
+            location: None,
+            thread: None,
             records: vec![FieldValueOwned {
                 name: "message".to_string(),
                 value: ValueOwned::Debug("a log".to_string()),
@@ -358,6 +1282,8 @@ pub mod tests {
             span: None,
             target: "target".to_string(),
             priority: Level::INFO,
+            location: None,
+            thread: None,
             records: Default::default(),
         };
 
@@ -389,4 +1315,276 @@ pub mod tests {
             r#"1970-01-01T00:00:00Z  INFO record{message="a log" a=b}:second{}: target:"#
         );
     }
+
+    struct Ipv4;
+    impl CustomValue for Ipv4 {
+        fn tag(&self) -> i8 {
+            4
+        }
+
+        fn encode(&self, _field: &str, debug: &str, out: &mut Vec<u8>) -> bool {
+            let Ok(addr) = debug.parse::<Ipv4Addr>() else {
+                return false;
+            };
+            out.extend(addr.octets());
+            true
+        }
+
+        fn render(&self, data: &[u8], out: &mut dyn Write) -> std::fmt::Result {
+            let octets: [u8; 4] = data.try_into().map_err(|_| std::fmt::Error)?;
+            write!(out, "{}", Ipv4Addr::from(octets))
+        }
+    }
+
+    #[test]
+    fn custom_value_print() {
+        let custom_values = CustomValues::new().with(Ipv4);
+        let mut data = Vec::new();
+        assert_eq!(custom_values.encode("addr", "10.0.0.1", &mut data), Some(4));
+
+        let event = NewEvent {
+            time: Default::default(),
+            span: None,
+            target: "target".to_string(),
+            priority: Level::INFO,
+            location: None,
+            thread: None,
+            records: vec![
+                FieldValueOwned {
+                    name: "addr".to_string(),
+                    value: ValueOwned::Custom { tag: 4, data },
+                },
+                FieldValueOwned {
+                    name: "unknown".to_string(),
+                    value: ValueOwned::Custom {
+                        tag: 5,
+                        data: vec![0xab],
+                    },
+                },
+            ],
+        };
+
+        let mut line = String::new();
+        event.write_line_with(false, &custom_values, &[], &mut line);
+        assert_eq!(
+            line,
+            "1970-01-01T00:00:00Z  INFO target: addr=10.0.0.1 unknown=#5:ab"
+        );
+    }
+
+    #[test]
+    fn span_record_replaces_value() {
+        let mut printer = Printer::new(Vec::new(), false);
+        let span = NonZeroU64::new(1).unwrap();
+
+        printer.handle(Instruction::NewSpan {
+            parent: None,
+            span,
+            name: "request",
+        });
+        printer.handle(Instruction::AddValue(crate::tape::FieldValue {
+            name: "status",
+            value: crate::tape::Value::String("pending"),
+        }));
+        printer.handle(Instruction::FinishedSpan);
+        for (name, status) in [("status", "done"), ("user", "root")] {
+            printer.handle(Instruction::NewRecord(span));
+            printer.handle(Instruction::AddValue(crate::tape::FieldValue {
+                name,
+                value: crate::tape::Value::String(status),
+            }));
+            printer.handle(Instruction::FinishedRecord);
+        }
+        printer.handle(Instruction::StartEvent {
+            time: Default::default(),
+            span: Some(span),
+            target: "target",
+            priority: Level::INFO,
+            location: None,
+            thread: None,
+        });
+        printer.handle(Instruction::FinishedEvent);
+
+        assert_eq!(
+            String::from_utf8(printer.out).unwrap(),
+            "1970-01-01T00:00:00Z  INFO request{status=\"done\" user=\"root\"}: target:\n"
+        );
+    }
+
+    #[test]
+    fn thread_and_location() {
+        for (threads, expected) in [
+            (
+                true,
+                "1970-01-01T00:00:00Z  INFO worker#2 target: src/lib.rs:7:\n",
+            ),
+            (false, "1970-01-01T00:00:00Z  INFO target: src/lib.rs:7:\n"),
+        ] {
+            let mut printer = Printer::new(Vec::new(), false).with_threads(threads);
+            printer.handle(Instruction::StartEvent {
+                time: Default::default(),
+                span: None,
+                target: "target",
+                priority: Level::INFO,
+                location: Some(Location {
+                    file: "src/lib.rs",
+                    line: 7,
+                    module_path: "app",
+                }),
+                thread: Some(ThreadInfo {
+                    id: 2,
+                    name: "worker",
+                }),
+            });
+            printer.handle(Instruction::FinishedEvent);
+
+            assert_eq!(String::from_utf8(printer.out).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn span_filter() {
+        let filter: SpanFilter = "request{id=7, user=\"root\"}".parse().unwrap();
+        assert_eq!(
+            filter,
+            SpanFilter::new("request")
+                .field("id", "7")
+                .field("user", "root")
+        );
+        assert!("request{id=7".parse::<SpanFilter>().is_err());
+
+        let mut printer = Printer::new(Vec::new(), false).with_span_filter(filter);
+        for (id, parent, name, id_field) in [
+            (1, 0, "request", 7),
+            (2, 1, "query", 7),
+            (3, 0, "request", 8),
+        ] {
+            printer.handle(Instruction::NewSpan {
+                parent: NonZeroU64::new(parent),
+                span: NonZeroU64::new(id).unwrap(),
+                name,
+            });
+            for (name, value) in [
+                ("id", Value::Unsigned(id_field)),
+                ("user", Value::String("root")),
+            ] {
+                printer.handle(Instruction::AddValue(FieldValue { name, value }));
+            }
+            printer.handle(Instruction::FinishedSpan);
+        }
+        for (span, message) in [(Some(2), "inner"), (Some(3), "other"), (None, "root")] {
+            printer.handle(Instruction::StartEvent {
+                time: Default::default(),
+                span: span.and_then(NonZeroU64::new),
+                target: "target",
+                priority: Level::INFO,
+                location: None,
+                thread: None,
+            });
+            printer.handle(Instruction::AddValue(FieldValue {
+                name: "message",
+                value: Value::Debug(message),
+            }));
+            printer.handle(Instruction::FinishedEvent);
+        }
+
+        let out = String::from_utf8(printer.out).unwrap();
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.ends_with("target: inner\n"));
+    }
+
+    #[test]
+    fn day_separator() {
+        let mut printer = Printer::new(Vec::new(), false).with_separator(Separator::Day);
+        for hours in [0, 1, 25] {
+            printer.handle(Instruction::StartEvent {
+                time: DateTime::UNIX_EPOCH + chrono::TimeDelta::hours(hours),
+                span: None,
+                target: "target",
+                priority: Level::INFO,
+                location: None,
+                thread: None,
+            });
+            printer.handle(Instruction::FinishedEvent);
+        }
+
+        assert_eq!(
+            String::from_utf8(printer.out).unwrap(),
+            "--- 1970-01-01 (1970-W01, Thursday) ---\n\
+             1970-01-01T00:00:00Z  INFO target:\n\
+             1970-01-01T01:00:00Z  INFO target:\n\
+             --- 1970-01-02 (1970-W01, Friday) ---\n\
+             1970-01-02T01:00:00Z  INFO target:\n"
+        );
+    }
+
+    #[test]
+    fn line_sink() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let mut printer = Printer::new(io::sink(), false)
+            .with_separator(Separator::Day)
+            .with_sink({
+                let lines = lines.clone();
+                move |line| lines.lock().unwrap().push(line.to_string())
+            });
+        printer.handle(Instruction::StartEvent {
+            time: DateTime::UNIX_EPOCH,
+            span: None,
+            target: "target",
+            priority: Level::INFO,
+            location: None,
+            thread: None,
+        });
+        printer.handle(Instruction::AddValue(FieldValue {
+            name: "message",
+            value: Value::Debug("first\nsecond"),
+        }));
+        printer.handle(Instruction::FinishedEvent);
+
+        assert_eq!(
+            *lines.lock().unwrap(),
+            [
+                "--- 1970-01-01 (1970-W01, Thursday) ---",
+                "1970-01-01T00:00:00Z  INFO target: first\nsecond",
+            ]
+        );
+    }
+
+    #[test]
+    fn event_filter() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let mut printer = Printer::new(io::sink(), false)
+            .with_event_filter(Box::new(|event, _| {
+                event.records.iter().any(|record| {
+                    FieldFilter::value_text(&record.value).is_some_and(|text| text.contains("ok"))
+                })
+            }))
+            .with_sink({
+                let lines = lines.clone();
+                move |line| lines.lock().unwrap().push(line.to_string())
+            });
+        for message in ["not found", "ok", "broken"] {
+            printer.handle(Instruction::StartEvent {
+                time: DateTime::UNIX_EPOCH,
+                span: None,
+                target: "target",
+                priority: Level::INFO,
+                location: None,
+                thread: None,
+            });
+            printer.handle(Instruction::AddValue(FieldValue {
+                name: "message",
+                value: Value::Debug(message),
+            }));
+            printer.handle(Instruction::FinishedEvent);
+        }
+
+        assert_eq!(
+            *lines.lock().unwrap(),
+            [
+                "1970-01-01T00:00:00Z  INFO target: ok",
+                "1970-01-01T00:00:00Z  INFO target: broken",
+            ]
+        );
+    }
 }
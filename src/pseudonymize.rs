@@ -0,0 +1,144 @@
+use crate::tape::{FieldValue, Instruction, InstructionSet, TapeMachine, Value};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{collections::HashMap, fmt::Write};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Replaces the values of configured fields with a keyed hash (HMAC-SHA256) of the value.
+///
+/// The same value always produces the same pseudonym for the same key, so events can still be
+/// correlated without storing raw identifiers. Fields may use their own keys, so that pseudonyms
+/// of different tenants can't be correlated with each other.
+pub struct Pseudonymize<T> {
+    forward: T,
+    key: HmacSha256,
+    fields: HashMap<String, HmacSha256>,
+    buf: String,
+}
+impl<T> Pseudonymize<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    /// Amount of bytes of the HMAC kept in the pseudonym.
+    const LEN: usize = 16;
+
+    pub fn new(forward: T, key: &[u8]) -> Self {
+        Self {
+            forward,
+            key: HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size"),
+            fields: Default::default(),
+            buf: Default::default(),
+        }
+    }
+
+    /// Pseudonymizes `field` with the default key.
+    pub fn field(mut self, field: &str) -> Self {
+        self.fields.insert(field.to_owned(), self.key.clone());
+        self
+    }
+
+    /// Pseudonymizes `field` with its own key.
+    pub fn field_with_key(mut self, field: &str, key: &[u8]) -> Self {
+        let key = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+        self.fields.insert(field.to_owned(), key);
+        self
+    }
+
+    fn pseudonym(mac: &HmacSha256, value: Value<&str>, buf: &mut String) {
+        let mut mac = mac.clone();
+        buf.clear();
+        match value {
+            Value::Debug(str) | Value::String(str) => mac.update(str.as_bytes()),
+            Value::ByteArray(data) | Value::Custom { data, .. } => mac.update(data),
+            Value::Array(nested) | Value::Map(nested) => mac.update(nested.data()),
+            Value::Error { message, sources } => {
+                mac.update(message.as_bytes());
+                mac.update(sources.data());
+            }
+            Value::Float(value) => mac.update(value.to_string().as_bytes()),
+            Value::Integer(value) => mac.update(value.to_string().as_bytes()),
+            Value::Unsigned(value) => mac.update(value.to_string().as_bytes()),
+            Value::I128(value) => mac.update(value.to_string().as_bytes()),
+            Value::U128(value) => mac.update(value.to_string().as_bytes()),
+            Value::Bool(value) => mac.update(value.to_string().as_bytes()),
+        }
+
+        for byte in mac.finalize().into_bytes().iter().take(Self::LEN) {
+            write!(buf, "{byte:02x}").unwrap();
+        }
+    }
+}
+impl<T> TapeMachine<InstructionSet> for Pseudonymize<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    fn needs_restart(&mut self) -> bool {
+        self.forward.needs_restart()
+    }
+
+    fn flush(&mut self) {
+        self.forward.flush();
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        let Instruction::AddValue(FieldValue { name, value }) = instruction else {
+            self.forward.handle(instruction);
+            return;
+        };
+        let Some(mac) = self.fields.get(name) else {
+            self.forward.handle(instruction);
+            return;
+        };
+
+        Self::pseudonym(mac, value, &mut self.buf);
+        self.forward.handle(Instruction::AddValue(FieldValue {
+            name,
+            value: Value::String(&self.buf),
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Values(Vec<String>);
+    impl TapeMachine<InstructionSet> for Values {
+        fn needs_restart(&mut self) -> bool {
+            false
+        }
+
+        fn handle(&mut self, instruction: Instruction) {
+            if let Instruction::AddValue(FieldValue {
+                value: Value::String(value),
+                ..
+            }) = instruction
+            {
+                self.0.push(value.to_owned());
+            }
+        }
+    }
+
+    #[test]
+    fn stable_pseudonyms() {
+        let mut machine = Pseudonymize::new(Values(Vec::new()), b"key")
+            .field("user")
+            .field_with_key("tenant", b"other");
+
+        for (name, value) in [
+            ("user", Value::Integer(42)),
+            ("user", Value::Integer(42)),
+            ("tenant", Value::Integer(42)),
+            ("plain", Value::String("42")),
+        ] {
+            machine.handle(Instruction::AddValue(FieldValue { name, value }));
+        }
+
+        let values = machine.forward.0;
+        assert_eq!(values[0].len(), 32);
+        assert_eq!(values[0], values[1]);
+        assert_ne!(values[0], values[2]);
+        assert_eq!(values[3], "42");
+    }
+}
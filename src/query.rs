@@ -0,0 +1,427 @@
+use crate::{
+    printer::{FieldFilter, NewEvent, resolve_spans},
+    tape::{Instruction, InstructionSet, SpanRecords, TapeMachine},
+};
+use std::{
+    borrow::Cow,
+    cmp::Ordering,
+    collections::HashMap,
+    iter::Peekable,
+    num::NonZeroU64,
+    str::{Chars, FromStr},
+};
+use tracing::Level;
+
+/// Filter expression over events, e.g. `level>=warn && target~"db" && fields.user_id==42`.
+///
+/// Comparisons are `key op value`, combined with `&&`, `||`, `!` and parentheses. Keys are:
+/// - `level`, compared by severity: `level>=warn` keeps warnings and errors.
+/// - `target`.
+/// - `span`, the name of the span of the event or of any of its ancestors.
+/// - `message`, short for `fields.message`.
+/// - `fields.NAME`, a field of the event or of any of its spans, compared by its text like
+///   [`FieldFilter`]. Numbers are compared as numbers.
+///
+/// Operators are `==`, `!=`, `~` (contains), `<`, `<=`, `>` and `>=`. `a != b` is `!(a == b)`,
+/// so it also keeps events without the field. Values are quoted strings or bare words.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query(Expr);
+impl Query {
+    pub fn matches(&self, event: &NewEvent, spans: &[Cow<SpanRecords>]) -> bool {
+        self.0.matches(event, spans)
+    }
+}
+impl FromStr for Query {
+    type Err = String;
+
+    fn from_str(str: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            chars: str.chars().peekable(),
+        };
+        let expr = parser.or()?;
+        match parser.peek() {
+            None => Ok(Self(expr)),
+            Some(char) => Err(format!("Unexpected {char:?} in {str:?}")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Level(Op, Level),
+    Compare(Key, Op, String),
+}
+impl Expr {
+    fn matches(&self, event: &NewEvent, spans: &[Cow<SpanRecords>]) -> bool {
+        match self {
+            Expr::And(a, b) => a.matches(event, spans) && b.matches(event, spans),
+            Expr::Or(a, b) => a.matches(event, spans) || b.matches(event, spans),
+            Expr::Not(a) => !a.matches(event, spans),
+            // More severe levels are smaller
+            Expr::Level(op, level) => op.holds(level.cmp(&event.priority)),
+            Expr::Compare(key, Op::Ne, value) => {
+                !Expr::Compare(key.clone(), Op::Eq, value.clone()).matches(event, spans)
+            }
+            Expr::Compare(Key::Target, op, value) => op.compare(&event.target, value),
+            Expr::Compare(Key::Span, op, value) => {
+                spans.iter().any(|span| op.compare(&span.name, value))
+            }
+            Expr::Compare(Key::Field(name), op, value) => event
+                .records
+                .iter()
+                .chain(spans.iter().flat_map(|span| span.records.iter()))
+                .filter(|record| record.name == *name)
+                .filter_map(|record| FieldFilter::value_text(&record.value))
+                .any(|text| op.compare(&text, value)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Key {
+    Target,
+    Span,
+    Field(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Eq,
+    Ne,
+    Contains,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+impl Op {
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Op::Eq => ordering.is_eq(),
+            Op::Ne => ordering.is_ne(),
+            Op::Lt => ordering.is_lt(),
+            Op::Le => ordering.is_le(),
+            Op::Gt => ordering.is_gt(),
+            Op::Ge => ordering.is_ge(),
+            Op::Contains => false,
+        }
+    }
+
+    /// Compares as numbers when both sides are numbers.
+    fn compare(self, text: &str, value: &str) -> bool {
+        if self == Op::Contains {
+            return text.contains(value);
+        }
+        let ordering = match (text.parse::<f64>(), value.parse::<f64>()) {
+            (Ok(a), Ok(b)) => a.partial_cmp(&b),
+            _ => Some(text.cmp(value)),
+        };
+        ordering.is_some_and(|ordering| self.holds(ordering))
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+impl Parser<'_> {
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.next_if(|char| char.is_whitespace()).is_some() {}
+        self.chars.peek().copied()
+    }
+
+    /// Consumes `token` if it is next.
+    fn eat(&mut self, token: &str) -> bool {
+        self.peek();
+        let mut ahead = self.chars.clone();
+        if token.chars().all(|char| ahead.next() == Some(char)) {
+            self.chars = ahead;
+            return true;
+        }
+        false
+    }
+
+    fn or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.and()?;
+        while self.eat("||") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.unary()?;
+        while self.eat("&&") {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let expr = self.or()?;
+            if !self.eat(")") {
+                return Err("Missing closing parenthesis".to_string());
+            }
+            return Ok(expr);
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Expr, String> {
+        let key = self.word()?;
+        let op = [
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+            ("~", Op::Contains),
+            ("<=", Op::Le),
+            ("<", Op::Lt),
+            (">=", Op::Ge),
+            (">", Op::Gt),
+        ]
+        .into_iter()
+        .find_map(|(token, op)| self.eat(token).then_some(op))
+        .ok_or_else(|| format!("Expected an operator after {key:?}"))?;
+        let value = match self.eat("\"") {
+            true => self.quoted()?,
+            false => self.word()?,
+        };
+
+        let key = match key.as_str() {
+            "level" => {
+                let level = value
+                    .parse()
+                    .map_err(|_| format!("Expected a level, got {value:?}"))?;
+                if op == Op::Contains {
+                    return Err("Levels can't be compared with ~".to_string());
+                }
+                return Ok(Expr::Level(op, level));
+            }
+            "target" => Key::Target,
+            "span" => Key::Span,
+            "message" => Key::Field("message".to_owned()),
+            key => match key.strip_prefix("fields.") {
+                Some(name) if !name.is_empty() => Key::Field(name.to_owned()),
+                _ => return Err(format!("Unknown key {key:?}")),
+            },
+        };
+        Ok(Expr::Compare(key, op, value))
+    }
+
+    fn word(&mut self) -> Result<String, String> {
+        self.peek();
+        let mut word = String::new();
+        while let Some(char) = self
+            .chars
+            .next_if(|char| char.is_alphanumeric() || "_.-:+".contains(*char))
+        {
+            word.push(char);
+        }
+        match word.is_empty() {
+            true => Err(match self.peek() {
+                Some(char) => format!("Expected a word, got {char:?}"),
+                None => "Unexpected end of query".to_string(),
+            }),
+            false => Ok(word),
+        }
+    }
+
+    /// Rest of a string after its opening quote.
+    fn quoted(&mut self) -> Result<String, String> {
+        let mut string = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => match self.chars.next() {
+                    Some(char) => string.push(char),
+                    None => break,
+                },
+                Some(char) => string.push(char),
+                None => break,
+            }
+        }
+        Err("Missing closing quote".to_string())
+    }
+}
+
+/// Forwards only the events matching a [`Query`], along with everything else.
+pub struct QueryFilterMachine<T> {
+    forward: T,
+    query: Query,
+    span: HashMap<NonZeroU64, SpanRecords>,
+    new_records: Option<(NonZeroU64, SpanRecords)>,
+    new_event: Option<NewEvent>,
+}
+impl<T> QueryFilterMachine<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    pub fn new(forward: T, query: Query) -> Self {
+        Self {
+            forward,
+            query,
+            span: Default::default(),
+            new_records: None,
+            new_event: None,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.forward
+    }
+}
+impl<T> TapeMachine<InstructionSet> for QueryFilterMachine<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    fn needs_restart(&mut self) -> bool {
+        self.forward.needs_restart()
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Restart => {
+                self.new_records = None;
+                self.new_event = None;
+            }
+            Instruction::NewSpan { parent, span, name } => {
+                self.new_records = Some((
+                    span,
+                    SpanRecords {
+                        parent,
+                        name: name.to_owned(),
+                        records: Default::default(),
+                    },
+                ));
+            }
+            Instruction::NewRecord(span) => {
+                let records = self
+                    .span
+                    .get(&span)
+                    .cloned()
+                    .unwrap_or_else(|| SpanRecords::lost(span));
+                self.new_records = Some((span, records));
+            }
+            Instruction::FinishedSpan | Instruction::FinishedRecord => {
+                if let Some((span, records)) = self.new_records.take() {
+                    self.span.insert(span, records);
+                }
+            }
+            Instruction::StartEvent {
+                time,
+                span,
+                target,
+                priority,
+                location,
+                thread,
+            } => {
+                self.new_event = Some(NewEvent {
+                    time,
+                    span,
+                    target: target.to_owned(),
+                    priority,
+                    location: location.map(|location| location.map(str::to_owned)),
+                    thread: thread.map(|thread| thread.map(str::to_owned)),
+                    records: Default::default(),
+                });
+                return;
+            }
+            Instruction::AddValue(value) => {
+                if let Some(event) = self.new_event.as_mut() {
+                    event.records.push(value.to_owned());
+                    return;
+                }
+                if let Some((_, records)) = self.new_records.as_mut() {
+                    records.record(value.to_owned());
+                }
+            }
+            Instruction::FinishedEvent => {
+                let Some(event) = self.new_event.take() else {
+                    return;
+                };
+                let spans = resolve_spans(&self.span, event.span);
+                if self.query.matches(&event, &spans) {
+                    event.forward(&mut self.forward);
+                }
+                return;
+            }
+            Instruction::DeleteSpan(span) => {
+                self.span.remove(&span);
+            }
+            _ => (),
+        }
+
+        self.forward.handle(instruction);
+    }
+
+    fn flush(&mut self) {
+        self.forward.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tape::{FieldValueOwned, ValueOwned};
+    use chrono::DateTime;
+
+    fn event(priority: Level, target: &str, fields: &[(&str, ValueOwned)]) -> NewEvent {
+        NewEvent {
+            time: DateTime::UNIX_EPOCH,
+            span: None,
+            target: target.to_owned(),
+            priority,
+            location: None,
+            thread: None,
+            records: fields
+                .iter()
+                .map(|(name, value)| FieldValueOwned {
+                    name: name.to_string(),
+                    value: value.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn query_matches() {
+        let query = |str: &str| str.parse::<Query>().unwrap();
+        let spans = [Cow::Owned(SpanRecords {
+            parent: None,
+            name: "request".to_owned(),
+            records: vec![FieldValueOwned {
+                name: "user_id".to_owned(),
+                value: ValueOwned::Unsigned(42),
+            }],
+        })];
+        let warn = event(
+            Level::WARN,
+            "app::db",
+            &[("message", ValueOwned::Debug("slow query".to_owned()))],
+        );
+        let info = event(Level::INFO, "app", &[("elapsed", ValueOwned::Float(2.5))]);
+
+        let filter = query(r#"level>=warn && target~"db" && fields.user_id==42"#);
+        assert!(filter.matches(&warn, &spans));
+        assert!(!filter.matches(&warn, &[]));
+        assert!(!filter.matches(&info, &spans));
+
+        assert!(query("level<warn").matches(&info, &[]));
+        assert!(query("fields.elapsed>2 || message~slow").matches(&info, &[]));
+        assert!(query("fields.elapsed>2 || message~slow").matches(&warn, &[]));
+        assert!(query("!(span==request) && fields.missing!=1").matches(&info, &[]));
+        assert!(query("message == \"slow query\"").matches(&warn, &[]));
+
+        assert!("level~warn".parse::<Query>().is_err());
+        assert!("level>=loud".parse::<Query>().is_err());
+        assert!("host==a".parse::<Query>().is_err());
+        assert!("(target==a".parse::<Query>().is_err());
+        assert!("target==a &&".parse::<Query>().is_err());
+        assert!("target==\"a".parse::<Query>().is_err());
+    }
+}
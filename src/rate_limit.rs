@@ -0,0 +1,217 @@
+use crate::tape::{FieldValue, Instruction, InstructionSet, TapeMachine, Value};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use tracing::Level;
+
+/// Forwards at most a number of events per second of each target, so that a log storm doesn't
+/// fill the rotation budget at once.
+///
+/// Once a second with dropped events is over, they are reported by a WARN event with target
+/// [`SUMMARY_TARGET`](Self::SUMMARY_TARGET), naming the `target` and the number `suppressed`.
+/// Seconds are measured with the time of the events.
+pub struct RateLimit<T> {
+    forward: T,
+    max_per_second: u32,
+    window: HashMap<String, Window>,
+    skipping: bool,
+}
+
+struct Window {
+    second: i64,
+    count: u32,
+    suppressed: u64,
+    last: DateTime<Utc>,
+}
+
+impl<T> RateLimit<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    pub fn new(forward: T, max_per_second: u32) -> Self {
+        Self {
+            forward,
+            max_per_second,
+            window: Default::default(),
+            skipping: false,
+        }
+    }
+
+    pub const SUMMARY_TARGET: &str = "msgpack_tracing::rate_limit";
+
+    pub fn into_inner(self) -> T {
+        self.forward
+    }
+
+    /// Whether an event of `target` at `time` is within the limit.
+    fn allowed(&mut self, time: DateTime<Utc>, target: &str) -> bool {
+        let second = time.timestamp();
+        let window = match self.window.get_mut(target) {
+            Some(window) => window,
+            None => self.window.entry(target.to_owned()).or_insert(Window {
+                second,
+                count: 0,
+                suppressed: 0,
+                last: time,
+            }),
+        };
+
+        if window.second != second {
+            let suppressed = std::mem::take(&mut window.suppressed);
+            window.second = second;
+            window.count = 0;
+            if suppressed > 0 {
+                self.summary(time, target, suppressed);
+            }
+        }
+
+        let window = self.window.get_mut(target).unwrap();
+        if window.count < self.max_per_second {
+            window.count += 1;
+            return true;
+        }
+
+        window.suppressed += 1;
+        window.last = time;
+        false
+    }
+
+    fn summary(&mut self, time: DateTime<Utc>, target: &str, suppressed: u64) {
+        self.forward.handle(Instruction::StartEvent {
+            time,
+            span: None,
+            target: Self::SUMMARY_TARGET,
+            priority: Level::WARN,
+            location: None,
+            thread: None,
+        });
+        self.forward.handle(Instruction::AddValue(FieldValue {
+            name: "message",
+            value: Value::Debug("Events suppressed by rate limit"),
+        }));
+        self.forward.handle(Instruction::AddValue(FieldValue {
+            name: "target",
+            value: Value::String(target),
+        }));
+        self.forward.handle(Instruction::AddValue(FieldValue {
+            name: "suppressed",
+            value: Value::Unsigned(suppressed),
+        }));
+        self.forward.handle(Instruction::FinishedEvent);
+    }
+}
+impl<T> TapeMachine<InstructionSet> for RateLimit<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    fn needs_restart(&mut self) -> bool {
+        self.forward.needs_restart()
+    }
+
+    /// Also reports the events suppressed so far.
+    fn flush(&mut self) {
+        let pending = self
+            .window
+            .iter_mut()
+            .filter(|(_, window)| window.suppressed > 0)
+            .map(|(target, window)| {
+                (
+                    window.last,
+                    target.clone(),
+                    std::mem::take(&mut window.suppressed),
+                )
+            })
+            .collect::<Vec<_>>();
+        for (time, target, suppressed) in pending {
+            self.summary(time, &target, suppressed);
+        }
+        self.forward.flush();
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::StartEvent { time, target, .. } if !self.allowed(time, target) => {
+                self.skipping = true;
+            }
+            Instruction::AddValue(..) if self.skipping => (),
+            Instruction::FinishedEvent if self.skipping => self.skipping = false,
+            instruction => {
+                if let Instruction::Restart = instruction {
+                    self.skipping = false;
+                }
+                self.forward.handle(instruction);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    #[derive(Default)]
+    struct Events(Vec<(String, Option<u64>)>);
+    impl TapeMachine<InstructionSet> for Events {
+        fn needs_restart(&mut self) -> bool {
+            false
+        }
+
+        fn handle(&mut self, instruction: Instruction) {
+            match instruction {
+                Instruction::StartEvent { target, .. } => self.0.push((target.to_owned(), None)),
+                Instruction::AddValue(FieldValue {
+                    name: "suppressed",
+                    value: Value::Unsigned(suppressed),
+                }) => self.0.last_mut().unwrap().1 = Some(suppressed),
+                _ => (),
+            }
+        }
+    }
+
+    fn event(limit: &mut RateLimit<Events>, time: DateTime<Utc>, target: &str) {
+        limit.handle(Instruction::StartEvent {
+            time,
+            span: None,
+            target,
+            priority: Level::INFO,
+            location: None,
+            thread: None,
+        });
+        limit.handle(Instruction::FinishedEvent);
+    }
+
+    #[test]
+    fn limits_storms() {
+        let start = DateTime::UNIX_EPOCH;
+        let mut limit = RateLimit::new(Events::default(), 2);
+        for _ in 0..5 {
+            event(&mut limit, start, "storm");
+        }
+        event(&mut limit, start, "quiet");
+        event(&mut limit, start + TimeDelta::seconds(1), "storm");
+        for _ in 0..3 {
+            event(&mut limit, start + TimeDelta::seconds(1), "storm");
+        }
+        limit.flush();
+
+        let summary = |suppressed| {
+            (
+                RateLimit::<Events>::SUMMARY_TARGET.to_owned(),
+                Some(suppressed),
+            )
+        };
+        let storm = ("storm".to_owned(), None);
+        assert_eq!(
+            limit.into_inner().0,
+            [
+                storm.clone(),
+                storm.clone(),
+                ("quiet".to_owned(), None),
+                summary(3),
+                storm.clone(),
+                storm,
+                summary(2),
+            ]
+        );
+    }
+}
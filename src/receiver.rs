@@ -0,0 +1,640 @@
+use crate::{
+    printer::NewEvent,
+    slice::Slice,
+    storage::Load,
+    string_cache::StringUncache,
+    tape::{
+        FieldValue, FieldValueOwned, Instruction, InstructionSet, SpanRecords, TapeMachine, Value,
+        ValueOwned,
+    },
+};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Read},
+    net::TcpListener,
+    num::NonZeroU64,
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// Field added to every received event, naming the connection it came from.
+pub const SOURCE_FIELD: &str = "source";
+
+/// Receives tapes streamed by [`SocketStore`](crate::socket::SocketStore) and forwards them,
+/// interleaved, into a single machine.
+///
+/// Every event is tagged with a [`SOURCE_FIELD`], and spans get new ids, so the sources don't
+/// collide. Events and spans are forwarded only once complete. When a source restarts or
+/// disconnects, its spans are deleted. If the machine asks for a restart, e.g. a rotating file,
+/// wrap it in a [`RestartableMachine`](crate::restart::RestartableMachine) to keep the spans of
+/// every source.
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+impl<T> Clone for Receiver<T> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+impl<T> Receiver<T>
+where
+    T: TapeMachine<InstructionSet> + Send + 'static,
+{
+    pub fn new(mut machine: T) -> Self {
+        machine.handle(Instruction::Restart);
+        Self {
+            shared: Arc::new(Shared {
+                machine: Mutex::new(machine),
+                next_span: AtomicU64::new(1),
+                next_source: AtomicU64::new(1),
+            }),
+        }
+    }
+
+    /// Accepts connections forever, receiving each one in its own thread, tagged by the peer
+    /// address.
+    pub fn serve_tcp(&self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, peer) = listener.accept()?;
+            self.spawn(peer.to_string(), stream);
+        }
+    }
+
+    /// Accepts connections forever, receiving each one in its own thread, tagged as `unix-N`.
+    #[cfg(unix)]
+    pub fn serve_unix(&self, listener: std::os::unix::net::UnixListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept()?;
+            let source = self.shared.next_source.fetch_add(1, Ordering::Relaxed);
+            self.spawn(format!("unix-{source}"), stream);
+        }
+    }
+
+    fn spawn<R>(&self, source: String, read: R)
+    where
+        R: Read + Send + 'static,
+    {
+        let receiver = self.clone();
+        std::thread::spawn(move || receiver.receive(&source, read));
+    }
+
+    /// Receives one tape until its end, tagging its events with `source`.
+    pub fn receive<R: Read>(&self, source: &str, read: R) -> io::Result<()> {
+        let mut tape = StringUncache::new(SourceTape::new(self.shared.clone(), source));
+        let result = Load::new(read).forward_cached(&mut tape);
+        tape.into_inner().delete_spans();
+        result
+    }
+
+    /// Tape of a single source, tagging its events with `source`, to be fed by the caller.
+    pub(crate) fn source(&self, source: &str) -> SourceTape<T> {
+        SourceTape::new(self.shared.clone(), source)
+    }
+
+    /// Locks the machine, e.g. to flush it.
+    pub fn machine(&self) -> MutexGuard<'_, T> {
+        self.shared.lock()
+    }
+}
+
+struct Shared<T> {
+    machine: Mutex<T>,
+    next_span: AtomicU64,
+    next_source: AtomicU64,
+}
+impl<T> Shared<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    fn lock(&self) -> MutexGuard<'_, T> {
+        let mut machine = self.machine.lock().unwrap();
+        if machine.needs_restart() {
+            machine.handle(Instruction::Restart);
+        }
+        machine
+    }
+}
+
+/// Tape of a single source, renaming its spans and tagging its events.
+pub(crate) struct SourceTape<T> {
+    shared: Arc<Shared<T>>,
+    source: String,
+    span: HashMap<NonZeroU64, NonZeroU64>,
+    new_span: Option<(NonZeroU64, SpanRecords)>,
+    new_record: Option<(NonZeroU64, Vec<FieldValueOwned>)>,
+    new_event: Option<NewEvent>,
+}
+impl<T> SourceTape<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    fn new(shared: Arc<Shared<T>>, source: &str) -> Self {
+        Self {
+            shared,
+            source: source.to_owned(),
+            span: Default::default(),
+            new_span: None,
+            new_record: None,
+            new_event: None,
+        }
+    }
+
+    /// Forwarded id of a span of the source. Spans created before the tape was received are
+    /// unknown.
+    fn rename(&self, span: NonZeroU64) -> Option<NonZeroU64> {
+        self.span.get(&span).copied()
+    }
+
+    pub(crate) fn delete_spans(&mut self) {
+        self.new_span = None;
+        self.new_record = None;
+        self.new_event = None;
+        if self.span.is_empty() {
+            return;
+        }
+
+        let mut machine = self.shared.lock();
+        for (_, span) in self.span.drain() {
+            machine.handle(Instruction::DeleteSpan(span));
+        }
+    }
+}
+impl<T> TapeMachine<InstructionSet> for SourceTape<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    fn needs_restart(&mut self) -> bool {
+        false
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Restart => self.delete_spans(),
+            Instruction::NewSpan { parent, span, name } => {
+                let parent = parent.and_then(|parent| self.rename(parent));
+                let id = self.shared.next_span.fetch_add(1, Ordering::Relaxed);
+                let id = NonZeroU64::new(id).unwrap();
+                self.span.insert(span, id);
+                self.new_span = Some((
+                    id,
+                    SpanRecords {
+                        parent,
+                        name: name.to_owned(),
+                        records: Default::default(),
+                    },
+                ));
+            }
+            Instruction::FinishedSpan => {
+                let Some((span, records)) = self.new_span.take() else {
+                    return;
+                };
+                let mut machine = self.shared.lock();
+                machine.handle(Instruction::NewSpan {
+                    parent: records.parent,
+                    span,
+                    name: &records.name,
+                });
+                for record in records.records.iter() {
+                    machine.handle(Instruction::AddValue(record.as_ref()));
+                }
+                machine.handle(Instruction::FinishedSpan);
+            }
+            Instruction::NewRecord(span) => {
+                self.new_record = self.rename(span).map(|span| (span, Vec::new()));
+            }
+            Instruction::FinishedRecord => {
+                let Some((span, values)) = self.new_record.take() else {
+                    return;
+                };
+                let mut machine = self.shared.lock();
+                machine.handle(Instruction::NewRecord(span));
+                for value in values.iter() {
+                    machine.handle(Instruction::AddValue(value.as_ref()));
+                }
+                machine.handle(Instruction::FinishedRecord);
+            }
+            Instruction::StartEvent {
+                time,
+                span,
+                target,
+                priority,
+                location,
+                thread,
+            } => {
+                self.new_event = Some(NewEvent {
+                    time,
+                    span: span.and_then(|span| self.rename(span)),
+                    target: target.to_owned(),
+                    priority,
+                    location: location.map(|location| location.map(str::to_owned)),
+                    thread: thread.map(|thread| thread.map(str::to_owned)),
+                    records: Default::default(),
+                });
+            }
+            Instruction::AddValue(value) => {
+                let value = value.to_owned();
+                match (
+                    &mut self.new_span,
+                    &mut self.new_record,
+                    &mut self.new_event,
+                ) {
+                    (Some((_, records)), _, _) => records.record(value),
+                    (_, Some((_, values)), _) => values.push(value),
+                    (_, _, Some(event)) => event.records.push(value),
+                    _ => (),
+                }
+            }
+            Instruction::FinishedEvent => {
+                let Some(mut event) = self.new_event.take() else {
+                    return;
+                };
+                event.records.push(
+                    FieldValue {
+                        name: SOURCE_FIELD,
+                        value: Value::String(self.source.as_str()),
+                    }
+                    .to_owned(),
+                );
+                event.forward(&mut *self.shared.lock());
+            }
+            Instruction::DeleteSpan(span) => {
+                if let Some(span) = self.span.remove(&span) {
+                    self.shared.lock().handle(Instruction::DeleteSpan(span));
+                }
+            }
+            // Filters are of each source, not of the receiving tape
+            Instruction::FilterChanged(..) => (),
+            Instruction::EnterSpan { time, span } => {
+                if let Some(span) = self.rename(span) {
+                    self.shared
+                        .lock()
+                        .handle(Instruction::EnterSpan { time, span });
+                }
+            }
+            Instruction::ExitSpan { time, span } => {
+                if let Some(span) = self.rename(span) {
+                    self.shared
+                        .lock()
+                        .handle(Instruction::ExitSpan { time, span });
+                }
+            }
+            Instruction::SpanTiming {
+                time,
+                span,
+                target,
+                priority,
+                busy,
+                idle,
+            } => {
+                if let Some(span) = self.rename(span) {
+                    self.shared.lock().handle(Instruction::SpanTiming {
+                        time,
+                        span,
+                        target,
+                        priority,
+                        busy,
+                        idle,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Splits a tape written by a [`Receiver`] back into one machine per source, opened by `open`
+/// on the first event of the source. Each event goes along with the spans it is in, like with a
+/// [`Slice`], so every machine receives a valid tape of its own.
+///
+/// Events without a [`SOURCE_FIELD`] are dropped. Records and deletions of spans go to the
+/// sources they were forwarded to.
+pub struct SplitSources<T, F> {
+    open: F,
+    sources: HashMap<String, SplitSource<T>>,
+    span: HashMap<NonZeroU64, SpanRecords>,
+    new_span: Option<(NonZeroU64, SpanRecords)>,
+    new_record: Option<(NonZeroU64, Vec<FieldValueOwned>)>,
+    new_event: Option<NewEvent>,
+}
+
+/// Machine of a source of [`SplitSources`], with the spans forwarded to it.
+struct SplitSource<T> {
+    machine: T,
+    forwarded: HashSet<NonZeroU64>,
+}
+
+impl<T, F> SplitSources<T, F>
+where
+    T: TapeMachine<InstructionSet>,
+    F: FnMut(&str) -> T,
+{
+    pub fn new(open: F) -> Self {
+        Self {
+            open,
+            sources: Default::default(),
+            span: Default::default(),
+            new_span: None,
+            new_record: None,
+            new_event: None,
+        }
+    }
+
+    /// Machines of every source found so far.
+    pub fn into_sources(self) -> HashMap<String, T> {
+        self.sources
+            .into_iter()
+            .map(|(source, split)| (source, split.machine))
+            .collect()
+    }
+
+    fn finish_event(&mut self, event: NewEvent) {
+        let Some(source) = event.records.iter().find_map(|record| match &record.value {
+            ValueOwned::String(source) if record.name == SOURCE_FIELD => Some(source),
+            _ => None,
+        }) else {
+            return;
+        };
+
+        let split = match self.sources.get_mut(source.as_str()) {
+            Some(split) => split,
+            None => {
+                let mut machine = (self.open)(source);
+                machine.handle(Instruction::Restart);
+                let split = SplitSource {
+                    machine,
+                    forwarded: Default::default(),
+                };
+                self.sources.entry(source.clone()).or_insert(split)
+            }
+        };
+        if split.machine.needs_restart() {
+            split.machine.handle(Instruction::Restart);
+            split.forwarded.clear();
+        }
+
+        for (span, records) in Slice::<T>::span_from_root(&self.span, event.span) {
+            if !split.forwarded.insert(span) {
+                continue;
+            }
+            split.machine.handle(Instruction::NewSpan {
+                parent: records.parent,
+                span,
+                name: &records.name,
+            });
+            for record in records.records.iter() {
+                split.machine.handle(Instruction::AddValue(record.as_ref()));
+            }
+            split.machine.handle(Instruction::FinishedSpan);
+        }
+        event.forward(&mut split.machine);
+    }
+
+    /// Sources that `span` was forwarded to.
+    fn forwarded(&mut self, span: NonZeroU64) -> impl Iterator<Item = &mut T> {
+        self.sources
+            .values_mut()
+            .filter(move |split| split.forwarded.contains(&span))
+            .map(|split| &mut split.machine)
+    }
+}
+impl<T, F> TapeMachine<InstructionSet> for SplitSources<T, F>
+where
+    T: TapeMachine<InstructionSet>,
+    F: FnMut(&str) -> T + Send + 'static,
+{
+    fn needs_restart(&mut self) -> bool {
+        false
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Restart => {
+                self.span.clear();
+                self.new_span = None;
+                self.new_record = None;
+                self.new_event = None;
+                for split in self.sources.values_mut() {
+                    split.forwarded.clear();
+                    split.machine.handle(Instruction::Restart);
+                }
+            }
+            Instruction::NewSpan { parent, span, name } => {
+                self.new_span = Some((
+                    span,
+                    SpanRecords {
+                        parent,
+                        name: name.to_owned(),
+                        records: Default::default(),
+                    },
+                ));
+            }
+            Instruction::FinishedSpan => {
+                if let Some((span, records)) = self.new_span.take() {
+                    self.span.insert(span, records);
+                }
+            }
+            Instruction::NewRecord(span) => {
+                self.new_record = Some((span, Default::default()));
+            }
+            Instruction::FinishedRecord => {
+                let Some((span, values)) = self.new_record.take() else {
+                    return;
+                };
+                for machine in self.forwarded(span) {
+                    machine.handle(Instruction::NewRecord(span));
+                    for value in values.iter() {
+                        machine.handle(Instruction::AddValue(value.as_ref()));
+                    }
+                    machine.handle(Instruction::FinishedRecord);
+                }
+                let records = self
+                    .span
+                    .entry(span)
+                    .or_insert_with(|| SpanRecords::lost(span));
+                for value in values {
+                    records.record(value);
+                }
+            }
+            Instruction::StartEvent {
+                time,
+                span,
+                target,
+                priority,
+                location,
+                thread,
+            } => {
+                self.new_event = Some(NewEvent {
+                    time,
+                    span,
+                    target: target.to_owned(),
+                    priority,
+                    location: location.map(|location| location.map(str::to_owned)),
+                    thread: thread.map(|thread| thread.map(str::to_owned)),
+                    records: Default::default(),
+                });
+            }
+            Instruction::FinishedEvent => {
+                if let Some(event) = self.new_event.take() {
+                    self.finish_event(event);
+                }
+            }
+            Instruction::AddValue(value) => {
+                let value = value.to_owned();
+                match (
+                    &mut self.new_span,
+                    &mut self.new_record,
+                    &mut self.new_event,
+                ) {
+                    (Some((_, records)), _, _) => records.record(value),
+                    (_, Some((_, values)), _) => values.push(value),
+                    (_, _, Some(event)) => event.records.push(value),
+                    _ => (),
+                }
+            }
+            Instruction::DeleteSpan(span) => {
+                self.span.remove(&span);
+                for split in self.sources.values_mut() {
+                    if split.forwarded.remove(&span) {
+                        split.machine.handle(Instruction::DeleteSpan(span));
+                    }
+                }
+            }
+            // Filters of the receiving tape, not of any source
+            Instruction::FilterChanged(..) => (),
+            Instruction::EnterSpan { span, .. }
+            | Instruction::ExitSpan { span, .. }
+            | Instruction::SpanTiming { span, .. } => {
+                for machine in self.forwarded(span) {
+                    machine.handle(instruction);
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        for split in self.sources.values_mut() {
+            split.machine.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::Store, string_cache::StringCache};
+    use chrono::DateTime;
+    use tracing::Level;
+
+    #[derive(Default)]
+    struct Events(Vec<(Option<NonZeroU64>, String)>);
+    impl TapeMachine<InstructionSet> for Arc<Mutex<Events>> {
+        fn needs_restart(&mut self) -> bool {
+            false
+        }
+
+        fn handle(&mut self, instruction: Instruction) {
+            let mut events = self.lock().unwrap();
+            match instruction {
+                Instruction::StartEvent { span, .. } => events.0.push((span, String::new())),
+                Instruction::AddValue(FieldValue {
+                    name: SOURCE_FIELD,
+                    value: Value::String(source),
+                }) => events.0.last_mut().unwrap().1 = source.to_owned(),
+                _ => (),
+            }
+        }
+    }
+
+    fn tape() -> Vec<u8> {
+        let mut tape = StringCache::new(Store::new(Vec::new()));
+        let span = NonZeroU64::new(1).unwrap();
+        tape.handle(Instruction::Restart);
+        tape.handle(Instruction::NewSpan {
+            parent: None,
+            span,
+            name: "request",
+        });
+        tape.handle(Instruction::FinishedSpan);
+        tape.handle(Instruction::StartEvent {
+            time: DateTime::UNIX_EPOCH,
+            span: Some(span),
+            target: "target",
+            priority: Level::INFO,
+            location: None,
+            thread: None,
+        });
+        tape.handle(Instruction::FinishedEvent);
+        tape.into_inner().into_inner()
+    }
+
+    #[test]
+    fn tags_sources() {
+        let events = Arc::new(Mutex::new(Events::default()));
+        let receiver = Receiver::new(events.clone());
+        receiver.receive("a", tape().as_slice()).unwrap();
+        receiver.receive("b", tape().as_slice()).unwrap();
+
+        let events = &events.lock().unwrap().0;
+        assert_eq!(events[0].1, "a");
+        assert_eq!(events[1].1, "b");
+        assert_ne!(events[0].0, events[1].0);
+    }
+
+    #[test]
+    fn splits_sources() {
+        fn open(_: &str) -> Store<Vec<u8>> {
+            Store::new(Vec::new())
+        }
+
+        let receiver = Receiver::new(SplitSources::new(open as fn(&str) -> _));
+        receiver.receive("a", tape().as_slice()).unwrap();
+        receiver.receive("b", tape().as_slice()).unwrap();
+
+        let split = std::mem::replace(&mut *receiver.machine(), SplitSources::new(open));
+        let mut sources = split.into_sources();
+        assert_eq!(sources.len(), 2);
+        let mut loaded = |source: &str| {
+            let out = sources.remove(source).unwrap().into_inner();
+            let mut load = Load::new(out.as_slice());
+            let mut loaded = Vec::new();
+            while let Some(instruction) = load.fetch_one().unwrap() {
+                loaded.push(match instruction {
+                    Instruction::Restart => "restart".to_string(),
+                    Instruction::NewSpan { span, name, .. } => format!("span {span} {name}"),
+                    Instruction::AddValue(FieldValue {
+                        name: SOURCE_FIELD,
+                        value: Value::String(source),
+                    }) => format!("source {source}"),
+                    Instruction::StartEvent { span, .. } => format!("event {}", span.unwrap()),
+                    Instruction::DeleteSpan(span) => format!("delete {span}"),
+                    _ => continue,
+                });
+            }
+            loaded
+        };
+
+        assert_eq!(
+            loaded("a"),
+            [
+                "restart",
+                "span 1 request",
+                "event 1",
+                "source a",
+                "delete 1"
+            ]
+        );
+        assert_eq!(
+            loaded("b"),
+            [
+                "restart",
+                "span 2 request",
+                "event 2",
+                "source b",
+                "delete 2"
+            ]
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use crate::tape::{FieldValue, Instruction, InstructionSet, TapeMachine, Value};
+
+/// Value replacing redacted fields.
+pub const REDACTED: &str = "***";
+
+/// Replaces the string and debug values of fields matching a pattern with [`REDACTED`], before
+/// they are stored.
+///
+/// Patterns are field names where `*` matches any run of characters, e.g. `password` or
+/// `*_token`. Values of other types are kept.
+pub struct RedactMachine<T> {
+    forward: T,
+    patterns: Vec<String>,
+}
+impl<T> RedactMachine<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    pub fn new(forward: T) -> Self {
+        Self {
+            forward,
+            patterns: Vec::new(),
+        }
+    }
+
+    /// Redacts the fields matching `pattern`.
+    pub fn field(mut self, pattern: &str) -> Self {
+        self.patterns.push(pattern.to_owned());
+        self
+    }
+
+    pub fn into_inner(self) -> T {
+        self.forward
+    }
+
+    pub fn redacted(&self, name: &str) -> bool {
+        self.patterns.iter().any(|pattern| matches(pattern, name))
+    }
+}
+impl<T> TapeMachine<InstructionSet> for RedactMachine<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    fn needs_restart(&mut self) -> bool {
+        self.forward.needs_restart()
+    }
+
+    fn flush(&mut self) {
+        self.forward.flush();
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        let instruction = match instruction {
+            Instruction::AddValue(FieldValue {
+                name,
+                value: Value::String(_),
+            }) if self.redacted(name) => Instruction::AddValue(FieldValue {
+                name,
+                value: Value::String(REDACTED),
+            }),
+            Instruction::AddValue(FieldValue {
+                name,
+                value: Value::Debug(_),
+            }) if self.redacted(name) => Instruction::AddValue(FieldValue {
+                name,
+                value: Value::Debug(REDACTED),
+            }),
+            instruction => instruction,
+        };
+        self.forward.handle(instruction);
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any run of characters.
+fn matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+
+    rest.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{storage::Store, test_util::golden_tape};
+
+    struct Values(Vec<String>);
+    impl TapeMachine<InstructionSet> for Values {
+        fn needs_restart(&mut self) -> bool {
+            false
+        }
+
+        fn handle(&mut self, instruction: Instruction) {
+            if let Instruction::AddValue(FieldValue { name, value }) = instruction {
+                self.0.push(format!("{name}={value:?}"));
+            }
+        }
+    }
+
+    #[test]
+    fn redacts_matching_fields() {
+        let mut redact = RedactMachine::new(Values(Vec::new()))
+            .field("password")
+            .field("*_token");
+        for (name, value) in [
+            ("password", Value::String("hunter2")),
+            ("access_token", Value::Debug("abc")),
+            ("token_count", Value::String("3")),
+            ("refresh_token", Value::Unsigned(42)),
+        ] {
+            redact.handle(Instruction::AddValue(FieldValue { name, value }));
+        }
+
+        assert_eq!(
+            redact.into_inner().0,
+            [
+                "password=String(\"***\")",
+                "access_token=Debug(\"***\")",
+                "token_count=String(\"3\")",
+                "refresh_token=Unsigned(42)",
+            ]
+        );
+    }
+
+    #[test]
+    fn patterns() {
+        for (pattern, name, matched) in [
+            ("a*b*c", "aXbYc", true),
+            ("a*b*c", "aXcYb", false),
+            ("*", "", true),
+            ("*", "anything", true),
+            ("", "", true),
+            ("", "a", false),
+            ("a**", "a", true),
+            ("a*a", "a", false),
+            ("ab*ba", "aba", false),
+            ("*_token", "_token", true),
+        ] {
+            assert_eq!(matches(pattern, name), matched, "{pattern} {name}");
+        }
+    }
+
+    #[test]
+    fn golden_tape_values() {
+        let mut expected = Store::new(Vec::new());
+        golden_tape(&mut expected);
+        let mut unchanged = RedactMachine::new(Store::new(Vec::new()));
+        golden_tape(&mut unchanged);
+        assert_eq!(unchanged.into_inner().into_inner(), expected.into_inner());
+
+        // Span records are redacted too
+        let mut redact = RedactMachine::new(Values(Vec::new()))
+            .field("status")
+            .field("val*");
+        golden_tape(&mut redact);
+        let values = redact.into_inner().0;
+        assert_eq!(values.len(), 18);
+        assert_eq!(
+            values[..4],
+            [
+                "id=Unsigned(7)",
+                "status=String(\"***\")",
+                "value=Debug(\"***\")",
+                "value=String(\"***\")",
+            ]
+        );
+        assert_eq!(values[4], "value=Float(-0.5)");
+    }
+}
@@ -0,0 +1,175 @@
+use crate::{
+    storage::{FlushPolicy, Load, RESTART_HEADER, Store},
+    tape::{InstructionId, InstructionTrait},
+};
+use std::{
+    io::{self, Read},
+    ops::Range,
+};
+
+/// Decodes the tape in `input` and encodes it again into `output`, returning it.
+///
+/// Instructions are rewritten as they are, cached strings included, so a tape written by
+/// [`Store`] without checksums is rewritten byte for byte. Checksums are dropped, the rewritten
+/// tape has the same instructions. Tools rewriting tapes (compaction, redaction, recompression)
+/// change the instructions in between, see [`Load::forward_cached`].
+pub fn rewrite<R, W>(input: R, output: W) -> io::Result<W>
+where
+    R: Read,
+    W: io::Write + Send + 'static,
+{
+    let mut store = Store::with_flush_policy(output, FlushPolicy::Manual);
+    Load::new(input).forward_cached(&mut store)?;
+    store.flush()?;
+
+    Ok(store.into_inner())
+}
+
+/// Copies the tape in `input` into `output` like [`rewrite`], skipping the regions that can't be
+/// decoded, returning the output and the skipped regions as ranges of offsets.
+///
+/// Reading resumes at the `Restart` following each region. The span, record or event cut by a
+/// region is dropped, and a `Restart` takes the place of the region.
+pub fn repair<R, W>(input: R, mut output: W) -> io::Result<(W, Vec<Range<u64>>)>
+where
+    R: Read,
+    W: io::Write,
+{
+    let mut load = Load::new(input);
+    // Instructions since the last complete span, record or event
+    let mut pending = Vec::new();
+    let mut skipped: Vec<Range<u64>> = Vec::new();
+    let mut skipping = None;
+    // Only a `Restart` was read since the last region, which may be part of it
+    let mut resumed = false;
+    loop {
+        match load.fetch_one_cached() {
+            Ok(Some(instruction)) => {
+                resumed &= instruction.id() == InstructionId::Restart;
+                let complete = !matches!(
+                    instruction.id(),
+                    InstructionId::NewString
+                        | InstructionId::NewSpan
+                        | InstructionId::NewRecord
+                        | InstructionId::StartEvent
+                        | InstructionId::AddValue
+                );
+                Store::do_write_cached(&mut pending, instruction)?;
+                if complete {
+                    output.write_all(&pending)?;
+                    pending.clear();
+                }
+            }
+            Ok(None) => break,
+            Err(_) => {
+                pending.clear();
+                if skipping.is_none() && resumed {
+                    skipping = skipped.pop().map(|region| region.start);
+                }
+                if skipping.is_none() {
+                    skipping = Some(load.last_offset());
+                    output.write_all(&RESTART_HEADER)?;
+                }
+                load.restart();
+                continue;
+            }
+        }
+
+        if let Some(start) = skipping.take() {
+            skipped.push(start..load.last_offset());
+            resumed = true;
+        }
+    }
+    if let Some(start) = skipping {
+        skipped.push(start..load.offset());
+    }
+    output.write_all(&pending)?;
+    output.flush()?;
+
+    Ok((output, skipped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tape::Instruction, test_util::golden_tape};
+    use chrono::DateTime;
+    use std::num::NonZeroU64;
+    use tracing::Level;
+
+    #[test]
+    fn rewrite_is_lossless() {
+        use crate::string_cache::StringCache;
+
+        let mut tape = StringCache::new(Store::new(Vec::new()));
+        golden_tape(&mut tape);
+        let tape = tape.into_inner().into_inner();
+        assert_eq!(rewrite(tape.as_slice(), Vec::new()).unwrap(), tape);
+
+        let mut checksummed = StringCache::new(Store::new(Vec::new()).with_checksums(true));
+        golden_tape(&mut checksummed);
+        let checksummed = checksummed.into_inner().into_inner();
+        assert_ne!(checksummed, tape);
+        assert_eq!(rewrite(checksummed.as_slice(), Vec::new()).unwrap(), tape);
+
+        let mut uncached = Store::new(Vec::new());
+        golden_tape(&mut uncached);
+        let uncached = uncached.into_inner();
+        assert_eq!(rewrite(uncached.as_slice(), Vec::new()).unwrap(), uncached);
+    }
+
+    #[test]
+    fn repair_skips_corruption() {
+        use crate::verify::verify;
+
+        let span = NonZeroU64::new(1).unwrap();
+        let event = Instruction::StartEvent {
+            time: DateTime::UNIX_EPOCH,
+            span: Some(span),
+            target: "target",
+            priority: Level::INFO,
+            location: None,
+            thread: None,
+        };
+        let mut tape = Vec::new();
+        for instruction in [
+            Instruction::Restart,
+            Instruction::NewSpan {
+                parent: None,
+                span,
+                name: "request",
+            },
+            Instruction::FinishedSpan,
+            event,
+        ] {
+            Store::do_handle(&mut tape, instruction).unwrap();
+        }
+        let corrupted = tape.len();
+        Store::do_handle(&mut tape, Instruction::FinishedEvent).unwrap();
+        tape[corrupted] = 0x7f;
+        let restart = tape.len();
+        for instruction in [
+            Instruction::Restart,
+            Instruction::NewSpan {
+                parent: None,
+                span,
+                name: "request",
+            },
+            Instruction::FinishedSpan,
+            event,
+            Instruction::FinishedEvent,
+            Instruction::DeleteSpan(span),
+        ] {
+            Store::do_handle(&mut tape, instruction).unwrap();
+        }
+        tape.push(0x7f);
+        assert!(!verify(Load::new(tape.as_slice())).is_ok());
+
+        let (repaired, skipped) = repair(tape.as_slice(), Vec::new()).unwrap();
+        let end = tape.len() as u64;
+        assert_eq!(skipped, [corrupted as u64..restart as u64, end - 1..end]);
+        let verification = verify(Load::new(repaired.as_slice()));
+        assert_eq!(verification.problems(), []);
+        assert_eq!(verification.instructions(), 11);
+    }
+}
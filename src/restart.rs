@@ -1,10 +1,27 @@
-use crate::tape::{Instruction, InstructionSet, SpanRecords, TapeMachine};
-use std::{collections::HashMap, num::NonZeroU64};
+use crate::{
+    string_cache::CacheInstruction,
+    tape::{
+        FieldValue, Instruction, InstructionSet, InstructionSetTrait, SpanRecords, TapeMachine,
+        Value,
+    },
+};
+use chrono::Utc;
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    io,
+    num::NonZeroU64,
+};
+use tracing::Level;
 
 pub struct RestartableMachine<T> {
     forward: T,
     span: HashMap<NonZeroU64, SpanRecords>,
     current_span: Option<(NonZeroU64, SpanRecords)>,
+    filter: Option<String>,
+    max_replay: Option<usize>,
+    active: HashMap<NonZeroU64, u64>,
+    tick: u64,
 }
 impl<T> RestartableMachine<T>
 where
@@ -15,38 +32,67 @@ where
             forward,
             span: Default::default(),
             current_span: None,
+            filter: None,
+            max_replay: None,
+            active: Default::default(),
+            tick: 0,
         }
     }
-}
-impl<T> TapeMachine<InstructionSet> for RestartableMachine<T>
-where
-    T: TapeMachine<InstructionSet>,
-{
-    fn needs_restart(&mut self) -> bool {
-        self.forward.needs_restart()
+
+    /// Replays at most `max_replay` spans after a restart, preferring the most recently active
+    /// ones along with their ancestors.
+    ///
+    /// The number of spans left out is reported by a WARN event with target
+    /// [`SUMMARY_TARGET`](Self::SUMMARY_TARGET). They are still tracked, and may be replayed by
+    /// a later restart.
+    pub fn with_max_replay(mut self, max_replay: usize) -> Self {
+        self.max_replay = Some(max_replay);
+        self
     }
 
-    fn handle(&mut self, instruction: Instruction) {
-        match instruction {
-            Instruction::Restart => {
-                self.forward.handle(Instruction::Restart);
+    pub const SUMMARY_TARGET: &str = "msgpack_tracing::restart";
 
-                for (span, records) in self.span.iter() {
-                    self.forward.handle(Instruction::NewSpan {
-                        parent: records.parent,
-                        span: *span,
-                        name: records.name.as_ref(),
-                    });
+    fn touch(&mut self, span: NonZeroU64) {
+        self.tick += 1;
+        self.active.insert(span, self.tick);
+    }
 
-                    for record in records.records.iter() {
-                        self.forward.handle(Instruction::AddValue(record.as_ref()));
-                    }
+    fn replayed_spans(&self) -> Vec<NonZeroU64> {
+        let Some(max_replay) = self.max_replay else {
+            return self.span.keys().copied().collect();
+        };
 
-                    self.forward.handle(Instruction::FinishedSpan);
+        let mut recent = self.span.keys().copied().collect::<Vec<_>>();
+        recent.sort_by_key(|span| Reverse(self.active.get(span)));
+
+        let mut selected = Vec::new();
+        let mut seen = HashSet::new();
+        for span in recent {
+            let mut chain = Vec::new();
+            let mut next = Some(span);
+            while let Some(span) = next.filter(|span| self.span.contains_key(span)) {
+                if seen.contains(&span) {
+                    break;
                 }
+                chain.push(span);
+                next = self.span[&span].parent;
+            }
+
+            if selected.len() + chain.len() <= max_replay {
+                seen.extend(chain.iter().copied());
+                selected.extend(chain.into_iter().rev());
             }
+        }
+
+        selected
+    }
+
+    /// Keeps what a restart replays up to date with `instruction`.
+    fn track(&mut self, instruction: Instruction) {
+        match instruction {
             Instruction::NewSpan { parent, span, name } => {
                 assert!(self.current_span.is_none());
+                self.touch(span);
                 self.current_span = Some((
                     span,
                     SpanRecords {
@@ -55,49 +101,241 @@ where
                         records: Default::default(),
                     },
                 ));
-
-                self.forward
-                    .handle(Instruction::NewSpan { parent, span, name });
             }
-            Instruction::FinishedSpan => {
+            Instruction::FinishedSpan | Instruction::FinishedRecord => {
                 let (k, v) = self.current_span.take().unwrap();
                 self.span.insert(k, v);
-                self.forward.handle(Instruction::FinishedSpan)
             }
             Instruction::NewRecord(span) => {
                 assert!(self.current_span.is_none());
+                self.touch(span);
                 self.current_span = Some(self.span.remove_entry(&span).unwrap());
-                self.forward.handle(Instruction::NewRecord(span));
-            }
-            Instruction::FinishedRecord => {
-                let (k, v) = self.current_span.take().unwrap();
-                self.span.insert(k, v);
-                self.forward.handle(Instruction::FinishedRecord)
             }
             Instruction::StartEvent {
-                time,
-                span,
-                target,
-                priority,
-            } => {
-                self.forward.handle(Instruction::StartEvent {
-                    time,
-                    span,
-                    target,
-                    priority,
-                });
+                span: Some(span), ..
             }
-            Instruction::FinishedEvent => self.forward.handle(Instruction::FinishedEvent),
+            | Instruction::EnterSpan { span, .. }
+            | Instruction::ExitSpan { span, .. } => self.touch(span),
             Instruction::AddValue(field_value) => {
                 if let Some((_, current_span)) = self.current_span.as_mut() {
-                    current_span.records.push(field_value.to_owned());
+                    current_span.record(field_value.to_owned());
                 }
-                self.forward.handle(Instruction::AddValue(field_value));
             }
             Instruction::DeleteSpan(span) => {
                 self.span.remove(&span);
-                self.forward.handle(Instruction::DeleteSpan(span));
+                self.active.remove(&span);
             }
+            Instruction::FilterChanged(filter) => self.filter = Some(filter.to_owned()),
+            Instruction::Restart
+            | Instruction::StartEvent { span: None, .. }
+            | Instruction::FinishedEvent
+            | Instruction::SpanTiming { .. } => (),
         }
     }
+
+    fn replay_summary(&mut self, skipped: usize) {
+        self.forward.handle(Instruction::StartEvent {
+            time: Utc::now(),
+            span: None,
+            target: Self::SUMMARY_TARGET,
+            priority: Level::WARN,
+            location: None,
+            thread: None,
+        });
+        self.forward.handle(Instruction::AddValue(FieldValue {
+            name: "message",
+            value: Value::Debug("Spans not replayed after restart"),
+        }));
+        self.forward.handle(Instruction::AddValue(FieldValue {
+            name: "skipped",
+            value: Value::Unsigned(skipped as u64),
+        }));
+        self.forward.handle(Instruction::FinishedEvent);
+    }
+}
+impl<T> TapeMachine<InstructionSet> for RestartableMachine<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    fn needs_restart(&mut self) -> bool {
+        self.forward.needs_restart()
+    }
+
+    fn flush(&mut self) {
+        self.forward.flush();
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Restart => {
+                self.forward.handle(Instruction::Restart);
+
+                if let Some(filter) = self.filter.as_ref() {
+                    self.forward.handle(Instruction::FilterChanged(filter));
+                }
+
+                let replayed = self.replayed_spans();
+                for span in replayed.iter() {
+                    let records = &self.span[span];
+                    self.forward.handle(Instruction::NewSpan {
+                        parent: records.parent,
+                        span: *span,
+                        name: records.name.as_ref(),
+                    });
+
+                    for record in records.records.iter() {
+                        self.forward.handle(Instruction::AddValue(record.as_ref()));
+                    }
+
+                    self.forward.handle(Instruction::FinishedSpan);
+                }
+
+                let skipped = self.span.len() - replayed.len();
+                if skipped > 0 {
+                    self.replay_summary(skipped);
+                }
+            }
+            instruction => {
+                self.track(instruction);
+                self.forward.handle(instruction);
+            }
+        }
+    }
+
+    /// Decodes `chunk` only to track its spans, it is forwarded as it is.
+    fn handle_encoded(&mut self, chunk: &[u8]) -> io::Result<()> {
+        InstructionSet::decode(chunk, |instruction| self.track(instruction))?;
+        self.forward.handle_encoded(chunk)
+    }
+}
+
+/// Asks for a restart every `interval` bytes of a tape.
+///
+/// Strings right after a restart, e.g. snapshots of
+/// [`StringCache::with_snapshots`](crate::string_cache::StringCache::with_snapshots), are not
+/// counted, so that a large snapshot doesn't ask for another restart right away.
+pub(crate) struct RestartInterval {
+    interval: u64,
+    last_restart: u64,
+    snapshot: bool,
+}
+impl RestartInterval {
+    pub fn new(interval: u64) -> Self {
+        Self {
+            interval,
+            last_restart: 0,
+            snapshot: false,
+        }
+    }
+
+    /// Whether the tape, now at `offset`, went `interval` bytes without a restart.
+    pub fn needs_restart(&self, offset: u64) -> bool {
+        !self.snapshot && offset.saturating_sub(self.last_restart) > self.interval
+    }
+
+    /// Called before writing `instruction` at `offset` of the tape.
+    pub fn observe(&mut self, offset: u64, instruction: CacheInstruction) {
+        match instruction {
+            CacheInstruction::Restart => {
+                self.last_restart = offset;
+                self.snapshot = true;
+            }
+            CacheInstruction::NewString(..) => (),
+            _ if self.snapshot => {
+                self.last_restart = offset;
+                self.snapshot = false;
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Replayed {
+        spans: Vec<u64>,
+        skipped: Option<u64>,
+    }
+    impl TapeMachine<InstructionSet> for Replayed {
+        fn needs_restart(&mut self) -> bool {
+            false
+        }
+
+        fn handle(&mut self, instruction: Instruction) {
+            match instruction {
+                Instruction::Restart => *self = Default::default(),
+                Instruction::NewSpan { span, .. } => self.spans.push(span.get()),
+                Instruction::AddValue(FieldValue {
+                    name: "skipped",
+                    value: Value::Unsigned(skipped),
+                }) => self.skipped = Some(skipped),
+                _ => (),
+            }
+        }
+    }
+
+    #[test]
+    fn max_replay() {
+        let mut machine = RestartableMachine::new(Replayed::default()).with_max_replay(3);
+        let span = |id| NonZeroU64::new(id).unwrap();
+        for (id, parent) in [(1, None), (2, None), (3, Some(1)), (4, None), (5, None)] {
+            machine.handle(Instruction::NewSpan {
+                parent: parent.map(span),
+                span: span(id),
+                name: "span",
+            });
+            machine.handle(Instruction::FinishedSpan);
+        }
+        machine.handle(Instruction::StartEvent {
+            time: Utc::now(),
+            span: Some(span(3)),
+            target: "target",
+            priority: Level::INFO,
+            location: None,
+            thread: None,
+        });
+        machine.handle(Instruction::FinishedEvent);
+
+        machine.handle(Instruction::Restart);
+        assert_eq!(machine.forward.spans, [1, 3, 5]);
+        assert_eq!(machine.forward.skipped, Some(2));
+    }
+
+    #[test]
+    fn tracks_encoded_spans() {
+        let span = NonZeroU64::new(7).unwrap();
+        let mut chunk = Vec::new();
+        for instruction in [
+            Instruction::Restart,
+            Instruction::NewSpan {
+                parent: None,
+                span,
+                name: "span",
+            },
+            Instruction::FinishedSpan,
+        ] {
+            crate::storage::Store::do_handle(&mut chunk, instruction).unwrap();
+        }
+
+        let mut machine = RestartableMachine::new(Replayed::default());
+        machine.handle_encoded(&chunk).unwrap();
+        assert_eq!(machine.forward.spans, [7]);
+        machine.handle(Instruction::Restart);
+        assert_eq!(machine.forward.spans, [7]);
+    }
+
+    #[test]
+    fn interval_after_snapshot() {
+        let mut restarts = RestartInterval::new(50);
+        restarts.observe(0, CacheInstruction::Restart);
+        restarts.observe(20, CacheInstruction::NewString("cached"));
+        assert!(!restarts.needs_restart(100));
+
+        restarts.observe(100, CacheInstruction::FinishedEvent);
+        assert!(!restarts.needs_restart(150));
+        assert!(restarts.needs_restart(151));
+    }
 }
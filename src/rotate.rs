@@ -1,73 +1,338 @@
 use crate::{
-    storage::Store,
+    restart::RestartInterval,
+    storage::{DeltaTime, FlushPolicy, Flusher, RESTART_HEADER, Store},
     string_cache::{CacheInstruction, CacheInstructionSet},
-    tape::{Instruction, InstructionSet, TapeMachine},
+    tape::{
+        Instruction, InstructionId, InstructionSet, InstructionSetTrait, InstructionTrait,
+        TapeMachine,
+    },
+    time_index::{IndexWriter, index_path},
 };
+#[cfg(feature = "gzip")]
+use std::thread::JoinHandle;
 use std::{
+    ffi::OsStr,
     fs::File,
-    io::{self, Seek},
+    io::{self, BufWriter, Seek, Write},
     path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
     time::Duration,
 };
 
+/// Extension appended to rotated files compressed by [`Rotate::with_compression`].
+pub const GZIP_EXTENSION: &str = "gz";
+
+/// Extension of tapes written by a `CompressedStore` with LZ4.
+pub const LZ4_EXTENSION: &str = "lz4";
+
 pub struct Rotate {
-    file: Option<File>,
+    file: Option<BufWriter<File>>,
+    flush: Flusher,
     path: PathBuf,
-    path1: Option<PathBuf>,
-    max_len: u64,
+    keep: usize,
+    max_len: MaxLen,
+    index: Option<IndexWriter<File>>,
+    index_interval: u64,
+    restarts: Option<RestartInterval>,
+    delta: Option<DeltaTime>,
+    #[cfg(feature = "gzip")]
+    compress: bool,
+    #[cfg(feature = "gzip")]
+    compressing: Option<JoinHandle<io::Result<()>>>,
 }
 impl Rotate {
     pub fn new<P: AsRef<Path>>(path: P, max_len: u64) -> io::Result<Self> {
-        let file = File::options().append(true).create(true).open(&path)?;
-        let path1 = path
-            .as_ref()
-            .to_str()
-            .map(|str| PathBuf::from(format!("{str}.1")));
+        let mut file = File::options().append(true).create(true).open(&path)?;
+        // Appending only moves the position on the first write
+        file.seek(io::SeekFrom::End(0))?;
 
         Ok(Self {
-            file: Some(file),
+            file: Some(BufWriter::new(file)),
+            flush: FlushPolicy::default().into(),
             path: path.as_ref().to_owned(),
-            path1,
-            max_len,
+            keep: 1,
+            max_len: MaxLen(Arc::new(AtomicU64::new(max_len))),
+            index: None,
+            index_interval: 0,
+            restarts: None,
+            delta: None,
+            #[cfg(feature = "gzip")]
+            compress: false,
+            #[cfg(feature = "gzip")]
+            compressing: None,
         })
     }
 
+    /// Flushes the file according to `flush`. Defaults to [`FlushPolicy::EveryInstruction`].
+    pub fn with_flush_policy(mut self, flush: FlushPolicy) -> Self {
+        self.flush = flush.into();
+        self
+    }
+
+    /// Compresses rotated files with gzip in a background thread, as `file.1.gz`.
+    #[cfg(feature = "gzip")]
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Writes an [index](crate::time_index) next to every file, for
+    /// [`Load::seek_to_time`](crate::storage::Load::seek_to_time). Rotated indexes follow their
+    /// files.
+    ///
+    /// Also restarts every `interval` bytes, so that there are enough points to seek to.
+    pub fn with_index(mut self, interval: u64) -> io::Result<Self> {
+        self.index_interval = interval;
+        self.index = Some(self.open_index()?);
+        Ok(self)
+    }
+
+    /// Restarts every `interval` bytes, without an index. See
+    /// [`Store::with_restart_interval`].
+    pub fn with_restart_interval(mut self, interval: u64) -> Self {
+        self.restarts = Some(RestartInterval::new(interval));
+        self
+    }
+
+    /// Writes timestamps as deltas. See [`Store::with_delta_time`].
+    pub fn with_delta_time(mut self, delta: bool) -> Self {
+        self.delta = delta.then(Default::default);
+        self
+    }
+
+    fn open_index(&mut self) -> io::Result<IndexWriter<File>> {
+        let file = File::options()
+            .append(true)
+            .create(true)
+            .open(index_path(&self.path))?;
+        Ok(IndexWriter::new(file, self.index_interval))
+    }
+
+    /// Keeps the `keep` most recent rotated files, `file.1` being the newest. Defaults to 1.
+    pub fn with_retention(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// Handle changing the length at which files are rotated while the tape is being written.
+    pub fn max_len(&self) -> MaxLen {
+        self.max_len.clone()
+    }
+
+    /// Path of the `n`-th rotated file.
+    pub fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.as_os_str().to_owned();
+        path.push(format!(".{n}"));
+        PathBuf::from(path)
+    }
+
+    fn shift(&mut self) -> io::Result<()> {
+        #[cfg(feature = "gzip")]
+        self.wait_compression()?;
+
+        if self.keep == 0 {
+            if_exists(std::fs::remove_file(index_path(&self.path)))?;
+            return std::fs::remove_file(&self.path);
+        }
+
+        let index = |n| index_path(&self.rotated_path(n));
+        if_exists(std::fs::remove_file(index(self.keep)))?;
+        for n in (1..self.keep).rev() {
+            if_exists(std::fs::rename(index(n), index(n + 1)))?;
+        }
+        if_exists(std::fs::rename(index_path(&self.path), index(1)))?;
+
+        for extension in [None, Some(GZIP_EXTENSION)] {
+            let path = |n| {
+                let path = self.rotated_path(n);
+                match extension {
+                    Some(extension) => with_extension(path, extension),
+                    None => path,
+                }
+            };
+
+            if_exists(std::fs::remove_file(path(self.keep)))?;
+            for n in (1..self.keep).rev() {
+                if_exists(std::fs::rename(path(n), path(n + 1)))?;
+            }
+        }
+        std::fs::rename(&self.path, self.rotated_path(1))?;
+
+        #[cfg(feature = "gzip")]
+        if self.compress {
+            let path = self.rotated_path(1);
+            self.compressing = Some(std::thread::spawn(move || compress(&path)));
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the compression of the last rotated file.
+    #[cfg(feature = "gzip")]
+    fn wait_compression(&mut self) -> io::Result<()> {
+        match self.compressing.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(io::Error::other("compression thread panicked")),
+            None => Ok(()),
+        }
+    }
+
+    fn handle_cached(&mut self, instruction: CacheInstruction) -> io::Result<()> {
+        if self.index.is_some() || self.restarts.is_some() {
+            let position = self.position()?;
+            if let Some(index) = self.index.as_mut() {
+                index.observe(position, instruction)?;
+            }
+            if let Some(restarts) = self.restarts.as_mut() {
+                restarts.observe(position, instruction);
+            }
+        }
+
+        let file = self.file.as_mut().ok_or(io::ErrorKind::BrokenPipe)?;
+        Store::write_cached(file, instruction, self.delta.as_mut())?;
+        if self.flush.should_flush(instruction.id()) {
+            file.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Appends a chunk of [`TapeMachine::handle_encoded`] as it is, unless an option changes how
+    /// instructions are written, like [`Store`] does.
+    fn handle_encoded(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let plain = self.index.is_none() && self.restarts.is_none() && self.delta.is_none();
+        let Some(instructions) = chunk.strip_prefix(&RESTART_HEADER).filter(|_| plain) else {
+            return InstructionSet::decode(chunk, |instruction| {
+                let _ = self.handle_cached(Store::<File>::as_cached(instruction));
+            });
+        };
+
+        let Some(file) = self.file.as_mut() else {
+            return Ok(());
+        };
+        let _ = file.write_all(instructions);
+        if self.flush.should_flush(InstructionId::FinishedEvent) {
+            let _ = file.flush();
+        }
+
+        Ok(())
+    }
+
+    /// The current file, after writing out what is buffered.
     pub fn file_mut(&mut self) -> io::Result<&mut File> {
-        self.file
+        let file = self
+            .file
             .as_mut()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "file closed"))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "file closed"))?;
+        file.flush()?;
+        Ok(file.get_mut())
+    }
+
+    /// Length of the current file, including what is buffered.
+    fn position(&mut self) -> io::Result<u64> {
+        let file = self.file.as_mut().ok_or(io::ErrorKind::BrokenPipe)?;
+        let buffered = file.buffer().len() as u64;
+        Ok(file.get_mut().stream_position()? + buffered)
     }
 
     pub fn do_needs_restart(&mut self) -> io::Result<bool> {
-        let max_len = self.max_len;
-        let file = self.file_mut()?;
+        let max_len = self.max_len.get();
+        let position = self.position()?;
 
-        if file.stream_position()? <= max_len {
-            return Ok(false);
+        if position <= max_len {
+            return Ok(self
+                .index
+                .as_ref()
+                .is_some_and(|index| index.needs_restart(position))
+                || self
+                    .restarts
+                    .as_ref()
+                    .is_some_and(|restarts| restarts.needs_restart(position)));
         }
 
         std::thread::sleep(Duration::from_secs(1));
         self.file = None;
+        let index = self.index.take();
 
-        if let Some(path1) = self.path1.as_ref() {
-            std::fs::rename(&self.path, path1)?;
+        self.shift()?;
+        self.file = Some(BufWriter::new(File::create(&self.path)?));
+        if index.is_some() {
+            self.index = Some(self.open_index()?);
         }
-        self.file = Some(File::create(&self.path)?);
 
         Ok(true)
     }
 }
+fn with_extension(path: PathBuf, extension: &str) -> PathBuf {
+    let mut path = path.into_os_string();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+fn if_exists(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Whether `path` was compressed by [`Rotate::with_compression`] or a `CompressedStore`.
+pub fn is_compressed(path: &Path) -> bool {
+    let extension = path.extension();
+    extension == Some(OsStr::new(GZIP_EXTENSION)) || extension == Some(OsStr::new(LZ4_EXTENSION))
+}
+
+/// Path of the file `path` was rotated from, or `path` itself if it is not a rotated file, see
+/// [`Rotate::rotated_path`].
+pub fn rotated_from(path: &Path) -> PathBuf {
+    let name = match is_compressed(path) {
+        true => path.file_stem(),
+        false => path.file_name(),
+    };
+    let original = name
+        .and_then(OsStr::to_str)
+        .and_then(|name| name.rsplit_once('.'))
+        .filter(|(original, n)| !original.is_empty() && n.parse::<usize>().is_ok());
+    match original {
+        Some((original, _)) => path.with_file_name(original),
+        None => path.to_owned(),
+    }
+}
+
+#[cfg(feature = "gzip")]
+fn compress(path: &Path) -> io::Result<()> {
+    let mut input = File::open(path)?;
+    let output = File::create(with_extension(path.to_owned(), GZIP_EXTENSION))?;
+    let mut output = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+    io::copy(&mut input, &mut output)?;
+    output.finish()?.sync_all()?;
+
+    std::fs::remove_file(path)
+}
+
 impl TapeMachine<CacheInstructionSet> for Rotate {
     fn needs_restart(&mut self) -> bool {
         self.do_needs_restart().unwrap_or_default()
     }
 
     fn handle(&mut self, instruction: CacheInstruction) {
-        let Ok(file) = self.file_mut() else {
-            return;
-        };
+        let _ = self.handle_cached(instruction);
+    }
+
+    fn handle_encoded(&mut self, chunk: &[u8]) -> io::Result<()> {
+        Rotate::handle_encoded(self, chunk)
+    }
 
-        let _ = Store::do_handle_cached(file, instruction);
+    fn flush(&mut self) {
+        if let Ok(file) = self.file_mut() {
+            let _ = file.sync_data();
+        }
+        #[cfg(feature = "gzip")]
+        let _ = self.wait_compression();
     }
 }
 impl TapeMachine<InstructionSet> for Rotate {
@@ -76,10 +341,157 @@ impl TapeMachine<InstructionSet> for Rotate {
     }
 
     fn handle(&mut self, instruction: Instruction) {
-        let Ok(file) = self.file_mut() else {
-            return;
-        };
+        let _ = self.handle_cached(Store::<File>::as_cached(instruction));
+    }
 
-        let _ = Store::do_handle(file, instruction);
+    fn handle_encoded(&mut self, chunk: &[u8]) -> io::Result<()> {
+        Rotate::handle_encoded(self, chunk)
+    }
+
+    fn flush(&mut self) {
+        if let Ok(file) = self.file_mut() {
+            let _ = file.sync_data();
+        }
+        #[cfg(feature = "gzip")]
+        let _ = self.wait_compression();
+    }
+}
+
+/// Length at which a [`Rotate`] rotates its file, shared with the writing thread.
+#[derive(Clone, Debug)]
+pub struct MaxLen(Arc<AtomicU64>);
+impl MaxLen {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Applies from the next instruction on. A file already longer is rotated right away.
+    pub fn set(&self, max_len: u64) {
+        self.0.store(max_len, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retention() {
+        let dir = std::env::temp_dir().join(format!("rotate-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+
+        let mut rotate = Rotate::new(&path, 0).unwrap().with_retention(2);
+        rotate.file = None;
+        for content in ["first", "second", "third"] {
+            std::fs::write(&path, content).unwrap();
+            rotate.shift().unwrap();
+        }
+
+        assert!(!path.exists());
+        assert_eq!(
+            std::fs::read_to_string(rotate.rotated_path(1)).unwrap(),
+            "third"
+        );
+        assert_eq!(
+            std::fs::read_to_string(rotate.rotated_path(2)).unwrap(),
+            "second"
+        );
+        assert!(!rotate.rotated_path(3).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn original_path() {
+        for (path, original) in [
+            ("logs/app.log.1", "logs/app.log"),
+            ("logs/app.log.12.gz", "logs/app.log"),
+            ("logs/app.log", "logs/app.log"),
+            ("logs/app.log.gz", "logs/app.log.gz"),
+            ("logs/.1", "logs/.1"),
+        ] {
+            assert_eq!(rotated_from(Path::new(path)), Path::new(original), "{path}");
+        }
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn compression() {
+        use crate::storage::Load;
+
+        let dir = std::env::temp_dir().join(format!("rotate-gzip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+
+        let mut rotate = Rotate::new(&path, 0)
+            .unwrap()
+            .with_retention(2)
+            .with_compression(true);
+        rotate.file = None;
+        for filter in ["first", "second"] {
+            let mut out = Vec::new();
+            Store::do_handle(&mut out, Instruction::Restart).unwrap();
+            Store::do_handle(&mut out, Instruction::FilterChanged(filter)).unwrap();
+            std::fs::write(&path, out).unwrap();
+            rotate.shift().unwrap();
+        }
+        rotate.wait_compression().unwrap();
+
+        for (n, filter) in [(1, "second"), (2, "first")] {
+            assert!(!rotate.rotated_path(n).exists());
+            let mut load =
+                Load::open(with_extension(rotate.rotated_path(n), GZIP_EXTENSION)).unwrap();
+            assert!(matches!(
+                load.fetch_one().unwrap(),
+                Some(Instruction::Restart)
+            ));
+            assert!(matches!(
+                load.fetch_one().unwrap(),
+                Some(Instruction::FilterChanged(loaded)) if loaded == filter
+            ));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn seek_to_time() {
+        use crate::storage::Load;
+        use chrono::DateTime;
+        use tracing::Level;
+
+        let dir = std::env::temp_dir().join(format!("rotate-index-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+
+        let mut rotate = Rotate::new(&path, u64::MAX).unwrap().with_index(0).unwrap();
+        let rotate: &mut dyn TapeMachine<InstructionSet> = &mut rotate;
+        for (secs, target) in [(10, "first"), (20, "second"), (30, "third")] {
+            assert_eq!(rotate.needs_restart(), secs > 10);
+            rotate.handle(Instruction::Restart);
+            rotate.handle(Instruction::StartEvent {
+                time: DateTime::from_timestamp(secs, 0).unwrap(),
+                span: None,
+                target,
+                priority: Level::INFO,
+                location: None,
+                thread: None,
+            });
+            rotate.handle(Instruction::FinishedEvent);
+        }
+
+        let target = |secs| {
+            let mut load = Load::open(&path).unwrap();
+            load.seek_to_time(DateTime::from_timestamp(secs, 0).unwrap())
+                .unwrap();
+            loop {
+                if let Some(Instruction::StartEvent { target, .. }) = load.fetch_one().unwrap() {
+                    break target.to_owned();
+                }
+            }
+        };
+        assert_eq!(target(5), "first");
+        assert_eq!(target(25), "second");
+        assert_eq!(target(30), "third");
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,250 @@
+use crate::tape::{Instruction, InstructionSet, TapeMachine};
+use std::{
+    collections::HashMap,
+    num::NonZeroU64,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// Keeps a fraction of the events, deciding by their root span so that whole traces are either
+/// kept or dropped together.
+///
+/// The decision is a hash of the id of the root span, so it is the same for every event of a
+/// trace. Events outside spans are sampled one by one. Spans are always forwarded.
+pub struct SamplingMachine<T> {
+    forward: T,
+    rate: SampleRate,
+    root: HashMap<NonZeroU64, NonZeroU64>,
+    unspanned: u64,
+    skipping: bool,
+}
+impl<T> SamplingMachine<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    /// Keeps about `fraction` of the events, between 0 and 1.
+    pub fn new(forward: T, fraction: f64) -> Self {
+        Self {
+            forward,
+            rate: SampleRate(Arc::new(AtomicU64::new(threshold(fraction)))),
+            root: Default::default(),
+            unspanned: 0,
+            skipping: false,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.forward
+    }
+
+    /// Handle changing the fraction of kept events while the tape is being written.
+    pub fn rate(&self) -> SampleRate {
+        self.rate.clone()
+    }
+
+    fn sampled(&mut self, span: Option<NonZeroU64>) -> bool {
+        let key = match span {
+            Some(span) => self.root.get(&span).copied().unwrap_or(span).get(),
+            None => {
+                self.unspanned += 1;
+                // Apart from span ids
+                self.unspanned ^ (1 << 63)
+            }
+        };
+        mix(key) <= self.rate.0.load(Ordering::Relaxed)
+    }
+}
+impl<T> TapeMachine<InstructionSet> for SamplingMachine<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    fn needs_restart(&mut self) -> bool {
+        self.forward.needs_restart()
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::StartEvent { span, .. } if !self.sampled(span) => {
+                self.skipping = true;
+            }
+            Instruction::AddValue(..) if self.skipping => (),
+            Instruction::FinishedEvent if self.skipping => self.skipping = false,
+            instruction => {
+                match instruction {
+                    // Spans live across rotations without being replayed here, so their roots
+                    // are only forgotten when they are deleted
+                    Instruction::Restart => self.skipping = false,
+                    Instruction::NewSpan { parent, span, .. } => {
+                        let root = parent.map_or(span, |parent| {
+                            self.root.get(&parent).copied().unwrap_or(parent)
+                        });
+                        self.root.insert(span, root);
+                    }
+                    Instruction::DeleteSpan(span) => {
+                        self.root.remove(&span);
+                    }
+                    _ => (),
+                }
+                self.forward.handle(instruction);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.forward.flush();
+    }
+}
+
+/// Fraction of the events kept by a [`SamplingMachine`], shared with the writing thread.
+#[derive(Clone, Debug)]
+pub struct SampleRate(Arc<AtomicU64>);
+impl SampleRate {
+    pub fn get(&self) -> f64 {
+        self.0.load(Ordering::Relaxed) as f64 / u64::MAX as f64
+    }
+
+    /// Applies from the next event on, so a trace already started may be cut.
+    pub fn set(&self, fraction: f64) {
+        self.0.store(threshold(fraction), Ordering::Relaxed);
+    }
+}
+
+fn threshold(fraction: f64) -> u64 {
+    (fraction.clamp(0.0, 1.0) * u64::MAX as f64) as u64
+}
+
+/// Spreads ids over the whole range, see splitmix64.
+fn mix(key: u64) -> u64 {
+    let key = key.wrapping_add(0x9e3779b97f4a7c15);
+    let key = (key ^ (key >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    let key = (key ^ (key >> 27)).wrapping_mul(0x94d049bb133111eb);
+    key ^ (key >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{event, new_span, span, start_event};
+    use chrono::DateTime;
+    use std::collections::HashSet;
+    use tracing::Level;
+
+    #[derive(Default)]
+    struct Spans(Vec<Option<NonZeroU64>>);
+    impl TapeMachine<InstructionSet> for Spans {
+        fn needs_restart(&mut self) -> bool {
+            false
+        }
+
+        fn handle(&mut self, instruction: Instruction) {
+            if let Instruction::StartEvent { span, .. } = instruction {
+                self.0.push(span);
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_whole_traces() {
+        let mut sampling = SamplingMachine::new(Spans::default(), 0.5);
+        for root in 1..=1000 {
+            let root = NonZeroU64::new(root * 2).unwrap();
+            let child = NonZeroU64::new(root.get() + 1).unwrap();
+            sampling.handle(Instruction::NewSpan {
+                parent: None,
+                span: root,
+                name: "request",
+            });
+            sampling.handle(Instruction::NewSpan {
+                parent: Some(root),
+                span: child,
+                name: "query",
+            });
+            for span in [root, child] {
+                sampling.handle(Instruction::StartEvent {
+                    time: DateTime::UNIX_EPOCH,
+                    span: Some(span),
+                    target: "target",
+                    priority: Level::INFO,
+                    location: None,
+                    thread: None,
+                });
+                sampling.handle(Instruction::FinishedEvent);
+            }
+        }
+
+        let spans = sampling.into_inner().0;
+        let roots = spans
+            .iter()
+            .map(|span| span.unwrap().get() & !1)
+            .collect::<HashSet<_>>();
+        assert_eq!(spans.len(), roots.len() * 2);
+        assert!((400..600).contains(&roots.len()), "{}", roots.len());
+    }
+
+    #[test]
+    fn keeps_traces_across_restart() {
+        let mut sampling = SamplingMachine::new(Spans::default(), 0.5);
+        for root in 1..=200 {
+            new_span(&mut sampling, None, span(root * 2), "request", &[]);
+            new_span(
+                &mut sampling,
+                Some(span(root * 2)),
+                span(root * 2 + 1),
+                "query",
+                &[],
+            );
+        }
+        sampling.handle(Instruction::Restart);
+        for child in 1..=200 {
+            for _ in 0..3 {
+                let start = start_event(
+                    DateTime::UNIX_EPOCH,
+                    Some(span(child * 2 + 1)),
+                    "target",
+                    Level::INFO,
+                );
+                event(&mut sampling, start, &[]);
+            }
+        }
+
+        let spans = sampling.into_inner().0;
+        let children = spans.iter().collect::<HashSet<_>>();
+        assert_eq!(spans.len(), children.len() * 3);
+        for child in children {
+            let root = span(child.unwrap().get() & !1);
+            let mut reference = SamplingMachine::new(Spans::default(), 0.5);
+            let start = start_event(DateTime::UNIX_EPOCH, Some(root), "target", Level::INFO);
+            event(&mut reference, start, &[]);
+            assert_eq!(
+                reference.into_inner().0.len(),
+                1,
+                "{child:?} sampled by its own id"
+            );
+        }
+        assert!((60..140).contains(&(spans.len() / 3)), "{}", spans.len());
+    }
+
+    #[test]
+    fn change_rate() {
+        let mut sampling = SamplingMachine::new(Spans::default(), 1.0);
+        let rate = sampling.rate();
+        for fraction in [1.0, 0.0, 1.0] {
+            rate.set(fraction);
+            for _ in 0..100 {
+                sampling.handle(Instruction::StartEvent {
+                    time: DateTime::UNIX_EPOCH,
+                    span: None,
+                    target: "target",
+                    priority: Level::INFO,
+                    location: None,
+                    thread: None,
+                });
+                sampling.handle(Instruction::FinishedEvent);
+            }
+        }
+
+        assert_eq!(sampling.into_inner().0.len(), 200);
+    }
+}
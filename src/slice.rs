@@ -0,0 +1,339 @@
+use crate::{
+    printer::NewEvent,
+    tape::{FieldValueOwned, Instruction, InstructionSet, SpanRecords, TapeMachine},
+};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    num::NonZeroU64,
+};
+
+/// Decides whether an event goes into the slice. Spans are given from the root.
+pub type SliceMatcher = Box<dyn FnMut(&NewEvent, &[Cow<SpanRecords>]) -> bool + Send>;
+
+/// Forwards only the events accepted by a [`SliceMatcher`], along with the spans they are in and
+/// all their ancestors, so that the forwarded tape is a smaller but still valid tape.
+///
+/// Spans are only forwarded right before their first matching event. Later records and the
+/// deletion of forwarded spans are forwarded as well.
+pub struct Slice<T> {
+    forward: T,
+    matcher: SliceMatcher,
+    span: HashMap<NonZeroU64, SpanRecords>,
+    forwarded: HashSet<NonZeroU64>,
+    new_span: Option<(NonZeroU64, SpanRecords)>,
+    new_record: Option<(NonZeroU64, Vec<FieldValueOwned>)>,
+    new_event: Option<NewEvent>,
+}
+impl<T> Slice<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    pub fn new(forward: T, matcher: SliceMatcher) -> Self {
+        Self {
+            forward,
+            matcher,
+            span: Default::default(),
+            forwarded: Default::default(),
+            new_span: None,
+            new_record: None,
+            new_event: None,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.forward
+    }
+
+    /// Chain of spans from the root to `span`, with their ids, as known from `known`.
+    pub(crate) fn span_from_root(
+        known: &HashMap<NonZeroU64, SpanRecords>,
+        span: Option<NonZeroU64>,
+    ) -> Vec<(NonZeroU64, Cow<'_, SpanRecords>)> {
+        let mut spans = Vec::new();
+        let mut next = span;
+        while let Some(span) = next {
+            let records = match known.get(&span) {
+                Some(records) => Cow::Borrowed(records),
+                None => Cow::Owned(SpanRecords::lost(span)),
+            };
+            next = records.parent;
+            spans.push((span, records));
+        }
+        spans.reverse();
+        spans
+    }
+
+    fn forward_span(&mut self, span: NonZeroU64) {
+        if !self.forwarded.insert(span) {
+            return;
+        }
+        let Some(records) = self.span.get(&span) else {
+            return;
+        };
+
+        self.forward.handle(Instruction::NewSpan {
+            parent: records.parent,
+            span,
+            name: &records.name,
+        });
+        for record in records.records.iter() {
+            self.forward.handle(Instruction::AddValue(record.as_ref()));
+        }
+        self.forward.handle(Instruction::FinishedSpan);
+    }
+
+    fn finish_event(&mut self, event: NewEvent) {
+        let spans = Self::span_from_root(&self.span, event.span);
+        let ids = spans.iter().map(|(span, _)| *span).collect::<Vec<_>>();
+        let spans = spans
+            .into_iter()
+            .map(|(_, records)| records)
+            .collect::<Vec<_>>();
+        if !(self.matcher)(&event, &spans) {
+            return;
+        }
+
+        for span in ids {
+            self.forward_span(span);
+        }
+        event.forward(&mut self.forward);
+    }
+}
+impl<T> TapeMachine<InstructionSet> for Slice<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    fn needs_restart(&mut self) -> bool {
+        self.forward.needs_restart()
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Restart => {
+                // Spans alive are replayed after the restart
+                self.span.clear();
+                self.forwarded.clear();
+                self.new_span = None;
+                self.new_record = None;
+                self.new_event = None;
+                self.forward.handle(Instruction::Restart);
+            }
+            Instruction::NewSpan { parent, span, name } => {
+                self.new_span = Some((
+                    span,
+                    SpanRecords {
+                        parent,
+                        name: name.to_owned(),
+                        records: Default::default(),
+                    },
+                ));
+            }
+            Instruction::FinishedSpan => {
+                if let Some((span, records)) = self.new_span.take() {
+                    self.span.insert(span, records);
+                }
+            }
+            Instruction::NewRecord(span) => {
+                self.new_record = Some((span, Default::default()));
+            }
+            Instruction::FinishedRecord => {
+                let Some((span, values)) = self.new_record.take() else {
+                    return;
+                };
+                if self.forwarded.contains(&span) {
+                    self.forward.handle(Instruction::NewRecord(span));
+                    for value in values.iter() {
+                        self.forward.handle(Instruction::AddValue(value.as_ref()));
+                    }
+                    self.forward.handle(Instruction::FinishedRecord);
+                }
+                let records = self
+                    .span
+                    .entry(span)
+                    .or_insert_with(|| SpanRecords::lost(span));
+                for value in values {
+                    records.record(value);
+                }
+            }
+            Instruction::StartEvent {
+                time,
+                span,
+                target,
+                priority,
+                location,
+                thread,
+            } => {
+                self.new_event = Some(NewEvent {
+                    time,
+                    span,
+                    target: target.to_owned(),
+                    priority,
+                    location: location.map(|location| location.map(str::to_owned)),
+                    thread: thread.map(|thread| thread.map(str::to_owned)),
+                    records: Default::default(),
+                });
+            }
+            Instruction::FinishedEvent => {
+                if let Some(event) = self.new_event.take() {
+                    self.finish_event(event);
+                }
+            }
+            Instruction::AddValue(value) => {
+                let value = value.to_owned();
+                match (
+                    &mut self.new_span,
+                    &mut self.new_record,
+                    &mut self.new_event,
+                ) {
+                    (Some((_, records)), _, _) => records.record(value),
+                    (_, Some((_, values)), _) => values.push(value),
+                    (_, _, Some(event)) => event.records.push(value),
+                    _ => (),
+                }
+            }
+            Instruction::DeleteSpan(span) => {
+                self.span.remove(&span);
+                if self.forwarded.remove(&span) {
+                    self.forward.handle(Instruction::DeleteSpan(span));
+                }
+            }
+            Instruction::FilterChanged(filter) => {
+                self.forward.handle(Instruction::FilterChanged(filter));
+            }
+            Instruction::EnterSpan { span, .. }
+            | Instruction::ExitSpan { span, .. }
+            | Instruction::SpanTiming { span, .. } => {
+                if self.forwarded.contains(&span) {
+                    self.forward.handle(instruction);
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.forward.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::{Load, Store},
+        tape::{FieldValue, Value},
+        test_util::{event, golden_tape, new_span, span, start_event},
+    };
+    use chrono::DateTime;
+    use std::io;
+    use tracing::Level;
+
+    /// Instructions of the sliced tape, without their details.
+    fn loaded(out: Vec<u8>) -> Vec<String> {
+        let mut load = Load::new(io::Cursor::new(out));
+        let mut loaded = Vec::new();
+        while let Some(instruction) = load.fetch_one().unwrap() {
+            loaded.push(match instruction {
+                Instruction::Restart => "restart".to_string(),
+                Instruction::NewSpan { span, name, .. } => format!("span {span} {name}"),
+                Instruction::AddValue(value) => format!("value {}", value.name),
+                Instruction::StartEvent { target, .. } => format!("event {target}"),
+                Instruction::DeleteSpan(span) => format!("delete {span}"),
+                Instruction::FilterChanged(filter) => format!("filter {filter}"),
+                _ => continue,
+            });
+        }
+        loaded
+    }
+
+    fn wanted() -> Slice<Store<Vec<u8>>> {
+        Slice::new(
+            Store::new(Vec::new()),
+            Box::new(|event, _| event.target == "wanted"),
+        )
+    }
+
+    #[test]
+    fn keeps_span_context() {
+        let mut slice = wanted();
+
+        slice.handle(Instruction::Restart);
+        for (id, parent, name) in [
+            (1, None, "request"),
+            (2, Some(1), "query"),
+            (3, None, "idle"),
+        ] {
+            let values = [("id", Value::Unsigned(id))];
+            new_span(&mut slice, parent.map(span), span(id), name, &values);
+        }
+        for (id, target) in [(2, "wanted"), (3, "other")] {
+            let start = start_event(DateTime::UNIX_EPOCH, Some(span(id)), target, Level::INFO);
+            event(&mut slice, start, &[]);
+        }
+        for id in [2, 3] {
+            slice.handle(Instruction::DeleteSpan(span(id)));
+        }
+
+        assert_eq!(
+            loaded(slice.into_inner().into_inner()),
+            [
+                "restart",
+                "span 1 request",
+                "value id",
+                "span 2 query",
+                "value id",
+                "event wanted",
+                "delete 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_slice() {
+        let mut slice = wanted();
+        golden_tape(&mut slice);
+
+        assert_eq!(
+            loaded(slice.into_inner().into_inner()),
+            ["restart", "filter info", "restart"]
+        );
+    }
+
+    #[test]
+    fn event_cut_by_restart() {
+        let mut slice = wanted();
+
+        // The writer restarted before finishing the span and the event
+        slice.handle(Instruction::Restart);
+        slice.handle(Instruction::NewSpan {
+            parent: None,
+            span: span(1),
+            name: "cut",
+        });
+        slice.handle(Instruction::Restart);
+        slice.handle(start_event(
+            DateTime::UNIX_EPOCH,
+            None,
+            "wanted",
+            Level::INFO,
+        ));
+        slice.handle(Instruction::AddValue(FieldValue {
+            name: "cut",
+            value: Value::Bool(true),
+        }));
+        slice.handle(Instruction::Restart);
+        let start = start_event(DateTime::UNIX_EPOCH, None, "wanted", Level::INFO);
+        event(&mut slice, start, &[("kept", Value::Bool(true))]);
+
+        assert_eq!(
+            loaded(slice.into_inner().into_inner()),
+            [
+                "restart",
+                "restart",
+                "restart",
+                "event wanted",
+                "value kept"
+            ]
+        );
+    }
+}
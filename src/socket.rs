@@ -0,0 +1,352 @@
+use crate::{
+    encoding::encode_event,
+    printer::NewEvent,
+    storage::{Flusher, Store},
+    string_cache::{CacheInstruction, CacheInstructionSet},
+    tape::{Instruction, InstructionSet, InstructionTrait, TapeMachine},
+};
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+/// Where [`SocketStore`] connects to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SocketAddress {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+impl SocketAddress {
+    fn connect(&self) -> io::Result<Box<dyn io::Write + Send>> {
+        match self {
+            SocketAddress::Tcp(address) => {
+                let stream = std::net::TcpStream::connect(address)?;
+                stream.set_nodelay(true)?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(unix)]
+            SocketAddress::Unix(path) => {
+                Ok(Box::new(std::os::unix::net::UnixStream::connect(path)?))
+            }
+        }
+    }
+}
+
+/// Streams the tape to a TCP or Unix domain socket, reconnecting when the connection is lost.
+///
+/// Every connection is a new tape, so the store asks for a restart when the connection is lost,
+/// and the instructions are buffered from that `Restart` until the next connection. Wrap the
+/// store, behind a [`StringCache`](crate::string_cache::StringCache), in a
+/// [`RestartableMachine`](crate::restart::RestartableMachine) to send the spans still alive
+/// again. When the buffer is full, it is dropped along with the instructions until the next
+/// restart.
+///
+/// Instructions are sent once events and spans are complete.
+pub struct SocketStore {
+    address: SocketAddress,
+    stream: Option<Box<dyn io::Write + Send>>,
+    buffer: Vec<u8>,
+    max_buffer: usize,
+    retry: Duration,
+    last_attempt: Option<std::time::Instant>,
+    restart: bool,
+}
+impl SocketStore {
+    /// Connects right away, when the first instruction is sent if that fails.
+    pub fn new(address: SocketAddress) -> Self {
+        let mut store = Self {
+            address,
+            stream: None,
+            buffer: Vec::new(),
+            max_buffer: 1 << 20,
+            retry: Duration::from_secs(1),
+            last_attempt: None,
+            restart: false,
+        };
+        store.reconnect();
+        store
+    }
+
+    /// Bytes buffered while disconnected. Defaults to 1 MiB.
+    pub fn with_max_buffer(mut self, max_buffer: usize) -> Self {
+        self.max_buffer = max_buffer;
+        self
+    }
+
+    /// Time between connection attempts. Defaults to a second.
+    pub fn with_retry(mut self, retry: Duration) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    fn reconnect(&mut self) {
+        if self
+            .last_attempt
+            .is_some_and(|last_attempt| last_attempt.elapsed() < self.retry)
+        {
+            return;
+        }
+
+        self.last_attempt = Some(std::time::Instant::now());
+        self.stream = self.address.connect().ok();
+    }
+
+    /// Drops everything until the next `Restart`.
+    fn lost(&mut self) {
+        self.stream = None;
+        self.buffer.clear();
+        self.restart = true;
+    }
+
+    fn send(&mut self) {
+        if self.stream.is_none() {
+            self.reconnect();
+        }
+        let Some(stream) = self.stream.as_mut() else {
+            return;
+        };
+
+        match stream.write_all(&self.buffer).and_then(|()| stream.flush()) {
+            Ok(()) => self.buffer.clear(),
+            Err(_) => self.lost(),
+        }
+    }
+
+    fn handle_cached(&mut self, instruction: CacheInstruction) {
+        match instruction {
+            CacheInstruction::Restart => {
+                self.buffer.clear();
+                self.restart = false;
+            }
+            _ if self.restart => return,
+            _ => (),
+        }
+
+        let _ = Store::do_write_cached(&mut self.buffer, instruction);
+        if self.buffer.len() > self.max_buffer {
+            self.lost();
+            return;
+        }
+        if Flusher::completes(instruction.id()) {
+            self.send();
+        }
+    }
+}
+impl TapeMachine<CacheInstructionSet> for SocketStore {
+    fn needs_restart(&mut self) -> bool {
+        self.restart
+    }
+
+    fn handle(&mut self, instruction: CacheInstruction) {
+        self.handle_cached(instruction);
+    }
+
+    fn flush(&mut self) {
+        self.send();
+    }
+}
+impl TapeMachine<InstructionSet> for SocketStore {
+    fn needs_restart(&mut self) -> bool {
+        self.restart
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        self.handle_cached(Store::<Vec<u8>>::as_cached(instruction));
+    }
+
+    fn flush(&mut self) {
+        self.send();
+    }
+}
+
+/// Sends every event in its own UDP datagram, encoded by [`encode_event`], so receivers decode
+/// each datagram with [`decode_event`](crate::encoding::decode_event) on its own, whatever
+/// datagrams were lost.
+///
+/// Spans are not sent, events only keep the id of their span. Events that don't fit in a
+/// datagram, or fail to be sent, are dropped.
+pub struct DatagramStore {
+    socket: std::net::UdpSocket,
+    new_event: Option<NewEvent>,
+    buffer: Vec<u8>,
+    dropped: u64,
+}
+impl DatagramStore {
+    /// Largest UDP payload over IPv4.
+    pub const MAX_DATAGRAM: usize = 65507;
+
+    pub fn new<A: std::net::ToSocketAddrs>(address: A) -> io::Result<Self> {
+        let socket = std::net::UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.connect(address)?;
+
+        Ok(Self {
+            socket,
+            new_event: None,
+            buffer: Vec::new(),
+            dropped: 0,
+        })
+    }
+
+    /// Events dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    fn send(&mut self, event: &NewEvent) -> io::Result<()> {
+        self.buffer.clear();
+        encode_event(event, &mut self.buffer)?;
+        if self.buffer.len() > Self::MAX_DATAGRAM {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "event larger than a datagram",
+            ));
+        }
+
+        self.socket.send(&self.buffer)?;
+        Ok(())
+    }
+}
+impl TapeMachine<InstructionSet> for DatagramStore {
+    fn needs_restart(&mut self) -> bool {
+        false
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::StartEvent {
+                time,
+                span,
+                target,
+                priority,
+                location,
+                thread,
+            } => {
+                self.new_event = Some(NewEvent {
+                    time,
+                    span,
+                    target: target.to_owned(),
+                    priority,
+                    location: location.map(|location| location.map(str::to_owned)),
+                    thread: thread.map(|thread| thread.map(str::to_owned)),
+                    records: Default::default(),
+                });
+            }
+            Instruction::AddValue(value) if let Some(event) = self.new_event.as_mut() => {
+                event.records.push(value.to_owned());
+            }
+            Instruction::FinishedEvent => {
+                let Some(event) = self.new_event.take() else {
+                    return;
+                };
+                if self.send(&event).is_err() {
+                    self.dropped += 1;
+                }
+            }
+            Instruction::Restart => self.new_event = None,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        encoding::decode_event,
+        storage::{DatagramStore, Load, SocketAddress, SocketStore},
+        tape::{FieldValue, Value},
+    };
+    use chrono::DateTime;
+    use tracing::Level;
+
+    #[cfg(unix)]
+    #[test]
+    fn socket_store_buffers_until_connected() {
+        use crate::{
+            restart::RestartableMachine,
+            string_cache::{StringCache, StringUncache},
+        };
+        use std::os::unix::net::UnixListener;
+
+        let path =
+            std::env::temp_dir().join(format!("msgpack-tracing-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = SocketStore::new(SocketAddress::Unix(path.clone())).with_retry(Duration::ZERO);
+        assert!(!store.is_connected());
+        let mut machine = RestartableMachine::new(StringCache::new(store));
+        let event = |machine: &mut dyn TapeMachine<InstructionSet>, target| {
+            if machine.needs_restart() {
+                machine.handle(Instruction::Restart);
+            }
+            machine.handle(Instruction::StartEvent {
+                time: DateTime::UNIX_EPOCH,
+                span: None,
+                target,
+                priority: Level::INFO,
+                location: None,
+                thread: None,
+            });
+            machine.handle(Instruction::FinishedEvent);
+        };
+
+        machine.handle(Instruction::Restart);
+        event(&mut machine, "buffered");
+        let listener = UnixListener::bind(&path).unwrap();
+        event(&mut machine, "connected");
+        drop(machine);
+
+        let (stream, _) = listener.accept().unwrap();
+        let _ = std::fs::remove_file(&path);
+        let mut uncached = StringUncache::new(Store::new(Vec::new()));
+        Load::new(stream).forward_cached(&mut uncached).unwrap();
+        let tape = uncached.into_inner().into_inner();
+
+        let mut load = Load::new(tape.as_slice());
+        let mut targets = Vec::new();
+        while let Some(instruction) = load.fetch_one().unwrap() {
+            if let Instruction::StartEvent { target, .. } = instruction {
+                targets.push(target.to_owned());
+            }
+        }
+        assert_eq!(targets, ["buffered", "connected"]);
+    }
+
+    #[test]
+    fn datagram_per_event() {
+        let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut store = DatagramStore::new(server.local_addr().unwrap()).unwrap();
+        store.handle(Instruction::Restart);
+        for (target, len) in [
+            ("small", 10),
+            ("huge", DatagramStore::MAX_DATAGRAM),
+            ("last", 0),
+        ] {
+            store.handle(Instruction::StartEvent {
+                time: DateTime::UNIX_EPOCH,
+                span: None,
+                target,
+                priority: Level::INFO,
+                location: None,
+                thread: None,
+            });
+            store.handle(Instruction::AddValue(FieldValue {
+                name: "data",
+                value: Value::ByteArray(&vec![0; len]),
+            }));
+            store.handle(Instruction::FinishedEvent);
+        }
+        assert_eq!(store.dropped(), 1);
+
+        let mut buf = vec![0; DatagramStore::MAX_DATAGRAM];
+        for target in ["small", "last"] {
+            let n = server.recv(&mut buf).unwrap();
+            assert_eq!(decode_event(&buf[..n]).unwrap().target, target);
+        }
+    }
+}
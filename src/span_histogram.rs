@@ -0,0 +1,180 @@
+use crate::tape::{Instruction, InstructionSet, TapeMachine};
+use chrono::{DateTime, TimeDelta, Utc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    num::NonZeroU64,
+};
+
+/// Builds latency histograms per span name while replaying a tape.
+///
+/// The tape only timestamps events, so spans are timed against the time of the latest event:
+/// a span starts at the last event before its creation and ends at the last event before its
+/// deletion. Spans created before any event, or never deleted, are not measured.
+pub struct SpanDurations {
+    buckets: Vec<f64>,
+    clock: Option<DateTime<Utc>>,
+    open: HashMap<NonZeroU64, (String, Option<DateTime<Utc>>)>,
+    histograms: BTreeMap<String, Histogram>,
+}
+impl Default for SpanDurations {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl SpanDurations {
+    /// Bucket upper bounds, in seconds, used by [`new`](Self::new).
+    pub const DEFAULT_BUCKETS: [f64; 11] = [
+        0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+    ];
+
+    pub fn new() -> Self {
+        Self::with_buckets(Self::DEFAULT_BUCKETS.to_vec())
+    }
+
+    /// `buckets` are upper bounds in seconds. They are sorted, an implicit `+Inf` bucket is
+    /// always added.
+    pub fn with_buckets(mut buckets: Vec<f64>) -> Self {
+        buckets.sort_by(f64::total_cmp);
+        Self {
+            buckets,
+            clock: None,
+            open: Default::default(),
+            histograms: Default::default(),
+        }
+    }
+
+    pub fn histograms(&self) -> &BTreeMap<String, Histogram> {
+        &self.histograms
+    }
+
+    /// Writes the histograms in the Prometheus text exposition format.
+    pub fn write_prometheus<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        const METRIC: &str = "span_duration_seconds";
+
+        writeln!(out, "# TYPE {METRIC} histogram")?;
+        for (name, histogram) in self.histograms.iter() {
+            let name = name.replace('\\', "\\\\").replace('"', "\\\"");
+            let mut cumulative = 0;
+            for (le, count) in self.buckets.iter().zip(histogram.buckets.iter()) {
+                cumulative += count;
+                writeln!(
+                    out,
+                    "{METRIC}_bucket{{span=\"{name}\",le=\"{le}\"}} {cumulative}"
+                )?;
+            }
+            writeln!(
+                out,
+                "{METRIC}_bucket{{span=\"{name}\",le=\"+Inf\"}} {}",
+                histogram.count
+            )?;
+            writeln!(out, "{METRIC}_sum{{span=\"{name}\"}} {}", histogram.sum)?;
+            writeln!(out, "{METRIC}_count{{span=\"{name}\"}} {}", histogram.count)?;
+        }
+
+        Ok(())
+    }
+
+    fn observe(&mut self, name: String, duration: TimeDelta) {
+        let seconds = duration.as_seconds_f64();
+        let buckets = self.buckets.len();
+        let histogram = self.histograms.entry(name).or_insert_with(|| Histogram {
+            buckets: vec![0; buckets],
+            sum: 0.0,
+            count: 0,
+        });
+
+        if let Some(bucket) = self.buckets.iter().position(|le| seconds <= *le) {
+            histogram.buckets[bucket] += 1;
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+}
+impl TapeMachine<InstructionSet> for SpanDurations {
+    fn needs_restart(&mut self) -> bool {
+        false
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::NewSpan { span, name, .. } => {
+                // Spans are replayed after a restart, keep their original start
+                self.open
+                    .entry(span)
+                    .or_insert_with(|| (name.to_owned(), self.clock));
+            }
+            Instruction::StartEvent { time, .. } => {
+                self.clock = Some(time);
+            }
+            Instruction::DeleteSpan(span) => {
+                let Some((name, Some(start))) = self.open.remove(&span) else {
+                    return;
+                };
+                let Some(end) = self.clock else {
+                    return;
+                };
+
+                self.observe(name, end - start);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Non-cumulative counts per bucket of [`SpanDurations`].
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    pub buckets: Vec<u64>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Level;
+
+    #[test]
+    fn prometheus_export() {
+        let mut durations = SpanDurations::with_buckets(vec![1.0, 0.1]);
+        let start = DateTime::UNIX_EPOCH;
+        let event = |durations: &mut SpanDurations, millis| {
+            durations.handle(Instruction::StartEvent {
+                time: start + TimeDelta::milliseconds(millis),
+                span: None,
+                target: "target",
+                priority: Level::INFO,
+                location: None,
+                thread: None,
+            });
+            durations.handle(Instruction::FinishedEvent);
+        };
+        let span = |id| NonZeroU64::new(id).unwrap();
+
+        event(&mut durations, 0);
+        for (id, millis) in [(1, 50), (2, 500), (3, 5000)] {
+            durations.handle(Instruction::NewSpan {
+                parent: None,
+                span: span(id),
+                name: "request",
+            });
+            durations.handle(Instruction::FinishedSpan);
+            event(&mut durations, millis);
+            durations.handle(Instruction::DeleteSpan(span(id)));
+            event(&mut durations, 0);
+        }
+
+        let mut out = String::new();
+        durations.write_prometheus(&mut out).unwrap();
+        assert_eq!(
+            out,
+            "# TYPE span_duration_seconds histogram\n\
+             span_duration_seconds_bucket{span=\"request\",le=\"0.1\"} 1\n\
+             span_duration_seconds_bucket{span=\"request\",le=\"1\"} 2\n\
+             span_duration_seconds_bucket{span=\"request\",le=\"+Inf\"} 3\n\
+             span_duration_seconds_sum{span=\"request\"} 5.55\n\
+             span_duration_seconds_count{span=\"request\"} 3\n"
+        );
+    }
+}
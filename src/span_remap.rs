@@ -0,0 +1,238 @@
+use crate::tape::{Instruction, InstructionSet, TapeMachine};
+use std::{
+    collections::{BTreeSet, HashMap},
+    num::NonZeroU64,
+};
+
+/// Renumbers spans with the smallest ids not taken by live spans, so that they take a single
+/// byte in the tape instead of up to nine.
+///
+/// Ids given by `tracing` carry a generation in their upper bits, so even the first spans have
+/// large ids. Ids of deleted spans are reused, as `tracing` does. Ids extracted by
+/// [`TapeMachineLogger::with_span_id`](crate::tape::TapeMachineLogger::with_span_id) are
+/// renumbered as well, so tapes can't be joined on them anymore.
+pub struct SpanRemap<T> {
+    forward: T,
+    ids: HashMap<NonZeroU64, NonZeroU64>,
+    free: BTreeSet<NonZeroU64>,
+    next: NonZeroU64,
+}
+impl<T> SpanRemap<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    pub fn new(forward: T) -> Self {
+        Self {
+            forward,
+            ids: Default::default(),
+            free: Default::default(),
+            next: NonZeroU64::MIN,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.forward
+    }
+
+    /// Id recorded for `span`, taking a new one if it is not known yet.
+    fn id(&mut self, span: NonZeroU64) -> NonZeroU64 {
+        if let Some(&id) = self.ids.get(&span) {
+            return id;
+        }
+
+        let id = self.free.pop_first().unwrap_or_else(|| {
+            let id = self.next;
+            self.next = id.saturating_add(1);
+            id
+        });
+        self.ids.insert(span, id);
+        id
+    }
+
+    /// Id recorded for `span`, which is free again afterwards.
+    fn release(&mut self, span: NonZeroU64) -> NonZeroU64 {
+        let id = self.id(span);
+        self.ids.remove(&span);
+        self.free.insert(id);
+        id
+    }
+}
+impl<T> TapeMachine<InstructionSet> for SpanRemap<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    fn needs_restart(&mut self) -> bool {
+        self.forward.needs_restart()
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        let instruction = match instruction {
+            Instruction::NewSpan { parent, span, name } => Instruction::NewSpan {
+                parent: parent.map(|parent| self.id(parent)),
+                span: self.id(span),
+                name,
+            },
+            Instruction::NewRecord(span) => Instruction::NewRecord(self.id(span)),
+            Instruction::StartEvent {
+                time,
+                span,
+                target,
+                priority,
+                location,
+                thread,
+            } => Instruction::StartEvent {
+                time,
+                span: span.map(|span| self.id(span)),
+                target,
+                priority,
+                location,
+                thread,
+            },
+            Instruction::DeleteSpan(span) => Instruction::DeleteSpan(self.release(span)),
+            Instruction::EnterSpan { time, span } => Instruction::EnterSpan {
+                time,
+                span: self.id(span),
+            },
+            Instruction::ExitSpan { time, span } => Instruction::ExitSpan {
+                time,
+                span: self.id(span),
+            },
+            Instruction::SpanTiming {
+                time,
+                span,
+                target,
+                priority,
+                busy,
+                idle,
+            } => Instruction::SpanTiming {
+                time,
+                span: self.id(span),
+                target,
+                priority,
+                busy,
+                idle,
+            },
+            instruction => instruction,
+        };
+        self.forward.handle(instruction);
+    }
+
+    fn flush(&mut self) {
+        self.forward.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::{Load, Store},
+        tape::TapeMachineLogger,
+        test_util::{Shared, golden_tape, new_span, span},
+    };
+    use tracing_subscriber::{Registry, layer::SubscriberExt};
+
+    /// Span ids of the forwarded instructions.
+    #[derive(Default)]
+    struct Ids(Vec<String>);
+    impl TapeMachine<InstructionSet> for Ids {
+        fn needs_restart(&mut self) -> bool {
+            false
+        }
+
+        fn handle(&mut self, instruction: Instruction) {
+            let id = match instruction {
+                Instruction::NewSpan { parent, span, .. } => match parent {
+                    Some(parent) => format!("new {span} in {parent}"),
+                    None => format!("new {span}"),
+                },
+                Instruction::NewRecord(span) => format!("record {span}"),
+                Instruction::StartEvent {
+                    span: Some(span), ..
+                } => format!("event {span}"),
+                Instruction::DeleteSpan(span) => format!("delete {span}"),
+                _ => return,
+            };
+            self.0.push(id);
+        }
+    }
+
+    #[test]
+    fn small_reused_ids() {
+        let out = Shared::default();
+        let logger = TapeMachineLogger::new(SpanRemap::new(Store::new(out.clone())));
+        let handle = logger.handle();
+        tracing::subscriber::with_default(Registry::default().with(logger), || {
+            let outer = tracing::info_span!("outer");
+            outer.in_scope(|| {
+                tracing::info_span!("first").in_scope(|| tracing::info!("Inside"));
+                tracing::info_span!("second").in_scope(|| tracing::info!("Inside"));
+            });
+        });
+        handle.flush();
+
+        let out = out.0.lock().unwrap();
+        let mut load = Load::new(out.as_slice());
+        let mut spans = Vec::new();
+        while let Some(instruction) = load.fetch_one().unwrap() {
+            match instruction {
+                Instruction::NewSpan { parent, span, name } => {
+                    spans.push((name.to_owned(), parent.map(NonZeroU64::get), span.get()));
+                }
+                Instruction::StartEvent { span, .. } => {
+                    spans.push(("event".to_owned(), None, span.unwrap().get()));
+                }
+                _ => (),
+            }
+        }
+        let spans = spans
+            .iter()
+            .map(|(name, parent, span)| (name.as_str(), *parent, *span))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            spans,
+            [
+                ("outer", None, 1),
+                ("first", Some(1), 2),
+                ("event", None, 2),
+                ("second", Some(1), 2),
+                ("event", None, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn golden_tape_ids() {
+        let mut remap = SpanRemap::new(Ids::default());
+        golden_tape(&mut remap);
+
+        assert_eq!(
+            remap.into_inner().0,
+            [
+                "new 1",
+                "new 2 in 1",
+                "record 2",
+                "event 2",
+                "event 2",
+                "delete 2",
+                "delete 1"
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_spans() {
+        let mut remap = SpanRemap::new(Ids::default());
+        // Created before the tape was cut, then replayed after a restart
+        remap.handle(Instruction::DeleteSpan(span(100)));
+        remap.handle(Instruction::NewRecord(span(200)));
+        new_span(&mut remap, None, span(300), "request", &[]);
+        remap.handle(Instruction::Restart);
+        new_span(&mut remap, None, span(300), "request", &[]);
+
+        assert_eq!(
+            remap.into_inner().0,
+            ["delete 1", "record 1", "new 2", "new 2"]
+        );
+    }
+}
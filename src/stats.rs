@@ -0,0 +1,208 @@
+use crate::tape::{Instruction, InstructionSet, TapeMachine};
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    num::NonZeroU64,
+};
+use tracing::Level;
+
+/// Summarizes a tape while replaying it: events per level, per target and per minute, spans
+/// created, and the time range covered.
+#[derive(Default)]
+pub struct TapeStats {
+    levels: BTreeMap<Level, u64>,
+    targets: BTreeMap<String, u64>,
+    minutes: BTreeMap<DateTime<Utc>, u64>,
+    spans: u64,
+    open: HashSet<NonZeroU64>,
+    range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+}
+impl TapeStats {
+    /// Widest bar of the events per minute histogram.
+    const BAR_WIDTH: u64 = 40;
+
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn events(&self) -> u64 {
+        self.levels.values().sum()
+    }
+
+    /// Events per level, from ERROR to TRACE.
+    pub fn levels(&self) -> &BTreeMap<Level, u64> {
+        &self.levels
+    }
+
+    pub fn targets(&self) -> &BTreeMap<String, u64> {
+        &self.targets
+    }
+
+    /// Events per minute, keyed by the start of the minute.
+    pub fn minutes(&self) -> &BTreeMap<DateTime<Utc>, u64> {
+        &self.minutes
+    }
+
+    /// Spans created, not counting those replayed after a restart.
+    pub fn spans(&self) -> u64 {
+        self.spans
+    }
+
+    /// Times of the first and last events.
+    pub fn range(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        self.range
+    }
+
+    pub fn write_summary<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        match self.range {
+            Some((first, last)) => {
+                let span = (last - first).to_std().unwrap_or_default();
+                writeln!(out, "time range: {first} to {last} ({span:.0?})")?
+            }
+            None => writeln!(out, "time range: no events")?,
+        }
+        writeln!(out, "spans: {}", self.spans)?;
+        writeln!(out, "events: {}", self.events())?;
+        for (level, count) in self.levels.iter() {
+            writeln!(out, "  {level:<5} {count}")?;
+        }
+
+        writeln!(out, "events per target:")?;
+        let mut targets = self.targets.iter().collect::<Vec<_>>();
+        targets.sort_by_key(|(_, count)| std::cmp::Reverse(**count));
+        for (target, count) in targets {
+            writeln!(out, "  {count:>8} {target}")?;
+        }
+
+        writeln!(out, "events per minute:")?;
+        let max = self.minutes.values().copied().max().unwrap_or(1);
+        for (minute, count) in self.minutes.iter() {
+            let bar = "#".repeat((count * Self::BAR_WIDTH).div_ceil(max) as usize);
+            writeln!(
+                out,
+                "  {} {count:>8} {bar}",
+                minute.format("%Y-%m-%d %H:%M")
+            )?;
+        }
+
+        Ok(())
+    }
+}
+impl TapeMachine<InstructionSet> for TapeStats {
+    fn needs_restart(&mut self) -> bool {
+        false
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::NewSpan { span, .. } => {
+                // Spans are replayed after a restart
+                self.spans += u64::from(self.open.insert(span));
+            }
+            Instruction::DeleteSpan(span) => {
+                self.open.remove(&span);
+            }
+            Instruction::StartEvent {
+                time,
+                target,
+                priority,
+                ..
+            } => {
+                *self.levels.entry(priority).or_default() += 1;
+                match self.targets.get_mut(target) {
+                    Some(count) => *count += 1,
+                    None => {
+                        self.targets.insert(target.to_owned(), 1);
+                    }
+                }
+                if let Ok(minute) = time.duration_trunc(TimeDelta::minutes(1)) {
+                    *self.minutes.entry(minute).or_default() += 1;
+                }
+                self.range = Some(match self.range {
+                    Some((first, last)) => (first.min(time), last.max(time)),
+                    None => (time, time),
+                });
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{event, golden_tape, new_span, span, start_event};
+
+    fn summary(stats: &TapeStats) -> String {
+        let mut out = String::new();
+        stats.write_summary(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn summary_lines() {
+        let mut stats = TapeStats::new();
+        for _ in 0..2 {
+            // Replayed after a restart
+            new_span(&mut stats, None, span(1), "request", &[]);
+        }
+        for (seconds, target, priority) in [
+            (0, "app", Level::INFO),
+            (30, "app::db", Level::WARN),
+            (90, "app", Level::INFO),
+        ] {
+            let time = DateTime::from_timestamp(seconds, 0).unwrap();
+            event(&mut stats, start_event(time, None, target, priority), &[]);
+        }
+
+        assert_eq!(
+            summary(&stats),
+            "time range: 1970-01-01 00:00:00 UTC to 1970-01-01 00:01:30 UTC (90s)\n\
+             spans: 1\n\
+             events: 3\n  \
+               WARN  1\n  \
+               INFO  2\n\
+             events per target:\n         \
+               2 app\n         \
+               1 app::db\n\
+             events per minute:\n  \
+               1970-01-01 00:00        2 ########################################\n  \
+               1970-01-01 00:01        1 ####################\n"
+        );
+    }
+
+    #[test]
+    fn empty_tape() {
+        assert_eq!(
+            summary(&TapeStats::new()),
+            "time range: no events\n\
+             spans: 0\n\
+             events: 0\n\
+             events per target:\n\
+             events per minute:\n"
+        );
+    }
+
+    #[test]
+    fn golden_tape_summary() {
+        let mut stats = TapeStats::new();
+        golden_tape(&mut stats);
+
+        assert_eq!(stats.spans(), 2);
+        assert_eq!(stats.events(), 2);
+        assert_eq!(stats.levels().get(&Level::ERROR), Some(&2));
+        assert_eq!(
+            stats.range(),
+            Some((
+                DateTime::from_timestamp(-1, 5).unwrap(),
+                DateTime::UNIX_EPOCH
+            ))
+        );
+        assert_eq!(stats.minutes().len(), 2);
+
+        // A span deleted and created again counts twice
+        new_span(&mut stats, None, span(1), "request", &[]);
+        assert_eq!(stats.spans(), 3);
+    }
+}
@@ -1,29 +1,232 @@
 use crate::{
-    string_cache::{CacheInstruction, CacheInstructionSet, CacheString},
+    restart::RestartInterval,
+    rotate,
+    string_cache::{CacheInstruction, CacheInstructionSet, CacheString, StringUncache},
     tape::{
-        FieldValue, Instruction, InstructionId, InstructionSet, InstructionTrait, TapeMachine,
-        Value,
+        FieldValue, Instruction, InstructionId, InstructionOwned, InstructionSet,
+        InstructionSetTrait, InstructionTrait, Location, Nested, TapeMachine, ThreadInfo, Value,
     },
+    time_index::{self, IndexEntry, IndexWriter, SpanIndexEntry},
 };
-use chrono::DateTime;
+use chrono::{DateTime, TimeDelta, Utc};
 use rmp::{Marker, decode, encode};
 use std::{
-    io::{self, BufRead, BufReader, Read},
+    any::Any,
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Seek, Write},
     num::NonZeroU64,
+    path::Path,
+    time::{Duration, Instant},
 };
 use tracing::Level;
 
-pub struct Store<W>(W);
+#[cfg(any(feature = "gzip", feature = "lz4"))]
+use crate::compressed::Decoder;
+#[cfg(any(feature = "gzip", feature = "lz4"))]
+pub use crate::compressed::{CompressedStore, Compression};
+pub use crate::socket::{DatagramStore, SocketAddress, SocketStore};
+
+/// When [`Store`], [`Rotate`](rotate::Rotate) and [`Printer`](crate::printer::Printer) flush
+/// their writer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlushPolicy {
+    /// Flushes after every instruction.
+    #[default]
+    EveryInstruction,
+    /// Flushes only once events and spans are complete, so readers never see them half-written.
+    EveryEvent,
+    /// Flushes after every `n` events.
+    EveryEvents(u32),
+    /// Flushes once events and spans are complete, at most once per interval. What is written
+    /// after a flush waits for the next instruction, or for an explicit
+    /// [`flush`](TapeMachine::flush).
+    Interval(Duration),
+    /// Never flushes, instructions stay buffered until the buffer is full or an explicit
+    /// [`flush`](TapeMachine::flush).
+    Manual,
+}
+
+/// Applies a [`FlushPolicy`], counting the events and the time since the last flush.
+#[derive(Clone, Debug)]
+pub struct Flusher {
+    policy: FlushPolicy,
+    events: u32,
+    last_flush: Instant,
+}
+impl Flusher {
+    pub fn new(policy: FlushPolicy) -> Self {
+        Self {
+            policy,
+            events: 0,
+            last_flush: Instant::now(),
+        }
+    }
+
+    pub fn policy(&self) -> FlushPolicy {
+        self.policy
+    }
+
+    /// Whether to flush after writing `instruction`.
+    pub fn should_flush(&mut self, instruction: InstructionId) -> bool {
+        let flush = match self.policy {
+            FlushPolicy::EveryInstruction => true,
+            FlushPolicy::EveryEvent => Self::completes(instruction),
+            FlushPolicy::EveryEvents(n) => {
+                if instruction == InstructionId::FinishedEvent {
+                    self.events += 1;
+                }
+                instruction == InstructionId::FinishedEvent && self.events >= n
+            }
+            FlushPolicy::Interval(interval) => {
+                Self::completes(instruction) && self.last_flush.elapsed() >= interval
+            }
+            FlushPolicy::Manual => false,
+        };
+        if flush {
+            self.events = 0;
+            if let FlushPolicy::Interval(_) = self.policy {
+                self.last_flush = Instant::now();
+            }
+        }
+
+        flush
+    }
+
+    /// Whether no event or span is half-written after `instruction`.
+    pub(crate) fn completes(instruction: InstructionId) -> bool {
+        !matches!(
+            instruction,
+            InstructionId::NewString
+                | InstructionId::NewSpan
+                | InstructionId::NewRecord
+                | InstructionId::StartEvent
+                | InstructionId::AddValue
+        )
+    }
+}
+impl From<FlushPolicy> for Flusher {
+    fn from(policy: FlushPolicy) -> Self {
+        Self::new(policy)
+    }
+}
+
+/// Timestamps written by [`Store::with_delta_time`] since the last absolute one.
+#[derive(Default)]
+pub(crate) struct DeltaTime {
+    last: Option<DateTime<Utc>>,
+    deltas: u32,
+}
+impl DeltaTime {
+    /// Nanoseconds from the previous timestamp to `time`, or `None` if `time` should be written
+    /// as is.
+    fn next(&mut self, time: DateTime<Utc>) -> Option<i64> {
+        let delta = self
+            .last
+            .replace(time)
+            .filter(|_| self.deltas < DELTA_TIME_ANCHOR)
+            .and_then(|last| (time - last).num_nanoseconds());
+        self.deltas = match delta {
+            Some(_) => self.deltas + 1,
+            None => 0,
+        };
+        delta
+    }
+}
+
+/// Writes instructions through a [`BufWriter`](io::BufWriter), flushed according to its
+/// [`FlushPolicy`] or by [`flush`](Self::flush).
+pub struct Store<W: io::Write> {
+    out: io::BufWriter<W>,
+    flush: Flusher,
+    checksums: bool,
+    frame: Vec<u8>,
+    index: Option<IndexWriter<Box<dyn io::Write + Send>>>,
+    restarts: Option<RestartInterval>,
+    delta: Option<DeltaTime>,
+    written: u64,
+}
 impl<W> Store<W>
 where
     W: io::Write + Send + 'static,
 {
     pub fn new(out: W) -> Self {
-        Self(out)
+        Self::with_flush_policy(out, Default::default())
+    }
+
+    pub fn with_flush_policy(out: W, flush: FlushPolicy) -> Self {
+        Self {
+            out: io::BufWriter::new(out),
+            flush: flush.into(),
+            checksums: false,
+            frame: Vec::new(),
+            index: None,
+            restarts: None,
+            delta: None,
+            written: 0,
+        }
+    }
+
+    /// Writes out the buffered instructions and returns the writer. They are lost if the writer
+    /// fails.
+    pub fn into_inner(self) -> W {
+        self.out
+            .into_inner()
+            .unwrap_or_else(|e| e.into_inner().into_parts().0)
+    }
+
+    /// Writes out the buffered instructions and flushes the writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    /// Follows every instruction with its CRC32, so that [`Load`] detects corrupted instructions
+    /// and skips to the next `Restart`. Readers older than this option can't read the tape.
+    pub fn with_checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    /// Writes an [index](crate::time_index) of the tape to `index`, for
+    /// [`Load::seek_to_time`]. `offset` is the current length of the output, when appending.
+    ///
+    /// Asks for a restart every `interval` bytes, wrap the store in a
+    /// [`RestartableMachine`](crate::restart::RestartableMachine) to keep spans across them.
+    pub fn with_index<I>(mut self, index: I, offset: u64, interval: u64) -> Self
+    where
+        I: io::Write + Send + 'static,
+    {
+        self.index = Some(IndexWriter::new(Box::new(index), interval));
+        self.written = offset;
+        self
+    }
+
+    /// Asks for a restart every `interval` bytes, so that readers starting in the middle of the
+    /// tape soon find a point to start from. With
+    /// [`StringCache::with_snapshots`](crate::string_cache::StringCache::with_snapshots), every
+    /// restart is followed by all the strings cached so far.
+    ///
+    /// Wrap the store in a [`RestartableMachine`](crate::restart::RestartableMachine) to keep
+    /// spans across restarts.
+    pub fn with_restart_interval(mut self, interval: u64) -> Self {
+        self.restarts = Some(RestartInterval::new(interval));
+        self
+    }
+
+    /// Writes timestamps as the nanoseconds since the previous one, with an absolute one every
+    /// [`DELTA_TIME_ANCHOR`] and after every `Restart`, which saves several bytes per event.
+    /// The tape is written in format [`DELTA_TIME_VERSION`], which older readers can't read.
+    pub fn with_delta_time(mut self, delta: bool) -> Self {
+        self.delta = delta.then(Default::default);
+        self
     }
 
     pub fn do_handle(write: &mut W, instruction: Instruction) -> io::Result<()> {
-        let instruction = match instruction {
+        Self::do_handle_cached(write, Self::as_cached(instruction))
+    }
+
+    pub(crate) fn as_cached(instruction: Instruction) -> CacheInstruction {
+        match instruction {
             Instruction::Restart => CacheInstruction::Restart,
             Instruction::NewSpan { parent, span, name } => {
                 let name = CacheString::Present(name);
@@ -37,38 +240,103 @@ where
                 span,
                 target,
                 priority,
+                location,
+                thread,
             } => {
                 let target = CacheString::Present(target);
+                let location = location.map(|location| location.map(CacheString::Present));
+                let thread = thread.map(|thread| thread.map(CacheString::Present));
                 CacheInstruction::StartEvent {
                     time,
                     span,
                     target,
                     priority,
+                    location,
+                    thread,
                 }
             }
             Instruction::FinishedEvent => CacheInstruction::FinishedEvent,
             Instruction::AddValue(FieldValue { name, value }) => {
                 let name = CacheString::Present(name);
                 let value = match value {
-                    Value::Debug(str) => Value::String(CacheString::Present(str)),
+                    Value::Debug(str) => Value::Debug(CacheString::Present(str)),
                     Value::String(str) => Value::String(CacheString::Present(str)),
                     Value::Float(data) => Value::Float(data),
                     Value::Integer(data) => Value::Integer(data),
                     Value::Unsigned(data) => Value::Unsigned(data),
+                    Value::I128(data) => Value::I128(data),
+                    Value::U128(data) => Value::U128(data),
                     Value::Bool(data) => Value::Bool(data),
                     Value::ByteArray(items) => Value::ByteArray(items),
+                    Value::Custom { tag, data } => Value::Custom { tag, data },
+                    Value::Array(nested) => Value::Array(nested),
+                    Value::Map(nested) => Value::Map(nested),
+                    Value::Error { message, sources } => Value::Error {
+                        message: CacheString::Present(message),
+                        sources,
+                    },
                 };
 
                 CacheInstruction::AddValue(FieldValue { name, value })
             }
             Instruction::DeleteSpan(span) => CacheInstruction::DeleteSpan(span),
-        };
-
-        Self::do_handle_cached(write, instruction)
+            Instruction::FilterChanged(filter) => CacheInstruction::FilterChanged(filter),
+            Instruction::EnterSpan { time, span } => CacheInstruction::EnterSpan { time, span },
+            Instruction::ExitSpan { time, span } => CacheInstruction::ExitSpan { time, span },
+            Instruction::SpanTiming {
+                time,
+                span,
+                target,
+                priority,
+                busy,
+                idle,
+            } => CacheInstruction::SpanTiming {
+                time,
+                span,
+                target: CacheString::Present(target),
+                priority,
+                busy,
+                idle,
+            },
+        }
     }
 
     pub fn do_handle_cached(write: &mut W, instruction: CacheInstruction) -> io::Result<()> {
-        write.write_all(&[instruction.id().into()])?;
+        Self::do_write_cached(write, instruction)?;
+        write.flush()
+    }
+
+    /// Encodes the instruction without flushing.
+    pub fn do_write_cached(write: &mut W, instruction: CacheInstruction) -> io::Result<()> {
+        Self::write_cached(write, instruction, None)
+    }
+
+    /// Encodes the instruction without flushing, with timestamps relative to `delta`, see
+    /// [`with_delta_time`](Self::with_delta_time).
+    pub(crate) fn write_cached(
+        write: &mut W,
+        instruction: CacheInstruction,
+        delta: Option<&mut DeltaTime>,
+    ) -> io::Result<()> {
+        if let CacheInstruction::Restart = instruction {
+            // A single write, so that readers never see a `Restart` without its header
+            return match delta {
+                Some(delta) => {
+                    *delta = Default::default();
+                    write.write_all(&DELTA_RESTART_HEADER)
+                }
+                None => write.write_all(&RESTART_HEADER),
+            };
+        }
+
+        let id = instruction.id();
+        let byte = id.to_byte(FORMAT_VERSION).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{id:?} is missing from format version {FORMAT_VERSION}"),
+            )
+        })?;
+        write.write_all(&[byte])?;
         match instruction {
             CacheInstruction::Restart => (),
             CacheInstruction::NewString(data) => encode::write_str(write, data)?,
@@ -90,17 +358,31 @@ where
                 span,
                 target,
                 priority,
+                location,
+                thread,
             } => {
-                let time2 = time.timestamp_subsec_nanos();
-                let time = time.timestamp() as u64;
                 let span = span.map(Into::into).unwrap_or(0);
-                let priority = priority_num(priority);
+                let mut priority = priority_num(priority);
+                if location.is_some() {
+                    priority |= LOCATION_FLAG;
+                }
+                if thread.is_some() {
+                    priority |= THREAD_FLAG;
+                }
 
-                encode::write_uint(write, time)?;
-                encode::write_uint(write, time2 as u64)?;
+                Self::write_time(write, time, delta)?;
                 encode::write_uint(write, span)?;
                 Self::write_cache_str(write, target)?;
                 encode::write_uint(write, priority)?;
+                if let Some(location) = location {
+                    Self::write_cache_str(write, location.file)?;
+                    encode::write_uint(write, location.line.into())?;
+                    Self::write_cache_str(write, location.module_path)?;
+                }
+                if let Some(thread) = thread {
+                    encode::write_uint(write, thread.id)?;
+                    Self::write_cache_str(write, thread.name)?;
+                }
             }
             CacheInstruction::FinishedEvent => (),
             CacheInstruction::AddValue(field_value) => {
@@ -111,8 +393,120 @@ where
                 let span = span.into();
                 encode::write_uint(write, span)?;
             }
+            CacheInstruction::FilterChanged(filter) => encode::write_str(write, filter)?,
+            CacheInstruction::EnterSpan { time, span }
+            | CacheInstruction::ExitSpan { time, span } => {
+                Self::write_time(write, time, delta)?;
+                encode::write_uint(write, span.into())?;
+            }
+            CacheInstruction::SpanTiming {
+                time,
+                span,
+                target,
+                priority,
+                busy,
+                idle,
+            } => {
+                let nanos = |duration: Duration| duration.as_nanos().try_into().unwrap_or(u64::MAX);
+                Self::write_time(write, time, delta)?;
+                encode::write_uint(write, span.into())?;
+                Self::write_cache_str(write, target)?;
+                encode::write_uint(write, priority_num(priority))?;
+                encode::write_uint(write, nanos(busy))?;
+                encode::write_uint(write, nanos(idle))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_cached(&mut self, instruction: CacheInstruction) -> io::Result<()> {
+        if !self.checksums && self.index.is_none() && self.restarts.is_none() {
+            Store::write_cached(&mut self.out, instruction, self.delta.as_mut())?;
+        } else {
+            let frame = &mut self.frame;
+            frame.clear();
+            Store::write_cached(frame, instruction, self.delta.as_mut())?;
+            if self.checksums {
+                if let CacheInstruction::Restart = instruction {
+                    frame.push(CHECKSUMS_MARKER);
+                }
+                let crc = crc32fast::hash(frame);
+                frame.extend_from_slice(&crc.to_be_bytes());
+            }
+            if let Some(index) = self.index.as_mut() {
+                index.observe(self.written, instruction)?;
+            }
+            if let Some(restarts) = self.restarts.as_mut() {
+                restarts.observe(self.written, instruction);
+            }
+            self.written += frame.len() as u64;
+            self.out.write_all(frame)?;
+        }
+        if self.flush.should_flush(instruction.id()) {
+            self.out.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// Appends a chunk of [`TapeMachine::handle_encoded`] as it is, unless an option changes how
+    /// instructions are written. The chunk counts as one event for the [`FlushPolicy`].
+    fn handle_encoded(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let plain = !self.checksums
+            && self.index.is_none()
+            && self.restarts.is_none()
+            && self.delta.is_none();
+        let Some(instructions) = chunk.strip_prefix(&RESTART_HEADER).filter(|_| plain) else {
+            return InstructionSet::decode(chunk, |instruction| {
+                let _ = self.handle_cached(Self::as_cached(instruction));
+            });
+        };
+
+        let _ = self.out.write_all(instructions);
+        if self.flush.should_flush(InstructionId::FinishedEvent) {
+            let _ = self.out.flush();
+        }
+
+        Ok(())
+    }
+
+    /// Writes the nanoseconds since the previous timestamp as a msgpack integer, when `delta`
+    /// allows, or the timestamp itself.
+    fn write_time(
+        write: &mut W,
+        time: DateTime<Utc>,
+        delta: Option<&mut DeltaTime>,
+    ) -> io::Result<()> {
+        match delta.and_then(|delta| delta.next(time)) {
+            Some(nanos) => {
+                encode::write_sint(write, nanos)?;
+                Ok(())
+            }
+            None => Self::write_timestamp(write, time),
+        }
+    }
+
+    /// Writes the msgpack timestamp extension type.
+    fn write_timestamp(write: &mut W, time: DateTime<Utc>) -> io::Result<()> {
+        let secs = time.timestamp();
+        let nanos = time.timestamp_subsec_nanos();
+
+        match u64::try_from(secs) {
+            Ok(secs) if secs >> 34 == 0 && nanos == 0 && secs <= u32::MAX as u64 => {
+                encode::write_ext_meta(write, 4, TIMESTAMP_EXT)?;
+                write.write_all(&(secs as u32).to_be_bytes())?;
+            }
+            Ok(secs) if secs >> 34 == 0 => {
+                encode::write_ext_meta(write, 8, TIMESTAMP_EXT)?;
+                write.write_all(&((nanos as u64) << 34 | secs).to_be_bytes())?;
+            }
+            _ => {
+                encode::write_ext_meta(write, 12, TIMESTAMP_EXT)?;
+                write.write_all(&nanos.to_be_bytes())?;
+                write.write_all(&secs.to_be_bytes())?;
+            }
         }
-        write.flush()?;
 
         Ok(())
     }
@@ -128,6 +522,25 @@ where
         Ok(())
     }
 
+    /// Fixed ext markers are reserved for [`CacheIndex`], so custom values always use variable
+    /// length ext markers.
+    fn write_custom(write: &mut W, tag: i8, data: &[u8]) -> io::Result<()> {
+        let len = data.len();
+        if let Ok(len) = u8::try_from(len) {
+            write.write_all(&[Marker::Ext8.to_u8(), len])?;
+        } else if let Ok(len) = u16::try_from(len) {
+            write.write_all(&[Marker::Ext16.to_u8()])?;
+            write.write_all(&len.to_be_bytes())?;
+        } else {
+            write.write_all(&[Marker::Ext32.to_u8()])?;
+            write.write_all(&(len as u32).to_be_bytes())?;
+        }
+        write.write_all(&[tag as u8])?;
+        write.write_all(data)?;
+
+        Ok(())
+    }
+
     fn write_cache_value(write: &mut W, value: Value<CacheString>) -> io::Result<()> {
         match value {
             Value::Debug(str) => {
@@ -142,8 +555,37 @@ where
             Value::Unsigned(data) => {
                 encode::write_uint(write, data)?;
             }
+            Value::I128(data) => {
+                encode::write_ext_meta(write, 16, I128_EXT)?;
+                write.write_all(&data.to_be_bytes())?;
+            }
+            Value::U128(data) => {
+                encode::write_ext_meta(write, 16, U128_EXT)?;
+                write.write_all(&data.to_be_bytes())?;
+            }
             Value::Bool(data) => encode::write_bool(write, data)?,
             Value::ByteArray(data) => encode::write_bin(write, data)?,
+            Value::Custom { tag, data } => Self::write_custom(write, tag, data)?,
+            Value::Array(nested) => {
+                // A single element in a fixarray would be read back as a Debug value, see
+                // FORMAT_VERSION
+                if nested.len() == 1 {
+                    write.write_all(&[Marker::Array16.to_u8(), 0, 1])?;
+                } else {
+                    encode::write_array_len(write, nested.len())?;
+                }
+                write.write_all(nested.data())?;
+            }
+            Value::Map(nested) => {
+                encode::write_map_len(write, nested.len())?;
+                write.write_all(nested.data())?;
+            }
+            // Nil is not used by other values
+            Value::Error { message, sources } => {
+                encode::write_nil(write)?;
+                Self::write_cache_str(write, message)?;
+                Self::write_cache_value(write, Value::Array(sources))?;
+            }
         }
 
         Ok(())
@@ -154,11 +596,25 @@ where
     W: io::Write + Send + 'static,
 {
     fn needs_restart(&mut self) -> bool {
-        false
+        self.index
+            .as_ref()
+            .is_some_and(|index| index.needs_restart(self.written))
+            || self
+                .restarts
+                .as_ref()
+                .is_some_and(|restarts| restarts.needs_restart(self.written))
     }
 
     fn handle(&mut self, instruction: CacheInstruction) {
-        let _ = Self::do_handle_cached(&mut self.0, instruction);
+        let _ = self.handle_cached(instruction);
+    }
+
+    fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+
+    fn handle_encoded(&mut self, chunk: &[u8]) -> io::Result<()> {
+        Store::handle_encoded(self, chunk)
     }
 }
 impl<W> TapeMachine<InstructionSet> for Store<W>
@@ -166,60 +622,382 @@ where
     W: io::Write + Send + 'static,
 {
     fn needs_restart(&mut self) -> bool {
-        false
+        TapeMachine::<CacheInstructionSet>::needs_restart(self)
     }
 
     fn handle(&mut self, instruction: Instruction) {
-        let _ = Self::do_handle(&mut self.0, instruction);
+        let _ = self.handle_cached(Self::as_cached(instruction));
+    }
+
+    fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+
+    fn handle_encoded(&mut self, chunk: &[u8]) -> io::Result<()> {
+        Store::handle_encoded(self, chunk)
+    }
+}
+
+/// File opened by [`Load::open`].
+pub enum TapeFile {
+    Plain(File),
+    #[cfg(any(feature = "gzip", feature = "lz4"))]
+    Compressed(Box<CompressedTape>),
+}
+impl Read for TapeFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            TapeFile::Plain(file) => file.read(buf),
+            #[cfg(any(feature = "gzip", feature = "lz4"))]
+            TapeFile::Compressed(tape) => tape.read(buf),
+        }
+    }
+}
+impl Seek for TapeFile {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        match self {
+            TapeFile::Plain(file) => file.seek(pos),
+            #[cfg(any(feature = "gzip", feature = "lz4"))]
+            TapeFile::Compressed(tape) => tape.seek(pos),
+        }
+    }
+}
+
+/// Compressed tape, decompressed as it is read.
+///
+/// Seeking forward skips what is in between, while seeking backward or from the end decompresses
+/// the file again from its start.
+#[cfg(any(feature = "gzip", feature = "lz4"))]
+pub struct CompressedTape {
+    path: std::path::PathBuf,
+    compression: Compression,
+    decoder: Decoder<BufReader<File>>,
+    position: u64,
+}
+#[cfg(any(feature = "gzip", feature = "lz4"))]
+impl CompressedTape {
+    fn new(path: &Path, file: File, compression: Compression) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            compression,
+            decoder: Decoder::new(BufReader::new(file), compression),
+            position: 0,
+        }
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Skips up to `len` decompressed bytes, fewer at the end of the tape.
+    fn skip(&mut self, len: u64) -> io::Result<()> {
+        io::copy(&mut Read::take(self, len), &mut io::sink())?;
+        Ok(())
+    }
+}
+#[cfg(any(feature = "gzip", feature = "lz4"))]
+impl Read for CompressedTape {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.decoder.read(buf)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+}
+#[cfg(any(feature = "gzip", feature = "lz4"))]
+impl Seek for CompressedTape {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            io::SeekFrom::Start(offset) => Some(offset),
+            io::SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            io::SeekFrom::End(offset) => {
+                self.skip(u64::MAX)?;
+                self.position.checked_add_signed(offset)
+            }
+        };
+        let target = target.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the tape",
+            )
+        })?;
+
+        if target < self.position {
+            *self = Self::new(&self.path, File::open(&self.path)?, self.compression);
+        }
+        self.skip(target - self.position)?;
+        Ok(self.position)
+    }
+
+    fn stream_position(&mut self) -> io::Result<u64> {
+        Ok(self.position)
     }
 }
 
+/// What [`Load::forward`] does with an event cut by the end of the tape, as left by a writer that
+/// crashed mid-write.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Recovery {
+    /// Stops at the end of the tape, or returns the error of the cut instruction.
+    #[default]
+    Off,
+    /// Returns a [`PartialEvent`] error.
+    Report,
+    /// Finishes the event with the fields decoded so far, then returns a [`PartialEvent`] error.
+    Salvage,
+}
+
 pub struct Load<R> {
-    read: BufReader<R>,
+    read: TapeReader<R>,
     buf1: Vec<u8>,
     buf2: Vec<u8>,
+    buf3: Vec<u8>,
+    buf4: Vec<u8>,
     started: bool,
+    version: u8,
+    last_time: Option<DateTime<Utc>>,
+    index: Vec<IndexEntry>,
+    span_index: Vec<SpanIndexEntry>,
+    recovery: Recovery,
+    last_offset: u64,
+}
+impl Load<TapeFile> {
+    /// Opens a tape file, decompressing it when it was compressed by
+    /// [`Rotate::with_compression`](crate::rotate::Rotate). Its
+    /// [index](time_index::index_path) is read when present.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let index = match File::open(time_index::index_path(path.as_ref())) {
+            Ok(index) => time_index::read_index(index)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self::open_tape(path)?.with_index(index))
+    }
+
+    fn open_tape<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        if !rotate::is_compressed(path) {
+            return Ok(Self::new(TapeFile::Plain(file)));
+        }
+
+        #[cfg(any(feature = "gzip", feature = "lz4"))]
+        if let Some(compression) = Compression::of(path) {
+            let tape = CompressedTape::new(path, file, compression);
+            return Ok(Self::new(TapeFile::Compressed(Box::new(tape))));
+        }
+        Err(compression_disabled(path))
+    }
+}
+
+/// Decompresses a tape named `path`, compressed by [`Rotate::with_compression`](rotate::Rotate) or
+/// a [`CompressedStore`].
+pub(crate) fn decompress<R: Read>(read: R, path: &Path) -> io::Result<Vec<u8>> {
+    #[cfg(any(feature = "gzip", feature = "lz4"))]
+    if let Some(compression) = Compression::of(path) {
+        let mut data = Vec::new();
+        Decoder::new(read, compression).read_to_end(&mut data)?;
+        return Ok(data);
+    }
+    let _ = read;
+    Err(compression_disabled(path))
+}
+
+fn compression_disabled(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "{} is compressed, but the feature of its compression is disabled",
+            path.display()
+        ),
+    )
 }
 impl<R> Load<R>
 where
     R: io::Read,
 {
     pub fn new(input: R) -> Self {
+        Self::with_capacity(8 * 1024, input)
+    }
+
+    /// Reads the input in chunks of `capacity` bytes.
+    pub fn with_capacity(capacity: usize, input: R) -> Self {
         Self {
-            read: BufReader::new(input),
+            read: TapeReader {
+                read: BufReader::with_capacity(capacity, input),
+                crc: None,
+                offset: 0,
+            },
             buf1: Default::default(),
             buf2: Default::default(),
+            buf3: Default::default(),
+            buf4: Default::default(),
             started: false,
+            version: 0,
+            last_time: None,
+            index: Vec::new(),
+            span_index: Vec::new(),
+            recovery: Recovery::Off,
+            last_offset: 0,
         }
     }
 
+    /// How [`forward`](Self::forward) handles an event cut by the end of the tape.
+    pub fn with_recovery(mut self, recovery: Recovery) -> Self {
+        self.recovery = recovery;
+        self
+    }
+
+    /// Index used by [`seek_to_time`](Self::seek_to_time), sorted by offset.
+    pub fn with_index(mut self, index: Vec<IndexEntry>) -> Self {
+        self.index = index;
+        self
+    }
+
+    /// Span index used by [`seek_to_span`](Self::seek_to_span), sorted by span and offset, see
+    /// [`build_index`](time_index::build_index).
+    pub fn with_span_index(mut self, index: Vec<SpanIndexEntry>) -> Self {
+        self.span_index = index;
+        self
+    }
+
+    /// Format version given by the last `Restart`, 0 for tapes written before versions existed.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
     pub fn restart(&mut self) {
         self.started = false;
     }
 
+    /// Offset of the last fetched instruction from the start of the input, or of the
+    /// decompressed tape. Events are identified by the offset of their `StartEvent`, e.g. by
+    /// [annotations](crate::annotation).
+    pub fn last_offset(&self) -> u64 {
+        self.last_offset
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.read.read.get_ref()
+    }
+
+    /// Offset of the next byte to read, counted like [`last_offset`](Self::last_offset).
+    pub fn offset(&self) -> u64 {
+        self.read.offset
+    }
+
     pub fn forward<T>(&mut self, machine: &mut T) -> io::Result<()>
     where
         T: TapeMachine<InstructionSet>,
     {
-        while let Some(instruction) = self.fetch_one()? {
-            machine.handle(instruction);
-        }
+        let mut open = None;
+        let error = loop {
+            match self.fetch_one() {
+                Ok(Some(instruction)) => {
+                    open = Self::open_event(open, instruction.id());
+                    machine.handle(instruction);
+                }
+                Ok(None) => break None,
+                Err(e) => break Some(e),
+            }
+        };
 
-        Ok(())
+        self.end_of_tape(open, error, || machine.handle(Instruction::FinishedEvent))
     }
 
     pub fn forward_cached<T>(&mut self, machine: &mut T) -> io::Result<()>
     where
         T: TapeMachine<CacheInstructionSet>,
     {
-        while let Some(instruction) = self.fetch_one_cached()? {
-            machine.handle(instruction);
+        let mut open = None;
+        let error = loop {
+            match self.fetch_one_cached() {
+                Ok(Some(instruction)) => {
+                    open = Self::open_event(open, instruction.id());
+                    machine.handle(instruction);
+                }
+                Ok(None) => break None,
+                Err(e) => break Some(e),
+            }
+        };
+
+        self.end_of_tape(open, error, || {
+            machine.handle(CacheInstruction::FinishedEvent)
+        })
+    }
+
+    /// Number of fields of the event still open after `instruction`.
+    fn open_event(open: Option<usize>, instruction: InstructionId) -> Option<usize> {
+        match instruction {
+            InstructionId::StartEvent => Some(0),
+            InstructionId::AddValue => open.map(|fields| fields + 1),
+            InstructionId::FinishedEvent | InstructionId::Restart => None,
+            _ => open,
+        }
+    }
+
+    /// Result of forwarding once the input stopped, with `open` fields of an unfinished event,
+    /// applying the [`Recovery`] mode.
+    fn end_of_tape<F>(
+        &mut self,
+        open: Option<usize>,
+        error: Option<io::Error>,
+        finish: F,
+    ) -> io::Result<()>
+    where
+        F: FnOnce(),
+    {
+        let at_end = match &error {
+            None => true,
+            Some(_) => self.read.fill_buf()?.is_empty(),
+        };
+        let (Some(fields), true, false) = (open, at_end, self.recovery == Recovery::Off) else {
+            return error.map_or(Ok(()), Err);
+        };
+
+        if self.recovery == Recovery::Salvage {
+            finish();
         }
+        Err(PartialEvent { fields }.into())
+    }
 
-        Ok(())
+    /// Iterates the instructions of a tape written without a string cache, like
+    /// [`fetch_one`](Self::fetch_one). Ends after the first error.
+    pub fn iter(&mut self) -> impl Iterator<Item = io::Result<InstructionOwned>> + '_ {
+        let mut failed = false;
+        std::iter::from_fn(move || {
+            if failed {
+                return None;
+            }
+            let instruction = self.fetch_one().transpose()?;
+            failed = instruction.is_err();
+            Some(instruction.map(Instruction::to_owned))
+        })
+    }
+
+    /// Iterates the instructions of any tape, resolving cached strings like a [`StringUncache`].
+    /// Ends after the first error.
+    pub fn iter_cached(&mut self) -> impl Iterator<Item = io::Result<InstructionOwned>> + '_ {
+        let mut uncache = StringUncache::new(Collect::default());
+        let mut failed = false;
+        std::iter::from_fn(move || {
+            while !failed {
+                if let Some(instruction) = uncache.get_mut().0.pop_front() {
+                    return Some(Ok(instruction));
+                }
+                match self.fetch_one_cached() {
+                    Ok(Some(instruction)) => uncache.handle(instruction),
+                    Ok(None) => return None,
+                    Err(e) => {
+                        failed = true;
+                        return Some(Err(e));
+                    }
+                }
+            }
+            None
+        })
     }
 
-    pub fn fetch_one(&mut self) -> io::Result<Option<Instruction>> {
+    pub fn fetch_one(&mut self) -> io::Result<Option<Instruction<'_>>> {
         let Some(instruction) = self.fetch_one_cached()? else {
             return Ok(None);
         };
@@ -243,10 +1021,28 @@ where
                 span,
                 target,
                 priority,
+                location,
+                thread,
             } => {
-                let target = match target {
-                    CacheString::Present(str) => str,
-                    CacheString::Cached(_) => return Err(UnexpectedCached.into()),
+                let present = |str| match str {
+                    CacheString::Present(str) => Ok(str),
+                    CacheString::Cached(_) => Err(UnexpectedCached),
+                };
+                let target = present(target)?;
+                let location = match location {
+                    Some(location) => Some(Location {
+                        file: present(location.file)?,
+                        line: location.line,
+                        module_path: present(location.module_path)?,
+                    }),
+                    None => None,
+                };
+                let thread = match thread {
+                    Some(thread) => Some(ThreadInfo {
+                        id: thread.id,
+                        name: present(thread.name)?,
+                    }),
+                    None => None,
                 };
 
                 Instruction::StartEvent {
@@ -254,6 +1050,8 @@ where
                     span,
                     target,
                     priority,
+                    location,
+                    thread,
                 }
             }
             CacheInstruction::FinishedEvent => Instruction::FinishedEvent,
@@ -262,52 +1060,77 @@ where
                     CacheString::Present(str) => str,
                     CacheString::Cached(_) => return Err(UnexpectedCached.into()),
                 };
-                let value = match value {
-                    Value::Debug(CacheString::Present(str)) => Value::Debug(str),
-                    Value::Debug(CacheString::Cached(_)) => return Err(UnexpectedCached.into()),
-                    Value::String(CacheString::Present(str)) => Value::String(str),
-                    Value::String(CacheString::Cached(_)) => return Err(UnexpectedCached.into()),
-                    Value::Float(value) => Value::Float(value),
-                    Value::Integer(value) => Value::Integer(value),
-                    Value::Unsigned(value) => Value::Unsigned(value),
-                    Value::Bool(value) => Value::Bool(value),
-                    Value::ByteArray(items) => Value::ByteArray(items),
-                };
+                let value = present_value(value)?;
 
                 Instruction::AddValue(FieldValue { name, value })
             }
             CacheInstruction::DeleteSpan(span) => Instruction::DeleteSpan(span),
+            CacheInstruction::FilterChanged(filter) => Instruction::FilterChanged(filter),
+            CacheInstruction::EnterSpan { time, span } => Instruction::EnterSpan { time, span },
+            CacheInstruction::ExitSpan { time, span } => Instruction::ExitSpan { time, span },
+            CacheInstruction::SpanTiming {
+                time,
+                span,
+                target,
+                priority,
+                busy,
+                idle,
+            } => Instruction::SpanTiming {
+                time,
+                span,
+                target: present_str(target)?,
+                priority,
+                busy,
+                idle,
+            },
         }))
     }
 
-    pub fn fetch_one_cached(&mut self) -> io::Result<Option<CacheInstruction>> {
-        let instruction = loop {
+    pub fn fetch_one_cached(&mut self) -> io::Result<Option<CacheInstruction<'_>>> {
+        let id = loop {
             let Some(instruction) = self.read.fill_buf()?.first().copied() else {
                 return Ok(None);
             };
+            self.last_offset = self.read.offset;
             self.read.consume(1);
 
             if self.started {
                 break instruction;
             }
 
-            if instruction == u8::from(InstructionId::Restart) {
+            if instruction == InstructionId::RESTART {
                 self.started = true;
                 break instruction;
             }
         };
 
-        let instruction = InstructionId::try_from(instruction).map_err(|e| {
+        let instruction = InstructionId::from_byte(id, self.version).map_err(|e| {
             io::Error::new(io::ErrorKind::InvalidData, format!("bad instruction {e}"))
         })?;
 
-        Ok(Some(match instruction {
-            InstructionId::Restart => CacheInstruction::Restart,
-            InstructionId::NewString => CacheInstruction::NewString(self.read_str()?),
+        if let InstructionId::Restart = instruction {
+            // Only known to have checksums after the header
+            self.read.crc = Some(Default::default());
+        }
+        self.read.start_frame(id);
+
+        let instruction = match instruction {
+            InstructionId::Restart => {
+                self.version = Self::read_header(&mut self.read, &mut self.buf1)?;
+                self.last_time = None;
+                match self.read.fill_buf()?.first() {
+                    Some(&CHECKSUMS_MARKER) => self.read.consume(1),
+                    _ => self.read.crc = None,
+                }
+                CacheInstruction::Restart
+            }
+            InstructionId::NewString => {
+                CacheInstruction::NewString(Self::do_read_str(&mut self.read, &mut self.buf1)?)
+            }
             InstructionId::NewSpan => {
                 let parent: u64 = decode::read_int(&mut self.read).map_err(decode_err)?;
                 let span: u64 = decode::read_int(&mut self.read).map_err(decode_err)?;
-                let name = self.read_cache_str()?;
+                let name = Self::do_read_cache_str(&mut self.read, &mut self.buf1)?;
 
                 CacheInstruction::NewSpan {
                     parent: NonZeroU64::new(parent),
@@ -323,17 +1146,33 @@ where
             }
             InstructionId::FinishedRecord => CacheInstruction::FinishedRecord,
             InstructionId::StartEvent => {
-                let time: u64 = decode::read_int(&mut self.read).map_err(decode_err)?;
-                let time2: u64 = decode::read_int(&mut self.read).map_err(decode_err)?;
+                let time = Self::read_time(&mut self.read, self.version, &mut self.last_time)?;
                 let span = decode::read_int(&mut self.read).map_err(decode_err)?;
                 let target = Self::do_read_cache_str(&mut self.read, &mut self.buf1)?;
-                let priority = num_priority(decode::read_int(&mut self.read).map_err(decode_err)?);
+                let priority: u64 = decode::read_int(&mut self.read).map_err(decode_err)?;
+                let location = match priority & LOCATION_FLAG {
+                    0 => None,
+                    _ => Some(Location {
+                        file: Self::do_read_cache_str(&mut self.read, &mut self.buf2)?,
+                        line: decode::read_int(&mut self.read).map_err(decode_err)?,
+                        module_path: Self::do_read_cache_str(&mut self.read, &mut self.buf3)?,
+                    }),
+                };
+                let thread = match priority & THREAD_FLAG {
+                    0 => None,
+                    _ => Some(ThreadInfo {
+                        id: decode::read_int(&mut self.read).map_err(decode_err)?,
+                        name: Self::do_read_cache_str(&mut self.read, &mut self.buf4)?,
+                    }),
+                };
 
                 CacheInstruction::StartEvent {
-                    time: DateTime::from_timestamp(time as i64, time2 as u32).unwrap_or_default(),
+                    time,
                     span: NonZeroU64::new(span),
                     target,
-                    priority,
+                    priority: num_priority(priority & !(LOCATION_FLAG | THREAD_FLAG)),
+                    location,
+                    thread,
                 }
             }
             InstructionId::FinishedEvent => CacheInstruction::FinishedEvent,
@@ -347,14 +1186,129 @@ where
                 let span: u64 = decode::read_int(&mut self.read).map_err(decode_err)?;
                 CacheInstruction::DeleteSpan(NonZeroU64::new(span).ok_or(ZeroSpan)?)
             }
-        }))
+            InstructionId::FilterChanged => {
+                CacheInstruction::FilterChanged(Self::do_read_str(&mut self.read, &mut self.buf1)?)
+            }
+            InstructionId::EnterSpan => {
+                let time = Self::read_time(&mut self.read, self.version, &mut self.last_time)?;
+                let span: u64 = decode::read_int(&mut self.read).map_err(decode_err)?;
+                CacheInstruction::EnterSpan {
+                    time,
+                    span: NonZeroU64::new(span).ok_or(ZeroSpan)?,
+                }
+            }
+            InstructionId::ExitSpan => {
+                let time = Self::read_time(&mut self.read, self.version, &mut self.last_time)?;
+                let span: u64 = decode::read_int(&mut self.read).map_err(decode_err)?;
+                CacheInstruction::ExitSpan {
+                    time,
+                    span: NonZeroU64::new(span).ok_or(ZeroSpan)?,
+                }
+            }
+            InstructionId::SpanTiming => {
+                let time = Self::read_time(&mut self.read, self.version, &mut self.last_time)?;
+                let span: u64 = decode::read_int(&mut self.read).map_err(decode_err)?;
+                let target = Self::do_read_cache_str(&mut self.read, &mut self.buf1)?;
+                let priority: u64 = decode::read_int(&mut self.read).map_err(decode_err)?;
+                let busy = decode::read_int(&mut self.read).map_err(decode_err)?;
+                let idle = decode::read_int(&mut self.read).map_err(decode_err)?;
+
+                CacheInstruction::SpanTiming {
+                    time,
+                    span: NonZeroU64::new(span).ok_or(ZeroSpan)?,
+                    target,
+                    priority: num_priority(priority),
+                    busy: Duration::from_nanos(busy),
+                    idle: Duration::from_nanos(idle),
+                }
+            }
+        };
+
+        if let Err(e) = self.read.check_frame() {
+            // Skips to the next `Restart`
+            self.started = false;
+            return Err(e);
+        }
+
+        Ok(Some(instruction))
+    }
+
+    /// Reads the [`MAGIC`] and version following a `Restart`, if present.
+    fn read_header(read: &mut TapeReader<R>, buf: &mut Vec<u8>) -> io::Result<u8> {
+        match read.fill_buf()?.first().copied().map(Marker::from_u8) {
+            Some(Marker::FixStr(_) | Marker::Str8) => (),
+            _ => return Ok(0),
+        }
+
+        if Self::do_read_str(read, buf)? != MAGIC {
+            return Err(BadMagic.into());
+        }
+        let version = decode::read_int(read).map_err(decode_err)?;
+        if version > DELTA_TIME_VERSION {
+            return Err(UnsupportedVersion(version).into());
+        }
+
+        Ok(version)
     }
 
-    fn read_str(&mut self) -> io::Result<&str> {
-        Self::do_read_str(&mut self.read, &mut self.buf1)
+    /// Reads the msgpack timestamp extension type, the nanoseconds since `last` from
+    /// [`DELTA_TIME_VERSION`] on, or the legacy seconds and nanoseconds pair before.
+    fn read_time(
+        read: &mut TapeReader<R>,
+        version: u8,
+        last: &mut Option<DateTime<Utc>>,
+    ) -> io::Result<DateTime<Utc>> {
+        let (secs, nanos) = match Self::do_peek_marker(read)? {
+            Marker::FixExt4 | Marker::FixExt8 | Marker::Ext8 => {
+                let meta = decode::read_ext_meta(&mut *read).map_err(decode_err)?;
+                if meta.typeid != TIMESTAMP_EXT {
+                    return Err(decode_err(format!("Unexpected ext type {}", meta.typeid)));
+                }
+
+                match meta.size {
+                    4 => {
+                        let mut data = [0; 4];
+                        read.read_exact(&mut data)?;
+                        (u32::from_be_bytes(data) as i64, 0)
+                    }
+                    8 => {
+                        let mut data = [0; 8];
+                        read.read_exact(&mut data)?;
+                        let data = u64::from_be_bytes(data);
+                        ((data & 0x3_ffff_ffff) as i64, (data >> 34) as u32)
+                    }
+                    12 => {
+                        let mut nanos = [0; 4];
+                        let mut secs = [0; 8];
+                        read.read_exact(&mut nanos)?;
+                        read.read_exact(&mut secs)?;
+                        (i64::from_be_bytes(secs), u32::from_be_bytes(nanos))
+                    }
+                    size => return Err(decode_err(format!("Bad timestamp size {size}"))),
+                }
+            }
+            _ if version >= DELTA_TIME_VERSION => {
+                let nanos: i64 = decode::read_int(&mut *read).map_err(decode_err)?;
+                let time = last
+                    .ok_or_else(|| decode_err("Time delta without a previous time"))?
+                    .checked_add_signed(TimeDelta::nanoseconds(nanos))
+                    .ok_or_else(|| decode_err("Time delta out of range"))?;
+                *last = Some(time);
+                return Ok(time);
+            }
+            _ => {
+                let secs: u64 = decode::read_int(&mut *read).map_err(decode_err)?;
+                let nanos: u64 = decode::read_int(&mut *read).map_err(decode_err)?;
+                (secs as i64, nanos as u32)
+            }
+        };
+
+        let time = DateTime::from_timestamp(secs, nanos).unwrap_or_default();
+        *last = Some(time);
+        Ok(time)
     }
 
-    fn do_read_str<'a>(read: &mut BufReader<R>, buf: &'a mut Vec<u8>) -> io::Result<&'a str> {
+    fn do_read_str<'a>(read: &mut TapeReader<R>, buf: &'a mut Vec<u8>) -> io::Result<&'a str> {
         let len = decode::read_str_len(read).map_err(decode_err)?;
         buf.resize(len as usize, 0);
         read.read_exact(buf.as_mut_slice())?;
@@ -363,7 +1317,7 @@ where
     }
 
     fn do_read_value<'a>(
-        read: &mut BufReader<R>,
+        read: &mut TapeReader<R>,
         buf: &'a mut Vec<u8>,
     ) -> io::Result<Value<'a, CacheString<'a>>> {
         Ok(match Self::do_peek_marker(read)? {
@@ -385,14 +1339,83 @@ where
             | Marker::FixExt2
             | Marker::FixExt4
             | Marker::FixExt8 => Value::String(Self::do_read_cache_str(read, buf)?),
-            Marker::False => Value::Bool(false),
-            Marker::True => Value::Bool(true),
+            Marker::False => {
+                read.consume(1);
+                Value::Bool(false)
+            }
+            Marker::True => {
+                read.consume(1);
+                Value::Bool(true)
+            }
             Marker::Bin8 | Marker::Bin16 | Marker::Bin32 => {
                 let n = decode::read_bin_len(read).map_err(decode_err)?;
                 buf.resize(n as usize, 0);
                 read.read_exact(buf)?;
                 Value::ByteArray(buf)
             }
+            Marker::Ext8 | Marker::Ext16 | Marker::Ext32 => {
+                let meta = decode::read_ext_meta(read).map_err(decode_err)?;
+                buf.resize(meta.size as usize, 0);
+                read.read_exact(buf)?;
+                Value::Custom {
+                    tag: meta.typeid,
+                    data: buf,
+                }
+            }
+            Marker::Null => {
+                read.consume(1);
+                // The message and the sources share the buffer
+                buf.clear();
+                let cached = match Self::do_peek_marker(read)? {
+                    Marker::FixStr(_) | Marker::Str8 | Marker::Str16 | Marker::Str32 => {
+                        let len = decode::read_str_len(read).map_err(decode_err)?;
+                        buf.resize(len as usize, 0);
+                        read.read_exact(buf)?;
+                        None
+                    }
+                    Marker::FixExt1 | Marker::FixExt2 | Marker::FixExt4 | Marker::FixExt8 => {
+                        Some(CacheIndex::read(&mut *read)?.into())
+                    }
+                    marker => return Err(UnexpectedMarker(marker).into()),
+                };
+                let message_len = buf.len();
+                let len = decode::read_array_len(read).map_err(decode_err)?;
+                for _ in 0..len {
+                    copy_nested(read, buf, 1)?;
+                }
+
+                let (message, sources) = buf.split_at(message_len);
+                let message = match cached {
+                    Some(index) => CacheString::Cached(index),
+                    None => CacheString::Present(std::str::from_utf8(message).map_err(decode_err)?),
+                };
+                Value::Error {
+                    message,
+                    sources: Nested::new(len, sources),
+                }
+            }
+            Marker::FixExt16 => {
+                let meta = decode::read_ext_meta(read).map_err(decode_err)?;
+                let mut data = [0; 16];
+                read.read_exact(&mut data)?;
+                wide_value(meta.typeid, data)?
+            }
+            Marker::FixArray(_) | Marker::Array16 | Marker::Array32 => {
+                let len = decode::read_array_len(read).map_err(decode_err)?;
+                buf.clear();
+                for _ in 0..len {
+                    copy_nested(read, buf, 1)?;
+                }
+                Value::Array(Nested::new(len, buf))
+            }
+            Marker::FixMap(_) | Marker::Map16 | Marker::Map32 => {
+                let len = decode::read_map_len(read).map_err(decode_err)?;
+                buf.clear();
+                for _ in 0..len * 2 {
+                    copy_nested(read, buf, 1)?;
+                }
+                Value::Map(Nested::new(len, buf))
+            }
             Marker::F32 => Value::Float(decode::read_f32(read).map_err(decode_err)? as f64),
             Marker::F64 => Value::Float(decode::read_f64(read).map_err(decode_err)?),
             Marker::U8 | Marker::U16 | Marker::U32 | Marker::U64 => {
@@ -402,12 +1425,8 @@ where
         })
     }
 
-    fn read_cache_str(&mut self) -> io::Result<CacheString> {
-        Self::do_read_cache_str(&mut self.read, &mut self.buf1)
-    }
-
     fn do_read_cache_str<'a>(
-        read: &mut BufReader<R>,
+        read: &mut TapeReader<R>,
         buf: &'a mut Vec<u8>,
     ) -> io::Result<CacheString<'a>> {
         Ok(match Self::do_peek_marker(read)? {
@@ -421,13 +1440,490 @@ where
         })
     }
 
-    fn do_peek_marker(read: &mut BufReader<R>) -> io::Result<Marker> {
+    fn do_peek_marker(read: &mut TapeReader<R>) -> io::Result<Marker> {
         let marker = read.fill_buf()?.first().ok_or(EofOnMarker)?;
 
         Ok(Marker::from_u8(*marker))
     }
+
+    /// Counts the complete events up to the end, or `None` if they can't be decoded.
+    fn count_complete_events(&mut self) -> io::Result<Option<usize>> {
+        let mut count = 0;
+        let mut structure = Structure::default();
+        loop {
+            match Self::until_partial(self.fetch_one_cached()) {
+                Ok(Some(instruction)) if !structure.check(instruction) => return Ok(None),
+                Ok(Some(CacheInstruction::FinishedEvent)) => count += 1,
+                Ok(Some(_)) => (),
+                Ok(None) => return Ok(Some(count)),
+                Err(e) if e.kind() == io::ErrorKind::InvalidInput => return Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Treats an instruction cut by the end of the input as the end of the input.
+    fn until_partial<T>(result: io::Result<Option<T>>) -> io::Result<Option<T>> {
+        match result {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            result => result,
+        }
+    }
 }
 
+impl<R> Load<R>
+where
+    R: io::Read + io::Seek,
+{
+    const SCAN_CHUNK: u64 = 64 * 1024;
+
+    /// Jumps to the last `Restart` of the index whose first event is not after `time`, or to the
+    /// beginning without one. Events before `time` may still follow, up to the next indexed
+    /// `Restart`.
+    pub fn seek_to_time(&mut self, time: DateTime<Utc>) -> io::Result<()> {
+        let after = self.index.partition_point(|entry| entry.time <= time);
+        let offset = match after.checked_sub(1) {
+            Some(entry) => self.index[entry].offset,
+            None => 0,
+        };
+
+        self.read.seek(io::SeekFrom::Start(offset))?;
+        self.started = false;
+
+        Ok(())
+    }
+
+    /// Jumps to the `Restart` preceding the creation of the first span with id `span`, returning
+    /// whether the span index has it.
+    pub fn seek_to_span(&mut self, span: NonZeroU64) -> io::Result<bool> {
+        let entry = self.span_index.partition_point(|entry| entry.span < span);
+        let Some(entry) = self
+            .span_index
+            .get(entry)
+            .filter(|entry| entry.span == span)
+        else {
+            return Ok(false);
+        };
+
+        self.read.seek(io::SeekFrom::Start(entry.offset))?;
+        self.started = false;
+
+        Ok(true)
+    }
+
+    /// Forwards only the last `n` events of the input, along with everything needed to decode them
+    /// (strings and spans).
+    ///
+    /// Instead of decoding from the beginning, looks for a `Restart` followed by at least `n`
+    /// events, twice as far from the end at every try, using the restarts of the index if there
+    /// is one. A partially written event at the end of the input is ignored.
+    pub fn last_events<T>(&mut self, n: usize, machine: &mut T) -> io::Result<()>
+    where
+        T: TapeMachine<CacheInstructionSet>,
+    {
+        let (start, count) = self.last_events_start(n)?;
+
+        self.read.seek(io::SeekFrom::Start(start))?;
+        self.started = false;
+
+        let mut skip = count.saturating_sub(n);
+        let mut skipping = false;
+        while let Some(count) = self.complete_unit()? {
+            for _ in 0..count {
+                let Some(instruction) = self.fetch_one_cached()? else {
+                    break;
+                };
+                match instruction {
+                    CacheInstruction::StartEvent { .. } if skip > 0 => {
+                        skip -= 1;
+                        skipping = true;
+                    }
+                    CacheInstruction::AddValue(..) if skipping => (),
+                    CacheInstruction::FinishedEvent if skipping => skipping = false,
+                    instruction => machine.handle(instruction),
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forwards every complete instruction, stopping before a partially written event, span or
+    /// record. Calling it again resumes from there, which allows following a file being written.
+    ///
+    /// Writers should use [`FlushPolicy::EveryEvent`], so that readers rarely see partial events.
+    pub fn forward_complete<T>(&mut self, machine: &mut T) -> io::Result<()>
+    where
+        T: TapeMachine<CacheInstructionSet>,
+    {
+        while let Some(count) = self.complete_unit()? {
+            for _ in 0..count {
+                let Some(instruction) = self.fetch_one_cached()? else {
+                    break;
+                };
+                machine.handle(instruction);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Number of instructions of the next event, span, record or standalone instruction, if it
+    /// is completely written. The input is left at the same position.
+    fn complete_unit(&mut self) -> io::Result<Option<usize>> {
+        let start = self.read.stream_position()?;
+        let started = self.started;
+        let crc = self.read.crc.clone();
+        let (version, last_time) = (self.version, self.last_time);
+
+        let mut count = 0;
+        let mut open = false;
+        let complete = loop {
+            let Some(instruction) = Self::until_partial(self.fetch_one_cached())? else {
+                break false;
+            };
+            count += 1;
+
+            match instruction.id() {
+                InstructionId::NewSpan | InstructionId::NewRecord | InstructionId::StartEvent => {
+                    open = true
+                }
+                InstructionId::FinishedSpan
+                | InstructionId::FinishedRecord
+                | InstructionId::FinishedEvent => open = false,
+                _ => (),
+            }
+            if !open {
+                break true;
+            }
+        };
+
+        let end = self.read.stream_position()?;
+        self.read.seek_relative(start as i64 - end as i64)?;
+        self.started = started;
+        self.read.crc = crc;
+        (self.version, self.last_time) = (version, last_time);
+
+        Ok(complete.then_some(count))
+    }
+
+    /// Offset of a `Restart` followed by at least `n` complete events, with their number, or the
+    /// beginning of the input.
+    ///
+    /// The restarts of the index are tried going back 1, 2, 4... entries from the last one.
+    /// Without an index, the bytes that look like a `Restart` are tried from the first one of the
+    /// last [`SCAN_CHUNK`] bytes, until one can be decoded, then of the twice as many bytes before
+    /// those and so on. Either way, about twice the bytes holding the last `n` events are decoded,
+    /// instead of the whole input for every `Restart` before them.
+    ///
+    /// [`SCAN_CHUNK`]: Self::SCAN_CHUNK
+    fn last_events_start(&mut self, n: usize) -> io::Result<(u64, usize)> {
+        if !self.index.is_empty() {
+            let mut back = 1;
+            while back <= self.index.len() {
+                let offset = self.index[self.index.len() - back].offset;
+                if let Some(count) = self.count_events(offset)?
+                    && count >= n
+                {
+                    return Ok((offset, count));
+                }
+                back *= 2;
+            }
+        } else {
+            let end = self.read.seek(io::SeekFrom::End(0))?;
+            let mut window = Self::SCAN_CHUNK;
+            let mut scanned = end;
+            while scanned > 0 {
+                let from = end.saturating_sub(window);
+                if let Some((start, count)) = self.first_restart(from, scanned)?
+                    && count >= n
+                {
+                    return Ok((start, count));
+                }
+                (scanned, window) = (from, window * 2);
+            }
+        }
+
+        Ok((0, self.count_events(0)?.unwrap_or_default()))
+    }
+
+    /// First byte from `from` to `before` that looks like a `Restart` instruction and is followed
+    /// by complete events, with their number.
+    fn first_restart(&mut self, from: u64, before: u64) -> io::Result<Option<(u64, usize)>> {
+        let mut start = from;
+        let mut chunk = Vec::new();
+
+        while start < before {
+            let end = before.min(start + Self::SCAN_CHUNK);
+            chunk.resize((end - start) as usize, 0);
+            self.read.seek(io::SeekFrom::Start(start))?;
+            self.read.read_exact(&mut chunk)?;
+
+            let restart = InstructionId::RESTART;
+            let candidates = chunk
+                .iter()
+                .enumerate()
+                .filter(|(_, byte)| **byte == restart);
+            for (position, _) in candidates {
+                // Most bytes that only look like a `Restart` are told apart by the rest of the
+                // chunk, without reading the input again
+                let mut rest = Load::new(&chunk[position..]);
+                if rest.count_complete_events()?.is_none() {
+                    continue;
+                }
+
+                let candidate = start + position as u64;
+                if let Some(count) = self.count_events(candidate)? {
+                    return Ok(Some((candidate, count)));
+                }
+            }
+            start = end;
+        }
+
+        Ok(None)
+    }
+
+    /// Counts the complete events from `offset` to the end, or `None` if it can't be decoded.
+    fn count_events(&mut self, offset: u64) -> io::Result<Option<usize>> {
+        self.read.seek(io::SeekFrom::Start(offset))?;
+        self.started = false;
+        self.count_complete_events()
+    }
+}
+
+/// Follows a `Restart` written by [`Store::with_checksums`]. Every instruction up to the next
+/// `Restart`, this one included, is followed by the big-endian CRC32 of its bytes.
+///
+/// Never used by msgpack nor as an instruction id.
+const CHECKSUMS_MARKER: u8 = 0xc1;
+
+/// Buffered input of [`Load`], computing the checksum of the consumed bytes.
+struct TapeReader<R> {
+    read: BufReader<R>,
+    crc: Option<crc32fast::Hasher>,
+    offset: u64,
+}
+impl<R> TapeReader<R>
+where
+    R: io::Read,
+{
+    /// Starts the checksum of an instruction, if the tape has checksums.
+    fn start_frame(&mut self, id: u8) {
+        if let Some(crc) = self.crc.as_mut() {
+            crc.reset();
+            crc.update(&[id]);
+        }
+    }
+
+    /// Reads and verifies the checksum of the instruction, if the tape has checksums.
+    fn check_frame(&mut self) -> io::Result<()> {
+        let Some(crc) = self.crc.take() else {
+            return Ok(());
+        };
+        let mut expected = [0; 4];
+        let read = self.read.read_exact(&mut expected);
+        let actual = crc.clone().finalize();
+        self.crc = Some(crc);
+        read?;
+
+        if u32::from_be_bytes(expected) != actual {
+            return Err(ChecksumMismatch.into());
+        }
+
+        Ok(())
+    }
+}
+impl<R> Read for TapeReader<R>
+where
+    R: io::Read,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.read.read(buf)?;
+        if let Some(crc) = self.crc.as_mut() {
+            crc.update(&buf[..n]);
+        }
+        self.offset += n as u64;
+
+        Ok(n)
+    }
+}
+impl<R> BufRead for TapeReader<R>
+where
+    R: io::Read,
+{
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.read.fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        if let Some(crc) = self.crc.as_mut() {
+            crc.update(&self.read.buffer()[..amount]);
+        }
+        self.read.consume(amount);
+        self.offset += amount as u64;
+    }
+}
+impl<R> Seek for TapeReader<R>
+where
+    R: io::Seek,
+{
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.offset = self.read.seek(pos)?;
+        Ok(self.offset)
+    }
+
+    fn stream_position(&mut self) -> io::Result<u64> {
+        self.read.stream_position()
+    }
+
+    fn seek_relative(&mut self, offset: i64) -> io::Result<()> {
+        self.read.seek_relative(offset)?;
+        self.offset = self.offset.wrapping_add_signed(offset);
+        Ok(())
+    }
+}
+
+/// Checks that a sequence of instructions is consistent, to tell apart a real `Restart` from a
+/// byte that only looks like one.
+#[derive(Default)]
+struct Structure {
+    open: Option<InstructionId>,
+    strings: u64,
+}
+impl Structure {
+    fn check(&mut self, instruction: CacheInstruction) -> bool {
+        let defined = |str: CacheString| match str {
+            CacheString::Present(_) => true,
+            CacheString::Cached(index) => index < self.strings,
+        };
+        let strings_defined = match instruction {
+            CacheInstruction::NewSpan { name, .. } => defined(name),
+            CacheInstruction::StartEvent {
+                target,
+                location,
+                thread,
+                ..
+            } => {
+                defined(target)
+                    && location.is_none_or(|location| {
+                        defined(location.file) && defined(location.module_path)
+                    })
+                    && thread.is_none_or(|thread| defined(thread.name))
+            }
+            CacheInstruction::AddValue(FieldValue { name, value }) => {
+                defined(name)
+                    && match value {
+                        Value::Debug(str) | Value::String(str) => defined(str),
+                        _ => true,
+                    }
+            }
+            _ => true,
+        };
+        if !strings_defined {
+            return false;
+        }
+
+        let id = instruction.id();
+        let expected_open = match id {
+            InstructionId::Restart => {
+                self.strings = 0;
+                self.open = None;
+                return true;
+            }
+            InstructionId::NewString => {
+                self.strings += 1;
+                return true;
+            }
+            InstructionId::AddValue => return self.open.is_some(),
+            InstructionId::FinishedSpan => Some(InstructionId::NewSpan),
+            InstructionId::FinishedRecord => Some(InstructionId::NewRecord),
+            InstructionId::FinishedEvent => Some(InstructionId::StartEvent),
+            _ => None,
+        };
+
+        let open = std::mem::take(&mut self.open);
+        let valid = match (open, expected_open) {
+            (None, None) => true,
+            (Some(open), Some(expected)) => open == expected,
+            _ => false,
+        };
+        if matches!(
+            id,
+            InstructionId::NewSpan | InstructionId::NewRecord | InstructionId::StartEvent
+        ) {
+            self.open = Some(id);
+        }
+
+        valid
+    }
+}
+
+/// Identifies a tape, written after every `Restart` followed by the [`FORMAT_VERSION`].
+pub const MAGIC: &str = "msgpack-tracing";
+
+/// Version of the format written by [`Store`]. Readers refuse tapes with versions newer than
+/// [`DELTA_TIME_VERSION`].
+///
+/// Version 2 renumbered the instructions, see [`InstructionId::to_byte`].
+///
+/// Values are msgpack, except that a fixarray of one element holds a `Debug` string and nil
+/// starts an `Error`. An `Array` of one element is therefore always written with the `Array16`
+/// marker, which readers must keep decoding as an array.
+pub const FORMAT_VERSION: u8 = 2;
+
+/// Version of the format written by [`Store::with_delta_time`]. Timestamps are either the msgpack
+/// timestamp extension type or, as msgpack integers, the nanoseconds since the previous timestamp
+/// after the same `Restart`.
+pub const DELTA_TIME_VERSION: u8 = 3;
+
+/// Timestamps written as deltas by [`Store::with_delta_time`] before an absolute one.
+pub const DELTA_TIME_ANCHOR: u32 = 1024;
+
+/// `Restart` followed by [`MAGIC`] as a msgpack string and [`FORMAT_VERSION`] as a msgpack
+/// integer.
+pub(crate) const RESTART_HEADER: [u8; MAGIC.len() + 3] = restart_header(FORMAT_VERSION);
+
+/// [`RESTART_HEADER`] of [`DELTA_TIME_VERSION`].
+const DELTA_RESTART_HEADER: [u8; MAGIC.len() + 3] = restart_header(DELTA_TIME_VERSION);
+
+const fn restart_header(version: u8) -> [u8; MAGIC.len() + 3] {
+    let mut header = [0; MAGIC.len() + 3];
+    header[0] = InstructionId::RESTART;
+    header[1] = 0xa0 | MAGIC.len() as u8;
+    let mut i = 0;
+    while i < MAGIC.len() {
+        header[2 + i] = MAGIC.as_bytes()[i];
+        i += 1;
+    }
+    header[MAGIC.len() + 2] = version;
+    header
+}
+
+/// Msgpack extension type reserved for timestamps.
+const TIMESTAMP_EXT: i8 = -1;
+
+/// Extension types of 128 bits integers, big endian. They are the only fixext16 values, as
+/// custom values always use variable length ext markers.
+const I128_EXT: i8 = 0;
+const U128_EXT: i8 = 1;
+
+fn wide_value<'a, S>(typeid: i8, data: [u8; 16]) -> io::Result<Value<'a, S>> {
+    match typeid {
+        I128_EXT => Ok(Value::I128(i128::from_be_bytes(data))),
+        U128_EXT => Ok(Value::U128(u128::from_be_bytes(data))),
+        typeid => Err(decode_err(format!("Unexpected ext type {typeid}"))),
+    }
+}
+
+/// Set in the priority of a [`StartEvent`](InstructionId::StartEvent) followed by its
+/// [`Location`].
+const LOCATION_FLAG: u64 = 0x10;
+
+/// Set in the priority of a [`StartEvent`](InstructionId::StartEvent) followed by its
+/// [`ThreadInfo`], after the location.
+const THREAD_FLAG: u64 = 0x20;
+
 pub fn priority_num(level: Level) -> u64 {
     match level {
         Level::TRACE => 0,
@@ -449,8 +1945,303 @@ pub fn num_priority(num: u64) -> Level {
     }
 }
 
-fn decode_err<E: ToString>(error: E) -> io::Error {
-    io::Error::new(io::ErrorKind::InvalidInput, error.to_string())
+/// Instructions resolved by [`Load::iter_cached`], not yet returned.
+#[derive(Default)]
+struct Collect(VecDeque<InstructionOwned>);
+impl TapeMachine<InstructionSet> for Collect {
+    fn needs_restart(&mut self) -> bool {
+        false
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        self.0.push_back(instruction.to_owned());
+    }
+}
+
+/// Decoding errors are `InvalidInput`, unless caused by reaching the end of the input.
+pub(crate) fn decode_err<E: ToString + 'static>(error: E) -> io::Error {
+    let any = &error as &dyn Any;
+    let source = match any.downcast_ref::<decode::ValueReadError>() {
+        Some(decode::ValueReadError::InvalidMarkerRead(e))
+        | Some(decode::ValueReadError::InvalidDataRead(e)) => Some(e),
+        _ => match any.downcast_ref::<decode::NumValueReadError>() {
+            Some(decode::NumValueReadError::InvalidMarkerRead(e))
+            | Some(decode::NumValueReadError::InvalidDataRead(e)) => Some(e),
+            _ => None,
+        },
+    };
+    let kind = match source.map(io::Error::kind) {
+        Some(io::ErrorKind::UnexpectedEof) => io::ErrorKind::UnexpectedEof,
+        _ => io::ErrorKind::InvalidInput,
+    };
+
+    io::Error::new(kind, error.to_string())
+}
+
+/// Deepest nesting of arrays and maps that is decoded.
+const MAX_NESTING: usize = 64;
+
+/// Copies the next msgpack object of `read` into `out`, along with its elements.
+fn copy_nested<R: Read, W: io::Write>(read: &mut R, out: &mut W, depth: usize) -> io::Result<()> {
+    if depth > MAX_NESTING {
+        return Err(decode_err("Values nested too deep"));
+    }
+
+    let mut header = [0; 6];
+    read.read_exact(&mut header[..1])?;
+    let marker = Marker::from_u8(header[0]);
+    let (header_len, len_len) = match marker {
+        Marker::Str8 | Marker::Bin8 => (2, 1),
+        Marker::Str16 | Marker::Bin16 | Marker::Array16 | Marker::Map16 => (3, 2),
+        Marker::Str32 | Marker::Bin32 | Marker::Array32 | Marker::Map32 => (5, 4),
+        Marker::Ext8 => (3, 1),
+        Marker::Ext16 => (4, 2),
+        Marker::Ext32 => (6, 4),
+        _ => (1, 0),
+    };
+    read.read_exact(&mut header[1..header_len])?;
+    out.write_all(&header[..header_len])?;
+    let len = header[1..1 + len_len]
+        .iter()
+        .fold(0, |len, &byte| len << 8 | byte as u64);
+
+    let (bytes, elements) = match marker {
+        Marker::FixPos(_) | Marker::FixNeg(_) | Marker::True | Marker::False => (0, 0),
+        // Errors are nil followed by their message and sources
+        Marker::Null => (0, 2),
+        Marker::U8 | Marker::I8 => (1, 0),
+        Marker::U16 | Marker::I16 => (2, 0),
+        Marker::U32 | Marker::I32 | Marker::F32 => (4, 0),
+        Marker::U64 | Marker::I64 | Marker::F64 => (8, 0),
+        Marker::FixExt1 => (2, 0),
+        Marker::FixExt2 => (3, 0),
+        Marker::FixExt4 => (5, 0),
+        Marker::FixExt8 => (9, 0),
+        Marker::FixExt16 => (17, 0),
+        Marker::FixStr(len) => (len as u64, 0),
+        Marker::Str8
+        | Marker::Str16
+        | Marker::Str32
+        | Marker::Bin8
+        | Marker::Bin16
+        | Marker::Bin32
+        | Marker::Ext8
+        | Marker::Ext16
+        | Marker::Ext32 => (len, 0),
+        Marker::FixArray(len) => (0, len as u64),
+        Marker::Array16 | Marker::Array32 => (0, len),
+        Marker::FixMap(len) => (0, len as u64 * 2),
+        Marker::Map16 | Marker::Map32 => (0, len * 2),
+        marker => return Err(UnexpectedMarker(marker).into()),
+    };
+
+    if io::copy(&mut read.take(bytes), out)? < bytes {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    for _ in 0..elements {
+        copy_nested(read, out, depth + 1)?;
+    }
+
+    Ok(())
+}
+
+/// Takes the next `len` bytes of `data`.
+fn take_nested<'a>(data: &mut &'a [u8], len: u32) -> io::Result<&'a [u8]> {
+    if data.len() < len as usize {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let (taken, rest) = data.split_at(len as usize);
+    *data = rest;
+    Ok(taken)
+}
+
+/// Decodes a string of the elements of a [`Nested`] value, advancing `data`.
+pub(crate) fn read_nested_str<'a>(data: &mut &'a [u8]) -> io::Result<CacheString<'a>> {
+    let marker = Marker::from_u8(*data.first().ok_or(EofOnMarker)?);
+    Ok(match marker {
+        Marker::FixStr(_) | Marker::Str8 | Marker::Str16 | Marker::Str32 => {
+            let len = decode::read_str_len(data).map_err(decode_err)?;
+            let str = std::str::from_utf8(take_nested(data, len)?).map_err(decode_err)?;
+            CacheString::Present(str)
+        }
+        Marker::FixExt1 | Marker::FixExt2 | Marker::FixExt4 | Marker::FixExt8 => {
+            CacheString::Cached(CacheIndex::read(data)?.into())
+        }
+        marker => return Err(UnexpectedMarker(marker).into()),
+    })
+}
+
+/// Decodes a value of the elements of a [`Nested`] value, advancing `data`. Same encoding as the
+/// values of fields.
+pub(crate) fn read_nested_value<'a>(data: &mut &'a [u8]) -> io::Result<Value<'a, CacheString<'a>>> {
+    let marker = Marker::from_u8(*data.first().ok_or(EofOnMarker)?);
+    Ok(match marker {
+        Marker::FixArray(1) => {
+            *data = &data[1..];
+            Value::Debug(read_nested_str(data)?)
+        }
+        Marker::FixPos(_)
+        | Marker::FixNeg(_)
+        | Marker::I8
+        | Marker::I16
+        | Marker::I32
+        | Marker::I64 => Value::Integer(decode::read_int(data).map_err(decode_err)?),
+        Marker::FixStr(_)
+        | Marker::Str8
+        | Marker::Str16
+        | Marker::Str32
+        | Marker::FixExt1
+        | Marker::FixExt2
+        | Marker::FixExt4
+        | Marker::FixExt8 => Value::String(read_nested_str(data)?),
+        Marker::False | Marker::True => {
+            *data = &data[1..];
+            Value::Bool(marker == Marker::True)
+        }
+        Marker::Bin8 | Marker::Bin16 | Marker::Bin32 => {
+            let len = decode::read_bin_len(data).map_err(decode_err)?;
+            Value::ByteArray(take_nested(data, len)?)
+        }
+        Marker::Ext8 | Marker::Ext16 | Marker::Ext32 => {
+            let meta = decode::read_ext_meta(data).map_err(decode_err)?;
+            Value::Custom {
+                tag: meta.typeid,
+                data: take_nested(data, meta.size)?,
+            }
+        }
+        Marker::F32 => Value::Float(decode::read_f32(data).map_err(decode_err)? as f64),
+        Marker::F64 => Value::Float(decode::read_f64(data).map_err(decode_err)?),
+        Marker::U8 | Marker::U16 | Marker::U32 | Marker::U64 => {
+            Value::Unsigned(decode::read_int(data).map_err(decode_err)?)
+        }
+        Marker::Null => {
+            *data = &data[1..];
+            let message = read_nested_str(data)?;
+            let len = decode::read_array_len(data).map_err(decode_err)?;
+            Value::Error {
+                message,
+                sources: Nested::new(len, skip_nested(data, len)?),
+            }
+        }
+        Marker::FixExt16 => {
+            let meta = decode::read_ext_meta(data).map_err(decode_err)?;
+            wide_value(meta.typeid, take_nested(data, 16)?.try_into().unwrap())?
+        }
+        Marker::FixArray(_) | Marker::Array16 | Marker::Array32 => {
+            let len = decode::read_array_len(data).map_err(decode_err)?;
+            Value::Array(Nested::new(len, skip_nested(data, len)?))
+        }
+        Marker::FixMap(_) | Marker::Map16 | Marker::Map32 => {
+            let len = decode::read_map_len(data).map_err(decode_err)?;
+            Value::Map(Nested::new(len, skip_nested(data, len * 2)?))
+        }
+        marker => return Err(UnexpectedMarker(marker).into()),
+    })
+}
+
+/// Takes the encoding of the next `elements` objects of `data`.
+fn skip_nested<'a>(data: &mut &'a [u8], elements: u32) -> io::Result<&'a [u8]> {
+    let start = *data;
+    for _ in 0..elements {
+        copy_nested(data, &mut io::sink(), 1)?;
+    }
+    Ok(&start[..start.len() - data.len()])
+}
+
+pub(crate) fn write_nested_str(out: &mut Vec<u8>, str: &str) {
+    Store::write_cache_str(out, CacheString::Present(str)).expect("writing to a Vec");
+}
+
+pub(crate) fn write_nested_value(out: &mut Vec<u8>, value: Value<&str>) {
+    let value =
+        match Store::<Vec<u8>>::as_cached(Instruction::AddValue(FieldValue { name: "", value })) {
+            CacheInstruction::AddValue(FieldValue { value, .. }) => value,
+            _ => unreachable!(),
+        };
+    Store::write_cache_value(out, value).expect("writing to a Vec");
+}
+
+/// Re-encodes the elements of `nested` into `out`, replacing every string with `map`, including
+/// the keys of maps and the strings of inner arrays and maps.
+pub(crate) fn map_nested_strings<'a, 'b, F>(
+    nested: Nested<'a>,
+    is_map: bool,
+    out: &mut Vec<u8>,
+    map: &mut F,
+) -> io::Result<()>
+where
+    F: FnMut(CacheString<'a>) -> CacheString<'b>,
+{
+    let mut data = nested.data();
+    for _ in 0..nested.len() {
+        if is_map {
+            let key = map(read_nested_str(&mut data)?);
+            Store::write_cache_str(out, key)?;
+        }
+
+        let mut inner = Vec::new();
+        let value = match read_nested_value(&mut data)? {
+            Value::Debug(str) => Value::Debug(map(str)),
+            Value::String(str) => Value::String(map(str)),
+            Value::Float(value) => Value::Float(value),
+            Value::Integer(value) => Value::Integer(value),
+            Value::Unsigned(value) => Value::Unsigned(value),
+            Value::I128(value) => Value::I128(value),
+            Value::U128(value) => Value::U128(value),
+            Value::Bool(value) => Value::Bool(value),
+            Value::ByteArray(items) => Value::ByteArray(items),
+            Value::Custom { tag, data } => Value::Custom { tag, data },
+            Value::Array(nested) => {
+                map_nested_strings(nested, false, &mut inner, map)?;
+                Value::Array(Nested::new(nested.len(), &inner))
+            }
+            Value::Map(nested) => {
+                map_nested_strings(nested, true, &mut inner, map)?;
+                Value::Map(Nested::new(nested.len(), &inner))
+            }
+            Value::Error { message, sources } => {
+                map_nested_strings(sources, false, &mut inner, map)?;
+                Value::Error {
+                    message: map(message),
+                    sources: Nested::new(sources.len(), &inner),
+                }
+            }
+        };
+        Store::write_cache_value(out, value)?;
+    }
+
+    Ok(())
+}
+
+pub(crate) fn present_str(str: CacheString<'_>) -> io::Result<&str> {
+    match str {
+        CacheString::Present(str) => Ok(str),
+        CacheString::Cached(_) => Err(UnexpectedCached.into()),
+    }
+}
+
+/// Value without cached strings. Strings of arrays and maps are checked when they are decoded.
+pub(crate) fn present_value<'a>(
+    value: Value<'a, CacheString<'a>>,
+) -> io::Result<Value<'a, &'a str>> {
+    Ok(match value {
+        Value::Debug(str) => Value::Debug(present_str(str)?),
+        Value::String(str) => Value::String(present_str(str)?),
+        Value::Float(value) => Value::Float(value),
+        Value::Integer(value) => Value::Integer(value),
+        Value::Unsigned(value) => Value::Unsigned(value),
+        Value::I128(value) => Value::I128(value),
+        Value::U128(value) => Value::U128(value),
+        Value::Bool(value) => Value::Bool(value),
+        Value::ByteArray(items) => Value::ByteArray(items),
+        Value::Custom { tag, data } => Value::Custom { tag, data },
+        Value::Array(nested) => Value::Array(nested),
+        Value::Map(nested) => Value::Map(nested),
+        Value::Error { message, sources } => Value::Error {
+            message: present_str(message)?,
+            sources,
+        },
+    })
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -467,7 +2258,7 @@ impl From<UnexpectedMarker> for io::Error {
 pub struct EofOnMarker;
 impl From<EofOnMarker> for io::Error {
     fn from(value: EofOnMarker) -> Self {
-        decode_err(value)
+        io::Error::new(io::ErrorKind::UnexpectedEof, value)
     }
 }
 
@@ -480,6 +2271,45 @@ impl From<ZeroSpan> for io::Error {
     }
 }
 
+#[derive(thiserror::Error, Debug)]
+#[error("Not a msgpack-tracing tape, bad magic after restart")]
+pub struct BadMagic;
+impl From<BadMagic> for io::Error {
+    fn from(value: BadMagic) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, value)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("Tape format version {0} is newer than the supported version {DELTA_TIME_VERSION}")]
+pub struct UnsupportedVersion(pub u8);
+impl From<UnsupportedVersion> for io::Error {
+    fn from(value: UnsupportedVersion) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, value)
+    }
+}
+
+/// Event cut by the end of the tape, reported by [`Recovery::Report`] and [`Recovery::Salvage`].
+#[derive(thiserror::Error, Debug)]
+#[error("Tape ends in the middle of an event, after {fields} complete fields")]
+pub struct PartialEvent {
+    pub fields: usize,
+}
+impl From<PartialEvent> for io::Error {
+    fn from(value: PartialEvent) -> Self {
+        io::Error::new(io::ErrorKind::UnexpectedEof, value)
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("Instruction checksum mismatch")]
+pub struct ChecksumMismatch;
+impl From<ChecksumMismatch> for io::Error {
+    fn from(value: ChecksumMismatch) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, value)
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 #[error("Trying to load cached instruction file into uncached machine")]
 pub struct UnexpectedCached;
@@ -599,3 +2429,848 @@ impl CacheIndex {
         Ok(r)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        string_cache::{StringCache, StringUncache},
+        tape::{NestedOwned, ValueOwned},
+        test_util::{Targets, golden_tape},
+    };
+
+    #[test]
+    fn iterators() {
+        use crate::string_cache::StringCache;
+
+        let mut uncached = Store::new(Vec::new());
+        golden_tape(&mut uncached);
+        let uncached = uncached.into_inner();
+        let mut cached = StringCache::new(Store::new(Vec::new()));
+        golden_tape(&mut cached);
+        let cached = cached.into_inner().into_inner();
+
+        let mut load = Load::new(uncached.as_slice());
+        let instructions = load.iter().collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(
+            instructions[1],
+            InstructionOwned::FilterChanged("info".to_owned())
+        );
+        let mut load = Load::new(cached.as_slice());
+        assert_eq!(
+            load.iter_cached().collect::<io::Result<Vec<_>>>().unwrap(),
+            instructions
+        );
+
+        let mut load = Load::new(cached.as_slice());
+        let mut iter = load.iter();
+        assert!(iter.any(|instruction| instruction.is_err()));
+        assert!(iter.next().is_none());
+
+        let mut replayed = Vec::new();
+        for instruction in instructions.iter() {
+            Store::do_handle(&mut replayed, instruction.as_ref()).unwrap();
+        }
+        assert_eq!(replayed, uncached);
+    }
+
+    #[test]
+    fn flush_policies() {
+        let flushes = |policy| {
+            let mut flusher = Flusher::new(policy);
+            let event = [
+                InstructionId::StartEvent,
+                InstructionId::AddValue,
+                InstructionId::FinishedEvent,
+            ];
+            (0..4)
+                .flat_map(|_| event)
+                .filter(|id| flusher.should_flush(*id))
+                .count()
+        };
+
+        assert_eq!(flushes(FlushPolicy::EveryInstruction), 12);
+        assert_eq!(flushes(FlushPolicy::EveryEvent), 4);
+        assert_eq!(flushes(FlushPolicy::EveryEvents(3)), 1);
+        assert_eq!(flushes(FlushPolicy::Interval(Duration::ZERO)), 4);
+        assert_eq!(flushes(FlushPolicy::Interval(Duration::from_secs(3600))), 0);
+        assert_eq!(flushes(FlushPolicy::Manual), 0);
+    }
+
+    #[test]
+    fn string_snapshots() {
+        use crate::string_cache::{CacheInstructionOwned, CacheStringOwned, StringCache};
+
+        // Restarts before every event but the first
+        let store = Store::new(Vec::new()).with_restart_interval(1);
+        let mut cache = StringCache::new(store).with_snapshots(true);
+        cache.handle(Instruction::Restart);
+        for target in ["first", "second", "first"] {
+            if TapeMachine::<InstructionSet>::needs_restart(&mut cache) {
+                cache.handle(Instruction::Restart);
+            }
+            cache.handle(Instruction::StartEvent {
+                time: DateTime::UNIX_EPOCH,
+                span: None,
+                target,
+                priority: Level::INFO,
+                location: None,
+                thread: None,
+            });
+            cache.handle(Instruction::FinishedEvent);
+        }
+        let tape = cache.into_inner().into_inner();
+        // Starts reading at the second restart
+        let (second, _) = tape
+            .windows(RESTART_HEADER.len())
+            .enumerate()
+            .filter(|(_, window)| *window == RESTART_HEADER)
+            .nth(1)
+            .unwrap();
+
+        let mut load = Load::new(&tape[second..]);
+        let mut instructions = Vec::new();
+        while let Some(instruction) = load.fetch_one_cached().unwrap() {
+            instructions.push(instruction.to_owned());
+        }
+        let last = instructions
+            .rsplit(|instruction| *instruction == CacheInstructionOwned::Restart)
+            .next()
+            .unwrap();
+        assert!(matches!(
+            last,
+            [
+                CacheInstructionOwned::NewString(first),
+                CacheInstructionOwned::NewString(second),
+                CacheInstructionOwned::StartEvent {
+                    target: CacheStringOwned::Cached(0),
+                    ..
+                },
+                CacheInstructionOwned::FinishedEvent,
+            ] if first == "first" && second == "second"
+        ));
+
+        let mut load = Load::new(&tape[second..]);
+        let targets = load
+            .iter_cached()
+            .filter_map(|instruction| match instruction.unwrap() {
+                InstructionOwned::StartEvent { target, .. } => Some(target),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(targets, ["second", "first"]);
+    }
+
+    #[test]
+    fn filter_changed_roundtrip() {
+        let mut out = Vec::new();
+        Store::do_handle(&mut out, Instruction::Restart).unwrap();
+        Store::do_handle(&mut out, Instruction::FilterChanged("info,my_crate=debug")).unwrap();
+
+        let mut load = Load::new(out.as_slice());
+        assert!(matches!(
+            load.fetch_one().unwrap(),
+            Some(Instruction::Restart)
+        ));
+        assert!(matches!(
+            load.fetch_one().unwrap(),
+            Some(Instruction::FilterChanged("info,my_crate=debug"))
+        ));
+        assert!(load.fetch_one().unwrap().is_none());
+    }
+
+    #[test]
+    fn manual_flush() {
+        let mut store = Store::with_flush_policy(Vec::new(), FlushPolicy::Manual);
+        TapeMachine::<InstructionSet>::handle(&mut store, Instruction::Restart);
+        assert!(store.out.get_ref().is_empty());
+
+        TapeMachine::<InstructionSet>::flush(&mut store);
+        let flushed = store.out.get_ref().len();
+        assert!(flushed > 0);
+
+        TapeMachine::<InstructionSet>::handle(&mut store, Instruction::FilterChanged("info"));
+        assert_eq!(store.out.get_ref().len(), flushed);
+        store.flush().unwrap();
+        assert!(store.out.get_ref().len() > flushed);
+    }
+
+    #[test]
+    fn handle_encoded() {
+        let event = |target| {
+            let mut chunk = Vec::new();
+            for instruction in [
+                Instruction::Restart,
+                Instruction::StartEvent {
+                    time: DateTime::UNIX_EPOCH,
+                    span: None,
+                    target,
+                    priority: Level::INFO,
+                    location: None,
+                    thread: None,
+                },
+                Instruction::FinishedEvent,
+            ] {
+                Store::do_handle(&mut chunk, instruction).unwrap();
+            }
+            chunk
+        };
+        let targets = |tape: &[u8]| {
+            let mut targets = Vec::new();
+            InstructionSet::decode(tape, |instruction| {
+                if let Instruction::StartEvent { target, .. } = instruction {
+                    targets.push(target.to_owned());
+                }
+            })
+            .unwrap();
+            targets
+        };
+
+        let mut plain = StringCache::new(Store::new(Vec::new()));
+        plain.handle(Instruction::Restart);
+        plain.handle_encoded(&event("first")).unwrap();
+        let plain = plain.into_inner().into_inner();
+        assert_eq!(plain.len(), event("first").len());
+        assert_eq!(targets(&plain), ["first"]);
+
+        let mut checksummed = Store::new(Vec::new()).with_checksums(true);
+        TapeMachine::<InstructionSet>::handle(&mut checksummed, Instruction::Restart);
+        TapeMachine::<InstructionSet>::handle_encoded(&mut checksummed, &event("first")).unwrap();
+        let checksummed = checksummed.into_inner();
+        assert_ne!(
+            checksummed[RESTART_HEADER.len()..],
+            event("first")[RESTART_HEADER.len()..]
+        );
+        assert_eq!(targets(&checksummed), ["first"]);
+
+        // Skips to the next `Restart` after an undecodable instruction
+        let mut corrupted = event("first");
+        corrupted[RESTART_HEADER.len()] = 0x7f;
+        corrupted.extend(event("second"));
+        let mut store = Store::new(Vec::new()).with_delta_time(true);
+        TapeMachine::<InstructionSet>::handle(&mut store, Instruction::Restart);
+        let result = TapeMachine::<InstructionSet>::handle_encoded(&mut store, &corrupted);
+        assert!(result.is_err());
+        assert_eq!(targets(&store.into_inner()), ["second"]);
+    }
+
+    #[test]
+    fn cached_location_and_thread_roundtrip() {
+        let mut out = Vec::new();
+        Store::do_handle_cached(&mut out, CacheInstruction::Restart).unwrap();
+        Store::do_handle_cached(
+            &mut out,
+            CacheInstruction::StartEvent {
+                time: DateTime::UNIX_EPOCH,
+                span: None,
+                target: CacheString::Present("target"),
+                priority: Level::DEBUG,
+                location: Some(Location {
+                    file: CacheString::Cached(0),
+                    line: 7,
+                    module_path: CacheString::Present("app"),
+                }),
+                thread: Some(ThreadInfo {
+                    id: 3,
+                    name: CacheString::Cached(1),
+                }),
+            },
+        )
+        .unwrap();
+
+        let mut load = Load::new(out.as_slice());
+        load.fetch_one_cached().unwrap();
+        let Some(CacheInstruction::StartEvent {
+            priority,
+            location: Some(location),
+            thread: Some(thread),
+            ..
+        }) = load.fetch_one_cached().unwrap()
+        else {
+            panic!("Expected StartEvent with location and thread");
+        };
+        assert_eq!(priority, Level::DEBUG);
+        assert!(matches!(location.file, CacheString::Cached(0)));
+        assert_eq!(location.line, 7);
+        assert!(matches!(location.module_path, CacheString::Present("app")));
+        assert_eq!(thread.id, 3);
+        assert!(matches!(thread.name, CacheString::Cached(1)));
+    }
+
+    #[test]
+    fn last_events() {
+        let mut out = Vec::new();
+        Store::do_handle(&mut out, Instruction::Restart).unwrap();
+        for target in ["first", "second", "third"] {
+            Store::do_handle(
+                &mut out,
+                Instruction::StartEvent {
+                    time: DateTime::from_timestamp(0xff, 0xff).unwrap(),
+                    span: None,
+                    target,
+                    priority: Level::INFO,
+                    location: None,
+                    thread: None,
+                },
+            )
+            .unwrap();
+            Store::do_handle(&mut out, Instruction::FinishedEvent).unwrap();
+        }
+        // Partially written event
+        out.push(InstructionId::StartEvent.to_byte(FORMAT_VERSION).unwrap());
+
+        let mut targets = Targets(Vec::new());
+        Load::new(io::Cursor::new(out))
+            .last_events(2, &mut targets)
+            .unwrap();
+        assert_eq!(targets.0, ["second", "third"]);
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_last_events() {
+        use flate2::{Compression, write::GzEncoder};
+
+        // A member per event, as written by a `CompressedStore`
+        let path = std::env::temp_dir().join(format!("gzip-last-{}.log.gz", std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        for target in ["first", "second", "third"] {
+            let mut out = Vec::new();
+            if target == "first" {
+                Store::do_handle(&mut out, Instruction::Restart).unwrap();
+            }
+            Store::do_handle(
+                &mut out,
+                Instruction::StartEvent {
+                    time: DateTime::UNIX_EPOCH,
+                    span: None,
+                    target,
+                    priority: Level::INFO,
+                    location: None,
+                    thread: None,
+                },
+            )
+            .unwrap();
+            Store::do_handle(&mut out, Instruction::FinishedEvent).unwrap();
+            let mut encoder = GzEncoder::new(&mut file, Compression::default());
+            encoder.write_all(&out).unwrap();
+            encoder.finish().unwrap();
+        }
+        drop(file);
+
+        let mut load = Load::open(&path).unwrap();
+        let mut targets = Targets(Vec::new());
+        load.last_events(2, &mut targets).unwrap();
+        assert_eq!(targets.0, ["second", "third"]);
+
+        // Back to the start, decompressing again
+        load.seek_to_time(DateTime::UNIX_EPOCH).unwrap();
+        assert!(matches!(
+            load.fetch_one().unwrap(),
+            Some(Instruction::Restart)
+        ));
+        assert_eq!(load.offset(), RESTART_HEADER.len() as u64);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn last_events_single_restart() {
+        use std::{cell::Cell, rc::Rc};
+
+        /// Counts the bytes read.
+        struct Counted(io::Cursor<Vec<u8>>, Rc<Cell<u64>>);
+        impl io::Read for Counted {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let read = self.0.read(buf)?;
+                self.1.set(self.1.get() + read as u64);
+                Ok(read)
+            }
+        }
+        impl io::Seek for Counted {
+            fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+                self.0.seek(pos)
+            }
+        }
+
+        // Every event holds bytes that look like a `Restart`
+        let mut out = Vec::new();
+        Store::do_handle(&mut out, Instruction::Restart).unwrap();
+        for i in 0..20_000 {
+            Store::do_handle(
+                &mut out,
+                Instruction::StartEvent {
+                    time: DateTime::UNIX_EPOCH,
+                    span: None,
+                    target: if i < 19_998 { "old" } else { "new" },
+                    priority: Level::INFO,
+                    location: None,
+                    thread: None,
+                },
+            )
+            .unwrap();
+            for name in ["a", "b"] {
+                let value = Value::Integer(-1);
+                Store::do_handle(&mut out, Instruction::AddValue(FieldValue { name, value }))
+                    .unwrap();
+            }
+            Store::do_handle(&mut out, Instruction::FinishedEvent).unwrap();
+        }
+
+        let len = out.len() as u64;
+        let read = Rc::new(Cell::new(0));
+        let mut targets = Targets(Vec::new());
+        Load::new(Counted(io::Cursor::new(out), read.clone()))
+            .last_events(2, &mut targets)
+            .unwrap();
+        assert_eq!(targets.0, ["new", "new"]);
+        // A few times the input, not once for every byte that looks like a `Restart`
+        assert!(read.get() < 8 * len, "{} of {len}", read.get());
+    }
+
+    #[test]
+    fn forward_complete() {
+        let path = std::env::temp_dir().join(format!("follow-{}.log", std::process::id()));
+        let mut out = Vec::new();
+        Store::do_handle(&mut out, Instruction::Restart).unwrap();
+        for target in ["first", "second"] {
+            Store::do_handle(
+                &mut out,
+                Instruction::StartEvent {
+                    time: DateTime::UNIX_EPOCH,
+                    span: None,
+                    target,
+                    priority: Level::INFO,
+                    location: None,
+                    thread: None,
+                },
+            )
+            .unwrap();
+            Store::do_handle(&mut out, Instruction::FinishedEvent).unwrap();
+        }
+
+        // Second event is missing its FinishedEvent
+        let cut = out.len() - 1;
+        std::fs::write(&path, &out[..cut]).unwrap();
+        let mut load = Load::open(&path).unwrap();
+        let mut targets = Targets(Vec::new());
+        load.forward_complete(&mut targets).unwrap();
+        assert_eq!(targets.0, ["first"]);
+
+        let mut file = File::options().append(true).open(&path).unwrap();
+        io::Write::write_all(&mut file, &out[cut..]).unwrap();
+        load.forward_complete(&mut targets).unwrap();
+        assert_eq!(targets.0, ["first", "second"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn restart_header() {
+        let mut out = Vec::new();
+        Store::do_handle(&mut out, Instruction::Restart).unwrap();
+        assert_eq!(&out[..2], &[255, 0xaf]);
+        let mut load = Load::new(io::Cursor::new(out.clone()));
+        assert!(matches!(
+            load.fetch_one().unwrap(),
+            Some(Instruction::Restart)
+        ));
+        assert_eq!(load.version(), FORMAT_VERSION);
+
+        // Written before versions existed
+        let mut load = Load::new(io::Cursor::new(vec![
+            255,
+            InstructionId::FinishedEvent.to_byte(0).unwrap(),
+        ]));
+        assert!(matches!(
+            load.fetch_one().unwrap(),
+            Some(Instruction::Restart)
+        ));
+        assert!(matches!(
+            load.fetch_one().unwrap(),
+            Some(Instruction::FinishedEvent)
+        ));
+        assert_eq!(load.version(), 0);
+
+        *out.last_mut().unwrap() = DELTA_TIME_VERSION + 1;
+        let e = Load::new(io::Cursor::new(out.clone()))
+            .fetch_one()
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        out[2] = b'M';
+        let e = Load::new(io::Cursor::new(out)).fetch_one().unwrap_err();
+        assert!(e.to_string().contains("bad magic"));
+    }
+
+    #[test]
+    fn checksum_mismatch_skips_to_restart() {
+        let mut store = Store::new(Vec::new()).with_checksums(true);
+        for instruction in [
+            Instruction::Restart,
+            Instruction::FilterChanged("first"),
+            Instruction::FilterChanged("second"),
+            Instruction::Restart,
+            Instruction::FilterChanged("third"),
+        ] {
+            store
+                .handle_cached(Store::<Vec<u8>>::as_cached(instruction))
+                .unwrap();
+        }
+        let mut out = store.into_inner();
+        let corrupted = out.windows(6).position(|str| str == b"second").unwrap();
+        out[corrupted] = b'S';
+
+        let mut load = Load::new(io::Cursor::new(out));
+        assert!(matches!(
+            load.fetch_one().unwrap(),
+            Some(Instruction::Restart)
+        ));
+        assert!(matches!(
+            load.fetch_one().unwrap(),
+            Some(Instruction::FilterChanged("first"))
+        ));
+        let e = load.fetch_one().unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(
+            load.fetch_one().unwrap(),
+            Some(Instruction::Restart)
+        ));
+        assert!(matches!(
+            load.fetch_one().unwrap(),
+            Some(Instruction::FilterChanged("third"))
+        ));
+        assert!(load.fetch_one().unwrap().is_none());
+    }
+
+    #[test]
+    fn salvage_partial_event() {
+        let mut store = Store::new(Vec::new());
+        for instruction in [
+            Instruction::Restart,
+            Instruction::StartEvent {
+                time: DateTime::UNIX_EPOCH,
+                span: None,
+                target: "crashing",
+                priority: Level::ERROR,
+                location: None,
+                thread: None,
+            },
+            Instruction::AddValue(FieldValue {
+                name: "message",
+                value: Value::Debug("about to crash"),
+            }),
+            Instruction::AddValue(FieldValue {
+                name: "cut",
+                value: Value::String("never written entirely"),
+            }),
+        ] {
+            TapeMachine::<InstructionSet>::handle(&mut store, instruction);
+        }
+        let mut out = store.into_inner();
+        out.truncate(out.len() - 5);
+
+        let e = Load::new(out.as_slice())
+            .with_recovery(Recovery::Report)
+            .forward(&mut Store::new(Vec::new()))
+            .unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(
+            e.into_inner()
+                .unwrap()
+                .downcast::<PartialEvent>()
+                .unwrap()
+                .fields,
+            1
+        );
+
+        let mut salvaged = Store::new(Vec::new());
+        Load::new(out.as_slice())
+            .with_recovery(Recovery::Salvage)
+            .forward(&mut salvaged)
+            .unwrap_err();
+        let mut load = Load::new(io::Cursor::new(salvaged.into_inner()));
+        let mut loaded = Vec::new();
+        while let Some(instruction) = load.fetch_one().unwrap() {
+            loaded.push(instruction.id());
+        }
+        assert_eq!(
+            loaded,
+            [
+                InstructionId::Restart,
+                InstructionId::StartEvent,
+                InstructionId::AddValue,
+                InstructionId::FinishedEvent
+            ]
+        );
+    }
+
+    #[test]
+    fn timestamp_roundtrip() {
+        for time in [
+            DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            DateTime::from_timestamp(1_700_000_000, 123_456_789).unwrap(),
+            DateTime::from_timestamp(-1, 5).unwrap(),
+        ] {
+            let mut out = Vec::new();
+            Store::do_handle(&mut out, Instruction::Restart).unwrap();
+            Store::do_handle(
+                &mut out,
+                Instruction::StartEvent {
+                    time,
+                    span: None,
+                    target: "target",
+                    priority: Level::INFO,
+                    location: None,
+                    thread: None,
+                },
+            )
+            .unwrap();
+
+            let mut load = Load::new(out.as_slice());
+            load.fetch_one().unwrap();
+            let Some(Instruction::StartEvent { time: loaded, .. }) = load.fetch_one().unwrap()
+            else {
+                panic!();
+            };
+            assert_eq!(loaded, time);
+        }
+    }
+
+    #[test]
+    fn legacy_timestamp() {
+        let mut out = vec![InstructionId::RESTART];
+        out.push(InstructionId::StartEvent.to_byte(0).unwrap());
+        encode::write_uint(&mut out, 1_700_000_000).unwrap();
+        encode::write_uint(&mut out, 42).unwrap();
+        encode::write_uint(&mut out, 0).unwrap();
+        encode::write_str(&mut out, "target").unwrap();
+        encode::write_uint(&mut out, 2).unwrap();
+
+        let mut load = Load::new(out.as_slice());
+        load.fetch_one().unwrap();
+        let Some(Instruction::StartEvent { time, .. }) = load.fetch_one().unwrap() else {
+            panic!();
+        };
+        assert_eq!(time, DateTime::from_timestamp(1_700_000_000, 42).unwrap());
+    }
+
+    #[test]
+    fn delta_time() {
+        let start = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let mut times = (0..2 * DELTA_TIME_ANCHOR as i64)
+            .map(|i| start + TimeDelta::microseconds(i * 7))
+            .collect::<Vec<_>>();
+        // Out of order, as events of different threads may be
+        times.swap(10, 11);
+
+        let write = |delta| {
+            let mut store = Store::new(Vec::new()).with_delta_time(delta);
+            let mut handle =
+                |instruction| TapeMachine::<InstructionSet>::handle(&mut store, instruction);
+            handle(Instruction::Restart);
+            for (i, &time) in times.iter().enumerate() {
+                if i == 100 {
+                    handle(Instruction::Restart);
+                }
+                handle(Instruction::EnterSpan {
+                    time,
+                    span: NonZeroU64::MIN,
+                });
+            }
+            store.into_inner()
+        };
+        let absolute = write(false);
+        let delta = write(true);
+        assert!(delta.len() * 2 < absolute.len());
+
+        let mut load = Load::new(delta.as_slice());
+        let mut loaded = Vec::new();
+        while let Some(instruction) = load.fetch_one().unwrap() {
+            if let Instruction::EnterSpan { time, .. } = instruction {
+                loaded.push(time);
+            }
+        }
+        assert_eq!(load.version(), DELTA_TIME_VERSION);
+        assert_eq!(loaded, times);
+    }
+
+    struct Times(Vec<i64>);
+    impl TapeMachine<CacheInstructionSet> for Times {
+        fn needs_restart(&mut self) -> bool {
+            false
+        }
+
+        fn handle(&mut self, instruction: CacheInstruction) {
+            if let CacheInstruction::StartEvent { time, .. } = instruction {
+                self.0.push(time.timestamp());
+            }
+        }
+    }
+
+    #[test]
+    fn delta_time_lookahead() {
+        let mut store = Store::new(Vec::new()).with_delta_time(true);
+        let mut handle =
+            |instruction| TapeMachine::<InstructionSet>::handle(&mut store, instruction);
+        handle(Instruction::Restart);
+        for secs in [100, 200, 300, 400] {
+            handle(Instruction::StartEvent {
+                time: DateTime::from_timestamp(secs, 0).unwrap(),
+                span: None,
+                target: "target",
+                priority: Level::INFO,
+                location: None,
+                thread: None,
+            });
+            handle(Instruction::FinishedEvent);
+        }
+        let out = store.into_inner();
+
+        let mut times = Times(Vec::new());
+        Load::new(io::Cursor::new(out.clone()))
+            .forward_complete(&mut times)
+            .unwrap();
+        assert_eq!(times.0, [100, 200, 300, 400]);
+
+        let mut times = Times(Vec::new());
+        Load::new(io::Cursor::new(out))
+            .last_events(2, &mut times)
+            .unwrap();
+        assert_eq!(times.0, [300, 400]);
+    }
+
+    #[test]
+    fn custom_value_roundtrip() {
+        let mut out = Vec::new();
+        Store::do_handle(&mut out, Instruction::Restart).unwrap();
+        Store::do_handle(
+            &mut out,
+            Instruction::AddValue(FieldValue {
+                name: "addr",
+                value: Value::Custom {
+                    tag: 7,
+                    data: &[127, 0, 0, 1],
+                },
+            }),
+        )
+        .unwrap();
+
+        let mut load = Load::new(out.as_slice());
+        load.fetch_one().unwrap();
+        let Some(Instruction::AddValue(value)) = load.fetch_one().unwrap() else {
+            panic!();
+        };
+        assert_eq!(value.name, "addr");
+        assert!(matches!(
+            value.value,
+            Value::Custom {
+                tag: 7,
+                data: [127, 0, 0, 1]
+            }
+        ));
+    }
+
+    #[test]
+    fn wide_integer_roundtrip() {
+        let wide = NestedOwned::array([Value::I128(i128::MIN), Value::U128(u128::MAX)]);
+        let mut out = Vec::new();
+        Store::do_handle(&mut out, Instruction::Restart).unwrap();
+        for (name, value) in [
+            ("signed", Value::I128(-1 << 100)),
+            ("unsigned", Value::U128(1 << 100)),
+            ("array", Value::Array(wide.as_ref())),
+        ] {
+            Store::do_handle(&mut out, Instruction::AddValue(FieldValue { name, value })).unwrap();
+        }
+
+        let mut load = Load::new(out.as_slice());
+        load.fetch_one().unwrap();
+        let mut values = Vec::new();
+        while let Some(Instruction::AddValue(value)) = load.fetch_one().unwrap() {
+            values.push(value.to_owned().value);
+        }
+        assert_eq!(
+            values,
+            [
+                ValueOwned::I128(-1 << 100),
+                ValueOwned::U128(1 << 100),
+                ValueOwned::Array(wide.clone()),
+            ]
+        );
+        assert_eq!(
+            wide.values().unwrap(),
+            [ValueOwned::I128(i128::MIN), ValueOwned::U128(u128::MAX)]
+        );
+    }
+
+    #[test]
+    fn nested_value_roundtrip() {
+        struct Values(Vec<crate::tape::FieldValueOwned>);
+        impl TapeMachine<InstructionSet> for Values {
+            fn needs_restart(&mut self) -> bool {
+                false
+            }
+
+            fn handle(&mut self, instruction: Instruction) {
+                if let Instruction::AddValue(value) = instruction {
+                    self.0.push(value.to_owned());
+                }
+            }
+        }
+
+        let repeated = "a string long enough to be cached";
+        let single = NestedOwned::array([Value::String(repeated)]);
+        let array = NestedOwned::array([
+            Value::String(repeated),
+            Value::Debug("debug"),
+            Value::Integer(-1),
+            Value::Array(single.as_ref()),
+        ]);
+        let map = NestedOwned::map([
+            ("key", Value::String(repeated)),
+            ("flag", Value::Bool(true)),
+        ]);
+
+        let mut tape = StringCache::new(Store::new(Vec::new()));
+        tape.handle(Instruction::Restart);
+        for (name, value) in [
+            ("array", Value::Array(array.as_ref())),
+            ("single", Value::Array(single.as_ref())),
+            ("map", Value::Map(map.as_ref())),
+        ] {
+            tape.handle(Instruction::AddValue(FieldValue { name, value }));
+        }
+        let out = tape.into_inner().into_inner();
+        assert_eq!(
+            out.windows(repeated.len())
+                .filter(|window| *window == repeated.as_bytes())
+                .count(),
+            1
+        );
+
+        let mut load = Load::new(out.as_slice());
+        let mut values = StringUncache::new(Values(Vec::new()));
+        while let Some(instruction) = load.fetch_one_cached().unwrap() {
+            values.handle(instruction);
+        }
+        let values = values.into_inner().0;
+        assert_eq!(values[0].value, ValueOwned::Array(array));
+        assert_eq!(values[1].value, ValueOwned::Array(single.clone()));
+        assert_eq!(values[2].value, ValueOwned::Map(map));
+
+        let ValueOwned::Map(map) = &values[2].value else {
+            panic!();
+        };
+        assert_eq!(
+            map.entries().unwrap(),
+            [
+                ("key".to_owned(), ValueOwned::String(repeated.to_owned())),
+                ("flag".to_owned(), ValueOwned::Bool(true)),
+            ]
+        );
+        assert_eq!(
+            single.values().unwrap(),
+            [ValueOwned::String(repeated.to_owned())]
+        );
+    }
+}
@@ -1,9 +1,17 @@
-use crate::tape::{
-    FieldValue, Instruction, InstructionId, InstructionSet, InstructionSetTrait, InstructionTrait,
-    TapeMachine, Value,
+use crate::{
+    storage::{self, CacheIndex},
+    tape::{
+        FieldValue, FieldValueOwned, Instruction, InstructionId, InstructionSet,
+        InstructionSetTrait, InstructionTrait, Location, Nested, TapeMachine, ThreadInfo, Value,
+    },
 };
 use chrono::{DateTime, Utc};
-use std::{collections::HashMap, num::NonZeroU64};
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    num::NonZeroU64,
+    time::Duration,
+};
 use tracing::Level;
 
 #[derive(Clone, Copy, Debug)]
@@ -23,10 +31,29 @@ pub enum CacheInstruction<'a> {
         span: Option<NonZeroU64>,
         target: CacheString<'a>,
         priority: Level,
+        location: Option<Location<CacheString<'a>>>,
+        thread: Option<ThreadInfo<CacheString<'a>>>,
     },
     FinishedEvent,
     AddValue(FieldValue<'a, CacheString<'a>>),
     DeleteSpan(NonZeroU64),
+    FilterChanged(&'a str),
+    EnterSpan {
+        time: DateTime<Utc>,
+        span: NonZeroU64,
+    },
+    ExitSpan {
+        time: DateTime<Utc>,
+        span: NonZeroU64,
+    },
+    SpanTiming {
+        time: DateTime<Utc>,
+        span: NonZeroU64,
+        target: CacheString<'a>,
+        priority: Level,
+        busy: Duration,
+        idle: Duration,
+    },
 }
 impl InstructionTrait for CacheInstruction<'_> {
     fn id(self) -> InstructionId {
@@ -41,6 +68,179 @@ impl InstructionTrait for CacheInstruction<'_> {
             CacheInstruction::FinishedEvent => InstructionId::FinishedEvent,
             CacheInstruction::AddValue(..) => InstructionId::AddValue,
             CacheInstruction::DeleteSpan(..) => InstructionId::DeleteSpan,
+            CacheInstruction::FilterChanged(..) => InstructionId::FilterChanged,
+            CacheInstruction::EnterSpan { .. } => InstructionId::EnterSpan,
+            CacheInstruction::ExitSpan { .. } => InstructionId::ExitSpan,
+            CacheInstruction::SpanTiming { .. } => InstructionId::SpanTiming,
+        }
+    }
+}
+impl CacheInstruction<'_> {
+    pub fn to_owned(self) -> CacheInstructionOwned {
+        match self {
+            CacheInstruction::Restart => CacheInstructionOwned::Restart,
+            CacheInstruction::NewString(str) => CacheInstructionOwned::NewString(str.to_owned()),
+            CacheInstruction::NewSpan { parent, span, name } => CacheInstructionOwned::NewSpan {
+                parent,
+                span,
+                name: name.to_owned(),
+            },
+            CacheInstruction::FinishedSpan => CacheInstructionOwned::FinishedSpan,
+            CacheInstruction::NewRecord(span) => CacheInstructionOwned::NewRecord(span),
+            CacheInstruction::FinishedRecord => CacheInstructionOwned::FinishedRecord,
+            CacheInstruction::StartEvent {
+                time,
+                span,
+                target,
+                priority,
+                location,
+                thread,
+            } => CacheInstructionOwned::StartEvent {
+                time,
+                span,
+                target: target.to_owned(),
+                priority,
+                location: location.map(|location| location.map(CacheString::to_owned)),
+                thread: thread.map(|thread| thread.map(CacheString::to_owned)),
+            },
+            CacheInstruction::FinishedEvent => CacheInstructionOwned::FinishedEvent,
+            CacheInstruction::AddValue(value) => CacheInstructionOwned::AddValue(FieldValueOwned {
+                name: value.name.to_owned(),
+                value: value.value.into_owned_with(CacheString::to_owned),
+            }),
+            CacheInstruction::DeleteSpan(span) => CacheInstructionOwned::DeleteSpan(span),
+            CacheInstruction::FilterChanged(filter) => {
+                CacheInstructionOwned::FilterChanged(filter.to_owned())
+            }
+            CacheInstruction::EnterSpan { time, span } => {
+                CacheInstructionOwned::EnterSpan { time, span }
+            }
+            CacheInstruction::ExitSpan { time, span } => {
+                CacheInstructionOwned::ExitSpan { time, span }
+            }
+            CacheInstruction::SpanTiming {
+                time,
+                span,
+                target,
+                priority,
+                busy,
+                idle,
+            } => CacheInstructionOwned::SpanTiming {
+                time,
+                span,
+                target: target.to_owned(),
+                priority,
+                busy,
+                idle,
+            },
+        }
+    }
+}
+
+/// Owned [`CacheInstruction`], to be sent to another thread or kept after the next one is read.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CacheInstructionOwned {
+    Restart,
+    NewString(String),
+    NewSpan {
+        parent: Option<NonZeroU64>,
+        span: NonZeroU64,
+        name: CacheStringOwned,
+    },
+    FinishedSpan,
+    NewRecord(NonZeroU64),
+    FinishedRecord,
+    StartEvent {
+        time: DateTime<Utc>,
+        span: Option<NonZeroU64>,
+        target: CacheStringOwned,
+        priority: Level,
+        location: Option<Location<CacheStringOwned>>,
+        thread: Option<ThreadInfo<CacheStringOwned>>,
+    },
+    FinishedEvent,
+    AddValue(FieldValueOwned<CacheStringOwned>),
+    DeleteSpan(NonZeroU64),
+    FilterChanged(String),
+    EnterSpan {
+        time: DateTime<Utc>,
+        span: NonZeroU64,
+    },
+    ExitSpan {
+        time: DateTime<Utc>,
+        span: NonZeroU64,
+    },
+    SpanTiming {
+        time: DateTime<Utc>,
+        span: NonZeroU64,
+        target: CacheStringOwned,
+        priority: Level,
+        busy: Duration,
+        idle: Duration,
+    },
+}
+impl CacheInstructionOwned {
+    pub fn as_ref(&self) -> CacheInstruction<'_> {
+        match self {
+            CacheInstructionOwned::Restart => CacheInstruction::Restart,
+            CacheInstructionOwned::NewString(str) => CacheInstruction::NewString(str),
+            CacheInstructionOwned::NewSpan { parent, span, name } => CacheInstruction::NewSpan {
+                parent: *parent,
+                span: *span,
+                name: name.as_ref(),
+            },
+            CacheInstructionOwned::FinishedSpan => CacheInstruction::FinishedSpan,
+            CacheInstructionOwned::NewRecord(span) => CacheInstruction::NewRecord(*span),
+            CacheInstructionOwned::FinishedRecord => CacheInstruction::FinishedRecord,
+            CacheInstructionOwned::StartEvent {
+                time,
+                span,
+                target,
+                priority,
+                location,
+                thread,
+            } => CacheInstruction::StartEvent {
+                time: *time,
+                span: *span,
+                target: target.as_ref(),
+                priority: *priority,
+                location: location
+                    .as_ref()
+                    .map(|location| location.as_ref().map(CacheStringOwned::as_ref)),
+                thread: thread
+                    .as_ref()
+                    .map(|thread| thread.as_ref().map(CacheStringOwned::as_ref)),
+            },
+            CacheInstructionOwned::FinishedEvent => CacheInstruction::FinishedEvent,
+            CacheInstructionOwned::AddValue(value) => CacheInstruction::AddValue(FieldValue {
+                name: value.name.as_ref(),
+                value: value.value.as_ref_with(CacheStringOwned::as_ref),
+            }),
+            CacheInstructionOwned::DeleteSpan(span) => CacheInstruction::DeleteSpan(*span),
+            CacheInstructionOwned::FilterChanged(filter) => CacheInstruction::FilterChanged(filter),
+            CacheInstructionOwned::EnterSpan { time, span } => CacheInstruction::EnterSpan {
+                time: *time,
+                span: *span,
+            },
+            CacheInstructionOwned::ExitSpan { time, span } => CacheInstruction::ExitSpan {
+                time: *time,
+                span: *span,
+            },
+            CacheInstructionOwned::SpanTiming {
+                time,
+                span,
+                target,
+                priority,
+                busy,
+                idle,
+            } => CacheInstruction::SpanTiming {
+                time: *time,
+                span: *span,
+                target: target.as_ref(),
+                priority: *priority,
+                busy: *busy,
+                idle: *idle,
+            },
         }
     }
 }
@@ -48,6 +248,27 @@ impl InstructionTrait for CacheInstruction<'_> {
 pub struct CacheInstructionSet;
 impl InstructionSetTrait for CacheInstructionSet {
     type Instruction<'a> = CacheInstruction<'a>;
+
+    fn decode<F>(chunk: &[u8], mut handle: F) -> io::Result<()>
+    where
+        F: FnMut(CacheInstruction<'_>),
+    {
+        let mut load = storage::Load::new(chunk);
+        let mut error = None;
+        loop {
+            match load.fetch_one_cached() {
+                Ok(Some(CacheInstruction::Restart)) => (),
+                Ok(Some(instruction)) => handle(instruction),
+                Ok(None) => break,
+                Err(e) => {
+                    error.get_or_insert(e);
+                    load.restart();
+                }
+            }
+        }
+
+        error.map_or(Ok(()), Err)
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -55,10 +276,39 @@ pub enum CacheString<'a> {
     Present(&'a str),
     Cached(u64),
 }
+impl CacheString<'_> {
+    pub fn to_owned(self) -> CacheStringOwned {
+        match self {
+            CacheString::Present(str) => CacheStringOwned::Present(str.to_owned()),
+            CacheString::Cached(index) => CacheStringOwned::Cached(index),
+        }
+    }
+}
+
+/// Owned [`CacheString`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CacheStringOwned {
+    Present(String),
+    Cached(u64),
+}
+impl CacheStringOwned {
+    pub fn as_ref(&self) -> CacheString<'_> {
+        match self {
+            CacheStringOwned::Present(str) => CacheString::Present(str),
+            CacheStringOwned::Cached(index) => CacheString::Cached(*index),
+        }
+    }
+}
+
+/// Limit of the strings cached by [`LoggerBuilder`](crate::LoggerBuilder) with string snapshots,
+/// which keeps them for the whole tape. See [`StringCache::with_snapshots`].
+pub const SNAPSHOT_MAX_STRINGS: usize = 4096;
 
 pub struct StringCache<T> {
     forward: T,
     strings: HashMap<String, u64>,
+    max_strings: usize,
+    snapshots: bool,
 }
 impl<T> StringCache<T>
 where
@@ -68,18 +318,89 @@ where
         Self {
             forward,
             strings: Default::default(),
+            max_strings: usize::MAX,
+            snapshots: false,
         }
     }
 
-    fn cache_value<'a>(&mut self, value: Value<'a, &'a str>) -> Value<'a, CacheString<'a>> {
+    /// Stops caching new strings after `max_strings` are cached, until the next `Restart`.
+    ///
+    /// Protects memory when high-cardinality strings (e.g. dynamic targets) are logged. Strings
+    /// that do not fit in the cache are written in full.
+    pub fn with_limit(forward: T, max_strings: usize) -> Self {
+        Self {
+            max_strings,
+            ..Self::new(forward)
+        }
+    }
+
+    /// Keeps the cached strings across restarts, writing them all again after every `Restart`.
+    ///
+    /// Readers starting at any restart, e.g. after [`Load::restart`](crate::storage::Load::restart)
+    /// or when following a file, then know every cached string. Restarts are asked for by
+    /// [`Store::with_restart_interval`](crate::storage::Store::with_restart_interval), and the
+    /// limit of [`with_limit`](Self::with_limit) then holds for the whole tape, and should be
+    /// set (e.g. to [`SNAPSHOT_MAX_STRINGS`]) so that snapshots stay small when strings have a
+    /// high cardinality.
+    pub fn with_snapshots(mut self, snapshots: bool) -> Self {
+        self.snapshots = snapshots;
+        self
+    }
+
+    pub fn into_inner(self) -> T {
+        self.forward
+    }
+
+    /// Writes every cached string, in the order they were cached, so that a reader starting at
+    /// the last `Restart` gives them the same indexes.
+    fn snapshot(&mut self) {
+        let mut strings = self.strings.iter().collect::<Vec<_>>();
+        strings.sort_by_key(|(_, id)| **id);
+        for (string, _) in strings {
+            self.forward.handle(CacheInstruction::NewString(string));
+        }
+    }
+
+    /// Arrays and maps are re-encoded into `buf`.
+    fn cache_value<'a>(
+        &mut self,
+        value: Value<'a, &'a str>,
+        buf: &'a mut Vec<u8>,
+    ) -> Value<'a, CacheString<'a>> {
         match value {
             Value::Debug(string) => Value::Debug(self.cache_string(string)),
             Value::String(string) => Value::String(self.cache_string(string)),
             Value::Float(value) => Value::Float(value),
             Value::Integer(value) => Value::Integer(value),
             Value::Unsigned(value) => Value::Unsigned(value),
+            Value::I128(value) => Value::I128(value),
+            Value::U128(value) => Value::U128(value),
             Value::Bool(value) => Value::Bool(value),
             Value::ByteArray(value) => Value::ByteArray(value),
+            Value::Custom { tag, data } => Value::Custom { tag, data },
+            Value::Array(nested) => Value::Array(self.cache_nested(nested, false, buf)),
+            Value::Map(nested) => Value::Map(self.cache_nested(nested, true, buf)),
+            Value::Error { message, sources } => Value::Error {
+                message: self.cache_string(message),
+                sources: self.cache_nested(sources, false, buf),
+            },
+        }
+    }
+
+    /// Keeps the elements as they are if they are malformed.
+    fn cache_nested<'a>(
+        &mut self,
+        nested: Nested<'a>,
+        is_map: bool,
+        buf: &'a mut Vec<u8>,
+    ) -> Nested<'a> {
+        let mut cache = |str| match str {
+            CacheString::Present(str) => self.cache_string(str),
+            cached => cached,
+        };
+        match storage::map_nested_strings(nested, is_map, buf, &mut cache) {
+            Ok(()) => Nested::new(nested.len(), buf),
+            Err(_) => nested,
         }
     }
 
@@ -88,6 +409,10 @@ where
             return CacheString::Cached(*id);
         }
 
+        if self.strings.len() >= self.max_strings {
+            return CacheString::Present(string);
+        }
+
         let id = self.strings.len() as u64;
         let present = !matches!(
             (id, string.len()),
@@ -114,8 +439,21 @@ where
         self.forward.needs_restart()
     }
 
+    fn flush(&mut self) {
+        self.forward.flush();
+    }
+
+    /// The strings of `chunk` are written in full, as they were encoded without the cache.
+    fn handle_encoded(&mut self, chunk: &[u8]) -> io::Result<()> {
+        self.forward.handle_encoded(chunk)
+    }
+
     fn handle(&mut self, instruction: Instruction) {
         match instruction {
+            Instruction::Restart if self.snapshots => {
+                self.forward.handle(CacheInstruction::Restart);
+                self.snapshot();
+            }
             Instruction::Restart => {
                 self.strings.clear();
                 self.forward.handle(CacheInstruction::Restart);
@@ -139,13 +477,19 @@ where
                 span,
                 target,
                 priority,
+                location,
+                thread,
             } => {
                 let target = self.cache_string(target);
+                let location = location.map(|location| location.map(|str| self.cache_string(str)));
+                let thread = thread.map(|thread| thread.map(|str| self.cache_string(str)));
                 self.forward.handle(CacheInstruction::StartEvent {
                     time,
                     span,
                     target,
                     priority,
+                    location,
+                    thread,
                 });
             }
             Instruction::FinishedEvent => {
@@ -153,20 +497,115 @@ where
             }
             Instruction::AddValue(FieldValue { name, value }) => {
                 let name = self.cache_string(name);
-                let value = self.cache_value(value);
+                let mut buf = Vec::new();
+                let value = self.cache_value(value, &mut buf);
                 self.forward
                     .handle(CacheInstruction::AddValue(FieldValue { name, value }));
             }
             Instruction::DeleteSpan(span) => {
                 self.forward.handle(CacheInstruction::DeleteSpan(span));
             }
+            Instruction::FilterChanged(filter) => {
+                self.forward.handle(CacheInstruction::FilterChanged(filter));
+            }
+            Instruction::EnterSpan { time, span } => {
+                self.forward
+                    .handle(CacheInstruction::EnterSpan { time, span });
+            }
+            Instruction::ExitSpan { time, span } => {
+                self.forward
+                    .handle(CacheInstruction::ExitSpan { time, span });
+            }
+            Instruction::SpanTiming {
+                time,
+                span,
+                target,
+                priority,
+                busy,
+                idle,
+            } => {
+                let target = self.cache_string(target);
+                self.forward.handle(CacheInstruction::SpanTiming {
+                    time,
+                    span,
+                    target,
+                    priority,
+                    busy,
+                    idle,
+                });
+            }
         }
     }
 }
 
+/// Placeholder for cached strings that are unknown, either because they were never defined or
+/// because they were dropped due to the [`CacheLimit`].
+pub const MISSING_STRING: &str = "<missing string>";
+
+/// Caps the memory used by [`StringUncache`].
+#[derive(Clone, Copy, Debug)]
+pub struct CacheLimit {
+    /// Maximum amount of bytes kept for cached strings, including bookkeeping overhead.
+    pub max_bytes: usize,
+    pub on_full: CacheFull,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheFull {
+    /// Drops the oldest strings to make room for the new ones.
+    Evict,
+    /// Stops caching new strings until the next `Restart`, and flags the machine as overflowed.
+    Reject,
+}
+
+/// Statistics of the string cache usage, collected while decoding.
+#[derive(Clone, Debug, Default)]
+pub struct CacheStats {
+    /// Amount of `NewString` instructions.
+    pub strings: u64,
+    /// Amount of references to cached strings.
+    pub references: u64,
+    /// Bytes that would have been written if the strings were not cached.
+    pub bytes_saved: u64,
+    /// Largest cached strings, in decreasing size order.
+    pub largest: Vec<String>,
+}
+impl CacheStats {
+    const LARGEST: usize = 10;
+
+    fn new_string(&mut self, str: &str) {
+        self.strings += 1;
+
+        let position = self
+            .largest
+            .partition_point(|large| large.len() >= str.len());
+        if position < Self::LARGEST {
+            self.largest.insert(position, str.to_owned());
+            self.largest.truncate(Self::LARGEST);
+        }
+    }
+
+    fn reference(&mut self, index: u64, str: &str) {
+        let len = str.len() as u64;
+        let header = match len {
+            0..32 => 1,
+            32..256 => 2,
+            256..65536 => 3,
+            _ => 5,
+        };
+        let cached = 1 + CacheIndex::from(index).data().len() as u64;
+
+        self.references += 1;
+        self.bytes_saved += (header + len).saturating_sub(cached);
+    }
+}
+
 pub struct StringUncache<T> {
     forward: T,
-    strings: Vec<String>,
+    strings: UncacheStrings,
+    limit: Option<CacheLimit>,
+    overflowed: bool,
+    stats: CacheStats,
 }
 impl<T> StringUncache<T>
 where
@@ -176,28 +615,125 @@ where
         Self {
             forward,
             strings: Default::default(),
+            limit: None,
+            overflowed: false,
+            stats: Default::default(),
         }
     }
 
-    fn uncache<'a>(strings: &'a [String], string: CacheString<'a>) -> &'a str {
+    pub fn with_limit(forward: T, limit: CacheLimit) -> Self {
+        Self {
+            limit: Some(limit),
+            ..Self::new(forward)
+        }
+    }
+
+    pub fn stats(&self) -> &CacheStats {
+        &self.stats
+    }
+
+    /// Whether the tape tried to cache more strings than the limit allows.
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.forward
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.forward
+    }
+
+    pub fn into_inner(self) -> T {
+        self.forward
+    }
+
+    fn new_string(&mut self, str: &str) {
+        self.stats.new_string(str);
+
+        let size = UncacheStrings::size_of(str);
+        let Some(limit) = self.limit else {
+            self.strings.push(str, size);
+            return;
+        };
+
+        if self.strings.bytes + size > limit.max_bytes {
+            self.overflowed = true;
+        }
+
+        match limit.on_full {
+            CacheFull::Evict => {
+                while self.strings.bytes + size > limit.max_bytes && self.strings.evict() {}
+                if size <= limit.max_bytes {
+                    self.strings.push(str, size);
+                } else {
+                    self.strings.skip();
+                }
+            }
+            CacheFull::Reject if self.overflowed => (),
+            CacheFull::Reject => self.strings.push(str, size),
+        }
+    }
+
+    fn uncache<'a>(
+        strings: &'a UncacheStrings,
+        stats: &mut CacheStats,
+        string: CacheString<'a>,
+    ) -> &'a str {
         match string {
             CacheString::Present(str) => str,
-            CacheString::Cached(index) => strings[index as usize].as_str(),
+            CacheString::Cached(index) => {
+                let str = strings.get(index);
+                stats.reference(index, str);
+                str
+            }
         }
     }
 
+    /// Arrays and maps are re-encoded into `buf`.
     fn uncache_value<'a>(
-        strings: &'a [String],
+        strings: &'a UncacheStrings,
+        stats: &mut CacheStats,
         value: Value<'a, CacheString<'a>>,
+        buf: &'a mut Vec<u8>,
     ) -> Value<'a, &'a str> {
         match value {
-            Value::Debug(string) => Value::Debug(Self::uncache(strings, string)),
-            Value::String(string) => Value::String(Self::uncache(strings, string)),
+            Value::Debug(string) => Value::Debug(Self::uncache(strings, stats, string)),
+            Value::String(string) => Value::String(Self::uncache(strings, stats, string)),
             Value::Float(value) => Value::Float(value),
             Value::Integer(value) => Value::Integer(value),
             Value::Unsigned(value) => Value::Unsigned(value),
+            Value::I128(value) => Value::I128(value),
+            Value::U128(value) => Value::U128(value),
             Value::Bool(value) => Value::Bool(value),
             Value::ByteArray(items) => Value::ByteArray(items),
+            Value::Custom { tag, data } => Value::Custom { tag, data },
+            Value::Array(nested) => {
+                Value::Array(Self::uncache_nested(strings, stats, nested, false, buf))
+            }
+            Value::Map(nested) => {
+                Value::Map(Self::uncache_nested(strings, stats, nested, true, buf))
+            }
+            Value::Error { message, sources } => Value::Error {
+                message: Self::uncache(strings, stats, message),
+                sources: Self::uncache_nested(strings, stats, sources, false, buf),
+            },
+        }
+    }
+
+    /// Keeps the elements as they are if they are malformed.
+    fn uncache_nested<'a>(
+        strings: &'a UncacheStrings,
+        stats: &mut CacheStats,
+        nested: Nested<'a>,
+        is_map: bool,
+        buf: &'a mut Vec<u8>,
+    ) -> Nested<'a> {
+        let mut uncache = |str| CacheString::Present(Self::uncache(strings, stats, str));
+        match storage::map_nested_strings(nested, is_map, buf, &mut uncache) {
+            Ok(()) => Nested::new(nested.len(), buf),
+            Err(_) => nested,
         }
     }
 }
@@ -209,16 +745,22 @@ where
         self.forward.needs_restart()
     }
 
+    fn flush(&mut self) {
+        self.forward.flush();
+    }
+
     fn handle(&mut self, instruction: CacheInstruction) {
         match instruction {
             CacheInstruction::Restart => {
+                self.strings = Default::default();
+                self.overflowed = false;
                 self.forward.handle(Instruction::Restart);
             }
             CacheInstruction::NewString(str) => {
-                self.strings.push(str.to_owned());
+                self.new_string(str);
             }
             CacheInstruction::NewSpan { parent, span, name } => {
-                let name = Self::uncache(&self.strings, name);
+                let name = Self::uncache(&self.strings, &mut self.stats, name);
                 self.forward
                     .handle(Instruction::NewSpan { parent, span, name });
             }
@@ -236,28 +778,225 @@ where
                 span,
                 target,
                 priority,
+                location,
+                thread,
             } => {
-                let target = Self::uncache(&self.strings, target);
+                let target = Self::uncache(&self.strings, &mut self.stats, target);
+                let location = location.map(|location| {
+                    location.map(|str| Self::uncache(&self.strings, &mut self.stats, str))
+                });
+                let thread = thread.map(|thread| {
+                    thread.map(|str| Self::uncache(&self.strings, &mut self.stats, str))
+                });
 
                 self.forward.handle(Instruction::StartEvent {
                     time,
                     span,
                     target,
                     priority,
+                    location,
+                    thread,
                 });
             }
             CacheInstruction::FinishedEvent => {
                 self.forward.handle(Instruction::FinishedEvent);
             }
             CacheInstruction::AddValue(FieldValue { name, value }) => {
-                let name = Self::uncache(&self.strings, name);
-                let value = Self::uncache_value(&self.strings, value);
+                let name = Self::uncache(&self.strings, &mut self.stats, name);
+                let mut buf = Vec::new();
+                let value = Self::uncache_value(&self.strings, &mut self.stats, value, &mut buf);
                 self.forward
                     .handle(Instruction::AddValue(FieldValue { name, value }));
             }
             CacheInstruction::DeleteSpan(span) => {
                 self.forward.handle(Instruction::DeleteSpan(span));
             }
+            CacheInstruction::FilterChanged(filter) => {
+                self.forward.handle(Instruction::FilterChanged(filter));
+            }
+            CacheInstruction::EnterSpan { time, span } => {
+                self.forward.handle(Instruction::EnterSpan { time, span });
+            }
+            CacheInstruction::ExitSpan { time, span } => {
+                self.forward.handle(Instruction::ExitSpan { time, span });
+            }
+            CacheInstruction::SpanTiming {
+                time,
+                span,
+                target,
+                priority,
+                busy,
+                idle,
+            } => {
+                let target = Self::uncache(&self.strings, &mut self.stats, target);
+                self.forward.handle(Instruction::SpanTiming {
+                    time,
+                    span,
+                    target,
+                    priority,
+                    busy,
+                    idle,
+                });
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct UncacheStrings {
+    strings: VecDeque<String>,
+    first: u64,
+    bytes: usize,
+}
+impl UncacheStrings {
+    fn size_of(str: &str) -> usize {
+        str.len() + std::mem::size_of::<String>()
+    }
+
+    fn get(&self, index: u64) -> &str {
+        index
+            .checked_sub(self.first)
+            .and_then(|index| self.strings.get(index as usize))
+            .map(String::as_str)
+            .unwrap_or(MISSING_STRING)
+    }
+
+    fn push(&mut self, str: &str, size: usize) {
+        self.strings.push_back(str.to_owned());
+        self.bytes += size;
+    }
+
+    fn evict(&mut self) -> bool {
+        let Some(str) = self.strings.pop_front() else {
+            return false;
+        };
+        self.first += 1;
+        self.bytes -= Self::size_of(&str);
+        true
+    }
+
+    fn skip(&mut self) {
+        self.first += self.strings.len() as u64 + 1;
+        self.strings.clear();
+        self.bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tape::FieldValueOwned;
+
+    struct Collect(Vec<FieldValueOwned>);
+    impl TapeMachine<InstructionSet> for Collect {
+        fn needs_restart(&mut self) -> bool {
+            false
+        }
+
+        fn handle(&mut self, instruction: Instruction) {
+            if let Instruction::AddValue(value) = instruction {
+                self.0.push(value.to_owned());
+            }
+        }
+    }
+
+    fn add_cached(uncache: &mut StringUncache<Collect>, index: u64) {
+        uncache.handle(CacheInstruction::AddValue(FieldValue {
+            name: CacheString::Present("name"),
+            value: Value::String(CacheString::Cached(index)),
+        }));
+    }
+
+    fn values(uncache: &StringUncache<Collect>) -> Vec<String> {
+        uncache
+            .forward
+            .0
+            .iter()
+            .map(|value| match &value.value {
+                crate::tape::ValueOwned::String(str) => str.clone(),
+                _ => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn limit_evicts_oldest() {
+        let max_bytes = 2 * UncacheStrings::size_of("aaaa");
+        let mut uncache = StringUncache::with_limit(
+            Collect(Vec::new()),
+            CacheLimit {
+                max_bytes,
+                on_full: CacheFull::Evict,
+            },
+        );
+
+        for str in ["aaaa", "bbbb", "cccc"] {
+            uncache.handle(CacheInstruction::NewString(str));
+        }
+        for index in 0..3 {
+            add_cached(&mut uncache, index);
+        }
+
+        assert!(uncache.overflowed());
+        assert_eq!(values(&uncache), [MISSING_STRING, "bbbb", "cccc"]);
+    }
+
+    #[test]
+    fn limit_rejects_new() {
+        let max_bytes = 2 * UncacheStrings::size_of("aaaa");
+        let mut uncache = StringUncache::with_limit(
+            Collect(Vec::new()),
+            CacheLimit {
+                max_bytes,
+                on_full: CacheFull::Reject,
+            },
+        );
+
+        for str in ["aaaa", "bbbb", "cccc"] {
+            uncache.handle(CacheInstruction::NewString(str));
         }
+        for index in 0..3 {
+            add_cached(&mut uncache, index);
+        }
+
+        assert!(uncache.overflowed());
+        assert_eq!(values(&uncache), ["aaaa", "bbbb", MISSING_STRING]);
+    }
+
+    #[test]
+    fn owned_across_threads() {
+        struct Channel(std::sync::mpsc::Sender<CacheInstructionOwned>);
+        impl TapeMachine<CacheInstructionSet> for Channel {
+            fn needs_restart(&mut self) -> bool {
+                false
+            }
+
+            fn handle(&mut self, instruction: CacheInstruction) {
+                let owned = instruction.to_owned();
+                assert_eq!(owned.as_ref().to_owned(), owned);
+                self.0.send(owned).unwrap();
+            }
+        }
+
+        let (sender, receiver) = std::sync::mpsc::channel::<CacheInstructionOwned>();
+        let uncache = std::thread::spawn(move || {
+            let mut uncache = StringUncache::new(Collect(Vec::new()));
+            for instruction in receiver {
+                uncache.handle(instruction.as_ref());
+            }
+            uncache
+        });
+
+        let mut cache = StringCache::new(Channel(sender));
+        for str in ["aaaa", "bbbb", "aaaa"] {
+            cache.handle(Instruction::AddValue(FieldValue {
+                name: "name",
+                value: Value::String(str),
+            }));
+        }
+        drop(cache);
+
+        let uncache = uncache.join().unwrap();
+        assert_eq!(values(&uncache), ["aaaa", "bbbb", "aaaa"]);
     }
 }
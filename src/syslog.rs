@@ -0,0 +1,353 @@
+use crate::{
+    printer::{NewEvent, resolve_spans},
+    tape::{Instruction, InstructionSet, SpanRecords, TapeMachine},
+};
+use chrono::SecondsFormat;
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    net::{TcpStream, ToSocketAddrs, UdpSocket},
+    num::NonZeroU64,
+};
+use tracing::Level;
+
+/// Path of the local syslog socket.
+#[cfg(unix)]
+pub const DEV_LOG: &str = "/dev/log";
+
+/// Facility of the messages of [`Syslog`], see RFC 5424 section 6.2.1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Facility {
+    Kernel = 0,
+    User = 1,
+    Mail = 2,
+    Daemon = 3,
+    Auth = 4,
+    Syslog = 5,
+    Local0 = 16,
+    Local1 = 17,
+    Local2 = 18,
+    Local3 = 19,
+    Local4 = 20,
+    Local5 = 21,
+    Local6 = 22,
+    Local7 = 23,
+}
+
+/// Syslog severity of a level. TRACE and DEBUG are both debug.
+pub fn severity(level: Level) -> u8 {
+    match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+enum Transport {
+    #[cfg(unix)]
+    Local(std::os::unix::net::UnixDatagram),
+    Udp(UdpSocket),
+    /// Messages framed by octet counting, see RFC 6587.
+    Tcp(TcpStream),
+}
+impl Transport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        match self {
+            #[cfg(unix)]
+            Transport::Local(socket) => socket.send(message).map(drop),
+            Transport::Udp(socket) => socket.send(message).map(drop),
+            Transport::Tcp(stream) => {
+                write!(stream, "{} ", message.len())?;
+                stream.write_all(message)
+            }
+        }
+    }
+}
+
+/// Sends events as RFC 5424 messages to a syslog daemon.
+///
+/// The message is the event as printed by [`Printer`](crate::printer::Printer), without its time
+/// and level, which go in the header. Messages that fail to be sent are dropped.
+pub struct Syslog {
+    transport: Transport,
+    facility: Facility,
+    hostname: String,
+    app_name: String,
+    proc_id: u32,
+    span: HashMap<NonZeroU64, SpanRecords>,
+    new_records: Option<(NonZeroU64, SpanRecords)>,
+    new_event: Option<NewEvent>,
+}
+impl Syslog {
+    /// Sends to the local daemon through [`DEV_LOG`].
+    #[cfg(unix)]
+    pub fn local() -> io::Result<Self> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket.connect(DEV_LOG)?;
+        Ok(Self::new(Transport::Local(socket)))
+    }
+
+    /// Sends to a remote host over UDP, usually on port 514.
+    pub fn udp<A: ToSocketAddrs>(address: A) -> io::Result<Self> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.connect(address)?;
+        Ok(Self::new(Transport::Udp(socket)))
+    }
+
+    /// Sends to a remote host over TCP, usually on port 601.
+    pub fn tcp<A: ToSocketAddrs>(address: A) -> io::Result<Self> {
+        Ok(Self::new(Transport::Tcp(TcpStream::connect(address)?)))
+    }
+
+    fn new(transport: Transport) -> Self {
+        let app_name = std::env::current_exe()
+            .ok()
+            .and_then(|exe| Some(exe.file_name()?.to_str()?.to_owned()));
+
+        Self {
+            transport,
+            facility: Facility::User,
+            hostname: std::env::var("HOSTNAME").unwrap_or_default(),
+            app_name: app_name.unwrap_or_default(),
+            proc_id: std::process::id(),
+            span: Default::default(),
+            new_records: None,
+            new_event: None,
+        }
+    }
+
+    pub fn with_facility(mut self, facility: Facility) -> Self {
+        self.facility = facility;
+        self
+    }
+
+    /// Defaults to `$HOSTNAME`.
+    pub fn with_hostname(mut self, hostname: &str) -> Self {
+        self.hostname = hostname.to_owned();
+        self
+    }
+
+    /// Defaults to the name of the executable.
+    pub fn with_app_name(mut self, app_name: &str) -> Self {
+        self.app_name = app_name.to_owned();
+        self
+    }
+
+    /// RFC 5424 message of `event`, without structured data.
+    fn message(&self, event: &NewEvent) -> String {
+        let spans = resolve_spans(&self.span, event.span);
+        let mut body = String::new();
+        event.write_body_with(false, &Default::default(), &spans, &mut body);
+        let nil = |str: &str| match str.is_empty() {
+            true => "-".to_owned(),
+            false => str.replace(' ', "_"),
+        };
+
+        format!(
+            "<{}>1 {} {} {} {} - - {}",
+            self.facility as u8 * 8 + severity(event.priority),
+            event.time.to_rfc3339_opts(SecondsFormat::Micros, true),
+            nil(&self.hostname),
+            nil(&self.app_name),
+            self.proc_id,
+            body.trim_start()
+        )
+    }
+}
+impl TapeMachine<InstructionSet> for Syslog {
+    fn needs_restart(&mut self) -> bool {
+        false
+    }
+
+    fn flush(&mut self) {
+        if let Transport::Tcp(stream) = &mut self.transport {
+            let _ = stream.flush();
+        }
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Restart => {
+                self.span.clear();
+                self.new_records = None;
+                self.new_event = None;
+            }
+            Instruction::NewSpan { parent, span, name } => {
+                self.new_records = Some((
+                    span,
+                    SpanRecords {
+                        parent,
+                        name: name.to_owned(),
+                        records: Default::default(),
+                    },
+                ));
+            }
+            Instruction::NewRecord(span) => {
+                let records = self
+                    .span
+                    .remove(&span)
+                    .unwrap_or_else(|| SpanRecords::lost(span));
+                self.new_records = Some((span, records));
+            }
+            Instruction::FinishedSpan | Instruction::FinishedRecord => {
+                if let Some((span, records)) = self.new_records.take() {
+                    self.span.insert(span, records);
+                }
+            }
+            Instruction::StartEvent {
+                time,
+                span,
+                target,
+                priority,
+                location,
+                thread,
+            } => {
+                self.new_event = Some(NewEvent {
+                    time,
+                    span,
+                    target: target.to_owned(),
+                    priority,
+                    location: location.map(|location| location.map(str::to_owned)),
+                    thread: thread.map(|thread| thread.map(str::to_owned)),
+                    records: Default::default(),
+                });
+            }
+            Instruction::AddValue(value) => match (&mut self.new_records, &mut self.new_event) {
+                (Some((_, records)), _) => records.record(value.to_owned()),
+                (_, Some(event)) => event.records.push(value.to_owned()),
+                _ => (),
+            },
+            Instruction::FinishedEvent => {
+                let Some(event) = self.new_event.take() else {
+                    return;
+                };
+                let message = self.message(&event);
+                let _ = self.transport.send(message.as_bytes());
+            }
+            Instruction::DeleteSpan(span) => {
+                self.span.remove(&span);
+            }
+            Instruction::FilterChanged(..)
+            | Instruction::EnterSpan { .. }
+            | Instruction::ExitSpan { .. }
+            | Instruction::SpanTiming { .. } => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        tape::{FieldValue, Value},
+        test_util::{event, golden_tape, start_event},
+    };
+    use chrono::DateTime;
+    use std::{io::Read, net::TcpListener};
+
+    fn recv(server: &UdpSocket) -> String {
+        let mut buf = [0; 1024];
+        let n = server.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    }
+
+    fn udp(server: &UdpSocket) -> Syslog {
+        Syslog::udp(server.local_addr().unwrap())
+            .unwrap()
+            .with_facility(Facility::Local0)
+            .with_hostname("host")
+            .with_app_name("app")
+    }
+
+    #[test]
+    fn udp_message() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut syslog = udp(&server);
+        syslog.handle(Instruction::Restart);
+        event(
+            &mut syslog,
+            start_event(DateTime::UNIX_EPOCH, None, "target", Level::WARN),
+            &[("message", Value::Debug("disk full"))],
+        );
+
+        assert_eq!(
+            recv(&server),
+            format!(
+                "<132>1 1970-01-01T00:00:00.000000Z host app {} - - target: disk full",
+                std::process::id()
+            )
+        );
+    }
+
+    #[test]
+    fn event_cut_by_restart() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut syslog = udp(&server).with_hostname("");
+        syslog.handle(Instruction::Restart);
+        syslog.handle(start_event(DateTime::UNIX_EPOCH, None, "cut", Level::INFO));
+        syslog.handle(Instruction::AddValue(FieldValue {
+            name: "message",
+            value: Value::Debug("never finished"),
+        }));
+        syslog.handle(Instruction::Restart);
+        event(
+            &mut syslog,
+            start_event(DateTime::UNIX_EPOCH, None, "target", Level::INFO),
+            &[],
+        );
+
+        assert_eq!(
+            recv(&server),
+            format!(
+                "<134>1 1970-01-01T00:00:00.000000Z - app {} - - target:",
+                std::process::id()
+            )
+        );
+    }
+
+    #[test]
+    fn tcp_octet_counting() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut syslog = Syslog::tcp(listener.local_addr().unwrap())
+            .unwrap()
+            .with_hostname("host")
+            .with_app_name("app");
+        let (mut stream, _) = listener.accept().unwrap();
+        golden_tape(&mut syslog);
+        syslog.flush();
+        drop(syslog);
+
+        let mut out = String::new();
+        stream.read_to_string(&mut out).unwrap();
+        let mut messages = Vec::new();
+        let mut rest = out.as_str();
+        while let Some((len, message)) = rest.split_once(' ') {
+            let (message, next) = message.split_at(len.parse().unwrap());
+            messages.push(message);
+            rest = next;
+        }
+        assert_eq!(rest, "");
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].starts_with("<11>1 1970-01-01T00:00:00.000000Z host app "));
+        assert!(messages[1].starts_with("<11>1 1969-12-31T23:59:59.000000Z host app "));
+    }
+
+    #[test]
+    fn drops_unsent_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut syslog = Syslog::tcp(listener.local_addr().unwrap()).unwrap();
+        drop(listener.accept().unwrap());
+        drop(listener);
+
+        for _ in 0..2 {
+            event(
+                &mut syslog,
+                start_event(DateTime::UNIX_EPOCH, None, "target", Level::INFO),
+                &[],
+            );
+        }
+        syslog.flush();
+    }
+}
@@ -1,15 +1,34 @@
+pub use crate::level_filter::FilterMachine;
+use crate::{custom_value::CustomValues, storage, string_cache::CacheString};
 use chrono::{DateTime, Utc};
 use std::{
+    cell::{Cell, RefCell},
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
     num::NonZeroU64,
-    ops::DerefMut,
-    sync::{Mutex, MutexGuard},
+    ops::{BitOr, Deref, DerefMut},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex, MutexGuard,
+        atomic::{AtomicU64, Ordering},
+        mpsc,
+    },
+    time::{Duration, Instant},
 };
 use tracing::{
     Level, Subscriber,
     field::{Field, Visit},
     span,
 };
-use tracing_subscriber::{Layer, registry::LookupSpan};
+use tracing_subscriber::{
+    Layer,
+    registry::{LookupSpan, SpanRef},
+};
+
+/// Field that replaces the target of an event, allowing targets that are not known at compile time.
+///
+/// The field itself is not recorded. See [`event_with_target!`](crate::event_with_target).
+pub const TARGET_FIELD: &str = "tape.target";
 
 pub trait TapeMachine<I>: Send + 'static
 where
@@ -17,14 +36,120 @@ where
 {
     fn needs_restart(&mut self) -> bool;
     fn handle(&mut self, instruction: I::Instruction<'_>);
+
+    /// Writes out anything buffered by the machine.
+    fn flush(&mut self) {}
+
+    /// Handles the instructions of `chunk`, a tape encoded by [`Store`](storage::Store) without
+    /// any of its options, e.g. by [`TapeMachineLogger::with_staging`]. Its `Restart`s are not
+    /// handled.
+    ///
+    /// Machines writing the tape append the chunk as it is, the others decode it. Errors are of
+    /// decoding, the instructions after an undecodable one are skipped up to the next `Restart`.
+    fn handle_encoded(&mut self, chunk: &[u8]) -> io::Result<()> {
+        I::decode(chunk, |instruction| self.handle(instruction))
+    }
+}
+
+impl<I, T> TapeMachine<I> for Box<T>
+where
+    I: InstructionSetTrait,
+    T: TapeMachine<I> + ?Sized,
+{
+    fn needs_restart(&mut self) -> bool {
+        (**self).needs_restart()
+    }
+
+    fn handle(&mut self, instruction: I::Instruction<'_>) {
+        (**self).handle(instruction)
+    }
+
+    fn flush(&mut self) {
+        (**self).flush()
+    }
+
+    fn handle_encoded(&mut self, chunk: &[u8]) -> io::Result<()> {
+        (**self).handle_encoded(chunk)
+    }
+}
+
+/// Forwards every instruction to two machines, e.g. a file and a socket. Nest it to fan out to
+/// more.
+///
+/// Asks for a restart if any of them needs one, which both then receive.
+pub struct Tee<A, B> {
+    pub a: A,
+    pub b: B,
+}
+impl<A, B> Tee<A, B> {
+    pub fn new(a: A, b: B) -> Self {
+        Self { a, b }
+    }
+
+    pub fn into_inner(self) -> (A, B) {
+        (self.a, self.b)
+    }
+}
+impl<I, A, B> TapeMachine<I> for Tee<A, B>
+where
+    I: InstructionSetTrait,
+    A: TapeMachine<I>,
+    B: TapeMachine<I>,
+{
+    fn needs_restart(&mut self) -> bool {
+        // Both are asked, as asking may have effects like rotating a file
+        self.a.needs_restart() | self.b.needs_restart()
+    }
+
+    fn handle(&mut self, instruction: I::Instruction<'_>) {
+        self.a.handle(instruction);
+        self.b.handle(instruction);
+    }
+
+    fn flush(&mut self) {
+        self.a.flush();
+        self.b.flush();
+    }
+
+    fn handle_encoded(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let a = self.a.handle_encoded(chunk);
+        self.b.handle_encoded(chunk).and(a)
+    }
 }
 
 pub trait InstructionSetTrait {
     type Instruction<'a>: InstructionTrait;
+
+    /// Calls `handle` with the instructions of the tape in `chunk`, but its `Restart`s, see
+    /// [`TapeMachine::handle_encoded`].
+    fn decode<F>(chunk: &[u8], handle: F) -> io::Result<()>
+    where
+        F: FnMut(Self::Instruction<'_>);
 }
 pub struct InstructionSet;
 impl InstructionSetTrait for InstructionSet {
     type Instruction<'a> = Instruction<'a>;
+
+    fn decode<F>(chunk: &[u8], mut handle: F) -> io::Result<()>
+    where
+        F: FnMut(Instruction<'_>),
+    {
+        let mut load = storage::Load::new(chunk);
+        let mut error = None;
+        loop {
+            match load.fetch_one() {
+                Ok(Some(Instruction::Restart)) => (),
+                Ok(Some(instruction)) => handle(instruction),
+                Ok(None) => break,
+                Err(e) => {
+                    error.get_or_insert(e);
+                    load.restart();
+                }
+            }
+        }
+
+        error.map_or(Ok(()), Err)
+    }
 }
 
 pub trait InstructionTrait: Copy {
@@ -47,10 +172,30 @@ pub enum Instruction<'a> {
         span: Option<NonZeroU64>,
         target: &'a str,
         priority: Level,
+        location: Option<Location<&'a str>>,
+        thread: Option<ThreadInfo<&'a str>>,
     },
     FinishedEvent,
     AddValue(FieldValue<'a, &'a str>),
     DeleteSpan(NonZeroU64),
+    FilterChanged(&'a str),
+    EnterSpan {
+        time: DateTime<Utc>,
+        span: NonZeroU64,
+    },
+    ExitSpan {
+        time: DateTime<Utc>,
+        span: NonZeroU64,
+    },
+    /// Time spent inside and outside of a span, right before its `DeleteSpan`.
+    SpanTiming {
+        time: DateTime<Utc>,
+        span: NonZeroU64,
+        target: &'a str,
+        priority: Level,
+        busy: Duration,
+        idle: Duration,
+    },
 }
 impl InstructionTrait for Instruction<'_> {
     fn id(self) -> InstructionId {
@@ -64,11 +209,175 @@ impl InstructionTrait for Instruction<'_> {
             Instruction::FinishedEvent => InstructionId::FinishedEvent,
             Instruction::AddValue(..) => InstructionId::AddValue,
             Instruction::DeleteSpan(..) => InstructionId::DeleteSpan,
+            Instruction::FilterChanged(..) => InstructionId::FilterChanged,
+            Instruction::EnterSpan { .. } => InstructionId::EnterSpan,
+            Instruction::ExitSpan { .. } => InstructionId::ExitSpan,
+            Instruction::SpanTiming { .. } => InstructionId::SpanTiming,
+        }
+    }
+}
+impl Instruction<'_> {
+    pub fn to_owned(self) -> InstructionOwned {
+        match self {
+            Instruction::Restart => InstructionOwned::Restart,
+            Instruction::NewSpan { parent, span, name } => InstructionOwned::NewSpan {
+                parent,
+                span,
+                name: name.to_owned(),
+            },
+            Instruction::FinishedSpan => InstructionOwned::FinishedSpan,
+            Instruction::NewRecord(span) => InstructionOwned::NewRecord(span),
+            Instruction::FinishedRecord => InstructionOwned::FinishedRecord,
+            Instruction::StartEvent {
+                time,
+                span,
+                target,
+                priority,
+                location,
+                thread,
+            } => InstructionOwned::StartEvent {
+                time,
+                span,
+                target: target.to_owned(),
+                priority,
+                location: location.map(|location| location.map(str::to_owned)),
+                thread: thread.map(|thread| thread.map(str::to_owned)),
+            },
+            Instruction::FinishedEvent => InstructionOwned::FinishedEvent,
+            Instruction::AddValue(value) => InstructionOwned::AddValue(value.to_owned()),
+            Instruction::DeleteSpan(span) => InstructionOwned::DeleteSpan(span),
+            Instruction::FilterChanged(filter) => {
+                InstructionOwned::FilterChanged(filter.to_owned())
+            }
+            Instruction::EnterSpan { time, span } => InstructionOwned::EnterSpan { time, span },
+            Instruction::ExitSpan { time, span } => InstructionOwned::ExitSpan { time, span },
+            Instruction::SpanTiming {
+                time,
+                span,
+                target,
+                priority,
+                busy,
+                idle,
+            } => InstructionOwned::SpanTiming {
+                time,
+                span,
+                target: target.to_owned(),
+                priority,
+                busy,
+                idle,
+            },
         }
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+/// Owned [`Instruction`], to be sent to another thread or kept after the next one is read, e.g. by
+/// [`Load::iter`](crate::storage::Load::iter).
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum InstructionOwned {
+    Restart,
+    NewSpan {
+        parent: Option<NonZeroU64>,
+        span: NonZeroU64,
+        name: String,
+    },
+    FinishedSpan,
+    NewRecord(NonZeroU64),
+    FinishedRecord,
+    StartEvent {
+        time: DateTime<Utc>,
+        span: Option<NonZeroU64>,
+        target: String,
+        #[cfg_attr(feature = "serde", serde(with = "serde_fields::level"))]
+        priority: Level,
+        location: Option<Location<String>>,
+        thread: Option<ThreadInfo<String>>,
+    },
+    FinishedEvent,
+    AddValue(FieldValueOwned),
+    DeleteSpan(NonZeroU64),
+    FilterChanged(String),
+    EnterSpan {
+        time: DateTime<Utc>,
+        span: NonZeroU64,
+    },
+    ExitSpan {
+        time: DateTime<Utc>,
+        span: NonZeroU64,
+    },
+    SpanTiming {
+        time: DateTime<Utc>,
+        span: NonZeroU64,
+        target: String,
+        #[cfg_attr(feature = "serde", serde(with = "serde_fields::level"))]
+        priority: Level,
+        busy: Duration,
+        idle: Duration,
+    },
+}
+impl InstructionOwned {
+    pub fn as_ref(&self) -> Instruction<'_> {
+        match self {
+            InstructionOwned::Restart => Instruction::Restart,
+            InstructionOwned::NewSpan { parent, span, name } => Instruction::NewSpan {
+                parent: *parent,
+                span: *span,
+                name,
+            },
+            InstructionOwned::FinishedSpan => Instruction::FinishedSpan,
+            InstructionOwned::NewRecord(span) => Instruction::NewRecord(*span),
+            InstructionOwned::FinishedRecord => Instruction::FinishedRecord,
+            InstructionOwned::StartEvent {
+                time,
+                span,
+                target,
+                priority,
+                location,
+                thread,
+            } => Instruction::StartEvent {
+                time: *time,
+                span: *span,
+                target,
+                priority: *priority,
+                location: location
+                    .as_ref()
+                    .map(|location| location.as_ref().map(String::as_str)),
+                thread: thread
+                    .as_ref()
+                    .map(|thread| thread.as_ref().map(String::as_str)),
+            },
+            InstructionOwned::FinishedEvent => Instruction::FinishedEvent,
+            InstructionOwned::AddValue(value) => Instruction::AddValue(value.as_ref()),
+            InstructionOwned::DeleteSpan(span) => Instruction::DeleteSpan(*span),
+            InstructionOwned::FilterChanged(filter) => Instruction::FilterChanged(filter),
+            InstructionOwned::EnterSpan { time, span } => Instruction::EnterSpan {
+                time: *time,
+                span: *span,
+            },
+            InstructionOwned::ExitSpan { time, span } => Instruction::ExitSpan {
+                time: *time,
+                span: *span,
+            },
+            InstructionOwned::SpanTiming {
+                time,
+                span,
+                target,
+                priority,
+                busy,
+                idle,
+            } => Instruction::SpanTiming {
+                time: *time,
+                span: *span,
+                target,
+                priority: *priority,
+                busy: *busy,
+                idle: *idle,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum InstructionId {
     Restart,
     NewString,
@@ -80,40 +389,64 @@ pub enum InstructionId {
     FinishedEvent,
     AddValue,
     DeleteSpan,
+    FilterChanged,
+    EnterSpan,
+    ExitSpan,
+    SpanTiming,
 }
-impl From<InstructionId> for u8 {
-    fn from(val: InstructionId) -> Self {
-        match val {
-            InstructionId::Restart => 255,
-            InstructionId::NewString => 1,
-            InstructionId::NewSpan => 2,
-            InstructionId::FinishedSpan => 4,
-            InstructionId::NewRecord => 8,
-            InstructionId::FinishedRecord => 16,
-            InstructionId::StartEvent => 32,
-            InstructionId::FinishedEvent => 64,
-            InstructionId::AddValue => 128,
-            InstructionId::DeleteSpan => 0,
-        }
-    }
-}
-impl TryFrom<u8> for InstructionId {
-    type Error = u8;
-
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        Ok(match value {
-            255 => InstructionId::Restart,
-            1 => InstructionId::NewString,
-            2 => InstructionId::NewSpan,
-            4 => InstructionId::FinishedSpan,
-            8 => InstructionId::NewRecord,
-            16 => InstructionId::FinishedRecord,
-            32 => InstructionId::StartEvent,
-            64 => InstructionId::FinishedEvent,
-            128 => InstructionId::AddValue,
-            0 => InstructionId::DeleteSpan,
-            e => return Err(e),
-        })
+impl InstructionId {
+    /// Byte of `Restart` in every format version, so that readers find it before knowing the
+    /// version.
+    pub const RESTART: u8 = 255;
+
+    /// Every instruction but `Restart`, numbered from 1 since format version 2. Bytes up to 127
+    /// are left for new instructions.
+    const TABLE: [InstructionId; 13] = [
+        InstructionId::NewString,
+        InstructionId::NewSpan,
+        InstructionId::FinishedSpan,
+        InstructionId::NewRecord,
+        InstructionId::FinishedRecord,
+        InstructionId::StartEvent,
+        InstructionId::FinishedEvent,
+        InstructionId::AddValue,
+        InstructionId::DeleteSpan,
+        InstructionId::FilterChanged,
+        InstructionId::EnterSpan,
+        InstructionId::ExitSpan,
+        InstructionId::SpanTiming,
+    ];
+
+    /// Bytes of the start of [`TABLE`](Self::TABLE) up to format version 1, which has no later
+    /// instructions.
+    const LEGACY_TABLE: [u8; 10] = [1, 2, 4, 8, 16, 32, 64, 128, 0, 3];
+
+    /// Byte of the instruction in the tape format `version`, or `None` if the instruction does not
+    /// exist in `version`.
+    pub fn to_byte(self, version: u8) -> Option<u8> {
+        let Some(index) = Self::TABLE.iter().position(|id| *id == self) else {
+            return Some(Self::RESTART);
+        };
+
+        match version {
+            0 | 1 => Self::LEGACY_TABLE.get(index).copied(),
+            _ => Some(index as u8 + 1),
+        }
+    }
+
+    /// Instruction of `byte` in the tape format `version`, or the unknown byte.
+    pub fn from_byte(byte: u8, version: u8) -> Result<Self, u8> {
+        if byte == Self::RESTART {
+            return Ok(InstructionId::Restart);
+        }
+
+        let index = match version {
+            0 | 1 => Self::LEGACY_TABLE.iter().position(|id| *id == byte),
+            _ => byte.checked_sub(1).map(usize::from),
+        };
+        index
+            .and_then(|index| Self::TABLE.get(index).copied())
+            .ok_or(byte)
     }
 }
 
@@ -131,13 +464,16 @@ impl<'a> FieldValue<'a, &'a str> {
     }
 }
 
-#[derive(Clone)]
-pub struct FieldValueOwned {
-    pub name: String,
-    pub value: ValueOwned,
+/// Owned [`FieldValue`]. Strings are `String`s, or
+/// [`CacheStringOwned`](crate::string_cache::CacheStringOwned)s for cached instructions.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FieldValueOwned<S = String> {
+    pub name: S,
+    pub value: ValueOwned<S>,
 }
 impl FieldValueOwned {
-    pub fn as_ref(&self) -> FieldValue<&str> {
+    pub fn as_ref(&self) -> FieldValue<'_, &str> {
         FieldValue {
             name: &self.name,
             value: self.value.as_ref(),
@@ -145,6 +481,82 @@ impl FieldValueOwned {
     }
 }
 
+/// Source code location of an event. See [`TapeMachineLogger::with_source_location`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Location<S> {
+    pub file: S,
+    pub line: u32,
+    /// Empty when unknown.
+    pub module_path: S,
+}
+impl<S> Location<S> {
+    pub fn as_ref(&self) -> Location<&S> {
+        Location {
+            file: &self.file,
+            line: self.line,
+            module_path: &self.module_path,
+        }
+    }
+
+    pub fn map<T, F>(self, mut f: F) -> Location<T>
+    where
+        F: FnMut(S) -> T,
+    {
+        Location {
+            file: f(self.file),
+            line: self.line,
+            module_path: f(self.module_path),
+        }
+    }
+}
+impl<'a> Location<&'a str> {
+    pub fn from_metadata(metadata: &'a tracing::Metadata<'a>) -> Option<Self> {
+        Some(Location {
+            file: metadata.file()?,
+            line: metadata.line()?,
+            module_path: metadata.module_path().unwrap_or_default(),
+        })
+    }
+}
+
+/// Thread that emitted an event. See [`TapeMachineLogger::with_thread_info`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ThreadInfo<S> {
+    /// Sequential id, assigned on the first event of each thread.
+    pub id: u64,
+    /// Empty for unnamed threads.
+    pub name: S,
+}
+impl<S> ThreadInfo<S> {
+    pub fn as_ref(&self) -> ThreadInfo<&S> {
+        ThreadInfo {
+            id: self.id,
+            name: &self.name,
+        }
+    }
+
+    pub fn map<T, F>(self, f: F) -> ThreadInfo<T>
+    where
+        F: FnOnce(S) -> T,
+    {
+        ThreadInfo {
+            id: self.id,
+            name: f(self.name),
+        }
+    }
+}
+
+fn current_thread_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    thread_local! {
+        static ID: u64 = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    }
+
+    ID.with(|id| *id)
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum Value<'a, S> {
     Debug(S),
@@ -152,8 +564,25 @@ pub enum Value<'a, S> {
     Float(f64),
     Integer(i64),
     Unsigned(u64),
+    I128(i128),
+    U128(u128),
     Bool(bool),
     ByteArray(&'a [u8]),
+    /// Value encoded by a [`CustomValue`](crate::custom_value::CustomValue).
+    Custom {
+        tag: i8,
+        data: &'a [u8],
+    },
+    /// Elements stored as values of their own, see [`Nested::values`].
+    Array(Nested<'a>),
+    /// Entries of a string key and a value, see [`Nested::entries`].
+    Map(Nested<'a>),
+    /// Error with the messages of its [`source`](std::error::Error::source) chain, as an array of
+    /// strings.
+    Error {
+        message: S,
+        sources: Nested<'a>,
+    },
 }
 impl<S> From<f64> for Value<'_, S> {
     fn from(value: f64) -> Self {
@@ -170,6 +599,16 @@ impl<S> From<u64> for Value<'_, S> {
         Self::Unsigned(value)
     }
 }
+impl<S> From<i128> for Value<'_, S> {
+    fn from(value: i128) -> Self {
+        Self::I128(value)
+    }
+}
+impl<S> From<u128> for Value<'_, S> {
+    fn from(value: u128) -> Self {
+        Self::U128(value)
+    }
+}
 impl<S> From<bool> for Value<'_, S> {
     fn from(value: bool) -> Self {
         Value::Bool(value)
@@ -180,204 +619,1608 @@ impl<'a, S> From<&'a [u8]> for Value<'a, S> {
         Value::ByteArray(value)
     }
 }
-impl<'a> Value<'a, &'a str> {
-    fn to_owned(self) -> ValueOwned {
+impl<'a, S> Value<'a, S> {
+    /// Copies the value, with its strings given by `str`.
+    pub(crate) fn into_owned_with<T, F>(self, mut str: F) -> ValueOwned<T>
+    where
+        F: FnMut(S) -> T,
+    {
         match self {
-            Value::Debug(str) => ValueOwned::Debug(str.to_owned()),
-            Value::String(str) => ValueOwned::String(str.to_owned()),
+            Value::Debug(value) => ValueOwned::Debug(str(value)),
+            Value::String(value) => ValueOwned::String(str(value)),
             Value::Float(value) => ValueOwned::Float(value),
             Value::Integer(value) => ValueOwned::Integer(value),
             Value::Unsigned(value) => ValueOwned::Unsigned(value),
+            Value::I128(value) => ValueOwned::I128(value),
+            Value::U128(value) => ValueOwned::U128(value),
             Value::Bool(value) => ValueOwned::Bool(value),
             Value::ByteArray(items) => ValueOwned::ByteArray(items.to_owned()),
+            Value::Custom { tag, data } => ValueOwned::Custom {
+                tag,
+                data: data.to_owned(),
+            },
+            Value::Array(nested) => ValueOwned::Array(nested.to_owned()),
+            Value::Map(nested) => ValueOwned::Map(nested.to_owned()),
+            Value::Error { message, sources } => ValueOwned::Error {
+                message: str(message),
+                sources: sources.to_owned(),
+            },
         }
     }
 }
-
-#[derive(Clone, Debug)]
-pub enum ValueOwned {
-    Debug(String),
-    String(String),
-    Float(f64),
-    Integer(i64),
-    Unsigned(u64),
-    Bool(bool),
-    ByteArray(Vec<u8>),
-}
-impl ValueOwned {
-    pub fn as_ref(&self) -> Value<&str> {
-        match self {
-            ValueOwned::Debug(value) => Value::Debug(value),
-            ValueOwned::String(value) => Value::String(value),
-            ValueOwned::Float(value) => Value::Float(*value),
-            ValueOwned::Integer(value) => Value::Integer(*value),
-            ValueOwned::Unsigned(value) => Value::Unsigned(*value),
-            ValueOwned::Bool(value) => Value::Bool(*value),
-            ValueOwned::ByteArray(items) => Value::ByteArray(items),
-        }
+impl<'a> Value<'a, &'a str> {
+    fn to_owned(self) -> ValueOwned {
+        self.into_owned_with(str::to_owned)
     }
 }
 
-pub struct TapeMachineLogger<T> {
-    inner: Mutex<TapeMachineLoggerInner<T>>,
+/// Elements of an [`Array`](Value::Array) or a [`Map`](Value::Map), kept in their msgpack encoding
+/// so that values can be borrowed from the tape.
+///
+/// Strings of the elements are cached like other strings when they come from a
+/// [`CacheInstruction`](crate::string_cache::CacheInstruction).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Nested<'a> {
+    len: u32,
+    data: &'a [u8],
 }
-impl<T> TapeMachineLogger<T>
-where
-    T: TapeMachine<InstructionSet>,
-{
-    pub fn new(mut machine: T) -> Self {
-        machine.handle(Instruction::Restart);
-        TapeMachineLogger {
-            inner: Mutex::new(TapeMachineLoggerInner { machine }),
-        }
+impl<'a> Nested<'a> {
+    pub(crate) fn new(len: u32, data: &'a [u8]) -> Self {
+        Self { len, data }
     }
 
-    fn machine(&self) -> MutexGuard<'_, TapeMachineLoggerInner<T>> {
-        let mut machine = self.inner.lock().unwrap();
-        if machine.machine.needs_restart() {
-            machine.handle(Instruction::Restart);
-        }
-        machine
-    }
-}
-impl<T, S> Layer<S> for TapeMachineLogger<T>
-where
-    T: TapeMachine<InstructionSet>,
-    S: Subscriber + for<'a> LookupSpan<'a>,
-{
-    fn on_new_span(
-        &self,
-        attrs: &span::Attributes<'_>,
-        id: &span::Id,
-        ctx: tracing_subscriber::layer::Context<'_, S>,
-    ) {
-        let mut machine = self.machine();
-        let name = attrs.metadata().name();
-        let span = ctx.span(id).unwrap();
-        machine.handle(Instruction::NewSpan {
-            parent: span.parent().map(|parent| parent.id().into_non_zero_u64()),
-            span: id.into_non_zero_u64(),
-            name,
-        });
-        attrs.record(&mut VisitMachine(machine.deref_mut()));
-        machine.handle(Instruction::FinishedSpan);
+    /// Amount of elements, or of entries of a map.
+    pub fn len(&self) -> u32 {
+        self.len
     }
 
-    fn on_record(
-        &self,
-        id: &span::Id,
-        values: &span::Record<'_>,
-        _ctx: tracing_subscriber::layer::Context<'_, S>,
-    ) {
-        let mut machine = self.machine();
-        machine.handle(Instruction::NewRecord(id.into_non_zero_u64()));
-        values.record(&mut VisitMachine(machine.deref_mut()));
-        machine.handle(Instruction::FinishedRecord);
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
     }
 
-    fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
-        let mut machine = self.machine();
+    /// Encoded elements, one after the other.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
 
-        let time = Utc::now();
-        let span = ctx
-            .event_span(event)
-            .map(|span| span.id().into_non_zero_u64());
-        let priority = *event.metadata().level();
-        let target = event.metadata().target();
-        machine.handle(Instruction::StartEvent {
-            time,
-            span,
-            target,
-            priority,
-        });
-        event.record(&mut VisitMachine(machine.deref_mut()));
+    /// Decodes the elements of an array.
+    pub fn values(self) -> impl Iterator<Item = io::Result<Value<'a, CacheString<'a>>>> {
+        let mut data = self.data;
+        (0..self.len).map(move |_| storage::read_nested_value(&mut data))
+    }
 
-        machine.handle(Instruction::FinishedEvent);
+    /// Decodes the entries of a map.
+    pub fn entries(
+        self,
+    ) -> impl Iterator<Item = io::Result<(CacheString<'a>, Value<'a, CacheString<'a>>)>> {
+        let mut data = self.data;
+        (0..self.len).map(move |_| {
+            let key = storage::read_nested_str(&mut data)?;
+            Ok((key, storage::read_nested_value(&mut data)?))
+        })
     }
 
-    fn on_close(&self, id: span::Id, _ctx: tracing_subscriber::layer::Context<'_, S>) {
-        let mut machine = self.machine();
-        machine.handle(Instruction::DeleteSpan(id.into_non_zero_u64()));
+    fn to_owned(self) -> NestedOwned {
+        NestedOwned {
+            len: self.len,
+            data: self.data.to_owned(),
+        }
     }
 }
 
-struct TapeMachineLoggerInner<T> {
-    machine: T,
+/// Owned [`Nested`], without cached strings.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NestedOwned {
+    len: u32,
+    data: Vec<u8>,
 }
-impl<T> TapeMachineLoggerInner<T>
-where
-    T: TapeMachine<InstructionSet>,
-{
-    fn field_value<'a, V>(&mut self, field: &Field, value: V) -> FieldValue<'a, &'a str>
+impl NestedOwned {
+    /// Encodes the elements of an array.
+    pub fn array<'a, I>(values: I) -> Self
     where
-        V: Into<Value<'a, &'a str>>,
+        I: IntoIterator<Item = Value<'a, &'a str>>,
     {
-        let name = field.name();
-        let value = value.into();
-
-        FieldValue { name, value }
+        let mut nested = Self {
+            len: 0,
+            data: Vec::new(),
+        };
+        for value in values {
+            storage::write_nested_value(&mut nested.data, value);
+            nested.len += 1;
+        }
+        nested
     }
 
-    fn handle(&mut self, instruction: Instruction) {
-        self.machine.handle(instruction);
+    /// Encodes the entries of a map.
+    pub fn map<'a, I>(entries: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a str, Value<'a, &'a str>)>,
+    {
+        let mut nested = Self {
+            len: 0,
+            data: Vec::new(),
+        };
+        for (key, value) in entries {
+            storage::write_nested_str(&mut nested.data, key);
+            storage::write_nested_value(&mut nested.data, value);
+            nested.len += 1;
+        }
+        nested
     }
-}
 
-struct VisitMachine<'a, T>(&'a mut TapeMachineLoggerInner<T>);
-impl<T> Visit for VisitMachine<'_, T>
-where
-    T: TapeMachine<InstructionSet>,
-{
-    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
-        let value = format!("{value:?}");
-        let value = self.0.field_value(field, Value::Debug(value.as_str()));
-        self.0.handle(Instruction::AddValue(value));
+    pub fn as_ref(&self) -> Nested<'_> {
+        Nested::new(self.len, &self.data)
     }
 
-    fn record_f64(&mut self, field: &Field, value: f64) {
-        let value = self.0.field_value(field, value);
-        self.0.handle(Instruction::AddValue(value));
+    /// Decodes the elements of an array.
+    pub fn values(&self) -> io::Result<Vec<ValueOwned>> {
+        self.as_ref()
+            .values()
+            .map(|value| Ok(storage::present_value(value?)?.to_owned()))
+            .collect()
     }
 
-    fn record_i64(&mut self, field: &Field, value: i64) {
-        let value = self.0.field_value(field, value);
-        self.0.handle(Instruction::AddValue(value));
+    /// Strings among the elements of an array, e.g. the sources of an
+    /// [`Error`](ValueOwned::Error).
+    pub fn strings(&self) -> impl Iterator<Item = String> {
+        self.values()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|value| match value {
+                ValueOwned::String(str) => Some(str),
+                _ => None,
+            })
     }
 
-    fn record_u64(&mut self, field: &Field, value: u64) {
-        let value = self.0.field_value(field, value);
-        self.0.handle(Instruction::AddValue(value));
+    /// Decodes the entries of a map.
+    pub fn entries(&self) -> io::Result<Vec<(String, ValueOwned)>> {
+        self.as_ref()
+            .entries()
+            .map(|entry| {
+                let (key, value) = entry?;
+                let key = storage::present_str(key)?;
+                Ok((key.to_owned(), storage::present_value(value)?.to_owned()))
+            })
+            .collect()
     }
+}
 
-    fn record_i128(&mut self, field: &Field, value: i128) {
-        self.record_bytes(field, &value.to_le_bytes());
+/// Owned [`Value`]. With the `serde` feature, arrays and maps are serialized decoded, as sequences
+/// and maps of values.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ValueOwned<S = String> {
+    Debug(S),
+    String(S),
+    Float(f64),
+    Integer(i64),
+    Unsigned(u64),
+    I128(i128),
+    U128(u128),
+    Bool(bool),
+    ByteArray(Vec<u8>),
+    Custom {
+        tag: i8,
+        data: Vec<u8>,
+    },
+    Array(#[cfg_attr(feature = "serde", serde(with = "serde_fields::array"))] NestedOwned),
+    Map(#[cfg_attr(feature = "serde", serde(with = "serde_fields::map"))] NestedOwned),
+    Error {
+        message: S,
+        #[cfg_attr(feature = "serde", serde(with = "serde_fields::array"))]
+        sources: NestedOwned,
+    },
+}
+impl<S> ValueOwned<S> {
+    /// Borrows the value, with its strings given by `str`.
+    pub(crate) fn as_ref_with<'a, T, F>(&'a self, mut str: F) -> Value<'a, T>
+    where
+        F: FnMut(&'a S) -> T,
+    {
+        match self {
+            ValueOwned::Debug(value) => Value::Debug(str(value)),
+            ValueOwned::String(value) => Value::String(str(value)),
+            ValueOwned::Float(value) => Value::Float(*value),
+            ValueOwned::Integer(value) => Value::Integer(*value),
+            ValueOwned::Unsigned(value) => Value::Unsigned(*value),
+            ValueOwned::I128(value) => Value::I128(*value),
+            ValueOwned::U128(value) => Value::U128(*value),
+            ValueOwned::Bool(value) => Value::Bool(*value),
+            ValueOwned::ByteArray(items) => Value::ByteArray(items),
+            ValueOwned::Custom { tag, data } => Value::Custom { tag: *tag, data },
+            ValueOwned::Array(nested) => Value::Array(nested.as_ref()),
+            ValueOwned::Map(nested) => Value::Map(nested.as_ref()),
+            ValueOwned::Error { message, sources } => Value::Error {
+                message: str(message),
+                sources: sources.as_ref(),
+            },
+        }
     }
-
-    fn record_u128(&mut self, field: &Field, value: u128) {
-        self.record_bytes(field, &value.to_le_bytes());
+}
+impl ValueOwned {
+    pub fn as_ref(&self) -> Value<'_, &str> {
+        self.as_ref_with(String::as_str)
     }
 
-    fn record_bool(&mut self, field: &Field, value: bool) {
-        let value = self.0.field_value(field, value);
-        self.0.handle(Instruction::AddValue(value));
-    }
+    /// Numbers and booleans keep their JSON type, bytes are encoded with base64 and custom values
+    /// become `{"tag": .., "data": ..}`. Arrays and maps become JSON arrays and objects, a
+    /// malformed one becomes `null`. Errors become `{"message": .., "sources": [..]}`. Non-finite
+    /// floats are `null`.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> serde_json::Value {
+        use base64::{Engine, engine::general_purpose::STANDARD};
 
-    fn record_str(&mut self, field: &Field, value: &str) {
-        let value = self.0.field_value(field, Value::String(value));
-        self.0.handle(Instruction::AddValue(value));
+        match self {
+            ValueOwned::Debug(value) | ValueOwned::String(value) => value.as_str().into(),
+            ValueOwned::Float(value) => (*value).into(),
+            ValueOwned::Integer(value) => (*value).into(),
+            ValueOwned::Unsigned(value) => (*value).into(),
+            ValueOwned::I128(value) => match i64::try_from(*value) {
+                Ok(value) => value.into(),
+                Err(_) => value.to_string().into(),
+            },
+            ValueOwned::U128(value) => match u64::try_from(*value) {
+                Ok(value) => value.into(),
+                Err(_) => value.to_string().into(),
+            },
+            ValueOwned::Bool(value) => (*value).into(),
+            ValueOwned::ByteArray(items) => STANDARD.encode(items).into(),
+            ValueOwned::Custom { tag, data } => serde_json::json!({
+                "tag": tag,
+                "data": STANDARD.encode(data),
+            }),
+            ValueOwned::Array(nested) => match nested.values() {
+                Ok(values) => values.iter().map(ValueOwned::to_json).collect(),
+                Err(_) => serde_json::Value::Null,
+            },
+            ValueOwned::Map(nested) => match nested.entries() {
+                Ok(entries) => entries
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.to_json()))
+                    .collect::<serde_json::Map<_, _>>()
+                    .into(),
+                Err(_) => serde_json::Value::Null,
+            },
+            ValueOwned::Error { message, sources } => serde_json::json!({
+                "message": message,
+                "sources": ValueOwned::Array(sources.clone()).to_json(),
+            }),
+        }
+    }
+}
+
+/// Serde representations of fields whose types have none: levels by name, and arrays and maps
+/// decoded. Malformed arrays and maps fail to serialize.
+#[cfg(feature = "serde")]
+mod serde_fields {
+    pub mod level {
+        use serde::{Deserialize, Deserializer, Serializer, de::Error};
+        use tracing::Level;
+
+        pub fn serialize<S: Serializer>(level: &Level, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(level.as_str())
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Level, D::Error> {
+            String::deserialize(deserializer)?
+                .parse()
+                .map_err(D::Error::custom)
+        }
+    }
+
+    pub mod array {
+        use crate::tape::{NestedOwned, ValueOwned};
+        use serde::{Deserialize, Deserializer, Serialize, Serializer, ser::Error};
+
+        pub fn serialize<S: Serializer>(
+            nested: &NestedOwned,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            nested
+                .values()
+                .map_err(S::Error::custom)?
+                .serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<NestedOwned, D::Error> {
+            let values = Vec::<ValueOwned>::deserialize(deserializer)?;
+            Ok(NestedOwned::array(values.iter().map(ValueOwned::as_ref)))
+        }
+    }
+
+    pub mod map {
+        use crate::tape::{NestedOwned, ValueOwned};
+        use serde::{
+            Deserializer, Serializer,
+            de::{MapAccess, Visitor},
+            ser::Error,
+        };
+        use std::fmt;
+
+        pub fn serialize<S: Serializer>(
+            nested: &NestedOwned,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            let entries = nested.entries().map_err(S::Error::custom)?;
+            serializer.collect_map(entries.iter().map(|(key, value)| (key, value)))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<NestedOwned, D::Error> {
+            deserializer.deserialize_map(Entries)
+        }
+
+        /// Keeps the entries in their order.
+        struct Entries;
+        impl<'de> Visitor<'de> for Entries {
+            type Value = NestedOwned;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<NestedOwned, A::Error> {
+                let mut entries = Vec::<(String, ValueOwned)>::new();
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(NestedOwned::map(
+                    entries
+                        .iter()
+                        .map(|(key, value)| (key.as_str(), value.as_ref())),
+                ))
+            }
+        }
+    }
+}
+
+/// Hook appending extra fields to every event. See [`TapeMachineLogger::with_enrichment`].
+pub type EnrichHook = Box<dyn Fn(&tracing::Event<'_>, &mut Enrichment<'_>) + Send + Sync>;
+
+/// Fields being appended to an event by an [`EnrichHook`].
+pub struct Enrichment<'a> {
+    add: &'a mut dyn FnMut(FieldValue<'_, &str>),
+}
+impl Enrichment<'_> {
+    pub fn add<'b, V>(&mut self, name: &'b str, value: V)
+    where
+        V: Into<Value<'b, &'b str>>,
+    {
+        (self.add)(FieldValue {
+            name,
+            value: value.into(),
+        });
+    }
+}
+
+/// Encoder of data stored in span extensions. See [`TapeMachineLogger::with_span_extension`].
+#[cfg(feature = "span-extensions")]
+pub type SpanExtensionHook =
+    Box<dyn Fn(&tracing_subscriber::registry::Extensions<'_>, &mut Enrichment<'_>) + Send + Sync>;
+
+/// Hook deriving the id a span is recorded with, see [`TapeMachineLogger::with_span_id`].
+pub type SpanIdHook = Box<dyn Fn(&span::Attributes<'_>) -> Option<NonZeroU64> + Send + Sync>;
+
+/// Id of a span given by a [`SpanIdHook`], kept in its extensions.
+struct RecordedSpanId(NonZeroU64);
+
+/// Extractor for [`TapeMachineLogger::with_span_id`] taking the id from the `field` of the span.
+///
+/// Integers are used as they are, and strings are parsed as hexadecimal, how trace ids usually
+/// travel in headers. Zero and invalid values are ignored.
+pub fn span_id_field(
+    field: &'static str,
+) -> impl Fn(&span::Attributes<'_>) -> Option<NonZeroU64> + Send + Sync + 'static {
+    move |attrs| {
+        let mut visit = VisitSpanId { field, id: None };
+        attrs.record(&mut visit);
+        visit.id
+    }
+}
+
+struct VisitSpanId {
+    field: &'static str,
+    id: Option<NonZeroU64>,
+}
+impl Visit for VisitSpanId {
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == self.field {
+            self.id = NonZeroU64::new(value);
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record_u64(field, value as u64);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == self.field {
+            let value = value.trim_start_matches("0x");
+            self.id = u64::from_str_radix(value, 16)
+                .ok()
+                .and_then(NonZeroU64::new);
+        }
+    }
+}
+
+/// Field with the tokio task id, see [`TapeMachineLogger::with_task_id`].
+#[cfg(feature = "tokio")]
+pub const TASK_ID_FIELD: &str = "task.id";
+
+#[cfg(feature = "tokio")]
+fn current_task_id() -> Option<u64> {
+    // The id only exposes its number through Display
+    tokio::task::try_id()?.to_string().parse().ok()
+}
+
+/// What a [`TapeMachineLogger`] does with the spans and events emitted while its tape is locked by
+/// the same thread, e.g. by a machine or a hook using `tracing` itself, instead of deadlocking.
+pub enum Reentrancy {
+    /// Drops them.
+    Drop,
+    /// Records them once the instruction being handled is done.
+    ///
+    /// Spans and events emitted while the queued ones are recorded wait for the next instruction.
+    Queue,
+    /// Sends them to another machine, e.g. a [`Printer`](crate::printer::Printer) on stderr.
+    /// Spans and events emitted while that machine is itself locked are dropped.
+    Fallback(Box<dyn TapeMachine<InstructionSet>>),
+}
+
+type ReentrantSink = Mutex<TapeMachineLoggerInner<Box<dyn TapeMachine<InstructionSet>>>>;
+
+/// Encodes the instructions of [`Reentrancy::Queue`], as a tape of its own.
+struct Queued(Arc<Mutex<Vec<u8>>>);
+impl TapeMachine<InstructionSet> for Queued {
+    fn needs_restart(&mut self) -> bool {
+        false
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        stage(&mut self.0.lock().unwrap(), instruction);
+    }
+}
+
+/// Encodes the instructions of [`TapeMachineLogger::with_staging`] and
+/// [`TapeMachineLogger::with_pipeline`], as a tape of its own.
+struct Staged(Vec<u8>);
+impl TapeMachine<InstructionSet> for Staged {
+    fn needs_restart(&mut self) -> bool {
+        false
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        stage(&mut self.0, instruction);
+    }
+}
+
+/// Appends `instruction` to the tape in `buf`, which is restarted when empty.
+fn stage(buf: &mut Vec<u8>, instruction: Instruction) {
+    if let Instruction::Restart = instruction {
+        return;
+    }
+
+    if buf.is_empty() {
+        let _ = storage::Store::do_handle(&mut *buf, Instruction::Restart);
+    }
+    let _ = storage::Store::do_handle(&mut *buf, instruction);
+}
+
+/// Message sent to the thread of [`TapeMachineLogger::with_pipeline`].
+enum Piped {
+    /// Tape of [`Staged`] instructions.
+    Chunk(Vec<u8>),
+    /// Flushes the machine, then answers.
+    Flush(mpsc::Sender<()>),
+}
+
+/// Capacity of the channel of [`TapeMachineLogger::with_pipeline`], in messages, each holding the
+/// instructions of one span or event.
+pub const PIPELINE_CAPACITY: usize = 4096;
+
+/// What [`TapeMachineLogger::with_pipeline`] does with the spans and events emitted while its
+/// channel is full, i.e. [`PIPELINE_CAPACITY`] messages behind.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum PipelineFull {
+    /// Drops them, recording how many in a warning of [`LOGGER_TARGET`] before the next ones.
+    /// The events of a dropped span are recorded without it.
+    #[default]
+    Drop,
+    /// Waits for room, as if the tape was written on the emitting thread.
+    Block,
+    /// Appends them to a file at this path, created when first needed, until the thread of the
+    /// pipeline catches up with the channel and records them. The file is deleted once the
+    /// pipeline ends. Spans and events that can't be written to it are dropped, as with
+    /// [`Drop`](Self::Drop).
+    Spill(PathBuf),
+}
+
+/// Sending end of [`TapeMachineLogger::with_pipeline`].
+#[derive(Clone)]
+struct Pipeline {
+    sender: mpsc::SyncSender<Piped>,
+    full: PipelineFull,
+    dropped: Arc<AtomicU64>,
+    spill: Arc<Mutex<Option<SpillFile>>>,
+}
+impl Pipeline {
+    /// Sends `piped` as per [`PipelineFull`], returning whether it was sent.
+    ///
+    /// The thread of the pipeline never waits for room, as it alone makes it.
+    fn send(&self, piped: Piped) -> bool {
+        match (&self.full, piped) {
+            (PipelineFull::Spill(path), Piped::Chunk(chunk)) => self.spill(path, chunk),
+            (PipelineFull::Block, piped) if !PIPELINE_THREAD.get() => {
+                self.sender.send(piped).is_ok()
+            }
+            (_, piped) => self.try_send(piped),
+        }
+    }
+
+    fn try_send(&self, piped: Piped) -> bool {
+        match self.sender.try_send(piped) {
+            Ok(()) => true,
+            Err(mpsc::TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            Err(mpsc::TrySendError::Disconnected(_)) => false,
+        }
+    }
+
+    /// Sends `chunk`, or appends it to the spill file while the channel is full or the file not
+    /// yet recorded, so that chunks keep their order.
+    fn spill(&self, path: &Path, chunk: Vec<u8>) -> bool {
+        let mut spill = self.spill.lock().unwrap();
+        let chunk = match spill.as_ref() {
+            Some(spill) if spill.len > 0 => chunk,
+            _ => match self.sender.try_send(Piped::Chunk(chunk)) {
+                Ok(()) => return true,
+                Err(mpsc::TrySendError::Full(Piped::Chunk(chunk))) => chunk,
+                Err(_) => return false,
+            },
+        };
+
+        let spilled = match spill.as_mut() {
+            Some(spill) => spill.append(&chunk),
+            None => SpillFile::create(path).and_then(|new| spill.insert(new).append(&chunk)),
+        };
+        if spilled.is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        spilled.is_ok()
+    }
+}
+
+/// File of [`PipelineFull::Spill`], holding the chunks not yet recorded one after the other.
+struct SpillFile {
+    path: PathBuf,
+    file: File,
+    len: u64,
+}
+impl SpillFile {
+    fn create(path: &Path) -> io::Result<Self> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            file,
+            len: 0,
+        })
+    }
+
+    fn append(&mut self, chunk: &[u8]) -> io::Result<()> {
+        // Counted even if partially written, so that the next read skips what is left of it
+        self.len += chunk.len() as u64;
+        self.file.write_all(chunk)
+    }
+
+    /// Reads the chunks out, emptying the file.
+    fn take(&mut self) -> io::Result<Vec<u8>> {
+        let mut chunks = Vec::with_capacity(self.len as usize);
+        self.len = 0;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut chunks)?;
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        Ok(chunks)
+    }
+}
+
+/// Records the chunks spilled so far, returning whether there were any.
+fn unspill<T>(machine: &mut T, spill: &Mutex<Option<SpillFile>>) -> bool
+where
+    T: TapeMachine<InstructionSet> + ?Sized,
+{
+    let chunks = match spill.lock().unwrap().as_mut() {
+        Some(spill) if spill.len > 0 => spill.take(),
+        _ => return false,
+    };
+
+    let result = chunks.and_then(|chunks| machine.handle_encoded(&chunks));
+    report_staged(machine, result);
+    true
+}
+
+/// Starts the thread of [`TapeMachineLogger::with_pipeline`], which runs until every sender is
+/// dropped.
+fn spawn_pipeline<T>(inner: Arc<Mutex<TapeMachineLoggerInner<T>>>, full: PipelineFull) -> Pipeline
+where
+    T: TapeMachine<InstructionSet>,
+{
+    let (sender, receiver) = mpsc::sync_channel(PIPELINE_CAPACITY);
+    let dropped = Arc::new(AtomicU64::new(0));
+    let spill = Arc::new(Mutex::new(None::<SpillFile>));
+    let pipeline = Pipeline {
+        sender,
+        full,
+        dropped: dropped.clone(),
+        spill: spill.clone(),
+    };
+    std::thread::spawn(move || {
+        PIPELINE_THREAD.set(true);
+        while let Ok(piped) = receiver.recv() {
+            let Some(mut machine) = lock_machine(&inner) else {
+                return;
+            };
+            // Drains whatever is already queued, then spilled, before letting go of the lock
+            let mut piped = Some(piped);
+            loop {
+                while let Some(next) = piped.take().or_else(|| receiver.try_recv().ok()) {
+                    // Reported before the next message, which may be a flush waiting for it
+                    let dropped = dropped.swap(0, Ordering::Relaxed);
+                    if dropped > 0 {
+                        warn(
+                            &mut machine.machine,
+                            "Pipeline full, dropped spans and events",
+                            FieldValue {
+                                name: "dropped",
+                                value: Value::Unsigned(dropped),
+                            },
+                        );
+                    }
+
+                    match next {
+                        Piped::Chunk(chunk) => {
+                            let result = machine.machine.handle_encoded(&chunk);
+                            report_staged(&mut machine.machine, result);
+                        }
+                        Piped::Flush(flushed) => {
+                            // Spilled before the flush was sent
+                            unspill(&mut machine.machine, &spill);
+                            machine.machine.flush();
+                            let _ = flushed.send(());
+                        }
+                    }
+                }
+                if !unspill(&mut machine.machine, &spill) {
+                    break;
+                }
+            }
+        }
+
+        if let Some(mut machine) = lock_machine(&inner) {
+            unspill(&mut machine.machine, &spill);
+        }
+        if let Some(spill) = spill.lock().unwrap().take() {
+            let _ = std::fs::remove_file(spill.path);
+        }
+    });
+    pipeline
+}
+
+/// Target of the warnings that a [`TapeMachineLogger`] records about itself.
+pub const LOGGER_TARGET: &str = "msgpack_tracing::tape";
+
+/// Records a warning of the logger into its own tape, as `tracing` drops the events emitted
+/// while dispatching one.
+fn warn<T>(machine: &mut T, message: &str, field: FieldValue<&str>)
+where
+    T: TapeMachine<InstructionSet> + ?Sized,
+{
+    machine.handle(Instruction::StartEvent {
+        time: Utc::now(),
+        span: None,
+        target: LOGGER_TARGET,
+        priority: Level::WARN,
+        location: None,
+        thread: None,
+    });
+    machine.handle(Instruction::AddValue(FieldValue {
+        name: "message",
+        value: Value::Debug(message),
+    }));
+    machine.handle(Instruction::AddValue(field));
+    machine.handle(Instruction::FinishedEvent);
+}
+
+/// Warns about the staged instructions that `machine` could not handle, which were skipped.
+fn report_staged<T>(machine: &mut T, result: io::Result<()>)
+where
+    T: TapeMachine<InstructionSet> + ?Sized,
+{
+    if let Err(error) = result {
+        let error = error.to_string();
+        let field = FieldValue {
+            name: "error",
+            value: Value::String(&error),
+        };
+        warn(machine, "Skipped unreadable staged instructions", field);
+    }
+}
+
+/// Moments of the life of spans recorded as events by
+/// [`TapeMachineLogger::with_span_events`], like `FmtSpan` of `tracing_subscriber::fmt`. Combined
+/// with `|`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SpanEvents(u8);
+impl SpanEvents {
+    pub const NONE: Self = Self(0);
+    /// Span created, with message `new`.
+    pub const NEW: Self = Self(1);
+    /// Span entered, with message `enter`.
+    pub const ENTER: Self = Self(1 << 1);
+    /// Span exited, with message `exit`.
+    pub const EXIT: Self = Self(1 << 2);
+    /// Span closed, with message `close` and the `time.busy` and `time.idle` spent in the span.
+    pub const CLOSE: Self = Self(1 << 3);
+    pub const ACTIVE: Self = Self(Self::ENTER.0 | Self::EXIT.0);
+    pub const FULL: Self = Self(Self::NEW.0 | Self::ACTIVE.0 | Self::CLOSE.0);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+impl BitOr for SpanEvents {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+pub struct TapeMachineLogger<T> {
+    inner: Arc<Mutex<TapeMachineLoggerInner<T>>>,
+    reentrant: Option<ReentrantSink>,
+    queue: Option<Arc<Mutex<Vec<u8>>>>,
+    custom_values: CustomValues,
+    staging: bool,
+    pipeline: Option<Pipeline>,
+    pipeline_full: PipelineFull,
+    enrich: Vec<EnrichHook>,
+    #[cfg(feature = "span-extensions")]
+    span_extensions: Vec<SpanExtensionHook>,
+    span_id: Option<SpanIdHook>,
+    location: bool,
+    thread: bool,
+    span_timing: bool,
+    span_events: SpanEvents,
+    #[cfg(feature = "tokio")]
+    task: bool,
+}
+impl<T> TapeMachineLogger<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    pub fn new(mut machine: T) -> Self {
+        machine.handle(Instruction::Restart);
+        TapeMachineLogger {
+            inner: Arc::new(Mutex::new(TapeMachineLoggerInner {
+                custom_values: Default::default(),
+                custom_buf: Default::default(),
+                machine,
+            })),
+            reentrant: None,
+            queue: None,
+            custom_values: Default::default(),
+            staging: false,
+            pipeline: None,
+            pipeline_full: Default::default(),
+            enrich: Default::default(),
+            #[cfg(feature = "span-extensions")]
+            span_extensions: Default::default(),
+            span_id: None,
+            location: false,
+            thread: false,
+            span_timing: false,
+            span_events: SpanEvents::NONE,
+            #[cfg(feature = "tokio")]
+            task: false,
+        }
+    }
+
+    /// Sets what happens to spans and events emitted while the tape is locked by the same thread,
+    /// [`Reentrancy::Drop`] by default.
+    pub fn with_reentrancy(mut self, reentrancy: Reentrancy) -> Self {
+        self.queue = None;
+        let machine: Box<dyn TapeMachine<InstructionSet>> = match reentrancy {
+            Reentrancy::Drop => {
+                self.reentrant = None;
+                return self;
+            }
+            Reentrancy::Queue => {
+                let queue = Arc::new(Mutex::new(Vec::new()));
+                self.queue = Some(queue.clone());
+                Box::new(Queued(queue))
+            }
+            Reentrancy::Fallback(mut machine) => {
+                machine.handle(Instruction::Restart);
+                machine
+            }
+        };
+        self.reentrant = Some(Mutex::new(TapeMachineLoggerInner {
+            custom_values: self.custom_values.clone(),
+            custom_buf: Default::default(),
+            machine,
+        }));
+        self
+    }
+
+    /// Encodes spans and events into a buffer of the emitting thread before locking the tape,
+    /// which is then only locked to append the encoded bytes, so that threads logging at the
+    /// same time wait less for each other. See [`TapeMachine::handle_encoded`]: [`Store`] and
+    /// [`Rotate`] without options append them as they are, behind a
+    /// [`StringCache`](crate::string_cache::StringCache) too, their strings then being written in
+    /// full. Other machines decode them under the lock.
+    ///
+    /// Events emitted while encoding (e.g. by a `Debug` implementation) are recorded before the
+    /// one being encoded, and events of different threads may be recorded slightly out of order
+    /// of their timestamps.
+    ///
+    /// [`Store`]: storage::Store
+    /// [`Rotate`]: crate::rotate::Rotate
+    pub fn with_staging(mut self, staging: bool) -> Self {
+        self.staging = staging;
+        self
+    }
+
+    /// Encodes spans and events on the emitting thread, like [`with_staging`](Self::with_staging),
+    /// and sends them through a channel to a thread of the logger, which alone writes into the
+    /// tape. Emitting doesn't wait for the tape, which suits threads that can't block on a mutex,
+    /// as long as it is at most [`PIPELINE_CAPACITY`] spans and events behind; see
+    /// [`with_pipeline_full`](Self::with_pipeline_full) for what happens then.
+    ///
+    /// The tape receives the instructions of every thread in the order they were sent, and so
+    /// the instructions of one thread in the order they were emitted. A span is recorded before
+    /// any event or record of it, as long as it is only used after being created. The time of
+    /// an event is when it was emitted, not when it was written.
+    ///
+    /// Spans and events emitted by the machines of the tape are recorded after the instruction
+    /// being handled, whatever the [`Reentrancy`]. [`TapeHandle::flush`] waits for everything
+    /// sent before it to be written.
+    pub fn with_pipeline(mut self, pipeline: bool) -> Self {
+        self.pipeline =
+            pipeline.then(|| spawn_pipeline(self.inner.clone(), self.pipeline_full.clone()));
+        self
+    }
+
+    /// What the [pipeline](Self::with_pipeline) does once its channel is full, dropping spans
+    /// and events by default.
+    pub fn with_pipeline_full(mut self, full: PipelineFull) -> Self {
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            pipeline.full = full.clone();
+        }
+        self.pipeline_full = full;
+        self
+    }
+
+    /// Records the file, line and module path of every event.
+    pub fn with_source_location(mut self, location: bool) -> Self {
+        self.location = location;
+        self
+    }
+
+    /// Records the id and name of the thread emitting every event.
+    pub fn with_thread_info(mut self, thread: bool) -> Self {
+        self.thread = thread;
+        self
+    }
+
+    /// Records when spans are entered and exited, and the time spent inside (busy) and outside
+    /// (idle) of every span when it closes.
+    pub fn with_span_timing(mut self, span_timing: bool) -> Self {
+        self.span_timing = span_timing;
+        self
+    }
+
+    /// Records an event at the level and target of the span, inside of it, on every moment of
+    /// `span_events`. Machines that don't track spans still show where spans begin and end.
+    pub fn with_span_events(mut self, span_events: SpanEvents) -> Self {
+        self.span_events = span_events;
+        self
+    }
+
+    /// Records the id of the tokio task emitting every event or creating every span, as a
+    /// [`TASK_ID_FIELD`] field. Nothing is added outside of tasks.
+    #[cfg(feature = "tokio")]
+    pub fn with_task_id(mut self, task: bool) -> Self {
+        self.task = task;
+        self
+    }
+
+    /// Calls `hook` on every event, after its own fields, so it can append extra fields (e.g.
+    /// request id from a task-local or the build version).
+    ///
+    /// The hook runs while the tape is locked, so the events it emits follow the [`Reentrancy`]
+    /// policy.
+    pub fn with_enrichment<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&tracing::Event<'_>, &mut Enrichment<'_>) + Send + Sync + 'static,
+    {
+        self.enrich.push(Box::new(hook));
+        self
+    }
+
+    /// Calls `encode` on new spans holding an `E` in their extensions, so it can append fields
+    /// to the span (e.g. the HTTP route or peer address stored by another layer).
+    ///
+    /// Only data inserted by layers added before this one, when the span is created, is seen.
+    #[cfg(feature = "span-extensions")]
+    pub fn with_span_extension<E, F>(mut self, encode: F) -> Self
+    where
+        E: 'static,
+        F: Fn(&E, &mut Enrichment<'_>) + Send + Sync + 'static,
+    {
+        self.span_extensions
+            .push(Box::new(move |extensions, fields| {
+                if let Some(data) = extensions.get::<E>() {
+                    encode(data, fields);
+                }
+            }));
+        self
+    }
+
+    /// Records spans with the id given by `extract`, e.g. [`span_id_field`], instead of the id
+    /// local to the process, so that tapes of several services can be joined on shared ids.
+    /// Spans without an extracted id keep their local one.
+    ///
+    /// Extracted ids must be unique among the spans alive in the tape, including local ids.
+    pub fn with_span_id<F>(mut self, extract: F) -> Self
+    where
+        F: Fn(&span::Attributes<'_>) -> Option<NonZeroU64> + Send + Sync + 'static,
+    {
+        self.span_id = Some(Box::new(extract));
+        self
+    }
+
+    /// Id the span is recorded with, see [`with_span_id`](Self::with_span_id).
+    fn recorded_id<S>(&self, span: &SpanRef<'_, S>) -> NonZeroU64
+    where
+        S: for<'a> LookupSpan<'a>,
+    {
+        let recorded = match self.span_id {
+            Some(_) => span.extensions().get::<RecordedSpanId>().map(|id| id.0),
+            None => None,
+        };
+        recorded.unwrap_or_else(|| span.id().into_non_zero_u64())
+    }
+
+    /// Encodes Debug values through `custom_values`, when they apply.
+    pub fn with_custom_values(mut self, custom_values: CustomValues) -> Self {
+        if let Some(reentrant) = self.reentrant.as_ref() {
+            reentrant.lock().unwrap().custom_values = custom_values.clone();
+        }
+        self.inner.lock().unwrap().custom_values = custom_values.clone();
+        self.custom_values = custom_values;
+        self
+    }
+
+    /// Handle for writing instructions into the tape from outside of the tracing callbacks.
+    pub fn handle(&self) -> TapeHandle<T> {
+        TapeHandle {
+            inner: self.inner.clone(),
+            pipeline: self.pipeline.clone(),
+        }
+    }
+
+    /// Calls `f` with the tape, or with the [`Reentrancy`] sink if this thread already locked it.
+    fn with_machine<F>(&self, f: F)
+    where
+        F: FnOnce(&mut TapeMachineLoggerInner<dyn TapeMachine<InstructionSet>>),
+    {
+        let Some(mut machine) = lock_machine(&self.inner) else {
+            if let Some(mut reentrant) = self.reentrant.as_ref().and_then(lock_machine) {
+                f(&mut *reentrant);
+            }
+            return;
+        };
+        f(&mut *machine);
+
+        if let Some(queue) = self.queue.as_ref() {
+            let queued = std::mem::take(&mut *queue.lock().unwrap());
+            let result = machine.machine.handle_encoded(&queued);
+            report_staged(&mut machine.machine, result);
+        }
+    }
+
+    /// Calls `f` with a buffer of this thread, appended to the tape or sent to the
+    /// [pipeline](Self::with_pipeline) afterwards, or directly with the tape, see
+    /// [`with_staging`](Self::with_staging).
+    fn with_staged<F>(&self, f: F)
+    where
+        F: FnOnce(&mut TapeMachineLoggerInner<dyn TapeMachine<InstructionSet>>),
+    {
+        if !self.staging && self.pipeline.is_none() {
+            return self.with_machine(f);
+        }
+
+        let mut staged = TapeMachineLoggerInner {
+            custom_values: self.custom_values.clone(),
+            custom_buf: Default::default(),
+            machine: Staged(STAGING.take()),
+        };
+        f(&mut staged);
+        let mut chunk = staged.machine.0;
+        if let Some(pipeline) = self.pipeline.as_ref() {
+            if !chunk.is_empty() {
+                let _ = pipeline.send(Piped::Chunk(chunk));
+            }
+            return;
+        }
+
+        if !chunk.is_empty() {
+            self.with_machine(|machine| {
+                let result = machine.machine.handle_encoded(&chunk);
+                report_staged(&mut machine.machine, result);
+            });
+        }
+        chunk.clear();
+        STAGING.set(chunk);
+    }
+}
+
+pub struct TapeHandle<T> {
+    inner: Arc<Mutex<TapeMachineLoggerInner<T>>>,
+    pipeline: Option<Pipeline>,
+}
+impl<T> Clone for TapeHandle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            pipeline: self.pipeline.clone(),
+        }
+    }
+}
+impl<T> TapeHandle<T>
+where
+    T: TapeMachine<InstructionSet>,
+{
+    /// Ignored when called by a machine of this tape while it handles an instruction, unless
+    /// the tape has a [pipeline](TapeMachineLogger::with_pipeline).
+    pub fn handle(&self, instruction: Instruction) {
+        if let Some(pipeline) = self.pipeline.as_ref() {
+            let mut chunk = Vec::new();
+            stage(&mut chunk, instruction);
+            let _ = pipeline.send(Piped::Chunk(chunk));
+            return;
+        }
+
+        if let Some(mut machine) = lock_machine(&self.inner) {
+            machine.handle(instruction);
+        }
+    }
+
+    pub fn flush(&self) {
+        if let Some(pipeline) = self.pipeline.as_ref()
+            && !locked_by_this_thread(&self.inner)
+        {
+            let (flushed, wait) = mpsc::channel();
+            if pipeline.sender.send(Piped::Flush(flushed)).is_ok() {
+                let _ = wait.recv();
+            }
+            return;
+        }
+
+        if let Some(mut machine) = lock_machine(&self.inner) {
+            machine.machine.flush();
+        }
+    }
+}
+
+thread_local! {
+    /// Addresses of the tapes locked by this thread, see [`Reentrancy`].
+    static LOCKED: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+    /// Buffer reused by [`TapeMachineLogger::with_staging`], taken while in use.
+    static STAGING: Cell<Vec<u8>> = const { Cell::new(Vec::new()) };
+    /// Whether this is the thread of a [`TapeMachineLogger::with_pipeline`].
+    static PIPELINE_THREAD: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Lock of a tape, which is remembered as locked by this thread until dropped.
+struct MachineGuard<'a, T: ?Sized> {
+    guard: MutexGuard<'a, TapeMachineLoggerInner<T>>,
+    address: usize,
+}
+impl<T: ?Sized> Deref for MachineGuard<'_, T> {
+    type Target = TapeMachineLoggerInner<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+impl<T: ?Sized> DerefMut for MachineGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+impl<T: ?Sized> Drop for MachineGuard<'_, T> {
+    fn drop(&mut self) {
+        LOCKED.with_borrow_mut(|locked| locked.retain(|&address| address != self.address));
+    }
+}
+
+fn locked_by_this_thread<T: ?Sized>(inner: &Mutex<TapeMachineLoggerInner<T>>) -> bool {
+    let address = inner as *const _ as *const () as usize;
+    LOCKED.with_borrow(|locked| locked.contains(&address))
+}
+
+/// Locks the tape, or returns `None` if this thread already locked it.
+fn lock_machine<T>(inner: &Mutex<TapeMachineLoggerInner<T>>) -> Option<MachineGuard<'_, T>>
+where
+    T: TapeMachine<InstructionSet> + ?Sized,
+{
+    if locked_by_this_thread(inner) {
+        return None;
+    }
+
+    let address = inner as *const _ as *const () as usize;
+    let mut machine = MachineGuard {
+        guard: inner.lock().unwrap(),
+        address,
+    };
+    LOCKED.with_borrow_mut(|locked| locked.push(address));
+    if machine.machine.needs_restart() {
+        machine.handle(Instruction::Restart);
+    }
+    Some(machine)
+}
+impl<T, S> Layer<S> for TapeMachineLogger<T>
+where
+    T: TapeMachine<InstructionSet>,
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        attrs: &span::Attributes<'_>,
+        id: &span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let name = attrs.metadata().name();
+        // Missing under some per-layer filters, the span is then recorded without a parent
+        let span = ctx.span(id);
+        if let Some(span) = span.as_ref()
+            && let Some(extract) = self.span_id.as_ref()
+            && let Some(recorded) = extract(attrs)
+        {
+            span.extensions_mut().insert(RecordedSpanId(recorded));
+        }
+        if let Some(span) = span.as_ref()
+            && (self.span_timing || self.span_events.contains(SpanEvents::CLOSE))
+        {
+            span.extensions_mut().insert(SpanTimings {
+                busy: Duration::ZERO,
+                idle: Duration::ZERO,
+                last: Instant::now(),
+            });
+        }
+        let recorded = span
+            .as_ref()
+            .map_or_else(|| id.into_non_zero_u64(), |span| self.recorded_id(span));
+        self.with_staged(|machine| {
+            machine.handle(Instruction::NewSpan {
+                parent: span
+                    .as_ref()
+                    .and_then(|span| span.parent())
+                    .map(|parent| self.recorded_id(&parent)),
+                span: recorded,
+                name,
+            });
+            attrs.record(&mut VisitMachine::new(machine));
+            #[cfg(feature = "span-extensions")]
+            if let Some(span) = span.as_ref()
+                && !self.span_extensions.is_empty()
+            {
+                let extensions = span.extensions();
+                for hook in self.span_extensions.iter() {
+                    let mut add = |value: FieldValue<'_, &str>| {
+                        machine.handle(Instruction::AddValue(value));
+                    };
+                    hook(&extensions, &mut Enrichment { add: &mut add });
+                }
+            }
+            #[cfg(feature = "tokio")]
+            if self.task
+                && let Some(id) = current_task_id()
+            {
+                machine.handle(Instruction::AddValue(FieldValue {
+                    name: TASK_ID_FIELD,
+                    value: Value::Unsigned(id),
+                }));
+            }
+            machine.handle(Instruction::FinishedSpan);
+            if self.span_events.contains(SpanEvents::NEW) {
+                self.span_event(machine, recorded, attrs.metadata(), "new", None);
+            }
+        });
+    }
+
+    fn on_record(
+        &self,
+        id: &span::Id,
+        values: &span::Record<'_>,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let span = ctx
+            .span(id)
+            .map_or_else(|| id.into_non_zero_u64(), |span| self.recorded_id(&span));
+        self.with_staged(|machine| {
+            machine.handle(Instruction::NewRecord(span));
+            values.record(&mut VisitMachine::new(machine));
+            machine.handle(Instruction::FinishedRecord);
+        });
+    }
+
+    fn on_event(&self, event: &tracing::Event<'_>, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        self.with_staged(|machine| {
+            let time = Utc::now();
+            let span = ctx.event_span(event).map(|span| self.recorded_id(&span));
+            let priority = *event.metadata().level();
+            let target_field = event.metadata().fields().field(TARGET_FIELD);
+            let target_override = target_field.as_ref().and_then(|field| {
+                let mut visit = VisitTarget {
+                    field,
+                    target: None,
+                };
+                event.record(&mut visit);
+                visit.target
+            });
+            let target = target_override
+                .as_deref()
+                .unwrap_or(event.metadata().target());
+            let location = self
+                .location
+                .then(|| Location::from_metadata(event.metadata()))
+                .flatten();
+            let current = self.thread.then(std::thread::current);
+            let thread = current.as_ref().map(|current| ThreadInfo {
+                id: current_thread_id(),
+                name: current.name().unwrap_or_default(),
+            });
+            machine.handle(Instruction::StartEvent {
+                time,
+                span,
+                target,
+                priority,
+                location,
+                thread,
+            });
+            event.record(&mut VisitMachine::new(machine).skipping(target_field));
+            #[cfg(feature = "tokio")]
+            if self.task
+                && let Some(id) = current_task_id()
+            {
+                machine.handle(Instruction::AddValue(FieldValue {
+                    name: TASK_ID_FIELD,
+                    value: Value::Unsigned(id),
+                }));
+            }
+            for hook in self.enrich.iter() {
+                let mut add = |value: FieldValue<'_, &str>| {
+                    machine.handle(Instruction::AddValue(value));
+                };
+                hook(event, &mut Enrichment { add: &mut add });
+            }
+
+            machine.handle(Instruction::FinishedEvent);
+        });
+    }
+
+    fn on_enter(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if !self.span_timing && self.span_events == SpanEvents::NONE {
+            return;
+        }
+        let span = ctx.span(id);
+        if let Some(span) = span.as_ref()
+            && let Some(timings) = span.extensions_mut().get_mut::<SpanTimings>()
+        {
+            let elapsed = timings.elapsed();
+            timings.idle += elapsed;
+        }
+        let metadata = span.as_ref().map(|span| span.metadata());
+        let span = span.map_or_else(|| id.into_non_zero_u64(), |span| self.recorded_id(&span));
+        self.with_staged(|machine| {
+            if self.span_timing {
+                machine.handle(Instruction::EnterSpan {
+                    time: Utc::now(),
+                    span,
+                });
+            }
+            if let Some(metadata) = metadata
+                && self.span_events.contains(SpanEvents::ENTER)
+            {
+                self.span_event(machine, span, metadata, "enter", None);
+            }
+        });
+    }
+
+    fn on_exit(&self, id: &span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        if !self.span_timing && self.span_events == SpanEvents::NONE {
+            return;
+        }
+        let span = ctx.span(id);
+        if let Some(span) = span.as_ref()
+            && let Some(timings) = span.extensions_mut().get_mut::<SpanTimings>()
+        {
+            let elapsed = timings.elapsed();
+            timings.busy += elapsed;
+        }
+        let metadata = span.as_ref().map(|span| span.metadata());
+        let span = span.map_or_else(|| id.into_non_zero_u64(), |span| self.recorded_id(&span));
+        self.with_staged(|machine| {
+            if self.span_timing {
+                machine.handle(Instruction::ExitSpan {
+                    time: Utc::now(),
+                    span,
+                });
+            }
+            if let Some(metadata) = metadata
+                && self.span_events.contains(SpanEvents::EXIT)
+            {
+                self.span_event(machine, span, metadata, "exit", None);
+            }
+        });
+    }
+
+    fn on_close(&self, id: span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let span = ctx.span(&id);
+        let metadata = span.as_ref().map(|span| span.metadata());
+        let timing = span.as_ref().and_then(|span| {
+            let mut extensions = span.extensions_mut();
+            let timings = extensions.get_mut::<SpanTimings>()?;
+            let elapsed = timings.elapsed();
+            timings.idle += elapsed;
+            Some((timings.busy, timings.idle))
+        });
+        let span = span.map_or_else(|| id.into_non_zero_u64(), |span| self.recorded_id(&span));
+        self.with_staged(|machine| {
+            if let Some(metadata) = metadata
+                && self.span_events.contains(SpanEvents::CLOSE)
+            {
+                self.span_event(machine, span, metadata, "close", timing);
+            }
+            if let Some(metadata) = metadata
+                && let Some((busy, idle)) = timing
+                && self.span_timing
+            {
+                machine.handle(Instruction::SpanTiming {
+                    time: Utc::now(),
+                    span,
+                    target: metadata.target(),
+                    priority: *metadata.level(),
+                    busy,
+                    idle,
+                });
+            }
+            machine.handle(Instruction::DeleteSpan(span));
+        });
+    }
+}
+
+impl<T> TapeMachineLogger<T> {
+    /// Records the event of a moment of [`SpanEvents`], with the busy and idle `timing` of the
+    /// span, if known.
+    fn span_event(
+        &self,
+        machine: &mut TapeMachineLoggerInner<dyn TapeMachine<InstructionSet>>,
+        span: NonZeroU64,
+        metadata: &tracing::Metadata<'_>,
+        message: &str,
+        timing: Option<(Duration, Duration)>,
+    ) {
+        let location = self
+            .location
+            .then(|| Location::from_metadata(metadata))
+            .flatten();
+        let current = self.thread.then(std::thread::current);
+        let thread = current.as_ref().map(|current| ThreadInfo {
+            id: current_thread_id(),
+            name: current.name().unwrap_or_default(),
+        });
+        machine.handle(Instruction::StartEvent {
+            time: Utc::now(),
+            span: Some(span),
+            target: metadata.target(),
+            priority: *metadata.level(),
+            location,
+            thread,
+        });
+        machine.handle(Instruction::AddValue(FieldValue {
+            name: "message",
+            value: Value::Debug(message),
+        }));
+        if let Some((busy, idle)) = timing {
+            for (name, duration) in [("time.busy", busy), ("time.idle", idle)] {
+                let duration = crate::duration::format_duration(duration);
+                machine.handle(Instruction::AddValue(FieldValue {
+                    name,
+                    value: Value::Debug(&duration),
+                }));
+            }
+        }
+        machine.handle(Instruction::FinishedEvent);
+    }
+}
+
+/// Span extension accumulating the time spent inside and outside of the span.
+struct SpanTimings {
+    busy: Duration,
+    idle: Duration,
+    last: Instant,
+}
+impl SpanTimings {
+    /// Time since the last call, or since the span was created.
+    fn elapsed(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now - self.last;
+        self.last = now;
+        elapsed
+    }
+}
+
+struct TapeMachineLoggerInner<T: ?Sized> {
+    custom_values: CustomValues,
+    custom_buf: Vec<u8>,
+    machine: T,
+}
+impl<T> TapeMachineLoggerInner<T>
+where
+    T: TapeMachine<InstructionSet> + ?Sized,
+{
+    fn field_value<'a, V>(&mut self, field: &Field, value: V) -> FieldValue<'a, &'a str>
+    where
+        V: Into<Value<'a, &'a str>>,
+    {
+        let name = field.name();
+        let value = value.into();
+
+        FieldValue { name, value }
+    }
+
+    fn handle(&mut self, instruction: Instruction) {
+        self.machine.handle(instruction);
+    }
+}
+
+struct VisitMachine<'a, T: ?Sized> {
+    machine: &'a mut TapeMachineLoggerInner<T>,
+    skip: Option<Field>,
+}
+impl<'a, T> VisitMachine<'a, T>
+where
+    T: TapeMachine<InstructionSet> + ?Sized,
+{
+    fn new(machine: &'a mut TapeMachineLoggerInner<T>) -> Self {
+        Self {
+            machine,
+            skip: None,
+        }
+    }
+
+    fn skipping(mut self, field: Option<Field>) -> Self {
+        self.skip = field;
+        self
+    }
+
+    fn add_value<'b, V>(&mut self, field: &Field, value: V)
+    where
+        V: Into<Value<'b, &'b str>>,
+    {
+        if self.skip.as_ref() == Some(field) {
+            return;
+        }
+
+        let value = self.machine.field_value(field, value);
+        self.machine.handle(Instruction::AddValue(value));
+    }
+}
+impl<T> Visit for VisitMachine<'_, T>
+where
+    T: TapeMachine<InstructionSet> + ?Sized,
+{
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        let value = format!("{value:?}");
+
+        let mut buf = std::mem::take(&mut self.machine.custom_buf);
+        match self
+            .machine
+            .custom_values
+            .encode(field.name(), &value, &mut buf)
+        {
+            Some(tag) => self.add_value(field, Value::Custom { tag, data: &buf }),
+            None => self.add_value(field, Value::Debug(value.as_str())),
+        }
+        self.machine.custom_buf = buf;
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.add_value(field, value);
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.add_value(field, value);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.add_value(field, value);
+    }
+
+    fn record_i128(&mut self, field: &Field, value: i128) {
+        self.add_value(field, value);
+    }
+
+    fn record_u128(&mut self, field: &Field, value: u128) {
+        self.add_value(field, value);
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.add_value(field, value);
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.add_value(field, Value::String(value));
     }
 
     fn record_bytes(&mut self, field: &Field, value: &[u8]) {
-        let value = self.0.field_value(field, value);
-        self.0.handle(Instruction::AddValue(value));
+        self.add_value(field, value);
     }
 
     fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
-        self.record_debug(field, &value.to_string())
+        let message = value.to_string();
+        let sources = std::iter::successors(value.source(), |source| source.source())
+            .map(|source| source.to_string())
+            .collect::<Vec<_>>();
+        let sources =
+            NestedOwned::array(sources.iter().map(|source| Value::String(source.as_str())));
+        self.add_value(
+            field,
+            Value::Error {
+                message: message.as_str(),
+                sources: sources.as_ref(),
+            },
+        );
     }
 }
 
-#[derive(Clone)]
+/// Extracts the value of [`TARGET_FIELD`] from an event.
+struct VisitTarget<'a> {
+    field: &'a Field,
+    target: Option<String>,
+}
+impl Visit for VisitTarget<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field == self.field {
+            self.target = Some(format!("{value:?}"));
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field == self.field {
+            self.target = Some(value.to_owned());
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
 pub struct SpanRecords {
     pub parent: Option<NonZeroU64>,
     pub name: String,
@@ -391,4 +2234,678 @@ impl SpanRecords {
             records: Default::default(),
         }
     }
+
+    /// Sets the value of a field, replacing the previous value with the same name.
+    ///
+    /// Fields declared as `tracing::field::Empty` are only recorded later, so they are appended.
+    pub fn record(&mut self, value: FieldValueOwned) {
+        match self
+            .records
+            .iter_mut()
+            .find(|record| record.name == value.name)
+        {
+            Some(record) => *record = value,
+            None => self.records.push(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::Shared;
+    use tracing_subscriber::{Registry, layer::SubscriberExt};
+
+    type TargetFields = Vec<(String, Vec<String>)>;
+
+    struct Targets(Arc<Mutex<TargetFields>>);
+    impl TapeMachine<InstructionSet> for Targets {
+        fn needs_restart(&mut self) -> bool {
+            false
+        }
+
+        fn handle(&mut self, instruction: Instruction) {
+            let mut targets = self.0.lock().unwrap();
+            match instruction {
+                Instruction::NewSpan { name, .. } => {
+                    targets.push((format!("span {name}"), Vec::new()));
+                }
+                Instruction::StartEvent { target, .. } => {
+                    targets.push((target.to_owned(), Vec::new()));
+                }
+                Instruction::AddValue(value) => {
+                    targets.last_mut().unwrap().1.push(value.name.to_owned());
+                }
+                _ => (),
+            }
+        }
+    }
+
+    #[test]
+    fn reentrancy() {
+        fn event(
+            machine: &mut TapeMachineLoggerInner<dyn TapeMachine<InstructionSet>>,
+            target: &str,
+        ) {
+            machine.handle(Instruction::StartEvent {
+                time: DateTime::UNIX_EPOCH,
+                span: None,
+                target,
+                priority: Level::INFO,
+                location: None,
+                thread: None,
+            });
+            machine.handle(Instruction::FinishedEvent);
+        }
+
+        // tracing itself drops nested events under scoped dispatchers, so nest the locks directly
+        let run = |reentrancy| {
+            let targets = Arc::new(Mutex::new(Vec::new()));
+            let logger =
+                TapeMachineLogger::new(Targets(targets.clone())).with_reentrancy(reentrancy);
+            logger.with_machine(|machine| {
+                event(machine, "outer");
+                logger.with_machine(|machine| event(machine, "inner"));
+            });
+            let targets = targets.lock().unwrap();
+            targets
+                .iter()
+                .map(|(target, _)| target.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(run(Reentrancy::Drop), ["outer"]);
+        assert_eq!(run(Reentrancy::Queue), ["outer", "inner"]);
+        let fallback = Arc::new(Mutex::new(Vec::new()));
+        assert_eq!(
+            run(Reentrancy::Fallback(Box::new(Targets(fallback.clone())))),
+            ["outer"]
+        );
+        assert_eq!(fallback.lock().unwrap()[0].0, "inner");
+    }
+
+    struct NeedsRestart(bool);
+    impl TapeMachine<InstructionSet> for NeedsRestart {
+        fn needs_restart(&mut self) -> bool {
+            std::mem::take(&mut self.0)
+        }
+
+        fn handle(&mut self, _: Instruction) {}
+    }
+
+    #[test]
+    fn tee() {
+        let a = Arc::new(Mutex::new(TargetFields::new()));
+        let b = Arc::new(Mutex::new(TargetFields::new()));
+        let mut tee = Tee::new(
+            Targets(a.clone()),
+            Tee::new(Targets(b.clone()), NeedsRestart(true)),
+        );
+        assert!(tee.needs_restart());
+        assert!(!tee.needs_restart());
+
+        tee.handle(Instruction::StartEvent {
+            time: DateTime::UNIX_EPOCH,
+            span: None,
+            target: "target",
+            priority: Level::INFO,
+            location: None,
+            thread: None,
+        });
+        assert_eq!(a.lock().unwrap()[0].0, "target");
+        assert_eq!(b.lock().unwrap()[0].0, "target");
+    }
+
+    #[test]
+    fn instruction_bytes() {
+        for version in [0, 1, 2] {
+            let mut bytes = Vec::new();
+            let count = match version {
+                0 | 1 => InstructionId::LEGACY_TABLE.len(),
+                _ => InstructionId::TABLE.len(),
+            };
+            for id in InstructionId::TABLE
+                .into_iter()
+                .take(count)
+                .chain([InstructionId::Restart])
+            {
+                let byte = id.to_byte(version).unwrap();
+                assert_eq!(InstructionId::from_byte(byte, version), Ok(id));
+                assert!(!bytes.contains(&byte));
+                bytes.push(byte);
+            }
+        }
+        assert_eq!(InstructionId::StartEvent.to_byte(1), Some(32));
+        assert_eq!(InstructionId::StartEvent.to_byte(2), Some(6));
+        assert_eq!(InstructionId::SpanTiming.to_byte(2), Some(13));
+        assert_eq!(InstructionId::SpanTiming.to_byte(1), None);
+        assert_eq!(InstructionId::from_byte(13, 1), Err(13));
+        assert_eq!(InstructionId::from_byte(14, 2), Err(14));
+    }
+
+    #[test]
+    fn target_override() {
+        let targets = Arc::new(Mutex::new(Vec::new()));
+        let logger = TapeMachineLogger::new(Targets(targets.clone()))
+            .with_enrichment(|_, fields| fields.add("build", Value::String("abc123")));
+        let subscriber = Registry::default().with(logger);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let tenant = String::from("tenant-42");
+            crate::event_with_target!(tenant, Level::INFO, answer = 42, "Routed");
+            tracing::info!("Regular");
+        });
+
+        let targets = targets.lock().unwrap();
+        assert_eq!(targets[0].0, "tenant-42");
+        assert_eq!(targets[0].1, ["message", "answer", "build"]);
+        assert_eq!(targets[1].0, module_path!());
+    }
+
+    #[test]
+    fn staging() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let store = crate::string_cache::StringCache::new(storage::Store::new(Shared(out.clone())));
+        let logger = TapeMachineLogger::new(store).with_staging(true);
+        let dispatch = tracing::Dispatch::new(Registry::default().with(logger));
+
+        let threads = (0..4)
+            .map(|thread| {
+                let dispatch = dispatch.clone();
+                std::thread::spawn(move || {
+                    tracing::dispatcher::with_default(&dispatch, || {
+                        let span = tracing::info_span!("work", thread);
+                        let _entered = span.enter();
+                        for i in 0..100 {
+                            tracing::info!(i, "Staged");
+                        }
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let out = out.lock().unwrap();
+        let mut load = storage::Load::new(out.as_slice());
+        let (mut spans, mut events) = (0, 0);
+        while let Some(instruction) = load.fetch_one().unwrap() {
+            match instruction {
+                Instruction::NewSpan { name, .. } => {
+                    assert_eq!(name, "work");
+                    spans += 1;
+                }
+                Instruction::StartEvent { target, .. } => {
+                    assert_eq!(target, module_path!());
+                    events += 1;
+                }
+                _ => (),
+            }
+        }
+        assert_eq!((spans, events), (4, 400));
+    }
+
+    #[test]
+    fn pipeline() {
+        struct Lifetimes(Arc<Mutex<Vec<(&'static str, NonZeroU64)>>>);
+        impl TapeMachine<InstructionSet> for Lifetimes {
+            fn needs_restart(&mut self) -> bool {
+                false
+            }
+
+            fn handle(&mut self, instruction: Instruction) {
+                let moment = match instruction {
+                    Instruction::NewSpan { span, .. } => ("new", span),
+                    Instruction::StartEvent { span, .. } => ("event", span.unwrap()),
+                    Instruction::DeleteSpan(span) => ("delete", span),
+                    _ => return,
+                };
+                self.0.lock().unwrap().push(moment);
+            }
+        }
+
+        let ids = Arc::new(Mutex::new(Vec::new()));
+        let logger = TapeMachineLogger::new(Lifetimes(ids.clone())).with_pipeline(true);
+        let handle = logger.handle();
+        let dispatch = tracing::Dispatch::new(Registry::default().with(logger));
+
+        let threads = (0..4)
+            .map(|thread| {
+                let dispatch = dispatch.clone();
+                std::thread::spawn(move || {
+                    tracing::dispatcher::with_default(&dispatch, || {
+                        let span = tracing::info_span!("work", thread);
+                        span.in_scope(|| tracing::info!(thread, "First"));
+                        span.in_scope(|| tracing::info!(thread, "Second"));
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        handle.flush();
+
+        // Span ids are reused once closed, so follow which ones are alive
+        let ids = ids.lock().unwrap();
+        let mut alive = Vec::new();
+        for &(moment, span) in ids.iter() {
+            match moment {
+                "new" => {
+                    assert!(!alive.contains(&span));
+                    alive.push(span);
+                }
+                "event" => assert!(alive.contains(&span)),
+                _ => alive.retain(|&alive| alive != span),
+            }
+        }
+        assert_eq!(ids.len(), 16);
+        assert!(alive.is_empty());
+    }
+
+    #[test]
+    fn pipeline_full() {
+        #[derive(Default)]
+        struct Counts {
+            events: usize,
+            dropped: u64,
+            burst: Vec<u64>,
+        }
+
+        /// Waits for `gate` on the first event, counting them and the spans and events dropped.
+        struct Gated {
+            entered: mpsc::Sender<()>,
+            gate: Option<mpsc::Receiver<()>>,
+            counts: Arc<Mutex<Counts>>,
+        }
+        impl TapeMachine<InstructionSet> for Gated {
+            fn needs_restart(&mut self) -> bool {
+                false
+            }
+
+            fn handle(&mut self, instruction: Instruction) {
+                match instruction {
+                    Instruction::StartEvent { .. } => {
+                        self.counts.lock().unwrap().events += 1;
+                        if let Some(gate) = self.gate.take() {
+                            let _ = self.entered.send(());
+                            let _ = gate.recv();
+                        }
+                    }
+                    Instruction::AddValue(FieldValue {
+                        name: "dropped",
+                        value: Value::Unsigned(dropped),
+                    }) => self.counts.lock().unwrap().dropped += dropped,
+                    // Small numbers are decoded as signed
+                    Instruction::AddValue(FieldValue {
+                        name: "i",
+                        value: Value::Integer(i),
+                    }) => self.counts.lock().unwrap().burst.push(i as u64),
+                    Instruction::AddValue(FieldValue {
+                        name: "i",
+                        value: Value::Unsigned(i),
+                    }) => self.counts.lock().unwrap().burst.push(i),
+                    _ => (),
+                }
+            }
+        }
+
+        let spill = std::env::temp_dir().join(format!("spill-{}", std::process::id()));
+        for full in [
+            PipelineFull::Drop,
+            PipelineFull::Block,
+            PipelineFull::Spill(spill.clone()),
+        ] {
+            let (entered, wait_entered) = mpsc::channel();
+            let (open, gate) = mpsc::channel();
+            let counts = Arc::new(Mutex::new(Counts::default()));
+            let machine = Gated {
+                entered,
+                gate: Some(gate),
+                counts: counts.clone(),
+            };
+            let logger = TapeMachineLogger::new(machine)
+                .with_pipeline(true)
+                .with_pipeline_full(full.clone());
+            let handle = logger.handle();
+            let dispatch = tracing::Dispatch::new(Registry::default().with(logger));
+
+            tracing::dispatcher::with_default(&dispatch, || tracing::info!("First"));
+            wait_entered.recv().unwrap();
+            let burst = {
+                let dispatch = dispatch.clone();
+                std::thread::spawn(move || {
+                    tracing::dispatcher::with_default(&dispatch, || {
+                        for i in 0..PIPELINE_CAPACITY + 10 {
+                            tracing::info!(i, "Burst");
+                        }
+                    })
+                })
+            };
+            if full == PipelineFull::Block {
+                open.send(()).unwrap();
+                burst.join().unwrap();
+            } else {
+                burst.join().unwrap();
+                open.send(()).unwrap();
+            }
+            handle.flush();
+            tracing::dispatcher::with_default(&dispatch, || tracing::info!("Last"));
+            handle.flush();
+
+            let counts = counts.lock().unwrap();
+            let warnings = usize::from(counts.dropped > 0);
+            assert_eq!(counts.burst.len() + 2 + warnings, counts.events);
+            let counts = (counts.events, counts.dropped, counts.burst.is_sorted());
+            match full {
+                // The first, the burst but the dropped, the last and the warning
+                PipelineFull::Drop => assert_eq!(counts, (PIPELINE_CAPACITY + 3, 10, true)),
+                PipelineFull::Block => assert_eq!(counts, (PIPELINE_CAPACITY + 12, 0, true)),
+                PipelineFull::Spill(_) => {
+                    assert_eq!(counts, (PIPELINE_CAPACITY + 12, 0, true));
+                    assert_eq!(std::fs::metadata(&spill).unwrap().len(), 0);
+                }
+            }
+        }
+    }
+
+    /// Parent or deleted span, and new or event span.
+    type SpanIdPairs = Vec<(Option<NonZeroU64>, Option<NonZeroU64>)>;
+
+    struct SpanIds(Arc<Mutex<SpanIdPairs>>);
+    impl TapeMachine<InstructionSet> for SpanIds {
+        fn needs_restart(&mut self) -> bool {
+            false
+        }
+
+        fn handle(&mut self, instruction: Instruction) {
+            let mut ids = self.0.lock().unwrap();
+            match instruction {
+                Instruction::NewSpan { parent, span, .. } => ids.push((parent, Some(span))),
+                Instruction::StartEvent { span, .. } => ids.push((None, span)),
+                Instruction::DeleteSpan(span) => ids.push((Some(span), None)),
+                _ => (),
+            }
+        }
+    }
+
+    #[test]
+    fn error_sources() {
+        #[derive(Debug)]
+        struct Error(&'static str, Option<Box<Error>>);
+        impl std::fmt::Display for Error {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "{}", self.0)
+            }
+        }
+        impl std::error::Error for Error {
+            fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+                self.1.as_deref().map(|source| source as _)
+            }
+        }
+
+        struct Values(Arc<Mutex<Vec<FieldValueOwned>>>);
+        impl TapeMachine<InstructionSet> for Values {
+            fn needs_restart(&mut self) -> bool {
+                false
+            }
+
+            fn handle(&mut self, instruction: Instruction) {
+                if let Instruction::AddValue(value) = instruction {
+                    self.0.lock().unwrap().push(value.to_owned());
+                }
+            }
+        }
+
+        let tape = Arc::new(Mutex::new(Vec::new()));
+        let store = crate::storage::Store::new(Shared(tape.clone()));
+        let logger = TapeMachineLogger::new(crate::string_cache::StringCache::new(store));
+        tracing::subscriber::with_default(Registry::default().with(logger), || {
+            let error = Error(
+                "request failed",
+                Some(Box::new(Error("connection refused", None))),
+            );
+            tracing::error!(err = &error as &dyn std::error::Error);
+        });
+
+        let tape = tape.lock().unwrap();
+        let mut load = crate::storage::Load::new(tape.as_slice());
+        let values = Arc::new(Mutex::new(Vec::new()));
+        let mut uncache = crate::string_cache::StringUncache::new(Values(values.clone()));
+        while let Some(instruction) = load.fetch_one_cached().unwrap() {
+            uncache.handle(instruction);
+        }
+
+        let values = values.lock().unwrap();
+        let ValueOwned::Error { message, sources } = &values[0].value else {
+            panic!("{:?}", values[0]);
+        };
+        assert_eq!(message, "request failed");
+        assert_eq!(
+            sources.strings().collect::<Vec<_>>(),
+            ["connection refused"]
+        );
+        let event = crate::printer::NewEvent {
+            time: Default::default(),
+            span: None,
+            target: "target".to_string(),
+            priority: Level::ERROR,
+            location: None,
+            thread: None,
+            records: values.clone(),
+        };
+        assert!(
+            event
+                .to_line(false, &[])
+                .ends_with("err=request failed: caused by: connection refused")
+        );
+    }
+
+    #[test]
+    fn span_timing() {
+        let tape = Arc::new(Mutex::new(Vec::new()));
+        let store = crate::storage::Store::new(Shared(tape.clone()));
+        let logger = TapeMachineLogger::new(crate::string_cache::StringCache::new(store))
+            .with_span_timing(true);
+        tracing::subscriber::with_default(Registry::default().with(logger), || {
+            let request = tracing::info_span!("request", id = 1);
+            request.in_scope(|| std::thread::sleep(Duration::from_millis(2)));
+            request.in_scope(|| tracing::info!("Inside"));
+        });
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let sink = lines.clone();
+        let printer = crate::printer::Printer::new(io::sink(), false)
+            .with_span_close(true)
+            .with_sink(move |line| sink.lock().unwrap().push(line.to_owned()));
+        let mut uncache = crate::string_cache::StringUncache::new(printer);
+        let tape = tape.lock().unwrap();
+        let mut load = crate::storage::Load::new(tape.as_slice());
+        let mut entered = 0;
+        while let Some(instruction) = load.fetch_one_cached().unwrap() {
+            if let crate::string_cache::CacheInstruction::EnterSpan { .. } = instruction {
+                entered += 1;
+            }
+            uncache.handle(instruction);
+        }
+
+        assert_eq!(entered, 2);
+        let lines = lines.lock().unwrap();
+        assert_eq!(lines.len(), 2);
+        let close = lines[1].split_once(" request{id=1}: ").unwrap().1;
+        let (target, fields) = close.split_once(' ').unwrap();
+        assert_eq!(target, format!("{}:", module_path!()));
+        let busy = fields
+            .strip_prefix("close time.busy=")
+            .and_then(|fields| fields.split_once(" time.idle="))
+            .map(|(busy, _)| busy)
+            .unwrap();
+        assert!(busy.ends_with("ms"), "{busy}");
+    }
+
+    #[test]
+    fn span_events() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let sink = lines.clone();
+        let printer = crate::printer::Printer::new(io::sink(), false)
+            .with_sink(move |line| sink.lock().unwrap().push(line.to_owned()));
+        let logger = TapeMachineLogger::new(printer)
+            .with_span_events(SpanEvents::NEW | SpanEvents::ENTER | SpanEvents::CLOSE);
+        tracing::subscriber::with_default(Registry::default().with(logger), || {
+            let request = tracing::warn_span!("request", id = 1);
+            request.in_scope(|| tracing::info!("Inside"));
+        });
+
+        let lines = lines.lock().unwrap();
+        let messages = lines
+            .iter()
+            .map(|line| line.split_once(" request{id=1}: ").unwrap().1)
+            .map(|line| line.split_once(' ').unwrap())
+            .collect::<Vec<_>>();
+        let target = format!("{}:", module_path!());
+        assert_eq!(messages[0], (target.as_str(), "new"));
+        assert_eq!(messages[1], (target.as_str(), "enter"));
+        assert_eq!(messages[2], (target.as_str(), "Inside"));
+        assert!(messages[3].1.starts_with("close time.busy="));
+        assert!(lines[3].contains("WARN"));
+        assert_eq!(lines.len(), 4);
+    }
+
+    #[test]
+    fn extracted_span_id() {
+        let ids = Arc::new(Mutex::new(Vec::new()));
+        let logger =
+            TapeMachineLogger::new(SpanIds(ids.clone())).with_span_id(span_id_field("trace_id"));
+        let subscriber = Registry::default().with(logger);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let request = tracing::info_span!("request", trace_id = "00000000000000ff");
+            let _request = request.enter();
+            let query = tracing::info_span!("query");
+            query.in_scope(|| tracing::info!("Inside"));
+        });
+
+        let trace = NonZeroU64::new(0xff);
+        let ids = ids.lock().unwrap();
+        let query = ids[1].1;
+        assert_ne!(query, trace);
+        assert_eq!(
+            *ids,
+            [
+                (None, trace),
+                (trace, query),
+                (None, query),
+                (query, None),
+                (trace, None)
+            ]
+        );
+    }
+
+    #[cfg(feature = "span-extensions")]
+    #[test]
+    fn span_extension() {
+        struct Route(&'static str);
+        struct RouteLayer;
+        impl<S> Layer<S> for RouteLayer
+        where
+            S: Subscriber + for<'a> LookupSpan<'a>,
+        {
+            fn on_new_span(
+                &self,
+                _attrs: &span::Attributes<'_>,
+                id: &span::Id,
+                ctx: tracing_subscriber::layer::Context<'_, S>,
+            ) {
+                let span = ctx.span(id).unwrap();
+                span.extensions_mut().insert(Route("/users/{id}"));
+            }
+        }
+
+        let targets = Arc::new(Mutex::new(Vec::new()));
+        let logger = TapeMachineLogger::new(Targets(targets.clone())).with_span_extension(
+            |route: &Route, fields| fields.add("route", Value::String(route.0)),
+        );
+        let subscriber = Registry::default().with(RouteLayer).with(logger);
+
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("request", peer = "127.0.0.1").entered();
+        });
+
+        let targets = targets.lock().unwrap();
+        assert_eq!(targets[0].0, "span request");
+        assert_eq!(targets[0].1, ["peer", "route"]);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn task_id() {
+        let targets = Arc::new(Mutex::new(Vec::new()));
+        let logger = TapeMachineLogger::new(Targets(targets.clone())).with_task_id(true);
+        let subscriber = Registry::default().with(logger);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!("Outside");
+            runtime.block_on(async {
+                tokio::spawn(async {
+                    let _span = tracing::info_span!("task").entered();
+                    tracing::info!("Inside");
+                })
+                .await
+                .unwrap();
+            });
+        });
+
+        let targets = targets.lock().unwrap();
+        assert_eq!(targets[0].1, ["message"]);
+        assert_eq!(targets[1].0, "span task");
+        assert_eq!(targets[1].1, [TASK_ID_FIELD]);
+        assert_eq!(targets[2].1, ["message", TASK_ID_FIELD]);
+    }
+
+    #[cfg(all(feature = "serde", feature = "json"))]
+    #[test]
+    fn serde_roundtrip() {
+        let instructions = [
+            InstructionOwned::StartEvent {
+                time: DateTime::UNIX_EPOCH,
+                span: NonZeroU64::new(1),
+                target: "app".to_owned(),
+                priority: Level::WARN,
+                location: Some(Location {
+                    file: "main.rs".to_owned(),
+                    line: 7,
+                    module_path: "app".to_owned(),
+                }),
+                thread: None,
+            },
+            InstructionOwned::AddValue(FieldValueOwned {
+                name: "items".to_owned(),
+                value: ValueOwned::Array(NestedOwned::array([
+                    Value::Integer(-1),
+                    Value::String("a"),
+                ])),
+            }),
+            InstructionOwned::AddValue(FieldValueOwned {
+                name: "headers".to_owned(),
+                value: ValueOwned::Map(NestedOwned::map([
+                    ("b", Value::Bool(true)),
+                    ("a", Value::Unsigned(7)),
+                ])),
+            }),
+            InstructionOwned::FinishedEvent,
+        ];
+
+        let json = serde_json::to_value(&instructions).unwrap();
+        assert_eq!(json[0]["StartEvent"]["priority"], "WARN");
+        assert_eq!(json[1]["AddValue"]["value"]["Array"][1]["String"], "a");
+        assert_eq!(json[2]["AddValue"]["value"]["Map"]["b"]["Bool"], true);
+
+        let text = serde_json::to_string(&instructions).unwrap();
+        let decoded: Vec<InstructionOwned> = serde_json::from_str(&text).unwrap();
+        assert_eq!(decoded, instructions);
+    }
 }
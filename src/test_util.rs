@@ -0,0 +1,169 @@
+use crate::{
+    string_cache::{CacheInstruction, CacheInstructionSet, CacheString},
+    tape::{FieldValue, Instruction, InstructionSet, Location, TapeMachine, ThreadInfo, Value},
+};
+use chrono::{DateTime, Utc};
+use std::{
+    io,
+    num::NonZeroU64,
+    sync::{Arc, Mutex},
+};
+use tracing::Level;
+
+/// Writer whose output stays readable while a machine owns it.
+#[derive(Clone, Default)]
+pub struct Shared(pub Arc<Mutex<Vec<u8>>>);
+impl io::Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn span(id: u64) -> NonZeroU64 {
+    NonZeroU64::new(id).unwrap()
+}
+
+/// `StartEvent` without location nor thread.
+pub fn start_event(
+    time: DateTime<Utc>,
+    span: Option<NonZeroU64>,
+    target: &str,
+    priority: Level,
+) -> Instruction<'_> {
+    Instruction::StartEvent {
+        time,
+        span,
+        target,
+        priority,
+        location: None,
+        thread: None,
+    }
+}
+
+/// Writes an event with `values`, see [`start_event`].
+pub fn event<T: TapeMachine<InstructionSet>>(
+    tape: &mut T,
+    start: Instruction,
+    values: &[(&str, Value<&str>)],
+) {
+    tape.handle(start);
+    for (name, value) in values {
+        tape.handle(Instruction::AddValue(FieldValue {
+            name,
+            value: *value,
+        }));
+    }
+    tape.handle(Instruction::FinishedEvent);
+}
+
+/// Writes a span with `values`.
+pub fn new_span<T: TapeMachine<InstructionSet>>(
+    tape: &mut T,
+    parent: Option<NonZeroU64>,
+    span: NonZeroU64,
+    name: &str,
+    values: &[(&str, Value<&str>)],
+) {
+    tape.handle(Instruction::NewSpan { parent, span, name });
+    for (name, value) in values {
+        tape.handle(Instruction::AddValue(FieldValue {
+            name,
+            value: *value,
+        }));
+    }
+    tape.handle(Instruction::FinishedSpan);
+}
+
+/// Writes every kind of instruction, value and optional field.
+pub fn golden_tape<T: TapeMachine<InstructionSet>>(tape: &mut T) {
+    let span = NonZeroU64::new(1).unwrap();
+    let child = NonZeroU64::new(u64::MAX).unwrap();
+    tape.handle(Instruction::Restart);
+    tape.handle(Instruction::FilterChanged("info"));
+    tape.handle(Instruction::NewSpan {
+        parent: None,
+        span,
+        name: "request",
+    });
+    tape.handle(Instruction::FinishedSpan);
+    tape.handle(Instruction::NewSpan {
+        parent: Some(span),
+        span: child,
+        name: "request",
+    });
+    tape.handle(Instruction::AddValue(FieldValue {
+        name: "id",
+        value: Value::Unsigned(7),
+    }));
+    tape.handle(Instruction::FinishedSpan);
+    tape.handle(Instruction::NewRecord(child));
+    tape.handle(Instruction::AddValue(FieldValue {
+        name: "status",
+        value: Value::String("ok"),
+    }));
+    tape.handle(Instruction::FinishedRecord);
+    for time in [
+        DateTime::UNIX_EPOCH,
+        DateTime::from_timestamp(-1, 5).unwrap(),
+    ] {
+        tape.handle(Instruction::StartEvent {
+            time,
+            span: Some(child),
+            target: "target",
+            priority: Level::ERROR,
+            location: Some(Location {
+                file: "src/main.rs",
+                line: 42,
+                module_path: "app",
+            }),
+            thread: Some(ThreadInfo {
+                id: 3,
+                name: "main",
+            }),
+        });
+        for value in [
+            Value::Debug("debug"),
+            Value::String("status"),
+            Value::Float(-0.5),
+            Value::Integer(i64::MIN),
+            Value::Unsigned(u64::MAX),
+            Value::Bool(true),
+            Value::ByteArray(&[0, 255]),
+            Value::Custom {
+                tag: 5,
+                data: &[1, 2, 3],
+            },
+        ] {
+            tape.handle(Instruction::AddValue(FieldValue {
+                name: "value",
+                value,
+            }));
+        }
+        tape.handle(Instruction::FinishedEvent);
+    }
+    tape.handle(Instruction::DeleteSpan(child));
+    tape.handle(Instruction::Restart);
+    tape.handle(Instruction::DeleteSpan(span));
+}
+
+/// Collects the targets of the events of a cached tape.
+pub struct Targets(pub Vec<String>);
+impl TapeMachine<CacheInstructionSet> for Targets {
+    fn needs_restart(&mut self) -> bool {
+        false
+    }
+
+    fn handle(&mut self, instruction: CacheInstruction) {
+        if let CacheInstruction::StartEvent {
+            target: CacheString::Present(target),
+            ..
+        } = instruction
+        {
+            self.0.push(target.to_owned());
+        }
+    }
+}
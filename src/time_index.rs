@@ -0,0 +1,306 @@
+use crate::{restart::RestartInterval, rotate, storage::Load, string_cache::CacheInstruction};
+use chrono::{DateTime, Utc};
+use std::{
+    collections::HashSet,
+    io::{self, Read},
+    num::NonZeroU64,
+    path::{Path, PathBuf},
+};
+
+/// Extension of the index written next to a tape, see [`index_path`].
+pub const INDEX_EXTENSION: &str = "idx";
+
+/// Extension of the span index written next to a tape, see [`span_index_path`].
+pub const SPAN_INDEX_EXTENSION: &str = "spans.idx";
+
+/// Position of a `Restart`, where decoding can start, and the time of the first event after it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub offset: u64,
+    pub time: DateTime<Utc>,
+}
+impl IndexEntry {
+    /// Entries are stored with a fixed size, so that the index can be read without decoding.
+    pub const SIZE: usize = 20;
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut data = [0; Self::SIZE];
+        data[..8].copy_from_slice(&self.offset.to_be_bytes());
+        data[8..16].copy_from_slice(&self.time.timestamp().to_be_bytes());
+        data[16..].copy_from_slice(&self.time.timestamp_subsec_nanos().to_be_bytes());
+        data
+    }
+
+    pub fn decode(data: &[u8; Self::SIZE]) -> Self {
+        let offset = u64::from_be_bytes(data[..8].try_into().unwrap());
+        let secs = i64::from_be_bytes(data[8..16].try_into().unwrap());
+        let nanos = u32::from_be_bytes(data[16..].try_into().unwrap());
+
+        Self {
+            offset,
+            time: DateTime::from_timestamp(secs, nanos).unwrap_or_default(),
+        }
+    }
+}
+
+/// `Restart` after which a span was created, where decoding can start to find it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SpanIndexEntry {
+    pub span: NonZeroU64,
+    pub offset: u64,
+}
+impl SpanIndexEntry {
+    pub const SIZE: usize = 16;
+
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut data = [0; Self::SIZE];
+        data[..8].copy_from_slice(&self.span.get().to_be_bytes());
+        data[8..].copy_from_slice(&self.offset.to_be_bytes());
+        data
+    }
+
+    /// `None` for a zero span.
+    pub fn decode(data: &[u8; Self::SIZE]) -> Option<Self> {
+        let span = u64::from_be_bytes(data[..8].try_into().unwrap());
+        let offset = u64::from_be_bytes(data[8..].try_into().unwrap());
+
+        Some(Self {
+            span: NonZeroU64::new(span)?,
+            offset,
+        })
+    }
+}
+
+/// Path of the index of the tape at `path`: `app.log.idx` for both `app.log` and `app.log.gz`.
+pub fn index_path(path: &Path) -> PathBuf {
+    sidecar_path(path, INDEX_EXTENSION)
+}
+
+/// Path of the span index of the tape at `path`: `app.log.spans.idx` for both `app.log` and
+/// `app.log.gz`.
+pub fn span_index_path(path: &Path) -> PathBuf {
+    sidecar_path(path, SPAN_INDEX_EXTENSION)
+}
+
+fn sidecar_path(path: &Path, extension: &str) -> PathBuf {
+    let path = match rotate::is_compressed(path) {
+        true => path.with_extension(""),
+        false => path.to_owned(),
+    };
+    let mut path = path.into_os_string();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+/// Reads an index, ignoring a partially written last entry.
+pub fn read_index<R: Read>(mut read: R) -> io::Result<Vec<IndexEntry>> {
+    let mut data = Vec::new();
+    read.read_to_end(&mut data)?;
+
+    Ok(data
+        .chunks_exact(IndexEntry::SIZE)
+        .map(|entry| IndexEntry::decode(entry.try_into().unwrap()))
+        .collect())
+}
+
+/// Reads a span index, ignoring a partially written last entry.
+pub fn read_span_index<R: Read>(mut read: R) -> io::Result<Vec<SpanIndexEntry>> {
+    let mut data = Vec::new();
+    read.read_to_end(&mut data)?;
+
+    Ok(data
+        .chunks_exact(SpanIndexEntry::SIZE)
+        .filter_map(|entry| SpanIndexEntry::decode(entry.try_into().unwrap()))
+        .collect())
+}
+
+/// Index of an existing tape, built by [`build_index`].
+#[derive(Debug, Default)]
+pub struct Index {
+    /// Every `Restart` followed by an event, sorted by offset.
+    pub entries: Vec<IndexEntry>,
+    /// Every span, sorted by id and offset. Ids reused by `tracing` have an entry per span.
+    pub spans: Vec<SpanIndexEntry>,
+}
+impl Index {
+    pub fn write<W: io::Write>(&self, mut out: W) -> io::Result<()> {
+        for entry in self.entries.iter() {
+            out.write_all(&entry.encode())?;
+        }
+        out.flush()
+    }
+
+    pub fn write_spans<W: io::Write>(&self, mut out: W) -> io::Result<()> {
+        for entry in self.spans.iter() {
+            out.write_all(&entry.encode())?;
+        }
+        out.flush()
+    }
+}
+
+/// Scans a tape once, e.g. one written without [`IndexWriter`], indexing its restarts by time
+/// and by the spans created after them. Regions that can't be decoded are skipped up to the next
+/// `Restart`.
+///
+/// Seeking is only as fine as the restarts of the tape.
+pub fn build_index<R: Read>(mut load: Load<R>) -> Index {
+    let mut index = Index::default();
+    let mut live = HashSet::new();
+    let mut restart = None;
+    let mut pending = None;
+    loop {
+        let is_restart = match load.fetch_one_cached() {
+            Ok(Some(CacheInstruction::Restart)) => true,
+            Ok(Some(CacheInstruction::NewSpan { span, .. })) => {
+                // Spans are replayed after a restart
+                if let (true, Some(offset)) = (live.insert(span), restart) {
+                    index.spans.push(SpanIndexEntry { span, offset });
+                }
+                false
+            }
+            Ok(Some(CacheInstruction::DeleteSpan(span))) => {
+                live.remove(&span);
+                false
+            }
+            Ok(Some(CacheInstruction::StartEvent { time, .. })) => {
+                if let Some(offset) = pending.take() {
+                    index.entries.push(IndexEntry { offset, time });
+                }
+                false
+            }
+            Ok(Some(_)) => false,
+            Ok(None) => break,
+            Err(_) => {
+                load.restart();
+                false
+            }
+        };
+
+        if is_restart {
+            restart = Some(load.last_offset());
+            pending = restart;
+        }
+    }
+
+    index.spans.sort();
+    index
+}
+
+///
+/// Also asks for a restart every `interval` bytes, since the index only points to restarts.
+pub struct IndexWriter<W> {
+    out: W,
+    restarts: RestartInterval,
+    pending: Option<u64>,
+}
+impl<W> IndexWriter<W>
+where
+    W: io::Write,
+{
+    pub fn new(out: W, interval: u64) -> Self {
+        Self {
+            out,
+            restarts: RestartInterval::new(interval),
+            pending: None,
+        }
+    }
+
+    /// Whether the tape, now at `offset`, went `interval` bytes without a restart.
+    pub fn needs_restart(&self, offset: u64) -> bool {
+        self.restarts.needs_restart(offset)
+    }
+
+    /// Called before writing `instruction` at `offset` of the tape.
+    pub fn observe(&mut self, offset: u64, instruction: CacheInstruction) -> io::Result<()> {
+        self.restarts.observe(offset, instruction);
+        match instruction {
+            CacheInstruction::Restart => self.pending = Some(offset),
+            CacheInstruction::StartEvent { time, .. } => {
+                if let Some(offset) = self.pending.take() {
+                    self.out.write_all(&IndexEntry { offset, time }.encode())?;
+                    self.out.flush()?;
+                }
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_index_of_tape() {
+        use crate::{
+            storage::Store,
+            string_cache::StringCache,
+            tape::{Instruction, TapeMachine},
+        };
+        use tracing::Level;
+
+        let span = |id| NonZeroU64::new(id).unwrap();
+        let time = |secs| DateTime::from_timestamp(secs, 0).unwrap();
+        let mut store = StringCache::new(Store::new(Vec::new()));
+        for secs in [10, 20] {
+            // The span is replayed after the second restart
+            store.handle(Instruction::Restart);
+            store.handle(Instruction::NewSpan {
+                parent: None,
+                span: span(1),
+                name: "request",
+            });
+            store.handle(Instruction::FinishedSpan);
+            store.handle(Instruction::StartEvent {
+                time: time(secs),
+                span: None,
+                target: "target",
+                priority: Level::INFO,
+                location: None,
+                thread: None,
+            });
+            store.handle(Instruction::FinishedEvent);
+        }
+        let tape = store.into_inner().into_inner();
+
+        let index = build_index(Load::new(tape.as_slice()));
+        assert_eq!(index.entries.len(), 2);
+        assert_eq!(index.entries[1].time, time(20));
+        assert_eq!(
+            index.spans,
+            [SpanIndexEntry {
+                span: span(1),
+                offset: index.entries[0].offset
+            }]
+        );
+
+        let second = index.entries[1].offset;
+        let mut load = Load::new(std::io::Cursor::new(tape))
+            .with_index(index.entries)
+            .with_span_index(index.spans);
+        assert!(load.seek_to_span(span(1)).unwrap());
+        assert_eq!(load.offset(), 0);
+        assert!(!load.seek_to_span(span(2)).unwrap());
+        load.seek_to_time(time(25)).unwrap();
+        assert!(matches!(
+            load.fetch_one_cached().unwrap(),
+            Some(CacheInstruction::Restart)
+        ));
+        assert_eq!(load.last_offset(), second);
+    }
+
+    #[test]
+    fn index_path_of_compressed() {
+        assert_eq!(
+            index_path(Path::new("app.log.1")),
+            Path::new("app.log.1.idx")
+        );
+        assert_eq!(
+            index_path(Path::new("app.log.1.gz")),
+            Path::new("app.log.1.idx")
+        );
+    }
+}
@@ -0,0 +1,186 @@
+use crate::{
+    string_cache::{CacheInstruction, CacheInstructionSet},
+    tape::TapeMachine,
+};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeDelta, TimeZone, Utc};
+
+/// Drops events outside of a time window, before their strings are resolved.
+///
+/// Everything else, including the strings cached by the dropped events, is forwarded.
+pub struct TimeRangeFilter<T> {
+    forward: T,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    skipping: bool,
+}
+impl<T> TimeRangeFilter<T>
+where
+    T: TapeMachine<CacheInstructionSet>,
+{
+    /// Keeps events from `since`, inclusive, to `until`, exclusive. Both ends are optional.
+    pub fn new(forward: T, since: Option<DateTime<Utc>>, until: Option<DateTime<Utc>>) -> Self {
+        Self {
+            forward,
+            since,
+            until,
+            skipping: false,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.forward
+    }
+
+    fn contains(&self, time: DateTime<Utc>) -> bool {
+        self.since.is_none_or(|since| time >= since) && self.until.is_none_or(|until| time < until)
+    }
+}
+impl<T> TapeMachine<CacheInstructionSet> for TimeRangeFilter<T>
+where
+    T: TapeMachine<CacheInstructionSet>,
+{
+    fn needs_restart(&mut self) -> bool {
+        self.forward.needs_restart()
+    }
+
+    fn handle(&mut self, instruction: CacheInstruction) {
+        match instruction {
+            CacheInstruction::StartEvent { time, .. } if !self.contains(time) => {
+                self.skipping = true;
+            }
+            CacheInstruction::AddValue(..) if self.skipping => (),
+            CacheInstruction::FinishedEvent if self.skipping => self.skipping = false,
+            instruction => {
+                if let CacheInstruction::Restart = instruction {
+                    self.skipping = false;
+                }
+                self.forward.handle(instruction);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.forward.flush();
+    }
+}
+
+/// Times with an offset accepted by [`parse_time`], besides RFC 3339.
+const OFFSET_FORMATS: [&str; 3] = [
+    "%Y-%m-%dT%H:%M%:z",
+    "%Y-%m-%d %H:%M:%S%:z",
+    "%Y-%m-%d %H:%M%:z",
+];
+
+/// Times without an offset accepted by [`parse_time`], in the time zone of `now`.
+const NAIVE_FORMATS: [&str; 4] = [
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%d %H:%M",
+];
+
+/// Parses a time expression, as accepted by every tool of this crate:
+///
+/// - An RFC 3339 time, also without seconds, e.g. `2024-06-01T10:00+02:00`.
+/// - A time or date without offset, e.g. `2024-06-01 10:00` or `2024-06-01`, in the time zone
+///   of `now`.
+/// - `now`, or `today` and `yesterday` for their midnight in the time zone of `now`.
+/// - A time before `now`, e.g. `90s`, `-15m`, `2h` or `7d`.
+pub fn parse_time<Tz: TimeZone>(str: &str, now: DateTime<Tz>) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(str) {
+        return Ok(time.to_utc());
+    }
+    for format in OFFSET_FORMATS {
+        if let Ok(time) = DateTime::parse_from_str(str, format) {
+            return Ok(time.to_utc());
+        }
+    }
+    let naive = NAIVE_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(str, format).ok())
+        .or_else(|| {
+            let date = match str {
+                "today" => now.date_naive(),
+                "yesterday" => now.date_naive().pred_opt()?,
+                str => NaiveDate::parse_from_str(str, "%Y-%m-%d").ok()?,
+            };
+            Some(date.and_time(Default::default()))
+        });
+    if let Some(naive) = naive {
+        return now
+            .timezone()
+            .from_local_datetime(&naive)
+            .earliest()
+            .map(|time| time.to_utc())
+            .ok_or_else(|| format!("Time does not exist in the time zone: {str:?}"));
+    }
+    if str == "now" {
+        return Ok(now.to_utc());
+    }
+
+    let str = str.strip_prefix('-').unwrap_or(str);
+    let split = str.len() - str.chars().last().map_or(0, char::len_utf8);
+    let (amount, unit) = str.split_at(split);
+    let amount = amount
+        .parse::<i64>()
+        .map_err(|_| format!("Expected a time, a date or a duration like 2h, got {str:?}"))?;
+    let delta = match unit {
+        "s" => TimeDelta::try_seconds(amount),
+        "m" => TimeDelta::try_minutes(amount),
+        "h" => TimeDelta::try_hours(amount),
+        "d" => TimeDelta::try_days(amount),
+        _ => return Err(format!("Expected s, m, h or d as the unit, got {str:?}")),
+    };
+
+    delta
+        .and_then(|delta| now.to_utc().checked_sub_signed(delta))
+        .ok_or_else(|| format!("Duration out of range: {str:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::FixedOffset;
+
+    #[test]
+    fn relative_time() {
+        let now = DateTime::from_timestamp(100_000, 0).unwrap();
+        assert_eq!(
+            parse_time("2h", now).unwrap(),
+            DateTime::from_timestamp(100_000 - 7200, 0).unwrap()
+        );
+        assert_eq!(
+            parse_time("1970-01-01T00:01:00Z", now).unwrap(),
+            DateTime::from_timestamp(60, 0).unwrap()
+        );
+        assert_eq!(parse_time("-2h", now), parse_time("2h", now));
+        assert!(parse_time("2w", now).is_err());
+        assert!(parse_time("", now).is_err());
+    }
+
+    #[test]
+    fn time_zones() {
+        let offset = FixedOffset::east_opt(2 * 3600).unwrap();
+        let now = DateTime::from_timestamp(100_000, 0)
+            .unwrap()
+            .with_timezone(&offset);
+        let utc = |str| DateTime::parse_from_rfc3339(str).unwrap().to_utc();
+
+        assert_eq!(
+            parse_time("2024-06-01T10:00+02:00", now).unwrap(),
+            utc("2024-06-01T08:00:00Z")
+        );
+        assert_eq!(
+            parse_time("2024-06-01 10:00", now).unwrap(),
+            utc("2024-06-01T08:00:00Z")
+        );
+        assert_eq!(
+            parse_time("2024-06-01", now).unwrap(),
+            utc("2024-05-31T22:00:00Z")
+        );
+        assert_eq!(
+            parse_time("yesterday", now).unwrap(),
+            utc("1969-12-31T22:00:00Z")
+        );
+    }
+}
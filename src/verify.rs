@@ -0,0 +1,378 @@
+use crate::{
+    storage::Load,
+    string_cache::CacheInstruction,
+    tape::{InstructionId, InstructionTrait},
+};
+use std::{collections::HashSet, fmt, io::Read, num::NonZeroU64};
+
+/// Decodes a whole tape, looking for malformed regions, references to unknown spans and
+/// unbalanced instructions, e.g. a `StartEvent` without its `FinishedEvent`.
+pub fn verify<R: Read>(mut load: Load<R>) -> Verification {
+    let mut verification = Verification::default();
+    let mut malformed = None;
+    loop {
+        let (id, spans) = match load.fetch_one_cached() {
+            Ok(Some(instruction)) => (instruction.id(), Spans::of(instruction)),
+            Ok(None) => break,
+            Err(e) => {
+                malformed.get_or_insert((load.last_offset(), e.to_string()));
+                load.restart();
+                continue;
+            }
+        };
+
+        let offset = load.last_offset();
+        if let Some((start, error)) = malformed.take() {
+            verification.problems.push(Problem::Malformed {
+                offset: start,
+                end: offset,
+                error,
+            });
+        }
+        verification.offset = offset;
+        verification.instructions += 1;
+        verification.check_pairs(id);
+        verification.check_spans(spans);
+    }
+
+    let end = load.offset();
+    if let Some((offset, error)) = malformed {
+        verification
+            .problems
+            .push(Problem::Malformed { offset, end, error });
+    }
+    if let Some(open) = verification.open.take() {
+        verification
+            .problems
+            .push(Problem::Unfinished { offset: end, open });
+    }
+    verification
+}
+
+/// Result of [`verify`].
+#[derive(Default)]
+pub struct Verification {
+    instructions: u64,
+    problems: Vec<Problem>,
+    /// Offset of the instruction being checked.
+    offset: u64,
+    spans: HashSet<NonZeroU64>,
+    /// Instruction starting the span, record or event not yet finished.
+    open: Option<InstructionId>,
+}
+impl Verification {
+    /// Instructions decoded.
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Problems found, in the order of the tape.
+    pub fn problems(&self) -> &[Problem] {
+        &self.problems
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn write_report<W: fmt::Write>(&self, out: &mut W) -> fmt::Result {
+        writeln!(out, "instructions: {}", self.instructions)?;
+        writeln!(out, "problems: {}", self.problems.len())?;
+        for problem in self.problems.iter() {
+            writeln!(out, "  {problem}")?;
+        }
+        Ok(())
+    }
+
+    /// Checks that spans, records and events are finished before anything else.
+    fn check_pairs(&mut self, id: InstructionId) {
+        let (opens, closes) = match id {
+            InstructionId::NewSpan | InstructionId::NewRecord | InstructionId::StartEvent => {
+                (true, None)
+            }
+            InstructionId::FinishedSpan => (false, Some(InstructionId::NewSpan)),
+            InstructionId::FinishedRecord => (false, Some(InstructionId::NewRecord)),
+            InstructionId::FinishedEvent => (false, Some(InstructionId::StartEvent)),
+            InstructionId::AddValue | InstructionId::NewString => (false, None),
+            _ => {
+                self.close(id, None);
+                return;
+            }
+        };
+
+        if opens {
+            self.close(id, None);
+            self.open = Some(id);
+        } else if let Some(start) = closes {
+            self.close(id, Some(start));
+        } else if id == InstructionId::AddValue && self.open.is_none() {
+            self.problems.push(Problem::Unbalanced {
+                offset: self.offset,
+                instruction: id,
+                open: None,
+            });
+        }
+    }
+
+    /// Ends the open instruction with `id`, which expects `start` to be open.
+    fn close(&mut self, id: InstructionId, start: Option<InstructionId>) {
+        let open = self.open.take();
+        if open != start {
+            self.problems.push(Problem::Unbalanced {
+                offset: self.offset,
+                instruction: id,
+                open,
+            });
+        }
+    }
+
+    fn check_spans(&mut self, spans: Spans) {
+        let known = match spans {
+            Spans::None => return,
+            // Live spans are replayed after a restart
+            Spans::Restart => {
+                self.spans.clear();
+                return;
+            }
+            Spans::New { parent, span } => {
+                self.spans.insert(span);
+                match parent {
+                    Some(parent) => (parent, self.spans.contains(&parent)),
+                    None => return,
+                }
+            }
+            Spans::Reference(span) => (span, self.spans.contains(&span)),
+            Spans::Delete(span) => (span, self.spans.remove(&span)),
+        };
+
+        if let (span, false) = known {
+            self.problems.push(Problem::DanglingSpan {
+                offset: self.offset,
+                span,
+            });
+        }
+    }
+}
+
+/// Spans created, used or deleted by an instruction.
+enum Spans {
+    None,
+    Restart,
+    New {
+        parent: Option<NonZeroU64>,
+        span: NonZeroU64,
+    },
+    Reference(NonZeroU64),
+    Delete(NonZeroU64),
+}
+impl Spans {
+    fn of(instruction: CacheInstruction) -> Self {
+        match instruction {
+            CacheInstruction::Restart => Spans::Restart,
+            CacheInstruction::NewSpan { parent, span, .. } => Spans::New { parent, span },
+            CacheInstruction::StartEvent {
+                span: Some(span), ..
+            }
+            | CacheInstruction::NewRecord(span)
+            | CacheInstruction::EnterSpan { span, .. }
+            | CacheInstruction::ExitSpan { span, .. }
+            | CacheInstruction::SpanTiming { span, .. } => Spans::Reference(span),
+            CacheInstruction::DeleteSpan(span) => Spans::Delete(span),
+            _ => Spans::None,
+        }
+    }
+}
+
+/// Problem found by [`verify`], with the offset where it was found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// Bytes that could not be decoded, skipped up to the next `Restart` at `end`.
+    Malformed {
+        offset: u64,
+        end: u64,
+        error: String,
+    },
+    /// Reference to a span not created, or already deleted.
+    DanglingSpan { offset: u64, span: NonZeroU64 },
+    /// Instruction not expected while `open` is not finished, e.g. a `FinishedEvent` without its
+    /// `StartEvent`.
+    Unbalanced {
+        offset: u64,
+        instruction: InstructionId,
+        open: Option<InstructionId>,
+    },
+    /// Tape ended while `open` was not finished.
+    Unfinished { offset: u64, open: InstructionId },
+}
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Malformed { offset, end, error } => write!(
+                f,
+                "{offset}: malformed, {} bytes skipped: {error}",
+                end - offset
+            ),
+            Problem::DanglingSpan { offset, span } => {
+                write!(f, "{offset}: reference to unknown span {span}")
+            }
+            Problem::Unbalanced {
+                offset,
+                instruction,
+                open: Some(open),
+            } => write!(
+                f,
+                "{offset}: {instruction:?} while {open:?} is not finished"
+            ),
+            Problem::Unbalanced {
+                offset,
+                instruction,
+                open: None,
+            } => write!(f, "{offset}: {instruction:?} without its start"),
+            Problem::Unfinished { offset, open } => {
+                write!(f, "{offset}: tape ends while {open:?} is not finished")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        storage::{RESTART_HEADER, Store},
+        string_cache::StringCache,
+        tape::{FieldValue, Instruction, TapeMachine, Value},
+        test_util::{golden_tape, new_span, span, start_event},
+    };
+    use chrono::DateTime;
+    use tracing::Level;
+
+    fn report(verification: &Verification) -> String {
+        let mut out = String::new();
+        verification.write_report(&mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn problems() {
+        let event = |span| start_event(DateTime::UNIX_EPOCH, span, "target", Level::INFO);
+        let mut store = StringCache::new(Store::new(Vec::new()));
+        store.handle(Instruction::Restart);
+        new_span(&mut store, None, span(1), "request", &[]);
+        store.handle(event(Some(span(1))));
+        store.handle(Instruction::FinishedEvent);
+        store.handle(event(Some(span(2))));
+        store.handle(Instruction::FinishedEvent);
+        store.handle(event(None));
+        let mut tape = store.into_inner().into_inner();
+        let garbage = tape.len() as u64;
+        tape.extend_from_slice(&[0x7f, 0x7f, 0x7f]);
+        let mut store = StringCache::new(Store::new(tape));
+        store.handle(Instruction::Restart);
+        store.handle(Instruction::DeleteSpan(span(1)));
+        store.handle(event(None));
+        let tape = store.into_inner().into_inner();
+
+        let verification = verify(Load::new(tape.as_slice()));
+        assert_eq!(verification.instructions(), 14);
+        let problems = verification.problems();
+        assert!(matches!(
+            problems[0],
+            Problem::DanglingSpan { span: s, .. } if s == span(2)
+        ));
+        assert!(matches!(
+            problems[1],
+            Problem::Malformed { offset, end, .. } if offset == garbage && end == garbage + 3
+        ));
+        assert!(matches!(
+            problems[2],
+            Problem::Unbalanced {
+                instruction: InstructionId::Restart,
+                open: Some(InstructionId::StartEvent),
+                ..
+            }
+        ));
+        assert!(matches!(
+            problems[3],
+            Problem::DanglingSpan { span: s, .. } if s == span(1)
+        ));
+        assert!(matches!(
+            problems[4],
+            Problem::Unfinished {
+                open: InstructionId::StartEvent,
+                ..
+            }
+        ));
+        assert_eq!(problems.len(), 5);
+    }
+
+    #[test]
+    fn valid_tapes() {
+        let verification = verify(Load::new([].as_slice()));
+        assert_eq!(report(&verification), "instructions: 0\nproblems: 0\n");
+
+        let mut store = StringCache::new(Store::new(Vec::new()));
+        golden_tape(&mut store);
+        let tape = store.into_inner().into_inner();
+        let verification = verify(Load::new(tape.as_slice()));
+        // The last span is deleted after a restart that did not replay it
+        assert!(matches!(
+            verification.problems(),
+            [Problem::DanglingSpan { span: s, .. }] if *s == span(1)
+        ));
+    }
+
+    #[test]
+    fn value_without_start() {
+        let mut tape = Vec::new();
+        Store::do_handle(&mut tape, Instruction::Restart).unwrap();
+        let value = Value::Bool(true);
+        Store::do_handle(
+            &mut tape,
+            Instruction::AddValue(FieldValue {
+                name: "orphan",
+                value,
+            }),
+        )
+        .unwrap();
+
+        let verification = verify(Load::new(tape.as_slice()));
+        assert_eq!(
+            verification.problems(),
+            [Problem::Unbalanced {
+                offset: RESTART_HEADER.len() as u64,
+                instruction: InstructionId::AddValue,
+                open: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn truncated_tapes() {
+        let mut store = StringCache::new(Store::new(Vec::new()));
+        golden_tape(&mut store);
+        let tape = store.into_inner().into_inner();
+
+        let whole = verify(Load::new(tape.as_slice()));
+
+        // Only the cut instruction is reported, besides the problems of the whole tape
+        for len in 0..tape.len() {
+            let verification = verify(Load::new(&tape[..len]));
+            let cut = verification
+                .problems()
+                .iter()
+                .filter(|problem| !whole.problems().contains(problem))
+                .collect::<Vec<_>>();
+            assert!(
+                matches!(
+                    cut[..],
+                    [] | [Problem::Unfinished { .. }]
+                        | [Problem::Malformed { .. }]
+                        | [Problem::Malformed { .. }, Problem::Unfinished { .. }]
+                ),
+                "{len}: {}",
+                report(&verification)
+            );
+        }
+    }
+}